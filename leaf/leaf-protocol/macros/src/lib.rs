@@ -174,44 +174,42 @@ pub fn derive_component(input: TokenStream) -> TokenStream {
     }
 
     let expected_schema_id = attr_schema_id.map(|x| Digest(Hash::from_str(&x).unwrap()));
-    let schema_id = if !attr_no_compute_schema_id {
-        let spec_hash: Digest = {
-            let components = spec_files
-                .into_iter()
-                .map(|path| {
-                    let schema_id_str = path.file_name().unwrap().to_str().unwrap();
-                    let schema_id_str = if let Some((_prefix, id)) = schema_id_str.rsplit_once('_')
-                    {
-                        if let Some((id, _suffix)) = id.split_once('.') {
-                            id
-                        } else {
-                            id
-                        }
+    let spec_hash: Digest = {
+        let components = spec_files
+            .into_iter()
+            .map(|path| {
+                let schema_id_str = path.file_name().unwrap().to_str().unwrap();
+                let schema_id_str = if let Some((_prefix, id)) = schema_id_str.rsplit_once('_') {
+                    if let Some((id, _suffix)) = id.split_once('.') {
+                        id
                     } else {
-                        schema_id_str
-                    };
-                    let schema_id = Digest(Hash::from_str(schema_id_str).unwrap());
-                    let mut buf = Vec::new();
-                    let data = std::fs::read(&path).unwrap();
-                    ComponentKind::Unencrypted(ComponentData {
-                        schema: schema_id,
-                        data,
-                    })
-                    .serialize(&mut buf)
-                    .unwrap();
-                    let component_id = Digest(Hash::from(iroh_blake3::hash(&buf)));
-
-                    ComponentEntry {
-                        schema_id: Some(schema_id),
-                        component_id,
+                        id
                     }
+                } else {
+                    schema_id_str
+                };
+                let schema_id = Digest(Hash::from_str(schema_id_str).unwrap());
+                let mut buf = Vec::new();
+                let data = std::fs::read(&path).unwrap();
+                ComponentKind::Unencrypted(ComponentData {
+                    schema: schema_id,
+                    data,
                 })
-                .collect::<Vec<_>>();
-            let mut entity = Entity { components };
-            entity.sort_components();
-            entity.compute_digest()
-        };
+                .serialize(&mut buf)
+                .unwrap();
+                let component_id = Digest(Hash::from(iroh_blake3::hash(&buf)));
 
+                ComponentEntry {
+                    schema_id: Some(schema_id),
+                    component_id,
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut entity = Entity { components };
+        entity.sort_components();
+        entity.compute_digest()
+    };
+    let schema_id = if !attr_no_compute_schema_id {
         let mut schema_bytes = Vec::new();
         (&component_name, spec_hash)
             .serialize(&mut schema_bytes)
@@ -228,6 +226,7 @@ pub fn derive_component(input: TokenStream) -> TokenStream {
         );
     };
     let schema_id_bytes = *schema_id.0.as_bytes();
+    let spec_hash_bytes = *spec_hash.0.as_bytes();
 
     if !attr_no_check_schema_id && !attr_no_compute_schema_id {
         let expected = expected_schema_id.unwrap();
@@ -244,6 +243,13 @@ pub fn derive_component(input: TokenStream) -> TokenStream {
             fn schema_id() -> Digest {
                 Digest::from_bytes([#(#schema_id_bytes),*])
             }
+            fn schema() -> Schema {
+                Schema {
+                    name: #component_name.into(),
+                    format: <Self as HasBorshSchema>::borsh_schema(),
+                    specification: Digest::from_bytes([#(#spec_hash_bytes),*]),
+                }
+            }
         }
     }
     .into()
@@ -323,3 +329,116 @@ pub fn derive_has_borsh_schema(input: TokenStream) -> TokenStream {
     }
     .into()
 }
+
+/// Derive macro for the `LeafEntity` trait.
+///
+/// ```ignore
+/// #[derive(LeafEntity)]
+/// struct Profile {
+///     name: Name,
+///     description: Option<Description>,
+///     tags: Vec<Tag>,
+/// }
+/// ```
+///
+/// Every field must be a `Component`, which may be wrapped in an `Option` or a `Vec`:
+///
+/// - `C` - The entity must have a component of this type, and `apply()` will replace any existing
+///   components of the same type with it.
+/// - `Option<C>` - The first component of this type, if there is one. `apply()` will remove the
+///   components of this type when it is `None`.
+/// - `Vec<C>` - All of the components of this type. `apply()` will replace all existing components
+///   of this type with the ones in the list.
+///
+/// Like the `Component` derive, the generated code expects the `leaf_protocol::prelude` to be in
+/// scope.
+#[proc_macro_derive(LeafEntity)]
+pub fn derive_leaf_entity(input: TokenStream) -> TokenStream {
+    let input = venial::parse_item(input.into()).unwrap();
+
+    let venial::Item::Struct(s) = input else {
+        throw!(input, "You may only derive LeafEntity on structs.");
+    };
+    let name = &s.name;
+    let venial::Fields::Named(fields) = &s.fields else {
+        throw!(
+            s.fields,
+            "LeafEntity may only be derived on structs with named fields."
+        );
+    };
+
+    let mut field_names = Vec::new();
+    let mut load_exprs = Vec::new();
+    let mut apply_stmts = Vec::new();
+    for field in fields.fields.items() {
+        let field_name = &field.name;
+        field_names.push(field_name);
+        match component_field_kind(&field.ty.tokens) {
+            ComponentField::Required(ty) => {
+                load_exprs.push(quote! { entity.require_component::<#ty>().await? });
+                apply_stmts.push(quote! { entity.set_component(self.#field_name)?; });
+            }
+            ComponentField::Optional(ty) => {
+                load_exprs.push(quote! { entity.get_component::<#ty>().await? });
+                apply_stmts.push(quote! {
+                    entity.del_components::<#ty>();
+                    if let Some(component) = self.#field_name {
+                        entity.add_component(component)?;
+                    }
+                });
+            }
+            ComponentField::Many(ty) => {
+                load_exprs.push(quote! { entity.get_components::<#ty>().await? });
+                apply_stmts.push(quote! {
+                    entity.del_components::<#ty>();
+                    for component in self.#field_name {
+                        entity.add_component(component)?;
+                    }
+                });
+            }
+        }
+    }
+
+    quote! {
+        impl LeafEntity for #name {
+            async fn load<S: LeafStore>(entity: &LoadedEntity<S>) -> anyhow::Result<Self> {
+                Ok(Self {
+                    #(#field_names: #load_exprs),*
+                })
+            }
+            fn apply<S: LeafStore>(self, entity: &mut LoadedEntity<S>) -> anyhow::Result<()> {
+                #(#apply_stmts)*
+                Ok(())
+            }
+        }
+    }
+    .into()
+}
+
+/// How a field of a `LeafEntity` struct maps to the components on the entity.
+enum ComponentField {
+    Required(proc_macro2::TokenStream),
+    Optional(proc_macro2::TokenStream),
+    Many(proc_macro2::TokenStream),
+}
+
+/// Check whether a field type is a component, or an `Option` or `Vec` of components.
+fn component_field_kind(ty: &[proc_macro2::TokenTree]) -> ComponentField {
+    use proc_macro2::TokenTree;
+    let is_punct =
+        |token: &TokenTree, c: char| matches!(token, TokenTree::Punct(p) if p.as_char() == c);
+
+    let full_type = || ty.iter().cloned().collect();
+    let (Some(open), Some(close)) = (
+        ty.iter().position(|x| is_punct(x, '<')),
+        ty.iter().rposition(|x| is_punct(x, '>')),
+    ) else {
+        return ComponentField::Required(full_type());
+    };
+    let inner = ty[open + 1..close].iter().cloned().collect();
+    match open.checked_sub(1).map(|i| &ty[i]) {
+        Some(TokenTree::Ident(wrapper)) if wrapper == "Option" => ComponentField::Optional(inner),
+        Some(TokenTree::Ident(wrapper)) if wrapper == "Vec" => ComponentField::Many(inner),
+        _ => ComponentField::Required(full_type()),
+    }
+}
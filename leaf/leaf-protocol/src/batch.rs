@@ -0,0 +1,341 @@
+//! Batches of component changes that are made to multiple entities at once.
+
+use anyhow::Result;
+
+use crate::{
+    component_references, index,
+    store::{EntityWrite, LeafStore},
+    types::{ComponentData, ComponentKind, ExactLink, Reference},
+    Component, Digest, Leaf, LoadedEntity, SnapshotConflict,
+};
+
+/// A single change in a [`Batch`].
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone)]
+pub enum BatchOp {
+    /// Add a component to the entity, creating the entity if it doesn't exist.
+    AddComponent {
+        link: ExactLink,
+        component: ComponentData,
+    },
+    /// Delete all of the components with the given schema from the entity.
+    DelComponents { link: ExactLink, schema: Digest },
+    /// Require the entity to have the given snapshot digest when the batch is committed, or the
+    /// whole batch will fail with a [`BatchConflict`].
+    ///
+    /// A null digest means that the entity must not exist yet.
+    Expect { link: ExactLink, digest: Digest },
+}
+
+impl BatchOp {
+    /// The link to the entity that this operation applies to.
+    pub fn link(&self) -> &ExactLink {
+        match self {
+            BatchOp::AddComponent { link, .. }
+            | BatchOp::DelComponents { link, .. }
+            | BatchOp::Expect { link, .. } => link,
+        }
+    }
+}
+
+/// Error returned by [`Batch::commit()`] and [`LeafStore::store_entities()`] when one of the
+/// entities doesn't have the snapshot that it was expected to have.
+///
+/// All of the entities are checked before any of them are written, so nothing has been written
+/// when this is returned.
+#[derive(Debug, Clone)]
+pub struct BatchConflict {
+    /// The entity that didn't have its expected snapshot.
+    pub conflict: SnapshotConflict,
+}
+
+impl From<SnapshotConflict> for BatchConflict {
+    fn from(conflict: SnapshotConflict) -> Self {
+        Self { conflict }
+    }
+}
+
+impl std::fmt::Display for BatchConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.conflict)
+    }
+}
+
+impl std::error::Error for BatchConflict {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.conflict)
+    }
+}
+
+/// A builder for a set of component changes across multiple entities, created with
+/// [`Leaf::batch()`].
+///
+/// Nothing is written until [`commit()`][Self::commit] is called.
+#[derive(Debug)]
+pub struct Batch<'a, S: LeafStore + Clone> {
+    leaf: &'a Leaf<S>,
+    /// The operations in the batch, along with the blobs and snapshots referenced by added
+    /// components, if we know them yet.
+    ///
+    /// The references of raw component data are looked up from the component's published schema
+    /// when the batch is committed.
+    ops: Vec<(BatchOp, Option<Vec<Reference>>)>,
+}
+
+impl<'a, S: LeafStore + Clone> Batch<'a, S> {
+    pub(crate) fn new(leaf: &'a Leaf<S>) -> Self {
+        Self {
+            leaf,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Add an operation to the batch.
+    pub fn op(&mut self, op: BatchOp) -> &mut Self {
+        self.ops.push((op, None));
+        self
+    }
+
+    /// Add a component to the entity at the link.
+    pub fn add_component<C: Component, L: Into<ExactLink>>(
+        &mut self,
+        link: L,
+        component: C,
+    ) -> Result<&mut Self> {
+        let component = component.make_data()?;
+        let references = C::borsh_schema().references(&component.data)?;
+        let op = BatchOp::AddComponent {
+            link: link.into(),
+            component,
+        };
+        self.ops.push((op, Some(references)));
+        Ok(self)
+    }
+
+    /// Replace all of the components of the same type on the entity at the link with the given
+    /// component.
+    pub fn set_component<C: Component, L: Into<ExactLink>>(
+        &mut self,
+        link: L,
+        component: C,
+    ) -> Result<&mut Self> {
+        let link = link.into();
+        self.del_components::<C, _>(link.clone());
+        self.add_component(link, component)
+    }
+
+    /// Add raw component data to the entity at the link.
+    ///
+    /// When the batch is committed, the component's published schema is used to find the blobs
+    /// and snapshots that it references, so that they are pinned along with it, like with
+    /// [`LoadedEntity::add_component_data()`].
+    pub fn add_component_data<L: Into<ExactLink>>(
+        &mut self,
+        link: L,
+        component: ComponentData,
+    ) -> &mut Self {
+        self.op(BatchOp::AddComponent {
+            link: link.into(),
+            component,
+        })
+    }
+
+    /// Delete all of the components of the given type from the entity at the link.
+    pub fn del_components<C: Component, L: Into<ExactLink>>(&mut self, link: L) -> &mut Self {
+        self.del_components_by_schema(link, C::schema_id())
+    }
+
+    /// Delete all of the components with the given schema from the entity at the link.
+    pub fn del_components_by_schema<L: Into<ExactLink>>(
+        &mut self,
+        link: L,
+        schema: Digest,
+    ) -> &mut Self {
+        self.op(BatchOp::DelComponents {
+            link: link.into(),
+            schema,
+        })
+    }
+
+    /// Only commit the batch if the entity at the link has the given snapshot digest.
+    ///
+    /// A null digest means that the entity must not exist yet.
+    pub fn expect<L: Into<ExactLink>>(&mut self, link: L, digest: Digest) -> &mut Self {
+        self.op(BatchOp::Expect {
+            link: link.into(),
+            digest,
+        })
+    }
+
+    /// Apply the changes in the batch, returning the new snapshot digest of each entity that was
+    /// changed, in the order that they first appear in the batch.
+    ///
+    /// Entities that only have [`expect()`][Self::expect] operations are checked, but not written.
+    ///
+    /// Every changed entity is written with [`LeafStore::store_entities()`], only if it still has
+    /// the snapshot that it had when the batch loaded it, and the entities that are only expected
+    /// are checked again in the same call. If an entity has been changed, or doesn't
+    /// have the [`expect()`][Self::expect]ed digest, a [`BatchConflict`] error is returned and
+    /// nothing is written. The Iroh store has no transactions, so an I/O error part way through
+    /// writing the entities can still leave some of them written.
+    ///
+    /// If the batch would give an entity the same value in a unique index as another entity, either
+    /// in the store or in the batch, an [`IndexConflict`][crate::index::IndexConflict] error is
+    /// returned before anything is written.
+    pub async fn commit(self) -> Result<Vec<(ExactLink, Digest)>> {
+        struct Staged<S: LeafStore> {
+            entity: LoadedEntity<S>,
+            loaded_digest: Digest,
+            changed: bool,
+        }
+        let mut staged: Vec<Staged<S>> = Vec::new();
+        for (op, references) in self.ops {
+            let index = match staged.iter().position(|x| &x.entity.link == op.link()) {
+                Some(index) => index,
+                None => {
+                    let entity = self.leaf.entity(op.link().clone()).await?.get_or_init();
+                    staged.push(Staged {
+                        loaded_digest: entity.digest,
+                        entity,
+                        changed: false,
+                    });
+                    staged.len() - 1
+                }
+            };
+            let Staged {
+                entity,
+                loaded_digest,
+                changed,
+            } = &mut staged[index];
+
+            match op {
+                BatchOp::AddComponent { component, .. } => {
+                    let references = match references {
+                        Some(references) => references,
+                        None => component_references(&self.leaf.store, &component).await?,
+                    };
+                    let component = ComponentKind::Unencrypted(component);
+                    entity.push_pending_component(component, references)?;
+                    *changed = true;
+                }
+                BatchOp::DelComponents { schema, .. } => {
+                    entity.del_components_by_schema(schema);
+                    *changed = true;
+                }
+                BatchOp::Expect { link, digest } => {
+                    let actual = (*loaded_digest != Digest::default()).then_some(*loaded_digest);
+                    if let Some(conflict) = SnapshotConflict::check(&link, digest, actual) {
+                        return Err(BatchConflict::from(conflict).into());
+                    }
+                }
+            }
+        }
+
+        let mut prepared = Vec::new();
+        let mut writes = Vec::new();
+        for mut staged in staged {
+            if !staged.changed {
+                writes.push(EntityWrite {
+                    link: staged.entity.link.clone(),
+                    expected: staged.loaded_digest,
+                    data: None,
+                });
+                continue;
+            }
+            let old_snapshot_id =
+                (staged.loaded_digest != Digest::default()).then_some(staged.loaded_digest);
+            let save = staged.entity.prepare_save(old_snapshot_id).await?;
+            writes.push(EntityWrite {
+                link: staged.entity.link.clone(),
+                expected: staged.loaded_digest,
+                data: Some(save.data.clone()),
+            });
+            prepared.push((staged.entity, old_snapshot_id, save));
+        }
+        // Collected so that holding it across the write keeps the future `Send`.
+        let planned = prepared
+            .iter()
+            .map(|(entity, _, save)| (&entity.link, &save.field_index_updates[..]))
+            .collect::<Vec<_>>();
+        let store = &self.leaf.store;
+        index::with_unique_values(store, planned, store.store_entities(writes)).await?;
+        let mut digests = Vec::with_capacity(prepared.len());
+        for (mut entity, old_snapshot_id, save) in prepared {
+            entity.finish_save(old_snapshot_id, save).await?;
+            digests.push((entity.link, entity.digest));
+        }
+        Ok(digests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::Name,
+        test_util::{leaf, link, save_name},
+    };
+
+    #[tokio::test]
+    async fn expect_conflict_writes_nothing() {
+        let leaf = leaf();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        let digest = save_name(&leaf, &a, "a").await.unwrap();
+
+        let mut batch = leaf.batch();
+        batch.set_component(b.clone(), Name("b".into())).unwrap();
+        batch.expect(a.clone(), Digest::default());
+        let error = batch.commit().await.unwrap_err();
+        let conflict = error.downcast_ref::<BatchConflict>().unwrap();
+        assert_eq!(conflict.conflict.actual, Some(digest));
+        assert!(leaf.store.get_entity(&b).await.unwrap().is_none());
+
+        let mut batch = leaf.batch();
+        batch.set_component(b.clone(), Name("b".into())).unwrap();
+        batch.expect(a.clone(), digest);
+        batch.commit().await.unwrap();
+        assert!(leaf.store.get_entity(&b).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn expected_entities_are_checked_when_committing() {
+        let leaf = leaf();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        let digest = save_name(&leaf, &a, "a").await.unwrap();
+
+        let mut batch = leaf.batch();
+        batch.expect(a.clone(), digest);
+        batch.set_component(b.clone(), Name("b".into())).unwrap();
+        let changed = save_name(&leaf, &a, "changed").await.unwrap();
+        let error = batch.commit().await.unwrap_err();
+        let conflict = error.downcast_ref::<BatchConflict>().unwrap();
+        assert_eq!(conflict.conflict.actual, Some(changed));
+        assert!(leaf.store.get_entity(&b).await.unwrap().is_none());
+        assert_eq!(leaf.store.get_entity(&a).await.unwrap(), Some(changed));
+
+        // An expect-only batch writes nothing and returns no digests.
+        let mut batch = leaf.batch();
+        batch.expect(a.clone(), changed);
+        assert!(batch.commit().await.unwrap().is_empty());
+        assert_eq!(leaf.store.get_entity(&a).await.unwrap(), Some(changed));
+    }
+
+    #[tokio::test]
+    async fn commit_returns_the_new_digests_in_order() {
+        let leaf = leaf();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        save_name(&leaf, &b, "old").await.unwrap();
+
+        let mut batch = leaf.batch();
+        batch.set_component(b.clone(), Name("b".into())).unwrap();
+        batch.expect(a.clone(), Digest::default());
+        batch.set_component(a.clone(), Name("a".into())).unwrap();
+        let digests = batch.commit().await.unwrap();
+
+        let stored_a = leaf.store.get_entity(&a).await.unwrap().unwrap();
+        let stored_b = leaf.store.get_entity(&b).await.unwrap().unwrap();
+        assert_eq!(digests, [(b.clone(), stored_b), (a, stored_a)]);
+        let entity = leaf.entity(b).await.unwrap().entity().unwrap();
+        let name = entity.get_component::<Name>().await.unwrap().unwrap();
+        assert_eq!(name.0, "b");
+    }
+}
@@ -4,7 +4,7 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use leaf_protocol_macros::HasBorshSchema;
 
 use crate::{
-    types::{BorshSchema, HasBorshSchema},
+    types::{Blob, BorshSchema, HasBorshSchema, Link, Schema},
     Component, Digest,
 };
 
@@ -31,54 +31,85 @@ pub struct Name(pub String);
 )]
 pub struct Description(pub String);
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
-// #[component(
-//     specification = "leaf-schemas/DateCreated",
-//     schema_id = "6qrsqqeso44tmy4y2gpmb7o4x2j3kdzjeuypfnpvmc74zahhsiwa"
-// )]
-// pub struct DateCreated(pub u64);
+/// The time that the entity or what it represents was created, in seconds since the Unix epoch.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug, Clone, Copy)]
+#[component(
+    specification = "leaf-schemas/DateCreated",
+    schema_id = "w446roxsr6l3wclga7e4r65qvbhksawgg3l3doijeuoqj7k2xaya"
+)]
+pub struct DateCreated(pub u64);
+
+/// The time that the entity or what it represents was last updated, in seconds since the Unix
+/// epoch.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug, Clone, Copy)]
+#[component(
+    specification = "leaf-schemas/DateUpdated",
+    schema_id = "kjyvrj2w4zsn5jk7rkghxpvuronxhpwdycpqbcqhfgvoezqb74qa"
+)]
+pub struct DateUpdated(pub u64);
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
-// #[component(
-//     specification = "leaf-schemas/DateUpdated",
-//     schema_id = "3qdqzps5y3zu4sc4vc737xkwzl6va2vatcmtxplgruu2fd4joeqq"
-// )]
-// pub struct DateUpdated(pub u64);
+/// Rich text in the [CommonMark](https://spec.commonmark.org/0.31.2/) markdown format.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
+#[component(
+    specification = "leaf-schemas/CommonMark",
+    schema_id = "tdpzll7gid2vpf7y3h24qubce3w3qkbuqj3brxvqrcmc7kaxur4q"
+)]
+pub struct CommonMark(pub String);
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
-// #[component(
-//     specification = "leaf-schemas/CommonMark",
-//     schema_id = "uy5tkom2uchinxo5pyouwwobj4efhsvw7bws2rjkobl66je7cnbq"
-// )]
-// pub struct CommonMark(pub u64);
+/// Indicates that the entity is a reply to another entity, such as a chat message reply or a
+/// comment on a blog post.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
+#[component(
+    specification = "leaf-schemas/ReplyTo",
+    schema_id = "tx7rebqtmkaj2fcgrrpmrhgjwa3vcfrxzdmsxcibvrkaheagfqrq"
+)]
+pub struct ReplyTo(pub Link);
+
+/// Links to another entity that is meant to be embedded in this one.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
+#[component(
+    specification = "leaf-schemas/Embed",
+    schema_id = "en7dxldpu44xixvkv5zdvkw3shwiitbxuurevnivuiskxlwiklfa"
+)]
+pub struct Embed(pub Link);
+
+/// An image associated with the entity, such as its feature image, icon, or avatar.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
+#[component(
+    specification = "leaf-schemas/Image",
+    schema_id = "gq5bcyrckg2npzlxvuajnsgjsme6rano6a7ndgbw4auzby5s57va"
+)]
+pub struct Image {
+    pub mime_type: String,
+    pub size: ImageSize,
+    pub data: Blob,
+}
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
-// #[component(
-//     specification = "leaf-schemas/ReplyTo",
-//     schema_id = "wodv3ehssd64ooyl7bgzocbyvznihzranh4b5lgi6e7uh6wvbczq"
-// )]
-// pub struct ReplyTo(pub Link);
+/// The size of an [`Image`] in pixels.
+#[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Debug, Clone, Copy)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
-// #[component(
-//     specification = "leaf-schemas/Embed",
-//     schema_id = "wvnxhyf4p3ido3pfvvfjth4g3qt7sjusf3plfrqpo44l7o6cfa4a"
-// )]
-// pub struct Embed(pub Link);
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{image, leaf},
+        types::Value,
+        Component, Digest,
+    };
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Component, Debug)]
-// #[component(
-//     specification = "leaf-schemas/Image",
-//     schema_id = "b7yadsp7e2lt6swf7d6lc6e4244h2xk327k3aekzafcp4jnxi2jq"
-// )]
-// pub struct Image {
-//     mime_type: String,
-//     size: ImageSize,
-//     data: Blob,
-// }
+    #[tokio::test]
+    async fn components_round_trip_through_json() {
+        let leaf = leaf();
+        leaf.publish_standard_schemas().await.unwrap();
+        let data = image(Digest::new(b"image")).make_data().unwrap();
+        let schema = leaf.get_schema(data.schema).await.unwrap().unwrap();
 
-// #[derive(BorshDeserialize, BorshSerialize, HasBorshSchema, Debug)]
-// pub struct ImageSize {
-//     width: u32,
-//     height: u32,
-// }
+        let json = Value::decode(&schema.format, &data.data).unwrap().to_json();
+        assert_eq!(json["size"]["width"], 1);
+        let value = Value::from_json(&schema.format, &json).unwrap();
+        assert_eq!(value.encode(&schema.format).unwrap(), data.data);
+    }
+}
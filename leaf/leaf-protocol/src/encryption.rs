@@ -0,0 +1,187 @@
+//! Built-in encryption algorithms for encrypted components.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::{
+    store::EncryptionAlgorithmImpl,
+    types::{EncryptionAlgorithm, Entity},
+    Digest,
+};
+
+/// The name of the [`XChaCha20Poly1305Algorithm`] encryption algorithm.
+pub const XCHACHA20_POLY1305_NAME: &str = "XChaCha20-Poly1305";
+
+/// The [XChaCha20-Poly1305][xc] authenticated encryption algorithm.
+///
+/// Encrypted data is made up of the random 24 byte nonce followed by the ciphertext.
+///
+/// This keeps a set of 32 byte keys in memory, indexed by key IDs that are derived from the keys.
+/// Keys are not persisted, so they must be added again with [`add_key()`][Self::add_key] every
+/// time the store is created.
+///
+/// [xc]: https://datatracker.ietf.org/doc/html/draft-arciszewski-xchacha
+#[derive(Clone, Default)]
+pub struct XChaCha20Poly1305Algorithm {
+    keys: Arc<RwLock<HashMap<[u8; 32], [u8; 32]>>>,
+}
+
+impl std::fmt::Debug for XChaCha20Poly1305Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Don't print the keys
+        f.debug_struct("XChaCha20Poly1305Algorithm")
+            .finish_non_exhaustive()
+    }
+}
+
+impl XChaCha20Poly1305Algorithm {
+    /// Get the [`EncryptionAlgorithm`] that is stored in components encrypted with this algorithm.
+    pub fn algorithm() -> EncryptionAlgorithm {
+        EncryptionAlgorithm {
+            name: XCHACHA20_POLY1305_NAME.into(),
+            // There are no specification components for the algorithm yet, so we use an empty
+            // specification entity.
+            specification: Entity::default().compute_digest(),
+        }
+    }
+
+    /// Get the key ID that will be used for the given key.
+    pub fn key_id(key: &[u8; 32]) -> [u8; 32] {
+        let mut buf = Vec::with_capacity(XCHACHA20_POLY1305_NAME.len() + 32);
+        buf.extend_from_slice(XCHACHA20_POLY1305_NAME.as_bytes());
+        buf.extend_from_slice(key);
+        *Digest::new(&buf).as_bytes()
+    }
+
+    /// Add a key that may be used to encrypt and decrypt data, returning its key ID.
+    pub fn add_key(&self, key: [u8; 32]) -> [u8; 32] {
+        let key_id = Self::key_id(&key);
+        self.keys.write().unwrap().insert(key_id, key);
+        key_id
+    }
+
+    /// Generate a new random key and add it, returning the key and its key ID.
+    pub fn generate_key(&self) -> ([u8; 32], [u8; 32]) {
+        let key: [u8; 32] = XChaCha20Poly1305::generate_key(&mut OsRng).into();
+        (key, self.add_key(key))
+    }
+
+    /// Remove the key with the given ID.
+    pub fn remove_key(&self, key_id: [u8; 32]) {
+        self.keys.write().unwrap().remove(&key_id);
+    }
+
+    fn cipher(&self, key_id: [u8; 32]) -> anyhow::Result<XChaCha20Poly1305> {
+        let keys = self.keys.read().unwrap();
+        let key = keys.get(&key_id).ok_or_else(|| {
+            anyhow::format_err!("Encryption key not found: {}", Digest::from_bytes(key_id))
+        })?;
+        Ok(XChaCha20Poly1305::new(key.into()))
+    }
+}
+
+impl EncryptionAlgorithmImpl<Digest> for XChaCha20Poly1305Algorithm {
+    fn id(&self) -> Digest {
+        Self::algorithm().id()
+    }
+
+    fn algorithm(&self) -> EncryptionAlgorithm {
+        Self::algorithm()
+    }
+
+    fn has_key(&self, key_id: [u8; 32]) -> bool {
+        self.keys.read().unwrap().contains_key(&key_id)
+    }
+
+    fn encrypt(&self, key_id: [u8; 32], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let cipher = self.cipher(key_id)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, data)
+            .map_err(|_| anyhow::format_err!("Error encrypting data"))?;
+
+        let mut buf = Vec::with_capacity(nonce.len() + ciphertext.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        Ok(buf)
+    }
+
+    fn decrypt(&self, key_id: [u8; 32], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let cipher = self.cipher(key_id)?;
+        const NONCE_LEN: usize = 24;
+        if data.len() < NONCE_LEN {
+            anyhow::bail!("Encrypted data is too short");
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::format_err!("Error decrypting data"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::Name,
+        test_util::{leaf, link},
+    };
+
+    #[test]
+    fn data_round_trips_with_the_right_key() {
+        let algorithm = XChaCha20Poly1305Algorithm::default();
+        let (_, key_id) = algorithm.generate_key();
+        let (_, other_key_id) = algorithm.generate_key();
+
+        let encrypted = algorithm.encrypt(key_id, b"secret").unwrap();
+        assert_ne!(&encrypted[24..], b"secret");
+        assert_eq!(algorithm.decrypt(key_id, &encrypted).unwrap(), b"secret");
+        assert!(algorithm.decrypt(other_key_id, &encrypted).is_err());
+
+        algorithm.remove_key(key_id);
+        assert!(!algorithm.has_key(key_id));
+        assert!(algorithm.decrypt(key_id, &encrypted).is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_components_round_trip_through_the_store() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+        let key_id = leaf.store.encryption.add_key([7; 32]);
+        let algorithm = XChaCha20Poly1305Algorithm::algorithm().id();
+
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity
+            .add_encrypted_component(Name("secret".into()), key_id, algorithm)
+            .unwrap();
+        entity.save().await.unwrap();
+
+        let mut entity = leaf.entity(link.clone()).await.unwrap().entity().unwrap();
+        assert!(entity.get_component::<Name>().await.unwrap().is_none());
+        let names = entity.get_decrypted_components::<Name>().await.unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].0, "secret");
+
+        // Without the key the component is still there, but can't be read.
+        leaf.store.encryption.remove_key(key_id);
+        let names = entity.get_decrypted_components::<Name>().await.unwrap();
+        assert!(names.is_empty());
+
+        leaf.store.encryption.add_key([7; 32]);
+        entity.del_encrypted_components::<Name>().await.unwrap();
+        entity.save().await.unwrap();
+        let entity = leaf.entity(link).await.unwrap().entity().unwrap();
+        assert!(entity
+            .get_decrypted_components::<Name>()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
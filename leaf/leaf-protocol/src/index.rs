@@ -0,0 +1,436 @@
+//! Secondary indexes on the values of component fields.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use borsh::{BorshDeserialize, BorshSerialize};
+use futures::TryStreamExt;
+
+use crate::{
+    store::LeafStore,
+    types::{BorshSchema, ComponentEntry, ComponentKind, Entity, ExactLink},
+    Digest,
+};
+
+/// An index on the values of a field in a component, registered with
+/// [`Leaf::register_index()`][crate::Leaf::register_index].
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FieldIndex {
+    /// The schema ID of the component that the field is in.
+    pub schema: Digest,
+    /// The path to the field in the component's [`BorshSchema`], in the format used by
+    /// [`BorshSchema::field_schema()`].
+    pub field_path: Vec<String>,
+    /// Whether only one entity in each namespace may have each value.
+    pub unique: bool,
+}
+
+impl FieldIndex {
+    /// The ID of the index, which is the same for indexes on the same field whether or not they are
+    /// unique.
+    pub fn id(&self) -> Digest {
+        let mut buf = Vec::new();
+        (self.schema, &self.field_path).serialize(&mut buf).unwrap();
+        Digest::new(&buf)
+    }
+}
+
+/// Error returned when saving an entity would give it the same value as another entity in a
+/// [`unique`][FieldIndex::unique] index.
+#[derive(Debug, Clone)]
+pub struct IndexConflict {
+    /// The index that the conflict is in.
+    pub index: FieldIndex,
+    /// The borsh-encoded value that is already taken.
+    pub value: Vec<u8>,
+    /// The entity that already has the value.
+    pub existing: ExactLink,
+}
+
+impl std::fmt::Display for IndexConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Unique index conflict on `{}` of schema {}: value is already used by {:?}",
+            self.index.field_path.join("."),
+            self.index.schema,
+            self.existing
+        )
+    }
+}
+
+impl std::error::Error for IndexConflict {}
+
+/// The field indexes that a store keeps up to date, returned by
+/// [`LeafStore::field_indexes()`].
+///
+/// Clones share the same set of indexes.
+#[derive(Debug, Clone, Default)]
+pub struct FieldIndexes(Arc<RwLock<HashMap<Digest, (FieldIndex, BorshSchema)>>>);
+
+impl FieldIndexes {
+    /// Start maintaining an index, replacing any index on the same field and returning it.
+    ///
+    /// `format` is the [`BorshSchema`] of the indexed component.
+    pub fn register(&self, index: FieldIndex, format: BorshSchema) -> Result<Option<FieldIndex>> {
+        if format.field_schema(&index.field_path).is_none() {
+            anyhow::bail!(
+                "Schema {} has no field at `{}`",
+                index.schema,
+                index.field_path.join(".")
+            );
+        }
+        let previous = self.0.write().unwrap().insert(index.id(), (index, format));
+        Ok(previous.map(|(index, _)| index))
+    }
+
+    /// Stop maintaining the index with the given [ID][FieldIndex::id].
+    pub fn remove(&self, id: Digest) -> Option<FieldIndex> {
+        let removed = self.0.write().unwrap().remove(&id);
+        removed.map(|(index, _)| index)
+    }
+
+    /// Get a registered index by its [ID][FieldIndex::id].
+    pub fn get(&self, id: Digest) -> Option<FieldIndex> {
+        self.0
+            .read()
+            .unwrap()
+            .get(&id)
+            .map(|(index, _)| index.clone())
+    }
+
+    /// List the registered indexes.
+    pub fn list(&self) -> Vec<FieldIndex> {
+        let indexes = self.0.read().unwrap();
+        indexes.values().map(|(index, _)| index.clone()).collect()
+    }
+}
+
+/// The changes to make to a field index after an entity is written.
+pub(crate) struct FieldIndexUpdate {
+    index: Digest,
+    removed: Vec<Vec<u8>>,
+    added: Vec<Vec<u8>>,
+}
+
+/// Work out the changes to the store's field indexes for the entity at the link changing from the
+/// `old_snapshot_id` to a snapshot with the `new` components, checking for unique index conflicts.
+///
+/// `pending` holds the serialized [`ComponentKind`]s of new components that haven't been stored
+/// yet, by component ID. Values held by the `moved_from` entity don't conflict, because it is about
+/// to be deleted.
+///
+/// Like the schema index, all of the new values are re-added so that entities written before an
+/// index was registered get indexed the next time they are saved.
+pub(crate) async fn plan_field_index_updates<S: LeafStore>(
+    store: &S,
+    link: &ExactLink,
+    old_snapshot_id: Option<Digest>,
+    new: &[ComponentEntry],
+    pending: &HashMap<Digest, &[u8]>,
+    moved_from: Option<&ExactLink>,
+) -> Result<Vec<FieldIndexUpdate>> {
+    let indexes = store.field_indexes().0.read().unwrap().clone();
+    if indexes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let old = &snapshot_components(store, old_snapshot_id).await?;
+
+    let mut updates = Vec::new();
+    for (id, (index, format)) in indexes {
+        let old_values = field_values(store, &index, &format, old, pending).await?;
+        let new_values = field_values(store, &index, &format, new, pending).await?;
+        if old_values.is_empty() && new_values.is_empty() {
+            continue;
+        }
+
+        if index.unique {
+            for value in &new_values {
+                let links = store.lookup_field_index(link.namespace, id, value).await?;
+                if let Some(existing) = links
+                    .into_iter()
+                    .find(|x| x != link && Some(x) != moved_from)
+                {
+                    return Err(IndexConflict {
+                        index,
+                        value: value.clone(),
+                        existing,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        updates.push(FieldIndexUpdate {
+            index: id,
+            removed: old_values.difference(&new_values).cloned().collect(),
+            added: new_values.into_iter().collect(),
+        });
+    }
+    Ok(updates)
+}
+
+/// Run `write` with the values that the planned updates add to unique indexes claimed for their
+/// entities, releasing the values again if it fails.
+///
+/// [`plan_field_index_updates()`] checks the values before anything is written, but another entity
+/// could take one of them before ours is written. Claiming them with
+/// [`LeafStore::claim_field_index_values()`] makes sure only one of the entities gets each value,
+/// and also catches entities that are written together taking the same value.
+pub(crate) async fn with_unique_values<'a, S: LeafStore, T>(
+    store: &S,
+    planned: impl IntoIterator<Item = (&'a ExactLink, &'a [FieldIndexUpdate])>,
+    write: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let indexes = store.field_indexes();
+    let mut claimed = Vec::new();
+    let mut result = Ok(());
+    'claim: for (link, updates) in planned {
+        for update in updates {
+            let Some(index) = indexes.get(update.index).filter(|x| x.unique) else {
+                continue;
+            };
+            match store
+                .claim_field_index_values(link, &index, &update.added)
+                .await
+            {
+                Ok(values) => claimed.push((link, update.index, values)),
+                Err(e) => {
+                    result = Err(e);
+                    break 'claim;
+                }
+            }
+        }
+    }
+    let result = match result {
+        Ok(()) => write.await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        for (link, index, values) in claimed {
+            if let Err(e) = store.update_field_index(link, index, &values, &[]).await {
+                tracing::warn!(?link, "Could not release unique index values: {e}");
+            }
+        }
+    }
+    result
+}
+
+/// Apply the updates from [`plan_field_index_updates()`].
+pub(crate) async fn apply_field_index_updates<S: LeafStore>(
+    store: &S,
+    link: &ExactLink,
+    updates: Vec<FieldIndexUpdate>,
+) -> Result<()> {
+    for update in updates {
+        store
+            .update_field_index(link, update.index, &update.removed, &update.added)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Add the values of the entities that were saved before the index was registered to the index,
+/// checking for unique index conflicts before anything is added.
+///
+/// Entities are found with the schema index, so like [`LeafStore::find_by_schema()`], only the
+/// entities that have been saved locally are indexed.
+pub(crate) async fn index_existing_entities<S: LeafStore>(
+    store: &S,
+    index: &FieldIndex,
+    format: &BorshSchema,
+) -> Result<()> {
+    let id = index.id();
+    let namespaces = store
+        .list_namespaces()
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    let mut entities = Vec::new();
+    for namespace in namespaces {
+        let links = store
+            .find_by_schema(namespace, None, index.schema)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for link in links {
+            let snapshot_id = store.get_entity(&link).await?;
+            let components = snapshot_components(store, snapshot_id).await?;
+            let values = field_values(store, index, format, &components, &HashMap::new()).await?;
+            if !values.is_empty() {
+                entities.push((link, values));
+            }
+        }
+    }
+
+    if index.unique {
+        let mut taken = HashMap::new();
+        for (link, values) in &entities {
+            for value in values {
+                // Entities saved since the index was registered are already in the index.
+                let indexed = store.lookup_field_index(link.namespace, id, value).await?;
+                let existing = taken
+                    .insert((link.namespace, value), link)
+                    .into_iter()
+                    .chain(&indexed)
+                    .find(|x| *x != link);
+                if let Some(existing) = existing {
+                    return Err(IndexConflict {
+                        index: index.clone(),
+                        value: value.clone(),
+                        existing: existing.clone(),
+                    }
+                    .into());
+                }
+            }
+        }
+    }
+
+    for (link, values) in entities {
+        let values = values.into_iter().collect::<Vec<_>>();
+        store.update_field_index(&link, id, &[], &values).await?;
+    }
+    Ok(())
+}
+
+/// Get the components of an entity snapshot, if we have it.
+async fn snapshot_components<S: LeafStore>(
+    store: &S,
+    snapshot_id: Option<Digest>,
+) -> Result<Vec<ComponentEntry>> {
+    let Some(snapshot_id) = snapshot_id else {
+        return Ok(Vec::new());
+    };
+    let Ok(bytes) = store.get_blob(snapshot_id).await else {
+        return Ok(Vec::new());
+    };
+    Ok(Entity::deserialize(&mut &bytes[..])?.components)
+}
+
+/// Collect the values of the indexed field in all of the components that the index applies to.
+///
+/// Encrypted components, and components whose data we don't have, are skipped.
+async fn field_values<S: LeafStore>(
+    store: &S,
+    index: &FieldIndex,
+    format: &BorshSchema,
+    components: &[ComponentEntry],
+    pending: &HashMap<Digest, &[u8]>,
+) -> Result<BTreeSet<Vec<u8>>> {
+    let mut values = BTreeSet::new();
+    for entry in components {
+        if entry.schema_id != Some(index.schema) {
+            continue;
+        }
+        let data = match pending.get(&entry.component_id) {
+            Some(data) => data.to_vec(),
+            None => match store.get_blob(entry.component_id).await {
+                Ok(data) => data,
+                Err(_) => continue,
+            },
+        };
+        let ComponentKind::Unencrypted(component) = ComponentKind::deserialize(&mut &data[..])?
+        else {
+            continue;
+        };
+        values.extend(format.field_values(&component.data, &index.field_path)?);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::Name,
+        test_util::{leaf, link, save_name},
+        Component,
+    };
+
+    #[tokio::test]
+    async fn unique_index_rejects_duplicate_values() {
+        let leaf = leaf();
+        let index = leaf.register_index::<Name>(&[], true).await.unwrap();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+
+        save_name(&leaf, &a, "taken").await.unwrap();
+        let error = save_name(&leaf, &b, "taken").await.unwrap_err();
+        let conflict = error.downcast_ref::<IndexConflict>().unwrap();
+        assert_eq!(conflict.existing, a);
+        assert!(leaf.store.get_entity(&b).await.unwrap().is_none());
+
+        // Re-saving the entity that has the value isn't a conflict.
+        save_name(&leaf, &a, "taken").await.unwrap();
+        save_name(&leaf, &b, "free").await.unwrap();
+        let found = leaf.lookup(a.namespace, &index, "taken").await.unwrap();
+        assert_eq!(found, std::slice::from_ref(&a));
+
+        // Values are released when they change.
+        save_name(&leaf, &a, "changed").await.unwrap();
+        save_name(&leaf, &b, "taken").await.unwrap();
+        let found = leaf.lookup(a.namespace, &index, "taken").await.unwrap();
+        assert_eq!(found, [b]);
+    }
+
+    #[tokio::test]
+    async fn unique_index_rejects_duplicate_values_in_a_batch() {
+        let leaf = leaf();
+        leaf.register_index::<Name>(&[], true).await.unwrap();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+
+        let mut batch = leaf.batch();
+        batch
+            .set_component(a.clone(), Name("taken".into()))
+            .unwrap();
+        batch
+            .set_component(b.clone(), Name("taken".into()))
+            .unwrap();
+        let error = batch.commit().await.unwrap_err();
+        let conflict = error.downcast_ref::<IndexConflict>().unwrap();
+        assert_eq!(conflict.existing, a);
+        assert!(leaf.store.get_entity(&a).await.unwrap().is_none());
+        assert!(leaf.store.get_entity(&b).await.unwrap().is_none());
+
+        let mut batch = leaf.batch();
+        batch
+            .set_component(a.clone(), Name("taken".into()))
+            .unwrap();
+        batch.set_component(b.clone(), Name("free".into())).unwrap();
+        batch.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn registering_indexes_existing_entities() {
+        let leaf = leaf();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        save_name(&leaf, &a, "same").await.unwrap();
+        save_name(&leaf, &b, "same").await.unwrap();
+
+        // The existing values would conflict, so the unique index isn't registered.
+        let error = leaf.register_index::<Name>(&[], true).await.unwrap_err();
+        assert!(error.downcast_ref::<IndexConflict>().is_some());
+        let id = FieldIndex {
+            schema: Name::schema_id(),
+            field_path: Vec::new(),
+            unique: true,
+        }
+        .id();
+        assert!(leaf.store.field_indexes().get(id).is_none());
+        save_name(&leaf, &b, "same").await.unwrap();
+
+        let index = leaf.register_index::<Name>(&[], false).await.unwrap();
+        let found = leaf.lookup(a.namespace, &index, "same").await.unwrap();
+        assert_eq!(found.len(), 2);
+
+        // Making the index unique checks the values again, and keeps the old index on failure.
+        assert!(leaf.register_index::<Name>(&[], true).await.is_err());
+        assert!(!leaf.store.field_indexes().get(id).unwrap().unique);
+        save_name(&leaf, &b, "other").await.unwrap();
+        let index = leaf.register_index::<Name>(&[], true).await.unwrap();
+        let found = leaf.lookup(a.namespace, &index, "same").await.unwrap();
+        assert_eq!(found, [a]);
+    }
+}
@@ -2,32 +2,52 @@
 //!
 //! [lp]: https://github.com/muni-town/agentic-fediverse/blob/49791e6b3ec1df5e0a8604476417e88eed1f9497/leaf-protocol-draft.md
 
+pub mod batch;
 pub mod components;
+pub mod encryption;
+pub mod index;
 pub mod store;
+#[cfg(test)]
+mod test_util;
 pub use leaf_protocol_types as types;
 use leaf_protocol_types::Digest;
 
+use std::collections::{BTreeSet, HashMap};
+
 use anyhow::Result;
 use borsh::{BorshDeserialize, BorshSerialize};
 
+pub use anyhow;
 pub use borsh;
 
-use futures::stream::Stream;
+use batch::{Batch, BatchConflict};
+use futures::{stream::Stream, FutureExt, StreamExt, TryStreamExt};
+use index::FieldIndex;
 pub use leaf_protocol_macros::*;
-use store::LeafStore;
+use store::{EntityMeta, EntityWrite, GcReport, LeafStore, ListCursor, ListMode};
 use types::{
-    ComponentData, ComponentEntry, ComponentKind, Entity, ExactLink, NamespaceId,
-    NamespaceSecretKey, SubspaceId, SubspaceSecretKey,
+    ComponentData, ComponentEntry, ComponentKind, Entity, EntityPath, ExactLink, KeyResolverKind,
+    NamespaceId, NamespaceSecretKey, Reference, SubspaceId, SubspaceSecretKey,
 };
 
 #[cfg(feature = "backend_iroh")]
 pub use iroh;
 
 pub mod prelude {
+    pub use crate::batch::*;
     pub use crate::components::*;
+    pub use crate::encryption::*;
+    pub use crate::index::*;
     #[cfg(feature = "backend_iroh")]
     pub use crate::store::iroh::*;
-    pub use crate::store::{EncryptionAlgorithmImpl, KeyResolverImpl, LeafStore};
+    #[cfg(feature = "backend_memory")]
+    pub use crate::store::memory::*;
+    #[cfg(feature = "backend_redb")]
+    pub use crate::store::redb::*;
+    pub use crate::store::{
+        BlobRange, EncryptionAlgorithmImpl, EntityMeta, GcReport, KeyResolverImpl, LeafStore,
+        ListCursor, ListMode,
+    };
     pub use crate::types::*;
     pub use crate::*;
     pub use borsh::{BorshDeserialize, BorshSerialize};
@@ -40,6 +60,9 @@ pub mod prelude {
 pub trait Component: types::HasBorshSchema + BorshDeserialize + BorshSerialize {
     /// Returns the digest of the schema for this component.
     fn schema_id() -> Digest;
+    /// Returns the schema for this component, which can be published with
+    /// [`Leaf::publish_schema()`].
+    fn schema() -> types::Schema;
     fn make_data(&self) -> std::io::Result<ComponentData> {
         let mut data = Vec::new();
         self.serialize(&mut data)?;
@@ -50,17 +73,45 @@ pub trait Component: types::HasBorshSchema + BorshDeserialize + BorshSerialize {
     }
 }
 
+/// Trait implemented by typed views over several of the components on an entity, such as a
+/// profile made up of a [`Name`][components::Name], a [`Description`][components::Description],
+/// and so on.
+///
+/// Implementers should usually derive this using the
+/// [`LeafEntity`][leaf_protocol_macros::LeafEntity] macro.
+pub trait LeafEntity: Sized {
+    /// Load the components from the entity.
+    fn load<S: LeafStore>(
+        entity: &LoadedEntity<S>,
+    ) -> impl std::future::Future<Output = Result<Self>>;
+    /// Replace the components on the entity with the ones in this value.
+    ///
+    /// Components of types that aren't part of this value are left alone. The change will not be
+    /// persisted until [`save()`][LoadedEntity::save] is called.
+    fn apply<S: LeafStore>(self, entity: &mut LoadedEntity<S>) -> Result<()>;
+}
+
 /// The leaf store, the entrypoint to the leaf API.
 #[derive(Clone, Debug)]
 pub struct Leaf<Store: LeafStore> {
     /// The backend store.
     pub store: Store,
+    /// Whether entities loaded through this store should keep their
+    /// [`DateCreated`][components::DateCreated] and [`DateUpdated`][components::DateUpdated]
+    /// components up to date when they are saved.
+    ///
+    /// See [`LoadedEntity::track_dates`].
+    pub track_dates: bool,
 }
 
 #[derive(Debug)]
 pub enum EntityEntry<S: LeafStore> {
     Entity(LoadedEntity<S>),
-    Empty { link: ExactLink, store: S },
+    Empty {
+        link: ExactLink,
+        store: S,
+        track_dates: bool,
+    },
 }
 
 impl<S: LeafStore> EntityEntry<S> {
@@ -78,17 +129,167 @@ impl<S: LeafStore> EntityEntry<S> {
     pub fn get_or_init(self) -> LoadedEntity<S> {
         match self {
             EntityEntry::Entity(e) => e,
-            EntityEntry::Empty { link, store } => LoadedEntity {
+            EntityEntry::Empty {
+                link,
+                store,
+                track_dates,
+            } => LoadedEntity {
                 store,
                 link,
                 entity: Entity::default(),
                 digest: Digest::from_bytes([0; 32]),
+                meta: None,
                 pending_components: Default::default(),
+                pending_references: Default::default(),
+                track_dates,
             },
         }
     }
 }
 
+/// A change to an entity, reported by [`Leaf::watch()`].
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EntityEvent {
+    /// An entity was created where there wasn't one before.
+    Inserted { link: ExactLink, snapshot: Digest },
+    /// An existing entity was changed.
+    Updated {
+        link: ExactLink,
+        old_snapshot: Digest,
+        new_snapshot: Digest,
+    },
+    /// An entity was deleted.
+    Deleted {
+        link: ExactLink,
+        old_snapshot: Digest,
+    },
+}
+
+impl EntityEvent {
+    /// Get the link to the entity that changed.
+    pub fn link(&self) -> &ExactLink {
+        match self {
+            EntityEvent::Inserted { link, .. }
+            | EntityEvent::Updated { link, .. }
+            | EntityEvent::Deleted { link, .. } => link,
+        }
+    }
+}
+
+/// A page of entity links returned by [`Leaf::list_page()`].
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ListPage {
+    /// The links to the entities in this page.
+    pub links: Vec<ExactLink>,
+    /// The metadata of each of the entities in [`links`][Self::links], in the same order.
+    pub meta: Vec<EntityMeta>,
+    /// The cursor to pass to [`Leaf::list_page()`] to get the next page, or [`None`] if this is the
+    /// last page.
+    pub next: Option<ListCursor>,
+}
+
+/// Error returned by [`LoadedEntity::save_if()`] when the entity in the store has been changed
+/// since it was loaded.
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotConflict {
+    /// The link to the entity that could not be saved.
+    pub link: ExactLink,
+    /// The snapshot digest that the entity was expected to have.
+    pub expected: Digest,
+    /// The snapshot digest currently in the store, or [`None`] if the entity doesn't exist.
+    pub actual: Option<Digest>,
+}
+
+impl std::fmt::Display for SnapshotConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Entity snapshot conflict at {:?}: expected {} but found ",
+            self.link, self.expected
+        )?;
+        match &self.actual {
+            Some(actual) => write!(f, "{actual}"),
+            None => write!(f, "no entity"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotConflict {}
+
+impl SnapshotConflict {
+    /// Returns the conflict if the entity at the link has the `actual` snapshot instead of the
+    /// `expected` one. A null `expected` digest means that the entity must not exist.
+    pub fn check(
+        link: &ExactLink,
+        expected: Digest,
+        actual: Option<Digest>,
+    ) -> Option<SnapshotConflict> {
+        (actual.unwrap_or_default() != expected).then(|| SnapshotConflict {
+            link: link.clone(),
+            expected,
+            actual,
+        })
+    }
+}
+
+/// Error returned by [`Leaf::validate_component()`] when component data can't be checked against
+/// its schema, or doesn't match it.
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidComponent {
+    /// The component's schema has not been published, so there is nothing to check it against.
+    UnpublishedSchema(Digest),
+    /// The component's data does not match its schema.
+    Mismatch {
+        schema: Digest,
+        error: types::ValidationError,
+    },
+}
+
+impl std::fmt::Display for InvalidComponent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidComponent::UnpublishedSchema(schema) => {
+                write!(f, "Component schema {schema} has not been published")
+            }
+            InvalidComponent::Mismatch { schema, error } => {
+                write!(f, "Component does not match schema {schema}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidComponent {}
+
+/// Error returned by [`Leaf::del_subtree()`] when one of the entities could not be deleted.
+///
+/// The entities are deleted one at a time, so the ones listed before the failed entity have
+/// already been deleted, and the failed entity and the ones after it have not.
+#[derive(Debug)]
+pub struct SubtreeDeleteError {
+    /// The number of entities that were deleted before the failure.
+    pub deleted: usize,
+    /// The entity that could not be deleted.
+    pub failed: ExactLink,
+    /// The error from deleting the failed entity.
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for SubtreeDeleteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to delete entity {:?} after deleting {} entities: {:#}",
+            self.failed, self.deleted, self.error
+        )
+    }
+}
+
+impl std::error::Error for SubtreeDeleteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
 #[derive(Debug)]
 pub struct LoadedEntity<S: LeafStore> {
     pub store: S,
@@ -100,9 +301,30 @@ pub struct LoadedEntity<S: LeafStore> {
     /// It will also not be updated until [`save()`][LoadedEntity::save] is called when making
     /// changes to the entity.
     pub digest: Digest,
+    /// The metadata of the stored snapshot, or [`None`] if the entity hasn't been stored yet or was
+    /// loaded from a previous snapshot with [`Leaf::entity_at()`].
+    ///
+    /// It is updated when the entity is [saved][LoadedEntity::save].
+    pub meta: Option<EntityMeta>,
     /// The list of components that have been added to the entity, but haven't been written to
     /// storage yet.
     pub pending_components: Vec<ComponentKind>,
+    /// The blobs and snapshots referenced by each of the pending components, by component ID.
+    ///
+    /// These will be pinned along with the component when the entity is saved.
+    pub pending_references: HashMap<Digest, Vec<Reference>>,
+    /// Whether to call [`touch_dates()`][Self::touch_dates] every time the entity is saved with
+    /// different components.
+    pub track_dates: bool,
+}
+
+/// A new entity snapshot from [`LoadedEntity::prepare_save()`] that is ready to be written.
+pub(crate) struct PreparedSave {
+    snapshot: Entity,
+    pub snapshot_id: Digest,
+    /// The serialized snapshot.
+    pub data: Vec<u8>,
+    pub field_index_updates: Vec<index::FieldIndexUpdate>,
 }
 
 impl<S: LeafStore> LoadedEntity<S> {
@@ -136,13 +358,24 @@ impl<S: LeafStore> LoadedEntity<S> {
         }
         for entry in &self.entity.components {
             if entry.schema_id == Some(C::schema_id()) {
-                let data = self.store.get_blob(entry.component_id).await?;
+                let data = self.get_component_data(entry).await?;
                 return Ok(Some(C::deserialize(&mut &data[..])?));
             }
         }
         Ok(None)
     }
 
+    /// Get the first component of a given type on the entity, returning an error if there is no
+    /// component of that type.
+    pub async fn require_component<C: Component>(&self) -> Result<C> {
+        self.get_component::<C>().await?.ok_or_else(|| {
+            anyhow::format_err!(
+                "Entity is missing a `{}` component.",
+                std::any::type_name::<C>()
+            )
+        })
+    }
+
     /// Get all components of the given type on the entity.
     pub async fn get_components<C: Component>(&self) -> Result<Vec<C>> {
         let mut res = Vec::new();
@@ -155,13 +388,24 @@ impl<S: LeafStore> LoadedEntity<S> {
         }
         for entry in &self.entity.components {
             if entry.schema_id == Some(C::schema_id()) {
-                let data = self.store.get_blob(entry.component_id).await?;
+                let data = self.get_component_data(entry).await?;
                 res.push(C::deserialize(&mut &data[..])?);
             }
         }
         Ok(res)
     }
 
+    /// Load the data of an unencrypted component on the entity.
+    async fn get_component_data(&self, entry: &ComponentEntry) -> Result<Vec<u8>> {
+        let data = self.store.get_blob(entry.component_id).await?;
+        match ComponentKind::deserialize(&mut &data[..])? {
+            ComponentKind::Unencrypted(data) => Ok(data.data),
+            ComponentKind::Encrypted { .. } => {
+                anyhow::bail!("Component {} is encrypted.", entry.component_id)
+            }
+        }
+    }
+
     pub async fn get_components_by_schema(&self, schema: Digest) -> Result<Vec<Vec<u8>>> {
         let mut res = Vec::new();
         for comp in &self.pending_components {
@@ -202,28 +446,302 @@ impl<S: LeafStore> LoadedEntity<S> {
     ///
     /// The component will not be persisted until [`save()`][Self::save] is called.
     pub fn add_component<C: Component>(&mut self, data: C) -> Result<()> {
-        self.pending_components
-            .push(ComponentKind::Unencrypted(ComponentData {
-                schema: C::schema_id(),
-                data: {
-                    let mut buf = Vec::new();
-                    data.serialize(&mut buf)?;
-                    buf
-                },
-            }));
+        let data = data.make_data()?;
+        let references = C::borsh_schema().references(&data.data)?;
+        self.push_pending_component(ComponentKind::Unencrypted(data), references)
+    }
+
+    /// Add a component to the list of pending components, keeping track of the blobs and
+    /// snapshots that it references.
+    fn push_pending_component(
+        &mut self,
+        component: ComponentKind,
+        references: Vec<Reference>,
+    ) -> Result<()> {
+        if !references.is_empty() {
+            let mut buf = Vec::new();
+            component.serialize(&mut buf)?;
+            self.pending_references
+                .insert(Digest::new(&buf), references);
+        }
+        self.pending_components.push(component);
+        Ok(())
+    }
+
+    /// Add an encrypted component to the entity.
+    ///
+    /// The component is encrypted using the key with the given ID and the store's
+    /// [encryption algorithm][store::LeafStore::encryption_algorithms] with the given ID. The
+    /// component's schema is encrypted along with it's data, so readers without the key can't tell
+    /// what kind of component it is.
+    ///
+    /// The component will not be persisted until [`save()`][Self::save] is called.
+    pub fn add_encrypted_component<C: Component>(
+        &mut self,
+        data: C,
+        key_id: [u8; 32],
+        algorithm: Digest,
+    ) -> Result<()> {
+        let data = data.make_data()?;
+        let references = C::borsh_schema().references(&data.data)?;
+        let mut buf = Vec::new();
+        data.serialize(&mut buf)?;
+
+        let algorithm_impl = self
+            .store
+            .encryption_algorithms()
+            .find(|x| x.id() == algorithm)
+            .ok_or_else(|| {
+                anyhow::format_err!("Encryption algorithm not supported: {algorithm}")
+            })?;
+        let encrypted_data = algorithm_impl.encrypt(key_id, &buf)?;
+        let algorithm = algorithm_impl.algorithm();
+
+        self.push_pending_component(
+            ComponentKind::Encrypted {
+                algorithm,
+                key_id,
+                encrypted_data,
+            },
+            references,
+        )
+    }
+
+    /// Get all of the encrypted components of the given type on the entity, decrypted.
+    ///
+    /// Encrypted components that use a key or algorithm that isn't available in the store are
+    /// skipped.
+    pub async fn get_decrypted_components<C: Component>(&self) -> Result<Vec<C>> {
+        let mut res = Vec::new();
+        for comp in &self.pending_components {
+            if let Some(comp) = self.decrypt_component(comp)? {
+                if comp.schema == C::schema_id() {
+                    res.push(C::deserialize(&mut &comp.data[..])?);
+                }
+            }
+        }
+        for entry in &self.entity.components {
+            if entry.schema_id.is_none() {
+                let data = self.store.get_blob(entry.component_id).await?;
+                let component_kind = ComponentKind::deserialize(&mut &data[..])?;
+                if let Some(comp) = self.decrypt_component(&component_kind)? {
+                    if comp.schema == C::schema_id() {
+                        res.push(C::deserialize(&mut &comp.data[..])?);
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Delete all of the encrypted components of the given type that can be decrypted with the keys
+    /// available in the store.
+    ///
+    /// The changes will not be persisted until [`save()`][Self::save] is called.
+    pub async fn del_encrypted_components<C: Component>(&mut self) -> Result<()> {
+        let mut pending_components = std::mem::take(&mut self.pending_components);
+        let mut result = Ok(());
+        pending_components.retain(|comp| match self.decrypt_component(comp) {
+            Ok(comp) => comp.map(|x| x.schema != C::schema_id()).unwrap_or(true),
+            Err(e) => {
+                result = Err(e);
+                true
+            }
+        });
+        self.pending_components = pending_components;
+        result?;
+
+        let mut to_delete = Vec::new();
+        for entry in &self.entity.components {
+            if entry.schema_id.is_none() {
+                let data = self.store.get_blob(entry.component_id).await?;
+                let component_kind = ComponentKind::deserialize(&mut &data[..])?;
+                if let Some(comp) = self.decrypt_component(&component_kind)? {
+                    if comp.schema == C::schema_id() {
+                        to_delete.push(*entry);
+                    }
+                }
+            }
+        }
+        self.entity
+            .components
+            .retain(|entry| !to_delete.contains(entry));
         Ok(())
     }
 
-    pub fn add_component_data(&mut self, data: ComponentKind) {
-        self.pending_components.push(data);
+    /// Decrypt the component if it is encrypted with an algorithm and key that are available in the
+    /// store.
+    fn decrypt_component(&self, component: &ComponentKind) -> Result<Option<ComponentData>> {
+        let ComponentKind::Encrypted {
+            algorithm,
+            key_id,
+            encrypted_data,
+        } = component
+        else {
+            return Ok(None);
+        };
+        let algorithm = algorithm.id();
+        let Some(algorithm_impl) = self
+            .store
+            .encryption_algorithms()
+            .find(|x| x.id() == algorithm && x.has_key(*key_id))
+        else {
+            return Ok(None);
+        };
+        let data = algorithm_impl.decrypt(*key_id, encrypted_data)?;
+        Ok(Some(ComponentData::deserialize(&mut &data[..])?))
+    }
+
+    /// Add raw component data to the entity.
+    ///
+    /// The component's published schema is used to find the blobs and snapshots that it
+    /// references, so that they are pinned along with it. If an unencrypted component's schema
+    /// hasn't been published, the component is still added, but nothing it references is pinned.
+    /// Encrypted components are added as they are, without pinning anything.
+    ///
+    /// The component will not be persisted until [`save()`][Self::save] is called.
+    pub async fn add_component_data(&mut self, data: ComponentKind) -> Result<()> {
+        let references = match &data {
+            ComponentKind::Unencrypted(component) => {
+                component_references(&self.store, component).await?
+            }
+            ComponentKind::Encrypted { .. } => Vec::new(),
+        };
+        self.push_pending_component(data, references)
     }
 
     /// Persist updates made to this entity's components, writing updated entity and components to
     /// the store.
+    ///
+    /// This will overwrite whatever is currently stored at the entity's link, even if it has been
+    /// changed since this entity was loaded. Use [`save_if()`][Self::save_if] if you need to make
+    /// sure you don't clobber another writer's changes.
     pub async fn save(&mut self) -> anyhow::Result<()> {
-        // Clean up old blob pins if there was a previous version of this entity.
-        if let Some(old_snapshot_id) = self.store.get_entity(&self.link).await? {
-            self.store.del_blobs(&self.link, old_snapshot_id).await?;
+        let current_snapshot_id = self.store.get_entity(&self.link).await?;
+        self.save_over(current_snapshot_id).await
+    }
+
+    /// Persist updates made to this entity's components, but only if the snapshot currently
+    /// stored at the entity's link has the `expected` digest.
+    ///
+    /// A null digest ( all zeros ) is expected to mean that the entity does not exist yet, which
+    /// matches the [`digest`][Self::digest] of an entity created with
+    /// [`get_or_init()`][EntityEntry::get_or_init]. Usually you will want to pass in the
+    /// [`digest`][Self::digest] that the entity had when it was loaded.
+    ///
+    /// If the stored snapshot doesn't match, nothing is written and a [`SnapshotConflict`] error is
+    /// returned, which can be retrieved with [`anyhow::Error::downcast_ref()`].
+    ///
+    /// The check and the write are made together with [`LeafStore::store_entities()`], so no
+    /// other write through the same store can land in between. The Iroh store can't stop writes
+    /// synced from peers from landing in between, though, and the latest of those writes wins.
+    pub async fn save_if(&mut self, expected: Digest) -> anyhow::Result<()> {
+        let current_snapshot_id = self.store.get_entity(&self.link).await?;
+        if let Some(conflict) = SnapshotConflict::check(&self.link, expected, current_snapshot_id) {
+            return Err(conflict.into());
+        }
+
+        let old_snapshot_id = (expected != Digest::default()).then_some(expected);
+        let prepared = self.prepare_save(old_snapshot_id).await?;
+        let write = EntityWrite {
+            link: self.link.clone(),
+            expected,
+            data: Some(prepared.data.clone()),
+        };
+        let planned = [(&self.link, &prepared.field_index_updates[..])];
+        let write = self.store.store_entities(vec![write]);
+        let digests = index::with_unique_values(&self.store, planned, write)
+            .await
+            .map_err(|e| match e.downcast::<BatchConflict>() {
+                Ok(conflict) => conflict.conflict.into(),
+                Err(e) => e,
+            })?;
+        assert_eq!(
+            digests,
+            [prepared.snapshot_id],
+            "Entity snapshot digest incorrect"
+        );
+        self.finish_save(old_snapshot_id, prepared).await
+    }
+
+    /// Set the entity's [`DateUpdated`][components::DateUpdated] component to the current time,
+    /// and add a [`DateCreated`][components::DateCreated] component with the current time if it
+    /// doesn't have one yet.
+    ///
+    /// This is done automatically on every save that changes the entity's components if
+    /// [`track_dates`][Self::track_dates] is set.
+    pub fn touch_dates(&mut self) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let date_created = components::DateCreated::schema_id();
+        let has_date_created = self
+            .entity
+            .components
+            .iter()
+            .any(|entry| entry.schema_id == Some(date_created))
+            || self
+                .pending_components
+                .iter()
+                .any(|kind| kind.unencrypted().is_some_and(|x| x.schema == date_created));
+        if !has_date_created {
+            self.add_component(components::DateCreated(now))?;
+        }
+        self.set_component(components::DateUpdated(now))
+    }
+
+    /// Whether saving the entity would give it different components than the `old_snapshot_id`
+    /// snapshot has.
+    ///
+    /// If we don't have the old snapshot, the components are assumed to have changed.
+    async fn components_changed(&self, old_snapshot_id: Option<Digest>) -> Result<bool> {
+        let Some(old_snapshot_id) = old_snapshot_id else {
+            return Ok(true);
+        };
+        let Ok(bytes) = self.store.get_blob(old_snapshot_id).await else {
+            return Ok(true);
+        };
+        let old = Entity::deserialize(&mut &bytes[..])?;
+
+        let mut new = self.entity.components.clone();
+        for component in &self.pending_components {
+            let mut buf = Vec::new();
+            component.serialize(&mut buf)?;
+            new.push(ComponentEntry {
+                schema_id: component.unencrypted().map(|x| x.schema),
+                component_id: Digest::new(&buf),
+            });
+        }
+        new.sort();
+        new.dedup();
+        Ok(new != old.components)
+    }
+
+    /// Write the entity to the store, replacing the given previous snapshot.
+    async fn save_over(&mut self, old_snapshot_id: Option<Digest>) -> anyhow::Result<()> {
+        let prepared = self.prepare_save(old_snapshot_id).await?;
+        let planned = [(&self.link, &prepared.field_index_updates[..])];
+        let write = self.store.store_entity(&self.link, prepared.data.clone());
+        let verification_digest = index::with_unique_values(&self.store, planned, write).await?;
+        assert_eq!(
+            verification_digest, prepared.snapshot_id,
+            "Entity snapshot digest incorrect"
+        );
+        self.finish_save(old_snapshot_id, prepared).await
+    }
+
+    /// Build the new snapshot of the entity and store and pin everything that it needs, so that
+    /// only the snapshot itself is left to be written.
+    ///
+    /// Unique index conflicts are checked against the `old_snapshot_id` that the snapshot will
+    /// replace. If the snapshot ends up not being written, the pins are cleaned up by
+    /// [`Leaf::gc()`].
+    pub(crate) async fn prepare_save(
+        &mut self,
+        old_snapshot_id: Option<Digest>,
+    ) -> anyhow::Result<PreparedSave> {
+        if self.track_dates && self.components_changed(old_snapshot_id).await? {
+            self.touch_dates()?;
         }
 
         struct PendingComponent {
@@ -262,33 +780,104 @@ impl<S: LeafStore> LoadedEntity<S> {
 
         let new_entity_snapshot_id = Digest::new(&new_entity_snapshot_buf);
 
+        // Check for unique index conflicts before we write anything.
+        let pending_data = pending_components
+            .iter()
+            .map(|x| (x.data_hash, &x.data[..]))
+            .collect();
+        let field_index_updates = index::plan_field_index_updates(
+            &self.store,
+            &self.link,
+            old_snapshot_id,
+            &new_entity_snapshot.components,
+            &pending_data,
+            None,
+        )
+        .await?;
+
+        // Components that we are keeping from the previous version need to be pinned for the new
+        // snapshot, too.
+        for entry in &self.entity.components {
+            self.store
+                .pin_blob(entry.component_id, &self.link, new_entity_snapshot_id)
+                .await?;
+        }
         for comp in pending_components {
-            // TODO: Make sure that snapshots and link snapshots are added to the blob store pins.
-            //
-            // The component may contain a `Snapshot` or a `Link` with a snapshot that should be
-            // persisted. As it stands, nothing will make sure that the snapshot's blob is
-            // persisted. We need to add another `store_blob` call for each snapshot.
-            //
-            // We can tell when a component contains a snapashot by combing it's `BorshSchema`, but
-            // we must also walk through the component bytes as we recurse through the schema so
-            // that when we find a link we can load it's bytes.
             let dig = self
                 .store
                 .store_blob(&comp.data, &self.link, new_entity_snapshot_id)
                 .await?;
             assert_eq!(dig, comp.data_hash);
+
+            // Blobs and snapshots referenced by the component are pinned under the component ID
+            // instead of the entity snapshot ID, so that they stay pinned for as long as the
+            // component is on the entity.
+            for reference in self
+                .pending_references
+                .get(&comp.data_hash)
+                .into_iter()
+                .flatten()
+            {
+                pin_reference(&self.store, &self.link, comp.data_hash, *reference).await?;
+            }
         }
-        let verification_digest = self
-            .store
-            .store_entity(&self.link, new_entity_snapshot_buf)
-            .await?;
-        assert_eq!(
-            verification_digest, new_entity_snapshot_id,
-            "Entity snapshot digest incorrect"
-        );
+
+        Ok(PreparedSave {
+            snapshot: new_entity_snapshot,
+            snapshot_id: new_entity_snapshot_id,
+            data: new_entity_snapshot_buf,
+            field_index_updates,
+        })
+    }
+
+    /// Update the store's indexes and this entity handle after the prepared snapshot has been
+    /// written over the `old_snapshot_id`, and release the pins of the old snapshot.
+    pub(crate) async fn finish_save(
+        &mut self,
+        old_snapshot_id: Option<Digest>,
+        prepared: PreparedSave,
+    ) -> anyhow::Result<()> {
+        let PreparedSave {
+            snapshot: new_entity_snapshot,
+            snapshot_id: new_entity_snapshot_id,
+            field_index_updates,
+            ..
+        } = prepared;
+
+        // Clean up old blob pins if there was a different previous version of this entity. If
+        // nothing changed, the old pins are the ones that we just made.
+        let old_entity = match old_snapshot_id {
+            Some(old_snapshot_id) if old_snapshot_id != new_entity_snapshot_id => {
+                release_pins(
+                    &self.store,
+                    &self.link,
+                    old_snapshot_id,
+                    &new_entity_snapshot.components,
+                )
+                .await?
+            }
+            Some(_) => Some(new_entity_snapshot.clone()),
+            None => None,
+        };
+        update_schema_index(
+            &self.store,
+            &self.link,
+            old_entity.as_ref().map_or(&[], |x| &x.components[..]),
+            &new_entity_snapshot.components,
+        )
+        .await?;
+        index::apply_field_index_updates(&self.store, &self.link, field_index_updates).await?;
         self.pending_components.clear();
+        self.pending_references.clear();
         self.entity = new_entity_snapshot;
         self.digest = new_entity_snapshot_id;
+        // The metadata may belong to a newer snapshot if another write landed right after ours.
+        self.meta = self
+            .store
+            .get_entity_meta(&self.link)
+            .await?
+            .filter(|(digest, _)| *digest == new_entity_snapshot_id)
+            .map(|(_, meta)| meta);
 
         Ok(())
     }
@@ -296,24 +885,266 @@ impl<S: LeafStore> LoadedEntity<S> {
     /// Delete the entity. Changes are immediately written to the store.
     pub async fn delete(&mut self) -> anyhow::Result<()> {
         if let Some(old_snapshot_id) = self.store.get_entity(&self.link).await? {
+            let field_index_updates = index::plan_field_index_updates(
+                &self.store,
+                &self.link,
+                Some(old_snapshot_id),
+                &[],
+                &HashMap::new(),
+                None,
+            )
+            .await?;
             // Clean up old blob pins
-            self.store.del_blobs(&self.link, old_snapshot_id).await?;
+            let old_entity = release_pins(&self.store, &self.link, old_snapshot_id, &[]).await?;
             // Delete the entity
             self.store.del_entity(&self.link).await?;
+            if let Some(old_entity) = old_entity {
+                update_schema_index(&self.store, &self.link, &old_entity.components, &[]).await?;
+            }
+            index::apply_field_index_updates(&self.store, &self.link, field_index_updates).await?;
 
             // Clear the components on this entity handle
             self.entity.components.clear();
+            self.meta = None;
         }
         Ok(())
     }
 }
 
-// TODO: Store schema data in the network somehow, instead of just the schema hashes.
+/// Pin a blob or snapshot referenced by the component with the given ID.
+///
+/// Snapshots have the blobs of their components pinned, too, so that the whole entity snapshot
+/// can be loaded later. Referenced data that isn't in the local store can't be pinned, and is
+/// skipped.
+async fn pin_reference<S: LeafStore>(
+    store: &S,
+    link: &ExactLink,
+    component_id: Digest,
+    reference: Reference,
+) -> Result<()> {
+    match reference {
+        Reference::Blob(digest) => {
+            store.pin_blob(digest, link, component_id).await?;
+        }
+        Reference::Snapshot(digest) => {
+            if store.pin_blob(digest, link, component_id).await? {
+                let bytes = store.get_blob(digest).await?;
+                let entity = Entity::deserialize(&mut &bytes[..])?;
+                for entry in entity.components {
+                    store
+                        .pin_blob(entry.component_id, link, component_id)
+                        .await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove the blob pins for an entity snapshot, along with the pins for the data referenced by
+/// any of its components that are not in `keep`.
+///
+/// Returns the released snapshot, if we have it.
+async fn release_pins<S: LeafStore>(
+    store: &S,
+    link: &ExactLink,
+    snapshot_id: Digest,
+    keep: &[ComponentEntry],
+) -> Result<Option<Entity>> {
+    store.del_blobs(link, snapshot_id).await?;
+
+    // If we don't have the old snapshot we can't know which components it had.
+    let Ok(bytes) = store.get_blob(snapshot_id).await else {
+        return Ok(None);
+    };
+    let entity = Entity::deserialize(&mut &bytes[..])?;
+    for entry in &entity.components {
+        if !keep.contains(entry) {
+            store.del_blobs(link, entry.component_id).await?;
+        }
+    }
+    Ok(Some(entity))
+}
+
+/// Update the store's schema index after the entity at the link changed from the `old` components
+/// to the `new` ones.
+///
+/// All of the new schemas are re-added, so that entities written before they were indexed get
+/// indexed the next time they are saved.
+async fn update_schema_index<S: LeafStore>(
+    store: &S,
+    link: &ExactLink,
+    old: &[ComponentEntry],
+    new: &[ComponentEntry],
+) -> Result<()> {
+    let schemas = |entries: &[ComponentEntry]| {
+        entries
+            .iter()
+            .filter_map(|entry| entry.schema_id)
+            .collect::<BTreeSet<_>>()
+    };
+    let (old, new) = (schemas(old), schemas(new));
+    let removed = old.difference(&new).copied().collect::<Vec<_>>();
+    let added = new.into_iter().collect::<Vec<_>>();
+    store.update_schema_index(link, &removed, &added).await
+}
+
+/// Seed used to derive the secret key of the well-known namespace that schemas are published to.
+const SCHEMA_NAMESPACE_SEED: &[u8] = b"leaf:schemas:namespace";
+/// Seed used to derive the secret key of the well-known subspace that schemas are published to.
+const SCHEMA_SUBSPACE_SEED: &[u8] = b"leaf:schemas:subspace";
+
+/// The schema ID of the component that a published [`Schema`][types::Schema] is stored in.
+fn schema_component_schema_id() -> Digest {
+    let mut buf = Vec::new();
+    ("Schema", Entity::default().compute_digest())
+        .serialize(&mut buf)
+        .unwrap();
+    Digest::new(&buf)
+}
+
+/// The secret keys of the namespace and subspace that schemas are published to.
+fn schema_secrets() -> (NamespaceSecretKey, SubspaceSecretKey) {
+    (
+        *Digest::new(SCHEMA_NAMESPACE_SEED).as_bytes(),
+        *Digest::new(SCHEMA_SUBSPACE_SEED).as_bytes(),
+    )
+}
+
+/// The link to the entity that the schema with the given ID is published to.
+///
+/// This only derives the IDs of the schema namespace and subspace, without importing their
+/// secrets, so it doesn't touch the store.
+fn schema_link<S: LeafStore>(store: &S, schema_id: Digest) -> ExactLink {
+    let (namespace_secret, subspace_secret) = schema_secrets();
+    let namespace = store.namespace_id(&namespace_secret);
+    let subspace = store.subspace_id(&subspace_secret);
+    (namespace, subspace, [schema_id.as_bytes().to_vec()]).into()
+}
+
+/// Whether `schema_id` is the ID of `schema`.
+///
+/// Schema IDs are the digest of the schema's name and specification, except for the standard
+/// components that were assigned fixed IDs before that rule existed, which only match their own
+/// schemas.
+fn schema_id_matches(schema_id: Digest, schema: &types::Schema) -> bool {
+    use components::*;
+    schema.id() == schema_id
+        || (schema_id == Name::schema_id() && *schema == Name::schema())
+        || (schema_id == Description::schema_id() && *schema == Description::schema())
+}
+
+/// The compiled-in schemas of the standard [`components`], along with their IDs.
+fn standard_schemas() -> [(Digest, types::Schema); 9] {
+    use components::*;
+    [
+        (Utf8::schema_id(), Utf8::schema()),
+        (Name::schema_id(), Name::schema()),
+        (Description::schema_id(), Description::schema()),
+        (DateCreated::schema_id(), DateCreated::schema()),
+        (DateUpdated::schema_id(), DateUpdated::schema()),
+        (CommonMark::schema_id(), CommonMark::schema()),
+        (ReplyTo::schema_id(), ReplyTo::schema()),
+        (Embed::schema_id(), Embed::schema()),
+        (Image::schema_id(), Image::schema()),
+    ]
+}
+
+/// The compiled-in schema of the standard component with the given ID, if it is one.
+fn standard_schema(schema_id: Digest) -> Option<types::Schema> {
+    standard_schemas()
+        .into_iter()
+        .find(|(id, _)| *id == schema_id)
+        .map(|(_, schema)| schema)
+}
+
+/// Read the [`Schema`][types::Schema] stored at the given schema ID's link, whether or not it
+/// matches the ID.
+///
+/// Anyone can write to the schema namespace, so a publication that can't be read is treated as if
+/// nothing had been published, rather than failing every lookup of the schema.
+async fn get_published_schema<S: LeafStore>(
+    store: &S,
+    schema_id: Digest,
+) -> Result<Option<types::Schema>> {
+    let Some(digest) = store.get_entity(&schema_link(store, schema_id)).await? else {
+        return Ok(None);
+    };
+    let read = async {
+        let entity = Entity::deserialize(&mut &store.get_blob(digest).await?[..])?;
+        let schema_component_schema_id = schema_component_schema_id();
+        let Some(entry) = entity
+            .components
+            .iter()
+            .find(|x| x.schema_id == Some(schema_component_schema_id))
+        else {
+            anyhow::bail!("Published schema entity has no schema component");
+        };
+        let data = store.get_blob(entry.component_id).await?;
+        let ComponentKind::Unencrypted(component) = ComponentKind::deserialize(&mut &data[..])?
+        else {
+            anyhow::bail!("Published schema component is encrypted");
+        };
+        Ok(types::Schema::deserialize(&mut &component.data[..])?)
+    };
+    match read.await {
+        Ok(schema) => Ok(Some(schema)),
+        Err(e) => {
+            tracing::warn!(%schema_id, "Ignoring published schema that can't be read: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// Look up a [`Schema`][types::Schema] by its ID.
+///
+/// The standard components always resolve to their compiled-in schemas, without looking at the
+/// store. Anyone can write to the schema namespace, so other schemas are only returned if they
+/// match the ID they were published under.
+async fn get_schema<S: LeafStore>(store: &S, schema_id: Digest) -> Result<Option<types::Schema>> {
+    if let Some(standard) = standard_schema(schema_id) {
+        return Ok(Some(standard));
+    }
+    let Some(schema) = get_published_schema(store, schema_id).await? else {
+        return Ok(None);
+    };
+    if !schema_id_matches(schema_id, &schema) {
+        tracing::warn!(%schema_id, "Ignoring published schema that doesn't match its ID");
+        return Ok(None);
+    }
+    Ok(Some(schema))
+}
+
+/// Find the blobs and snapshots referenced by raw component data, using its published schema.
+///
+/// Publishing schemas is optional, so if the schema hasn't been published the component is
+/// assumed not to reference anything. Without the schema we can't tell which of the component's
+/// bytes are references, so anything it does reference may be garbage collected.
+async fn component_references<S: LeafStore>(
+    store: &S,
+    component: &ComponentData,
+) -> Result<Vec<Reference>> {
+    // Published schemas don't reference anything, and can't be published under themselves.
+    if component.schema == schema_component_schema_id() {
+        return Ok(Vec::new());
+    }
+    let Some(schema) = get_schema(store, component.schema).await? else {
+        tracing::debug!(
+            schema = %component.schema,
+            "Component schema has not been published, so its references will not be pinned"
+        );
+        return Ok(Vec::new());
+    };
+    Ok(schema.format.references(&component.data)?)
+}
 
 impl<S: store::LeafStore + Clone> Leaf<S> {
     /// Create a new leaf store around the given backend store.
     pub fn new(store: S) -> Self {
-        Self { store }
+        Self {
+            store,
+            track_dates: false,
+        }
     }
 
     pub async fn create_subspace(&self) -> Result<SubspaceId> {
@@ -341,47 +1172,642 @@ impl<S: store::LeafStore + Clone> Leaf<S> {
         self.store.get_namespace_secret(namespace).await
     }
 
-    /// Load an entity entry
-    pub async fn entity<L: Into<ExactLink>>(&self, link: L) -> Result<EntityEntry<S>> {
-        let link = link.into();
-        let Some(digest) = self.store.get_entity(&link).await? else {
-            return Ok(EntityEntry::Empty {
-                link,
-                store: self.store.clone(),
-            });
-        };
-        let bytes = self.store.get_blob(digest).await?;
-        let entity = Entity::deserialize(&mut &bytes[..])?;
+    /// Get the link that the schema with the given ID is published at.
+    ///
+    /// Schemas are published to a well-known namespace and subspace, the secret keys of which are
+    /// public, so that anybody can publish schemas and sync them with their peers.
+    pub fn schema_link(&self, schema_id: Digest) -> ExactLink {
+        schema_link(&self.store, schema_id)
+    }
 
-        Ok(EntityEntry::Entity(LoadedEntity {
-            store: self.store.clone(),
-            link,
-            entity,
-            digest,
-            pending_components: Default::default(),
-        }))
+    /// Publish the schema of a component, so that peers that find the component's schema ID can
+    /// look up how to interpret it with [`get_schema()`][Self::get_schema].
+    pub async fn publish_schema<C: Component>(&self) -> Result<()> {
+        self.publish_schema_data(C::schema_id(), C::schema()).await
     }
 
-    pub async fn del_entity<L: Into<ExactLink>>(&self, link: L) -> Result<()> {
-        let link = link.into();
-        if let Some(digest) = self.store.get_entity(&link).await? {
-            self.store.del_blobs(&link, digest).await?;
+    /// Publish the schemas of all of the standard [`components`], skipping any that are already
+    /// published.
+    ///
+    /// This build always knows the standard schemas, but peers that don't have them compiled in
+    /// can only interpret standard components once their schemas have been published.
+    pub async fn publish_standard_schemas(&self) -> Result<()> {
+        for (schema_id, schema) in standard_schemas() {
+            if get_published_schema(&self.store, schema_id).await?.as_ref() != Some(&schema) {
+                self.publish_schema_data(schema_id, schema).await?;
+            }
         }
+        Ok(())
+    }
+
+    /// Publish a [`Schema`][types::Schema] under the given schema ID.
+    ///
+    /// Prefer [`publish_schema()`][Self::publish_schema] when you have the component type.
+    ///
+    /// Returns an error if `schema_id` is not the ID of `schema`, if `schema_id` belongs to a
+    /// standard component and `schema` is not its compiled-in schema, if the schema is already
+    /// published with a different format, or if no data could ever be
+    /// [decoded][types::BorshSchema::is_decodable] with its format.
+    pub async fn publish_schema_data(
+        &self,
+        schema_id: Digest,
+        schema: types::Schema,
+    ) -> Result<()> {
+        if !schema_id_matches(schema_id, &schema) {
+            anyhow::bail!(
+                "Schema ID {schema_id} does not match the schema, which has ID {}",
+                schema.id()
+            );
+        }
+        if !schema.format.is_decodable() {
+            anyhow::bail!("Schema {schema_id} has fixed-size arrays that are too long to decode");
+        }
+        if standard_schema(schema_id).is_some_and(|standard| standard != schema) {
+            anyhow::bail!("Schema {schema_id} is a standard component schema and can't be changed");
+        }
+        if let Some(published) = self.get_schema(schema_id).await? {
+            if published.format != schema.format {
+                anyhow::bail!("Schema {schema_id} is already published with a different format");
+            }
+        }
+        let (namespace_secret, subspace_secret) = schema_secrets();
+        self.store.import_namespace_secret(namespace_secret).await?;
+        self.store.import_subspace_secret(subspace_secret).await?;
+        let link = self.schema_link(schema_id);
+        // Anyone can write to the schema namespace, so clear away an entity that can't be loaded.
+        if let Some(digest) = self.store.get_entity(&link).await? {
+            if Entity::deserialize(&mut &self.store.get_blob(digest).await?[..]).is_err() {
+                tracing::warn!(%schema_id, "Replacing published schema entity that can't be read");
+                self.store.del_entity(&link).await?;
+            }
+        }
+        let mut entity = self.entity(link).await?.get_or_init();
+        let schema_component_schema_id = schema_component_schema_id();
+        entity.del_components_by_schema(schema_component_schema_id);
+        let mut data = Vec::new();
+        schema.serialize(&mut data)?;
+        entity
+            .add_component_data(ComponentKind::Unencrypted(ComponentData {
+                schema: schema_component_schema_id,
+                data,
+            }))
+            .await?;
+        entity.save().await
+    }
+
+    /// Look up a [`Schema`][types::Schema] by its ID.
+    ///
+    /// The standard [`components`] always resolve to their compiled-in schemas. Other schemas
+    /// return [`None`] if they have not been published, or haven't been synced from a peer that
+    /// published them yet.
+    pub async fn get_schema(&self, schema_id: Digest) -> Result<Option<types::Schema>> {
+        get_schema(&self.store, schema_id).await
+    }
+
+    /// Check that the component's data matches its schema.
+    ///
+    /// Returns an [`InvalidComponent`] error if the schema isn't a standard one and hasn't been
+    /// published, or the data doesn't match it.
+    pub async fn validate_component(&self, component: &ComponentData) -> Result<()> {
+        let Some(schema) = self.get_schema(component.schema).await? else {
+            return Err(InvalidComponent::UnpublishedSchema(component.schema).into());
+        };
+        schema
+            .format
+            .validate(&component.data)
+            .map_err(|error| InvalidComponent::Mismatch {
+                schema: component.schema,
+                error,
+            })?;
+        Ok(())
+    }
+
+    /// Load an entity entry
+    pub async fn entity<L: Into<ExactLink>>(&self, link: L) -> Result<EntityEntry<S>> {
+        let link = link.into();
+        let Some((digest, meta)) = self.store.get_entity_meta(&link).await? else {
+            return Ok(EntityEntry::Empty {
+                link,
+                store: self.store.clone(),
+                track_dates: self.track_dates,
+            });
+        };
+        let bytes = self.store.get_blob(digest).await?;
+        let entity = Entity::deserialize(&mut &bytes[..])?;
+
+        Ok(EntityEntry::Entity(LoadedEntity {
+            store: self.store.clone(),
+            link,
+            entity,
+            digest,
+            meta: Some(meta),
+            pending_components: Default::default(),
+            pending_references: Default::default(),
+            track_dates: self.track_dates,
+        }))
+    }
+
+    /// Load an entity as it was at a previous snapshot, or [`None`] if the store doesn't have the
+    /// snapshot.
+    ///
+    /// Reading components will fail if they are no longer retained by the store.
+    ///
+    /// Note that [saving][LoadedEntity::save] the returned entity will overwrite the current version
+    /// of the entity with the old snapshot.
+    pub async fn entity_at<L: Into<ExactLink>>(
+        &self,
+        link: L,
+        snapshot: Digest,
+    ) -> Result<Option<LoadedEntity<S>>> {
+        let link = link.into();
+        if self.store.blob_size(snapshot).await?.is_none() {
+            return Ok(None);
+        }
+        let bytes = self.store.get_blob(snapshot).await?;
+        let entity = Entity::deserialize(&mut &bytes[..]).map_err(|e| {
+            anyhow::format_err!("Could not load snapshot {snapshot} of entity {link:?}: {e}")
+        })?;
+
+        Ok(Some(LoadedEntity {
+            store: self.store.clone(),
+            link,
+            entity,
+            digest: snapshot,
+            meta: None,
+            pending_components: Default::default(),
+            pending_references: Default::default(),
+            track_dates: self.track_dates,
+        }))
+    }
+
+    /// Resolve a [`Link`][types::Link] to an [`ExactLink`], using the store's key resolvers for any
+    /// keys that aren't stored inline.
+    pub fn resolve_link(&self, link: &types::Link) -> Result<ExactLink> {
+        Ok(ExactLink {
+            namespace: self.resolve_key(link.namespace())?,
+            subspace: self.resolve_key(link.subspace())?,
+            path: EntityPath(link.path().to_vec()),
+        })
+    }
+
+    fn resolve_key(&self, key: &KeyResolverKind) -> Result<[u8; 32]> {
+        match key {
+            KeyResolverKind::Inline(key) => Ok(*key),
+            KeyResolverKind::Custom { id, data } => {
+                let resolver = self
+                    .store
+                    .key_resolvers()
+                    .find(|resolver| resolver.id() == *id)
+                    .ok_or_else(|| anyhow::format_err!("Key resolver not supported: {id}"))?;
+                Ok(*resolver.resolve(data).as_bytes())
+            }
+        }
+    }
+
+    /// Load the entity that a [`Link`][types::Link] points to.
+    ///
+    /// If the link is pinned to a snapshot, the entity will be loaded at that snapshot, otherwise
+    /// the latest version of the entity is loaded.
+    pub async fn follow(&self, link: &types::Link) -> Result<EntityEntry<S>> {
+        let exact_link = self.resolve_link(link)?;
+        match link.snapshot() {
+            Some(snapshot) => {
+                let entity = self.entity_at(exact_link, snapshot).await?;
+                let entity =
+                    entity.ok_or_else(|| anyhow::format_err!("Snapshot not found: {snapshot}"))?;
+                Ok(EntityEntry::Entity(entity))
+            }
+            None => self.entity(exact_link).await,
+        }
+    }
+
+    /// Start a [`Batch`] of component changes to multiple entities, which are checked and then
+    /// written together when the batch is committed.
+    pub fn batch(&self) -> Batch<'_, S> {
+        Batch::new(self)
+    }
+
+    pub async fn del_entity<L: Into<ExactLink>>(&self, link: L) -> Result<()> {
+        let link = link.into();
+        let Some(digest) = self.store.get_entity(&link).await? else {
+            return self.store.del_entity(&link).await;
+        };
+        let field_index_updates = index::plan_field_index_updates(
+            &self.store,
+            &link,
+            Some(digest),
+            &[],
+            &HashMap::new(),
+            None,
+        )
+        .await?;
+        let old_entity = release_pins(&self.store, &link, digest, &[]).await?;
         self.store.del_entity(&link).await?;
+        if let Some(old_entity) = old_entity {
+            update_schema_index(&self.store, &link, &old_entity.components, &[]).await?;
+        }
+        index::apply_field_index_updates(&self.store, &link, field_index_updates).await?;
         Ok(())
     }
 
+    /// Delete the entity at the link and all of the entities under it, returning the number of
+    /// entities that were deleted.
+    ///
+    /// This is not atomic: the entities are deleted one at a time, and if one of them can't be
+    /// deleted a [`SubtreeDeleteError`] is returned with the number of entities that were already
+    /// deleted.
+    pub async fn del_subtree<L: Into<ExactLink>>(&self, link: L) -> Result<usize> {
+        let links = self
+            .store
+            .list(link.into(), ListMode::Descendants, None, None, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for (deleted, link) in links.iter().enumerate() {
+            if let Err(error) = self.del_entity(link.clone()).await {
+                return Err(SubtreeDeleteError {
+                    deleted,
+                    failed: link.clone(),
+                    error,
+                }
+                .into());
+            }
+        }
+        Ok(links.len())
+    }
+
+    /// Copy the entity at `from` to `to`, along with all of the entities under it if `recursive`
+    /// is set, returning the links of the new entities.
+    ///
+    /// The copies have exactly the same snapshots as the originals. The blobs are not re-uploaded,
+    /// they are just pinned again for the new links. Any entities that already exist at the
+    /// destination links are replaced.
+    pub async fn copy<L1: Into<ExactLink>, L2: Into<ExactLink>>(
+        &self,
+        from: L1,
+        to: L2,
+        recursive: bool,
+    ) -> Result<Vec<ExactLink>> {
+        let from = from.into();
+        let to = to.into();
+        let sources = self.copy_sources(&from, &to, recursive).await?;
+
+        let mut copied = Vec::with_capacity(sources.len());
+        for source in sources {
+            let mut dest = to.clone();
+            dest.path
+                .0
+                .extend_from_slice(&source.path.0[from.path.0.len()..]);
+            self.copy_entity(&source, &dest, false).await?;
+            copied.push(dest);
+        }
+        Ok(copied)
+    }
+
+    /// Move the entity at `from` to `to`, along with all of the entities under it if `recursive`
+    /// is set, returning the links of the moved entities.
+    ///
+    /// This is a [`copy()`][Self::copy] followed by deleting the originals.
+    ///
+    /// Moves are not atomic, and nothing is rolled back if they fail. Every entity is copied before
+    /// any of the originals are deleted, so if a copy fails all of the originals are still there,
+    /// though some of the copies may already have been written. If deleting an original fails, all
+    /// of the copies have been written but some of the originals are left behind.
+    pub async fn move_entity<L1: Into<ExactLink>, L2: Into<ExactLink>>(
+        &self,
+        from: L1,
+        to: L2,
+        recursive: bool,
+    ) -> Result<Vec<ExactLink>> {
+        let from = from.into();
+        let to = to.into();
+        let sources = self.copy_sources(&from, &to, recursive).await?;
+
+        let mut moved = Vec::with_capacity(sources.len());
+        for source in &sources {
+            let mut dest = to.clone();
+            dest.path
+                .0
+                .extend_from_slice(&source.path.0[from.path.0.len()..]);
+            self.copy_entity(source, &dest, true).await?;
+            moved.push(dest);
+        }
+        for source in sources {
+            self.del_entity(source).await?;
+        }
+        Ok(moved)
+    }
+
+    /// Collect the links of the entities to copy from `from` to `to`.
+    async fn copy_sources(
+        &self,
+        from: &ExactLink,
+        to: &ExactLink,
+        recursive: bool,
+    ) -> Result<Vec<ExactLink>> {
+        if from == to {
+            anyhow::bail!("Cannot copy entity {from:?} onto itself");
+        }
+        if !recursive {
+            if self.store.get_entity(from).await?.is_none() {
+                anyhow::bail!("Entity does not exist: {from:?}");
+            }
+            return Ok(vec![from.clone()]);
+        }
+
+        if to.namespace == from.namespace
+            && to.subspace == from.subspace
+            && to.path.0.starts_with(&from.path.0)
+        {
+            anyhow::bail!("Cannot copy entity {from:?} into its own subtree {to:?}");
+        }
+        let sources = self
+            .store
+            .list(from.clone(), ListMode::Descendants, None, None, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        if sources.is_empty() {
+            anyhow::bail!("Entity does not exist: {from:?}");
+        }
+        Ok(sources)
+    }
+
+    /// Write the latest snapshot of the entity at `from` to `to`, pinning its blobs for the new
+    /// link.
+    ///
+    /// If `moving` is set, `from` is about to be deleted, so it may share unique index values with
+    /// the copy.
+    async fn copy_entity(&self, from: &ExactLink, to: &ExactLink, moving: bool) -> Result<()> {
+        let snapshot_id = self
+            .store
+            .get_entity(from)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("Entity does not exist: {from:?}"))?;
+        let bytes = self.store.get_blob(snapshot_id).await?;
+        let entity = Entity::deserialize(&mut &bytes[..])?;
+
+        let old_snapshot_id = self.store.get_entity(to).await?;
+        let field_index_updates = index::plan_field_index_updates(
+            &self.store,
+            to,
+            old_snapshot_id,
+            &entity.components,
+            &HashMap::new(),
+            moving.then_some(from),
+        )
+        .await?;
+
+        // Release the pins of whatever we are replacing before re-pinning, in case the destination
+        // shares some of the same snapshots.
+        let old_entity = match old_snapshot_id {
+            Some(old_snapshot_id) => {
+                release_pins(&self.store, to, old_snapshot_id, &entity.components).await?
+            }
+            None => None,
+        };
+
+        for entry in &entity.components {
+            self.store
+                .pin_blob(entry.component_id, to, snapshot_id)
+                .await?;
+            for digest in self.store.pinned_blobs(from, entry.component_id).await? {
+                self.store.pin_blob(digest, to, entry.component_id).await?;
+            }
+        }
+        self.store.store_entity(to, bytes).await?;
+        update_schema_index(
+            &self.store,
+            to,
+            old_entity.as_ref().map_or(&[], |x| &x.components[..]),
+            &entity.components,
+        )
+        .await?;
+        index::apply_field_index_updates(&self.store, to, field_index_updates).await?;
+        Ok(())
+    }
+
+    /// List the entities in the namespace that have a component with the given schema, optionally
+    /// only looking in one subspace.
+    ///
+    /// This uses an index that is updated whenever an entity is saved or deleted through Leaf, so
+    /// entities that were received from other peers and haven't been saved locally won't be found.
+    pub async fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace_filter: Option<SubspaceId>,
+        schema: Digest,
+    ) -> Result<impl Stream<Item = Result<ExactLink>> + '_> {
+        self.store
+            .find_by_schema(namespace, subspace_filter, schema)
+            .await
+    }
+
+    /// Start indexing the values of a field in the component `C`, so that entities can be found by
+    /// the field's value with [`Leaf::lookup()`].
+    ///
+    /// `field_path` is the path to the field in the component's [`BorshSchema`], in the format used
+    /// by [`BorshSchema::field_schema()`][types::BorshSchema::field_schema]. If `unique` is set,
+    /// saving an entity fails with an [`IndexConflict`] when another entity in the namespace
+    /// already has the same value.
+    ///
+    /// Entities that were saved before the index was registered are indexed right away, which fails
+    /// with an [`IndexConflict`] if a unique index would have duplicate values. Like
+    /// [`Leaf::find_by_schema()`], this only covers entities that were saved through Leaf.
+    ///
+    /// Stores with a local database, like the redb and Iroh stores, remember the registration. The
+    /// memory store forgets it when it is dropped.
+    pub async fn register_index<C: Component>(
+        &self,
+        field_path: &[&str],
+        unique: bool,
+    ) -> Result<FieldIndex> {
+        self.register_index_with_schema(
+            C::schema_id(),
+            C::borsh_schema(),
+            field_path.iter().map(|x| x.to_string()).collect(),
+            unique,
+        )
+        .await
+    }
+
+    /// Start indexing the values of a field in components with the given schema.
+    ///
+    /// See [`Leaf::register_index()`].
+    pub async fn register_index_with_schema(
+        &self,
+        schema_id: Digest,
+        format: types::BorshSchema,
+        field_path: Vec<String>,
+        unique: bool,
+    ) -> Result<FieldIndex> {
+        let index = FieldIndex {
+            schema: schema_id,
+            field_path,
+            unique,
+        };
+        let previous = self
+            .store
+            .register_field_index(index.clone(), format.clone())
+            .await?;
+        if previous.as_ref() == Some(&index) {
+            return Ok(index);
+        }
+        if let Err(e) = index::index_existing_entities(&self.store, &index, &format).await {
+            // Go back to the way the index was before, so that a failed registration doesn't
+            // leave a unique index with duplicate values behind.
+            match previous {
+                Some(previous) => {
+                    self.store.register_field_index(previous, format).await?;
+                }
+                None => self.store.unregister_field_index(index.id()).await?,
+            }
+            return Err(e);
+        }
+        Ok(index)
+    }
+
+    /// Find the entities in the namespace with the given value in an index.
+    pub async fn lookup<V: BorshSerialize + ?Sized>(
+        &self,
+        namespace: NamespaceId,
+        index: &FieldIndex,
+        value: &V,
+    ) -> Result<Vec<ExactLink>> {
+        let mut bytes = Vec::new();
+        value.serialize(&mut bytes)?;
+        self.lookup_bytes(namespace, index.id(), &bytes).await
+    }
+
+    /// Find the entities in the namespace with the given borsh-encoded value in the index with the
+    /// given [ID][FieldIndex::id].
+    pub async fn lookup_bytes(
+        &self,
+        namespace: NamespaceId,
+        index_id: Digest,
+        value: &[u8],
+    ) -> Result<Vec<ExactLink>> {
+        if self.store.field_indexes().get(index_id).is_none() {
+            anyhow::bail!("Index {index_id} is not registered");
+        }
+        self.store
+            .lookup_field_index(namespace, index_id, value)
+            .await
+    }
+
+    /// Remove leaked garbage collector pins for entity snapshots that have been replaced.
+    ///
+    /// See [`LeafStore::gc()`].
+    pub async fn gc(&self) -> Result<GcReport> {
+        self.store.gc().await
+    }
+
+    /// List the entities under the given link, either just its direct children or all of its
+    /// descendants, depending on the [`ListMode`].
     pub async fn list<L: Into<ExactLink>>(
         &self,
         link: L,
-        // TODO: add a recursive option to allow only listing the entities that are direct children
-        // of the link.
+        mode: ListMode,
     ) -> Result<impl Stream<Item = Result<ExactLink>> + '_> {
         let link = link.into();
-        let s = self.store.list(link, None, None).await?;
+        let s = self.store.list(link, mode, None, None, None).await?;
         Ok(s)
     }
 
+    /// List up to `limit` of the entities under the given link, along with their metadata,
+    /// starting after the `cursor` returned with the previous page, or from the beginning if there
+    /// is no cursor.
+    pub async fn list_page<L: Into<ExactLink>>(
+        &self,
+        link: L,
+        mode: ListMode,
+        limit: u64,
+        cursor: Option<ListCursor>,
+    ) -> Result<ListPage> {
+        if limit == 0 {
+            anyhow::bail!("The page limit must be greater than zero");
+        }
+        // Get one extra link so we know if there is another page after this one.
+        let mut links = self
+            .store
+            .list(
+                link.into(),
+                mode,
+                Some(limit.saturating_add(1)),
+                None,
+                cursor,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let next = if links.len() as u64 > limit {
+            links.truncate(limit as usize);
+            links.last().map(ListCursor::after)
+        } else {
+            None
+        };
+
+        // Entities that are deleted while we are listing are left out of the page.
+        let mut listed = Vec::with_capacity(links.len());
+        let mut meta = Vec::with_capacity(links.len());
+        for link in links {
+            if let Some((_, entity_meta)) = self.store.get_entity_meta(&link).await? {
+                listed.push(link);
+                meta.push(entity_meta);
+            }
+        }
+        Ok(ListPage {
+            links: listed,
+            meta,
+            next,
+        })
+    }
+
+    /// Watch for changes to the entity at the given link, and all of the entities under it.
+    ///
+    /// The returned stream will yield an event every time one of the entities is inserted, updated,
+    /// or deleted, either locally or by syncing with other peers.
+    pub async fn watch<L: Into<ExactLink>>(
+        &self,
+        link: L,
+    ) -> Result<impl Stream<Item = Result<EntityEvent>> + '_> {
+        let link = link.into();
+
+        // The store's updates don't say what an entity's snapshot was before, so we load the
+        // snapshots before we start watching to have something to compare the updates to. Changes
+        // that land before the watch starts are caught by loading the snapshots again afterwards.
+        let before = self.snapshots_under(&link).await?;
+        let mut updates = Box::pin(self.store.watch(link.clone()).await?);
+        let after = self.snapshots_under(&link).await?;
+        let mut buffered = Vec::new();
+        while let Some(Some(update)) = updates.next().now_or_never() {
+            buffered.push(update?);
+        }
+
+        let (mut snapshots, events) = initial_events(before, after, buffered);
+        let updates = updates.try_filter_map(move |update| {
+            futures::future::ready(Ok(entity_event(&mut snapshots, update)))
+        });
+        Ok(futures::stream::iter(events.into_iter().map(Ok)).chain(updates))
+    }
+
+    /// Get the latest snapshot of the entity at the link and each of the entities under it.
+    async fn snapshots_under(&self, link: &ExactLink) -> Result<HashMap<ExactLink, Digest>> {
+        let mut snapshots = HashMap::new();
+        let entities = self
+            .store
+            .list(link.clone(), ListMode::Descendants, None, None, None)
+            .await?;
+        futures::pin_mut!(entities);
+        while let Some(link) = entities.next().await {
+            let link = link?;
+            if let Some(snapshot) = self.store.get_entity(&link).await? {
+                snapshots.insert(link, snapshot);
+            }
+        }
+        Ok(snapshots)
+    }
+
     pub async fn list_namespaces(
         &self,
     ) -> anyhow::Result<impl Stream<Item = std::result::Result<NamespaceId, anyhow::Error>> + '_>
@@ -395,3 +1821,849 @@ impl<S: store::LeafStore + Clone> Leaf<S> {
         self.store.list_subspaces().await
     }
 }
+
+/// Work out the events for the changes that were made while [`Leaf::watch()`] was starting, given
+/// the snapshots from `before` and `after` the store's watch started, and the updates that the
+/// store had already sent.
+///
+/// Returns the latest snapshots, to compare later updates to, along with the events.
+fn initial_events(
+    before: HashMap<ExactLink, Digest>,
+    after: HashMap<ExactLink, Digest>,
+    buffered: Vec<store::EntityUpdate>,
+) -> (HashMap<ExactLink, Digest>, Vec<EntityEvent>) {
+    let changed = before
+        .keys()
+        .chain(after.keys())
+        .filter(|link| before.get(*link) != after.get(*link))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let mut snapshots = before;
+    let mut events = Vec::new();
+    for link in changed {
+        // If none of the updates got the entity to the snapshot we found after the watch started,
+        // it changed before the watch started and there won't be an update for it.
+        let snapshot = after.get(&link).copied();
+        let reported = buffered
+            .iter()
+            .any(|update| update.link == link && update.snapshot == snapshot);
+        if !reported {
+            let update = store::EntityUpdate { link, snapshot };
+            events.extend(entity_event(&mut snapshots, update));
+        }
+    }
+    for update in buffered {
+        events.extend(entity_event(&mut snapshots, update));
+    }
+    (snapshots, events)
+}
+
+/// Apply a store update to the latest snapshots, returning the event for it if the snapshot
+/// changed.
+fn entity_event(
+    snapshots: &mut HashMap<ExactLink, Digest>,
+    update: store::EntityUpdate,
+) -> Option<EntityEvent> {
+    let old_snapshot = match update.snapshot {
+        Some(snapshot) => snapshots.insert(update.link.clone(), snapshot),
+        None => snapshots.remove(&update.link),
+    };
+    let link = update.link;
+    match (old_snapshot, update.snapshot) {
+        (None, Some(snapshot)) => Some(EntityEvent::Inserted { link, snapshot }),
+        (Some(old_snapshot), Some(new_snapshot)) if old_snapshot != new_snapshot => {
+            Some(EntityEvent::Updated {
+                link,
+                old_snapshot,
+                new_snapshot,
+            })
+        }
+        (Some(old_snapshot), None) => Some(EntityEvent::Deleted { link, old_snapshot }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::{CommonMark, Description, Image, Name},
+        test_util::{image, leaf, link, save_name},
+    };
+
+    #[derive(LeafEntity)]
+    struct Profile {
+        name: Name,
+        description: Option<Description>,
+        images: Vec<Image>,
+    }
+
+    #[tokio::test]
+    async fn save_if_rejects_stale_snapshots() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+
+        let mut first = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        first.set_component(Name("first".into())).unwrap();
+        first.save_if(Digest::default()).await.unwrap();
+
+        // The entity exists now, so expecting it not to fails.
+        let mut second = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        second.digest = Digest::default();
+        second.set_component(Name("second".into())).unwrap();
+        let error = second.save_if(Digest::default()).await.unwrap_err();
+        let conflict = error.downcast_ref::<SnapshotConflict>().unwrap();
+        assert_eq!(conflict.actual, Some(first.digest));
+
+        // Saving against the current snapshot works.
+        second.save_if(first.digest).await.unwrap();
+        let loaded = leaf.entity(link).await.unwrap().entity().unwrap();
+        let name = loaded.get_component::<Name>().await.unwrap().unwrap();
+        assert_eq!(name.0, "second");
+    }
+
+    #[tokio::test]
+    async fn watch_reports_changes_under_the_link() {
+        let leaf = leaf();
+        let root = link(&leaf, "root").await;
+        let mut child = root.clone();
+        child.path.0.push("child".into());
+        let old_snapshot = save_name(&leaf, &root, "old").await.unwrap();
+
+        let events = leaf.watch(root.clone()).await.unwrap();
+        futures::pin_mut!(events);
+        let new_snapshot = save_name(&leaf, &root, "new").await.unwrap();
+        // Saving the same components again doesn't change the snapshot, so nothing is reported.
+        save_name(&leaf, &root, "new").await.unwrap();
+        save_name(&leaf, &link(&leaf, "other").await, "other")
+            .await
+            .unwrap();
+        let snapshot = save_name(&leaf, &child, "child").await.unwrap();
+        leaf.del_entity(child.clone()).await.unwrap();
+
+        let mut next = || events.next().now_or_never().unwrap().unwrap().unwrap();
+        assert_eq!(
+            next(),
+            EntityEvent::Updated {
+                link: root.clone(),
+                old_snapshot,
+                new_snapshot
+            }
+        );
+        assert_eq!(
+            next(),
+            EntityEvent::Inserted {
+                link: child.clone(),
+                snapshot
+            }
+        );
+        assert_eq!(
+            next(),
+            EntityEvent::Deleted {
+                link: child,
+                old_snapshot: snapshot
+            }
+        );
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn follow_resolves_inline_links() {
+        let leaf = leaf();
+        let exact = link(&leaf, "a").await;
+        let old = save_name(&leaf, &exact, "old").await.unwrap();
+        save_name(&leaf, &exact, "new").await.unwrap();
+
+        let link = types::Link::from(exact.clone());
+        assert_eq!(leaf.resolve_link(&link).unwrap(), exact);
+        let name = |entity: LoadedEntity<_>| async move {
+            entity.get_component::<Name>().await.unwrap().unwrap().0
+        };
+        let latest = leaf.follow(&link).await.unwrap().entity().unwrap();
+        assert_eq!(name(latest).await, "new");
+        let pinned = leaf.follow(&link.with_snapshot(old)).await.unwrap();
+        assert_eq!(name(pinned.entity().unwrap()).await, "old");
+
+        let custom = types::Link::new(
+            KeyResolverKind::Custom {
+                id: Digest::new(b"resolver"),
+                data: Vec::new(),
+            },
+            KeyResolverKind::Inline(exact.subspace),
+            exact.path.clone(),
+        );
+        assert!(leaf.resolve_link(&custom).is_err());
+    }
+
+    #[tokio::test]
+    async fn gc_pins_raw_component_references() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+        let blob = leaf
+            .store
+            .store_blob(b"image", &link, Digest::default())
+            .await
+            .unwrap();
+        let app = types::Schema {
+            name: "App".into(),
+            format: Image::schema().format,
+            specification: Digest::new(b"app"),
+        };
+        let component = ComponentData {
+            schema: app.id(),
+            data: image(blob).make_data().unwrap().data,
+        };
+
+        // Raw data with an unpublished schema is still saved, but its references aren't pinned.
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity
+            .add_component_data(ComponentKind::Unencrypted(component.clone()))
+            .await
+            .unwrap();
+        entity.save().await.unwrap();
+        leaf.gc().await.unwrap();
+        assert!(leaf.store.get_blob(blob).await.is_err());
+
+        let blob = leaf
+            .store
+            .store_blob(b"image", &link, Digest::default())
+            .await
+            .unwrap();
+        leaf.publish_schema_data(app.id(), app.clone())
+            .await
+            .unwrap();
+        entity.del_components_by_schema(app.id());
+        entity
+            .add_component_data(ComponentKind::Unencrypted(component))
+            .await
+            .unwrap();
+        entity.save().await.unwrap();
+        leaf.gc().await.unwrap();
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+
+        // The standard schemas are compiled in, so they don't need to be published.
+        let standard = leaf
+            .store
+            .store_blob(b"standard", &link, Digest::default())
+            .await
+            .unwrap();
+        entity
+            .add_component_data(ComponentKind::Unencrypted(
+                image(standard).make_data().unwrap(),
+            ))
+            .await
+            .unwrap();
+        entity.save().await.unwrap();
+        leaf.gc().await.unwrap();
+        assert_eq!(leaf.store.get_blob(standard).await.unwrap(), b"standard");
+    }
+
+    #[tokio::test]
+    async fn derived_leaf_entity_loads_and_applies_components() {
+        let leaf = leaf();
+        let link = link(&leaf, "profile").await;
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity.add_component(CommonMark("bio".into())).unwrap();
+        // The name is required.
+        assert!(Profile::load(&entity).await.is_err());
+
+        let profile = Profile {
+            name: Name("name".into()),
+            description: Some(Description("description".into())),
+            images: vec![image(Digest::new(b"a")), image(Digest::new(b"b"))],
+        };
+        profile.apply(&mut entity).unwrap();
+        entity.save().await.unwrap();
+
+        let mut entity = leaf.entity(link.clone()).await.unwrap().entity().unwrap();
+        let mut profile = Profile::load(&entity).await.unwrap();
+        assert_eq!(profile.name.0, "name");
+        assert_eq!(profile.description.unwrap().0, "description");
+        assert_eq!(profile.images.len(), 2);
+
+        profile.description = None;
+        profile.images.truncate(1);
+        profile.apply(&mut entity).unwrap();
+        entity.save().await.unwrap();
+        let entity = leaf.entity(link).await.unwrap().entity().unwrap();
+        let profile = Profile::load(&entity).await.unwrap();
+        assert!(profile.description.is_none());
+        assert_eq!(profile.images.len(), 1);
+        // Components that aren't part of the profile are left alone.
+        let bio = entity.get_component::<CommonMark>().await.unwrap().unwrap();
+        assert_eq!(bio.0, "bio");
+    }
+
+    #[tokio::test]
+    async fn copies_keep_snapshots_and_blobs() {
+        let leaf = leaf();
+        leaf.publish_standard_schemas().await.unwrap();
+        let a = link(&leaf, "a").await;
+        let b = link(&leaf, "b").await;
+        let blob = leaf
+            .store
+            .store_blob(b"image", &a, Digest::default())
+            .await
+            .unwrap();
+        let mut entity = leaf.entity(a.clone()).await.unwrap().get_or_init();
+        entity.add_component(image(blob)).unwrap();
+        entity.save().await.unwrap();
+
+        assert_eq!(
+            leaf.copy(a.clone(), b.clone(), false).await.unwrap(),
+            vec![b.clone()]
+        );
+        assert_eq!(
+            leaf.store.get_entity(&a).await.unwrap(),
+            Some(entity.digest)
+        );
+        assert_eq!(
+            leaf.store.get_entity(&b).await.unwrap(),
+            Some(entity.digest)
+        );
+        assert!(leaf.copy(a.clone(), a.clone(), false).await.is_err());
+
+        // The copy pins the blob on its own.
+        leaf.del_entity(a.clone()).await.unwrap();
+        leaf.gc().await.unwrap();
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+        assert!(leaf.copy(a, b.clone(), false).await.is_err());
+
+        let c = link(&leaf, "c").await;
+        assert_eq!(
+            leaf.move_entity(b.clone(), c.clone(), false).await.unwrap(),
+            vec![c.clone()]
+        );
+        assert_eq!(leaf.store.get_entity(&b).await.unwrap(), None);
+        assert_eq!(
+            leaf.store.get_entity(&c).await.unwrap(),
+            Some(entity.digest)
+        );
+        leaf.gc().await.unwrap();
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+    }
+
+    #[tokio::test]
+    async fn recursive_copies_and_moves_include_descendants() {
+        let leaf = leaf();
+        let from = link(&leaf, "from").await;
+        let mut child = from.clone();
+        child.path.0.push("child".into());
+        let root_snapshot = save_name(&leaf, &from, "root").await.unwrap();
+        let child_snapshot = save_name(&leaf, &child, "child").await.unwrap();
+
+        let to = link(&leaf, "to").await;
+        let mut to_child = to.clone();
+        to_child.path.0.push("child".into());
+        let mut copied = leaf.copy(from.clone(), to.clone(), true).await.unwrap();
+        copied.sort_by(|a, b| a.path.0.cmp(&b.path.0));
+        assert_eq!(copied, [to.clone(), to_child.clone()]);
+        assert_eq!(
+            leaf.store.get_entity(&to_child).await.unwrap(),
+            Some(child_snapshot)
+        );
+        assert_eq!(
+            leaf.store.get_entity(&child).await.unwrap(),
+            Some(child_snapshot)
+        );
+        // A non-recursive copy only takes the entity itself.
+        let single = link(&leaf, "single").await;
+        leaf.copy(from.clone(), single.clone(), false)
+            .await
+            .unwrap();
+        let mut single_child = single.clone();
+        single_child.path.0.push("child".into());
+        assert_eq!(leaf.store.get_entity(&single_child).await.unwrap(), None);
+        // Nothing can be copied into its own subtree.
+        assert!(leaf.copy(from.clone(), child.clone(), true).await.is_err());
+
+        let moved_to = link(&leaf, "moved").await;
+        let moved = leaf
+            .move_entity(from.clone(), moved_to.clone(), true)
+            .await
+            .unwrap();
+        assert_eq!(moved.len(), 2);
+        assert_eq!(leaf.store.get_entity(&from).await.unwrap(), None);
+        assert_eq!(leaf.store.get_entity(&child).await.unwrap(), None);
+        assert_eq!(
+            leaf.store.get_entity(&moved_to).await.unwrap(),
+            Some(root_snapshot)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_moves_keep_the_originals() {
+        let leaf = leaf();
+        let from = link(&leaf, "from").await;
+        let mut child = from.clone();
+        child.path.0.push("child".into());
+        for link in [&from, &child] {
+            save_name(&leaf, link, "name").await.unwrap();
+        }
+        leaf.store
+            .store_entity(&child, b"not an entity".to_vec())
+            .await
+            .unwrap();
+
+        let to = link(&leaf, "to").await;
+        assert!(leaf.move_entity(from.clone(), to, true).await.is_err());
+        assert!(leaf.store.get_entity(&from).await.unwrap().is_some());
+        assert!(leaf.store.get_entity(&child).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn del_subtree_deletes_descendants_only() {
+        let leaf = leaf();
+        let root = link(&leaf, "root").await;
+        let mut child = root.clone();
+        child.path.0.push("child".into());
+        let mut grandchild = child.clone();
+        grandchild.path.0.push("grandchild".into());
+        let sibling = link(&leaf, "sibling").await;
+        for link in [&root, &child, &grandchild, &sibling] {
+            save_name(&leaf, link, "name").await.unwrap();
+        }
+
+        assert_eq!(leaf.del_subtree(child.clone()).await.unwrap(), 2);
+        assert_eq!(leaf.store.get_entity(&child).await.unwrap(), None);
+        assert_eq!(leaf.store.get_entity(&grandchild).await.unwrap(), None);
+        assert!(leaf.store.get_entity(&root).await.unwrap().is_some());
+        assert!(leaf.store.get_entity(&sibling).await.unwrap().is_some());
+
+        assert_eq!(leaf.del_subtree(root.clone()).await.unwrap(), 1);
+        assert_eq!(leaf.del_subtree(root).await.unwrap(), 0);
+        assert!(leaf.store.get_entity(&sibling).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn del_subtree_reports_partial_deletes() {
+        let leaf = leaf();
+        let root = link(&leaf, "root").await;
+        let mut child = root.clone();
+        child.path.0.push("child".into());
+        let mut grandchild = child.clone();
+        grandchild.path.0.push("grandchild".into());
+        for link in [&root, &child, &grandchild] {
+            save_name(&leaf, link, "name").await.unwrap();
+        }
+        leaf.store
+            .store_entity(&child, b"not an entity".to_vec())
+            .await
+            .unwrap();
+
+        let error = leaf.del_subtree(root.clone()).await.unwrap_err();
+        let error = error.downcast_ref::<SubtreeDeleteError>().unwrap();
+        assert_eq!(error.deleted, 1);
+        assert_eq!(error.failed, child);
+        assert_eq!(leaf.store.get_entity(&root).await.unwrap(), None);
+        assert!(leaf.store.get_entity(&child).await.unwrap().is_some());
+        assert!(leaf.store.get_entity(&grandchild).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn find_by_schema_follows_saves_and_deletes() {
+        let leaf = leaf();
+        let a = link(&leaf, "a").await;
+        let b = link(&leaf, "b").await;
+        let other_subspace = leaf.store.import_subspace_secret([3; 32]).await.unwrap();
+        let c = ExactLink::from((a.namespace, other_subspace, ["c"]));
+        for link in [&a, &b, &c] {
+            save_name(&leaf, link, "name").await.unwrap();
+        }
+        let mut entity = leaf.entity(a.clone()).await.unwrap().entity().unwrap();
+        entity.add_component(Description("a".into())).unwrap();
+        entity.save().await.unwrap();
+
+        let find = |subspace, schema| {
+            let leaf = &leaf;
+            async move {
+                let mut links = leaf
+                    .find_by_schema(a.namespace, subspace, schema)
+                    .await
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                links.sort_by(|x, y| (x.subspace, &x.path.0).cmp(&(y.subspace, &y.path.0)));
+                links
+            }
+        };
+        let mut all = vec![a.clone(), b.clone(), c.clone()];
+        all.sort_by(|x, y| (x.subspace, &x.path.0).cmp(&(y.subspace, &y.path.0)));
+        assert_eq!(find(None, Name::schema_id()).await, all);
+        assert_eq!(
+            find(Some(a.subspace), Name::schema_id()).await,
+            vec![a.clone(), b.clone()]
+        );
+        assert_eq!(
+            find(Some(other_subspace), Name::schema_id()).await,
+            vec![c.clone()]
+        );
+        assert_eq!(find(None, Description::schema_id()).await, vec![a.clone()]);
+
+        // Removing the component or the entity takes it out of the index.
+        entity.del_components::<Description>();
+        entity.save().await.unwrap();
+        assert!(find(None, Description::schema_id()).await.is_empty());
+        leaf.del_entity(b.clone()).await.unwrap();
+        assert_eq!(
+            find(Some(a.subspace), Name::schema_id()).await,
+            vec![a.clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn components_are_validated_against_published_schemas() {
+        let leaf = leaf();
+        let app = types::Schema {
+            name: "App".into(),
+            format: types::BorshSchema::U32,
+            specification: Digest::new(b"app"),
+        };
+        let component = ComponentData {
+            schema: app.id(),
+            data: 1u32.to_le_bytes().to_vec(),
+        };
+        assert!(matches!(
+            leaf.validate_component(&component)
+                .await
+                .unwrap_err()
+                .downcast_ref::<InvalidComponent>(),
+            Some(InvalidComponent::UnpublishedSchema(_))
+        ));
+        leaf.publish_schema_data(app.id(), app).await.unwrap();
+        leaf.validate_component(&component).await.unwrap();
+
+        // The standard schemas are compiled in, so they don't need to be published.
+        let name = Name("name".into()).make_data().unwrap();
+        leaf.validate_component(&name).await.unwrap();
+
+        let mut data = Vec::new();
+        (u32::MAX, 0u8).serialize(&mut data).unwrap();
+        let invalid = ComponentData {
+            schema: Description::schema_id(),
+            data,
+        };
+        let error = leaf.validate_component(&invalid).await.unwrap_err();
+        let Some(InvalidComponent::Mismatch { error, .. }) = error.downcast_ref() else {
+            panic!("Expected a mismatch: {error}");
+        };
+        assert_eq!(error.offset, 0);
+    }
+
+    #[tokio::test]
+    async fn schemas_under_the_wrong_id_are_ignored() {
+        let leaf = leaf();
+        leaf.publish_standard_schemas().await.unwrap();
+        let app = types::Schema {
+            name: "App".into(),
+            format: types::BorshSchema::U32,
+            specification: Digest::new(b"app"),
+        };
+        assert!(leaf
+            .publish_schema_data(app.id(), Image::schema())
+            .await
+            .is_err());
+
+        // Write the wrong schema straight to the entities, as a peer could.
+        for schema_id in [app.id(), CommonMark::schema_id()] {
+            let mut entity = leaf
+                .entity(leaf.schema_link(schema_id))
+                .await
+                .unwrap()
+                .get_or_init();
+            entity.del_components_by_schema(schema_component_schema_id());
+            let mut data = Vec::new();
+            Image::schema().serialize(&mut data).unwrap();
+            entity
+                .add_component_data(ComponentKind::Unencrypted(ComponentData {
+                    schema: schema_component_schema_id(),
+                    data,
+                }))
+                .await
+                .unwrap();
+            entity.save().await.unwrap();
+        }
+        assert_eq!(leaf.get_schema(app.id()).await.unwrap(), None);
+
+        // The standard components always resolve to their compiled-in schemas.
+        assert_eq!(
+            leaf.get_schema(CommonMark::schema_id()).await.unwrap(),
+            Some(CommonMark::schema())
+        );
+        assert_eq!(
+            leaf.get_schema(Name::schema_id()).await.unwrap(),
+            Some(Name::schema())
+        );
+    }
+
+    #[tokio::test]
+    async fn garbage_schema_publications_are_ignored() {
+        let leaf = leaf();
+        let app = types::Schema {
+            name: "App".into(),
+            format: types::BorshSchema::U32,
+            specification: Digest::new(b"app"),
+        };
+
+        // Write an undecodable entity under one ID, and an undecodable schema under the other.
+        leaf.store
+            .store_entity(&leaf.schema_link(Name::schema_id()), b"garbage".to_vec())
+            .await
+            .unwrap();
+        let mut entity = leaf
+            .entity(leaf.schema_link(app.id()))
+            .await
+            .unwrap()
+            .get_or_init();
+        entity
+            .add_component_data(ComponentKind::Unencrypted(ComponentData {
+                schema: schema_component_schema_id(),
+                data: b"garbage".to_vec(),
+            }))
+            .await
+            .unwrap();
+        entity.save().await.unwrap();
+
+        assert_eq!(
+            leaf.get_schema(Name::schema_id()).await.unwrap(),
+            Some(Name::schema())
+        );
+        assert_eq!(leaf.get_schema(app.id()).await.unwrap(), None);
+
+        // Raw components can still be written with either schema.
+        let link = link(&leaf, "a").await;
+        let mut entity = leaf.entity(link).await.unwrap().get_or_init();
+        for component in [
+            Name("name".into()).make_data().unwrap(),
+            ComponentData {
+                schema: app.id(),
+                data: 1u32.to_le_bytes().to_vec(),
+            },
+        ] {
+            entity
+                .add_component_data(ComponentKind::Unencrypted(component))
+                .await
+                .unwrap();
+        }
+        entity.save().await.unwrap();
+
+        // And the garbage can be replaced by publishing the real schemas.
+        leaf.publish_standard_schemas().await.unwrap();
+        leaf.publish_schema_data(app.id(), app.clone())
+            .await
+            .unwrap();
+        assert_eq!(leaf.get_schema(app.id()).await.unwrap(), Some(app));
+    }
+
+    #[tokio::test]
+    async fn published_formats_cant_be_replaced() {
+        use components::DateCreated;
+        let leaf = leaf();
+        let mut schema = DateCreated::schema();
+        schema.format = types::BorshSchema::String;
+        assert!(leaf
+            .publish_schema_data(DateCreated::schema_id(), schema.clone())
+            .await
+            .is_err());
+        leaf.publish_standard_schemas().await.unwrap();
+        assert!(leaf
+            .publish_schema_data(DateCreated::schema_id(), schema)
+            .await
+            .is_err());
+        assert_eq!(
+            leaf.get_schema(DateCreated::schema_id()).await.unwrap(),
+            Some(DateCreated::schema())
+        );
+
+        let app = types::Schema {
+            name: "App".into(),
+            format: types::BorshSchema::U32,
+            specification: Digest::new(b"app"),
+        };
+        leaf.publish_schema_data(app.id(), app.clone())
+            .await
+            .unwrap();
+        leaf.publish_schema_data(app.id(), app.clone())
+            .await
+            .unwrap();
+        let replaced = types::Schema {
+            format: types::BorshSchema::String,
+            ..app.clone()
+        };
+        assert!(leaf.publish_schema_data(app.id(), replaced).await.is_err());
+        assert_eq!(leaf.get_schema(app.id()).await.unwrap(), Some(app));
+    }
+
+    #[tokio::test]
+    async fn undecodable_schemas_are_not_published() {
+        let leaf = leaf();
+        let huge = types::Schema {
+            name: "Huge".into(),
+            format: types::BorshSchema::Array {
+                schema: Box::new(types::BorshSchema::Null),
+                len: 200_000_000,
+            },
+            specification: Digest::new(b"huge"),
+        };
+        assert!(leaf.publish_schema_data(huge.id(), huge).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dates_are_only_touched_when_the_components_change() {
+        use components::{DateCreated, DateUpdated};
+        let mut leaf = leaf();
+        leaf.track_dates = true;
+        let link = link(&leaf, "a").await;
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity.set_component(Name("name".into())).unwrap();
+        entity.save().await.unwrap();
+        let created = entity
+            .get_component::<DateCreated>()
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Backdate the entity, so that we can tell whether it is touched again.
+        entity.track_dates = false;
+        entity.set_component(DateUpdated(1)).unwrap();
+        entity.save().await.unwrap();
+        let digest = entity.digest;
+
+        // Saving the same components again leaves the snapshot alone.
+        entity.track_dates = true;
+        entity.set_component(Name("name".into())).unwrap();
+        entity.save().await.unwrap();
+        assert_eq!(entity.digest, digest);
+        let updated = entity
+            .get_component::<DateUpdated>()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.0, 1);
+
+        entity.set_component(Name("changed".into())).unwrap();
+        entity.save().await.unwrap();
+        let updated = entity
+            .get_component::<DateUpdated>()
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(updated.0 >= created.0);
+        let still_created = entity
+            .get_component::<DateCreated>()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(still_created.0, created.0);
+    }
+
+    #[tokio::test]
+    async fn entity_meta_follows_the_latest_snapshot() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        assert!(entity.meta.is_none());
+
+        entity.set_component(Name("first".into())).unwrap();
+        entity.save().await.unwrap();
+        let first = entity.meta.unwrap();
+        assert!(first.last_modified > 0);
+        assert_eq!(first.author_subspace, link.subspace);
+        let bytes = leaf.store.get_blob(entity.digest).await.unwrap();
+        assert_eq!(first.size, bytes.len() as u64);
+        let loaded = leaf.entity(link.clone()).await.unwrap().entity().unwrap();
+        assert_eq!(loaded.meta, Some(first));
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        entity
+            .set_component(Name("second, which is longer".into()))
+            .unwrap();
+        entity.save().await.unwrap();
+        let second = entity.meta.unwrap();
+        assert!(second.last_modified > first.last_modified);
+        assert_eq!(
+            leaf.store.get_entity_meta(&link).await.unwrap(),
+            Some((entity.digest, second))
+        );
+        let page = leaf
+            .list_page(link.clone(), ListMode::Descendants, 10, None)
+            .await
+            .unwrap();
+        assert_eq!(page.links, std::slice::from_ref(&link));
+        assert_eq!(page.meta, [second]);
+
+        entity.delete().await.unwrap();
+        assert!(entity.meta.is_none());
+        assert_eq!(leaf.store.get_entity_meta(&link).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn entity_at_loads_old_snapshots() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+        let old = save_name(&leaf, &link, "old").await.unwrap();
+        let new = save_name(&leaf, &link, "new").await.unwrap();
+
+        let entity = leaf.entity_at(link.clone(), old).await.unwrap().unwrap();
+        assert_eq!(entity.digest, old);
+        let name = entity.get_component::<Name>().await.unwrap().unwrap();
+        assert_eq!(name.0, "old");
+        let entity = leaf.entity_at(link.clone(), new).await.unwrap().unwrap();
+        let name = entity.get_component::<Name>().await.unwrap().unwrap();
+        assert_eq!(name.0, "new");
+
+        let unknown = Digest::new(b"unknown");
+        assert!(leaf.entity_at(link, unknown).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn changes_while_the_watch_starts_are_reported() {
+        let link = |path: &str| ExactLink::from(([1; 32], [2; 32], [path]));
+        let (a, b, c, d) = (link("a"), link("b"), link("c"), link("d"));
+        let digest = |x: &[u8]| Digest::new(x);
+        let update = |link: &ExactLink, snapshot: Option<&[u8]>| store::EntityUpdate {
+            link: link.clone(),
+            snapshot: snapshot.map(Digest::new),
+        };
+        let before = HashMap::from([(a.clone(), digest(b"a1")), (b.clone(), digest(b"b1"))]);
+        // `a` was updated and `c` was inserted while the store's watch was starting, so both the
+        // snapshots we found afterwards and the buffered updates have them. `b` was deleted before
+        // the store's watch started, so there's no update for it. `d` was inserted after.
+        let after = HashMap::from([(a.clone(), digest(b"a2")), (c.clone(), digest(b"c1"))]);
+        let buffered = vec![
+            update(&a, Some(b"a2")),
+            update(&c, Some(b"c1")),
+            update(&d, Some(b"d1")),
+        ];
+
+        let (mut snapshots, events) = initial_events(before, after, buffered);
+        assert_eq!(
+            events,
+            [
+                EntityEvent::Deleted {
+                    link: b.clone(),
+                    old_snapshot: digest(b"b1")
+                },
+                EntityEvent::Updated {
+                    link: a.clone(),
+                    old_snapshot: digest(b"a1"),
+                    new_snapshot: digest(b"a2")
+                },
+                EntityEvent::Inserted {
+                    link: c.clone(),
+                    snapshot: digest(b"c1")
+                },
+                EntityEvent::Inserted {
+                    link: d,
+                    snapshot: digest(b"d1")
+                },
+            ]
+        );
+
+        // A late update for a change we already reported isn't reported again.
+        assert_eq!(entity_event(&mut snapshots, update(&b, None)), None);
+        assert_eq!(entity_event(&mut snapshots, update(&c, Some(b"c1"))), None);
+        assert!(entity_event(&mut snapshots, update(&c, Some(b"c2"))).is_some());
+    }
+}
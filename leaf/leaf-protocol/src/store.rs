@@ -1,17 +1,29 @@
 //! Backend stores that may be used with the [`Leaf`] struct.
 
-use std::{fmt::Debug, future::Future};
+use std::{fmt::Debug, future::Future, time::Duration};
 
 use anyhow::Result;
 use futures::Stream;
 
 use crate::{
-    types::{ExactLink, NamespaceId, NamespaceSecretKey, SubspaceId, SubspaceSecretKey},
+    index::{FieldIndex, FieldIndexes},
+    types::{
+        BorshSchema, EncryptionAlgorithm, EntityPath, ExactLink, NamespaceId, NamespaceSecretKey,
+        SubspaceId, SubspaceSecretKey,
+    },
     Digest,
 };
 
 #[cfg(feature = "backend_iroh")]
 pub mod iroh;
+#[cfg(any(feature = "backend_iroh", feature = "backend_redb"))]
+mod local_index;
+#[cfg(feature = "backend_memory")]
+pub mod memory;
+#[cfg(feature = "backend_redb")]
+pub mod redb;
+#[cfg(any(feature = "backend_memory", feature = "backend_redb"))]
+mod watchers;
 
 pub trait KeyResolverImpl<KeyId> {
     /// Returns the `EncryptionAlgorithmId` that this implements.
@@ -22,14 +34,176 @@ pub trait KeyResolverImpl<KeyId> {
 
 pub trait EncryptionAlgorithmImpl<Digest> {
     /// Returns the `EncryptionAlgorithmId` that this implements.
+    ///
+    /// This must be the [`id()`][EncryptionAlgorithm::id] of the
+    /// [`algorithm()`][Self::algorithm].
     fn id(&self) -> Digest;
+    /// Returns the [`EncryptionAlgorithm`] that this implements.
+    fn algorithm(&self) -> EncryptionAlgorithm;
+    /// Returns whether the key with the given ID is available for encrypting and decrypting.
+    fn has_key(&self, key_id: [u8; 32]) -> bool;
     /// Encrypts the data using the provided key.
-    fn encrypt(&self, key_id: [u8; 32], data: &[u8]) -> Vec<u8>;
+    fn encrypt(&self, key_id: [u8; 32], data: &[u8]) -> Result<Vec<u8>>;
     /// Decrypts the data using the provided key.
-    fn decrypt(&self, key_id: [u8; 32], data: &[u8]) -> Vec<u8>;
+    fn decrypt(&self, key_id: [u8; 32], data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Which entities to return when listing the entities under a path.
+#[derive(
+    borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum ListMode {
+    /// Only list the entities that are direct children of the path.
+    Children,
+    /// List all of the entities under the path, recursively.
+    #[default]
+    Descendants,
+}
+
+/// An opaque position in the results of [`LeafStore::list()`], used to resume listing after the
+/// last entity that was returned.
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListCursor(EntityPath);
+
+impl ListCursor {
+    /// Create a cursor that resumes listing after the entity at the given link.
+    pub fn after(link: &ExactLink) -> Self {
+        Self(link.path.clone())
+    }
+
+    /// Get the link to the last entity that was listed, making sure that it is under the link
+    /// that is being listed.
+    #[cfg(any(
+        feature = "backend_iroh",
+        feature = "backend_memory",
+        feature = "backend_redb"
+    ))]
+    pub(crate) fn resume_link(&self, listed: &ExactLink) -> Result<ExactLink> {
+        if !self.0 .0.starts_with(&listed.path.0) {
+            anyhow::bail!("List cursor is not under the listed path: {listed:?}");
+        }
+        Ok(ExactLink {
+            namespace: listed.namespace,
+            subspace: listed.subspace,
+            path: self.0.clone(),
+        })
+    }
 }
 
-// TODO: Find way to avoid leaking blobs in the garbage collector.
+/// The size of the chunks returned by [`LeafStore::get_blob_reader()`] for stores that don't have
+/// their own chunking.
+#[cfg(any(feature = "backend_memory", feature = "backend_redb"))]
+const BLOB_READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A byte range of a blob, for [`LeafStore::get_blob_reader()`].
+#[derive(
+    borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub struct BlobRange {
+    /// The offset of the first byte to read.
+    pub offset: u64,
+    /// The maximum number of bytes to read, or [`None`] to read to the end of the blob.
+    pub len: Option<u64>,
+}
+
+impl BlobRange {
+    /// The range of the whole blob.
+    pub fn full() -> Self {
+        Self::default()
+    }
+
+    /// The range of `len` bytes starting at `offset`.
+    pub fn new(offset: u64, len: u64) -> Self {
+        Self {
+            offset,
+            len: Some(len),
+        }
+    }
+
+    /// Get the offset and length of the range in a blob of the given size, making sure that it
+    /// doesn't start past the end of the blob.
+    pub fn clamp(&self, size: u64) -> Result<(u64, u64)> {
+        if self.offset > size {
+            anyhow::bail!(
+                "Blob range starts at {} but the blob is only {size} bytes",
+                self.offset
+            );
+        }
+        let available = size - self.offset;
+        Ok((
+            self.offset,
+            self.len.map_or(available, |len| len.min(available)),
+        ))
+    }
+
+    /// Split the part of the blob data in the range into chunks, for stores that load the whole
+    /// blob into memory anyway.
+    #[cfg(any(feature = "backend_memory", feature = "backend_redb"))]
+    pub(crate) fn chunks(&self, data: &[u8]) -> Result<impl Stream<Item = Result<Vec<u8>>>> {
+        let (offset, len) = self.clamp(data.len() as u64)?;
+        let data = &data[offset as usize..(offset + len) as usize];
+        let chunks = data
+            .chunks(BLOB_READ_CHUNK_SIZE)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        Ok(futures::stream::iter(chunks))
+    }
+}
+
+/// An entity snapshot to store with [`LeafStore::store_entities()`].
+#[derive(Debug, Clone)]
+pub struct EntityWrite {
+    /// The link to the entity.
+    pub link: ExactLink,
+    /// The snapshot digest that the entity must have for the write to be made, or a null digest if
+    /// the entity must not exist yet.
+    pub expected: Digest,
+    /// The serialized [`Entity`][crate::types::Entity] snapshot, or [`None`] to only check that
+    /// the entity has its expected snapshot, without writing it.
+    pub data: Option<Vec<u8>>,
+}
+
+/// A change to the entity at a link, reported by [`LeafStore::watch()`].
+#[derive(Debug, Clone)]
+pub struct EntityUpdate {
+    /// The link to the entity that changed.
+    pub link: ExactLink,
+    /// The new snapshot digest of the entity, or [`None`] if the entity was deleted.
+    pub snapshot: Option<Digest>,
+}
+
+/// Metadata about the latest snapshot of an entity, returned by [`LeafStore::get_entity_meta()`].
+#[derive(
+    borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Hash,
+)]
+pub struct EntityMeta {
+    /// When the snapshot was stored, in microseconds since the unix epoch.
+    pub last_modified: u64,
+    /// The subspace that wrote the snapshot.
+    pub author_subspace: SubspaceId,
+    /// The size of the snapshot in bytes, not counting the data of its components.
+    pub size: u64,
+}
+
+/// The default time that [`LeafStore::gc()`] leaves new GC pins and newly unpinned blobs alone
+/// for, because they may belong to an entity snapshot or blob upload that is still in progress.
+pub const DEFAULT_GC_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// The result of a garbage collector reconciliation pass, returned by [`LeafStore::gc()`].
+#[derive(
+    borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub struct GcReport {
+    /// The number of blob pins that were removed.
+    pub pins_removed: u64,
+    /// The total size of the blobs whose pins were removed, in bytes.
+    ///
+    /// This is not how much space was freed: blobs that are still pinned by another entity
+    /// snapshot are counted too, and are kept.
+    pub bytes_unpinned: u64,
+}
+
+// Note on leaking blobs in the garbage collector:
 //
 // Right now the garbage collector cleans up data by the fact that every time you overwrite an
 // entity, it will look at the previous components of the entity, and remove all of the GC pins for
@@ -41,7 +215,7 @@ pub trait EncryptionAlgorithmImpl<Digest> {
 // collector pins.
 //
 // This situation means that live data should never have a problem getting deleted, but some dead
-// data might get left in the `_leaf_gc_` table forever.
+// data might get left in the `_leaf_gc_` table until `LeafStore::gc()` is run to sweep it up.
 
 pub trait LeafStore: Debug {
     /// Get an iterator over key resolver algorithms implemented by this backend.
@@ -52,6 +226,8 @@ pub trait LeafStore: Debug {
         &self,
     ) -> Box<dyn Iterator<Item = &dyn EncryptionAlgorithmImpl<Digest>> + '_>;
 
+    /// Get the ID of the subspace with the given secret key, without importing the secret.
+    fn subspace_id(&self, subspace_secret: &SubspaceSecretKey) -> SubspaceId;
     fn create_subspace(&self) -> impl Future<Output = Result<SubspaceId>>;
     fn get_subspace_secret(
         &self,
@@ -64,7 +240,17 @@ pub trait LeafStore: Debug {
         &self,
         subspace_secret: SubspaceSecretKey,
     ) -> impl Future<Output = Result<SubspaceId>>;
+    /// List the subspaces that have entities in the namespace.
+    ///
+    /// Unlike [`list_subspaces()`][Self::list_subspaces], this includes subspaces that we don't
+    /// have the secret for, such as ones whose entities were synced from peers.
+    fn list_namespace_subspaces(
+        &self,
+        namespace: NamespaceId,
+    ) -> impl Future<Output = Result<impl Stream<Item = anyhow::Result<SubspaceId>>>>;
 
+    /// Get the ID of the namespace with the given secret key, without importing the secret.
+    fn namespace_id(&self, secret: &NamespaceSecretKey) -> NamespaceId;
     fn create_namespace(&self) -> impl Future<Output = Result<NamespaceId>>;
     fn list_namespaces(
         &self,
@@ -91,6 +277,16 @@ pub trait LeafStore: Debug {
         link: &ExactLink,
         entity_snapshot_id: Digest,
     ) -> impl Future<Output = Result<Digest>>;
+    /// Pin a blob that is already in the store for an entity snapshot, just like
+    /// [`LeafStore::store_blob()`] does for new blobs.
+    ///
+    /// Returns `false` without pinning anything if the blob is not available in the local store.
+    fn pin_blob(
+        &self,
+        digest: Digest,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> impl Future<Output = Result<bool>>;
     /// Delete a blob. This doesn't necessarily delete the blob immediately, but it removes the
     /// garbage collector pin for the entity snapshot.
     ///
@@ -100,18 +296,447 @@ pub trait LeafStore: Debug {
         link: &ExactLink,
         entity_snapshot_id: Digest,
     ) -> impl Future<Output = Result<usize>>;
+    /// List the blobs pinned for an entity snapshot with [`LeafStore::store_blob()`] or
+    /// [`LeafStore::pin_blob()`].
+    fn pinned_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> impl Future<Output = Result<Vec<Digest>>>;
+    /// Remove the blob pins of entity snapshots that are no longer the latest version of their
+    /// entity.
+    ///
+    /// This cleans up pins that were leaked because an entity was overwritten right after a new
+    /// version of it was synced.
+    fn gc(&self) -> impl Future<Output = Result<GcReport>>;
     /// Get's a blob from the local store.
     fn get_blob(&self, digest: Digest) -> impl Future<Output = Result<Vec<u8>>>;
+    /// Store a blob from a stream of chunks, pinning it for an entity snapshot just like
+    /// [`LeafStore::store_blob()`].
+    ///
+    /// If the stream yields an error, nothing is pinned and the error is returned.
+    fn put_blob_stream(
+        &self,
+        data: impl Stream<Item = Result<Vec<u8>>>,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> impl Future<Output = Result<Digest>>;
+    /// Read a range of a blob from the local store as a stream of chunks.
+    ///
+    /// Fails if the blob is not in the local store or if the range starts past the end of it.
+    fn get_blob_reader(
+        &self,
+        digest: Digest,
+        range: BlobRange,
+    ) -> impl Future<Output = Result<impl Stream<Item = Result<Vec<u8>>>>>;
+    /// Get the size of a blob in bytes, or [`None`] if it is not in the local store.
+    fn blob_size(&self, digest: Digest) -> impl Future<Output = Result<Option<u64>>>;
 
     fn store_entity(&self, link: &ExactLink, data: Vec<u8>)
         -> impl Future<Output = Result<Digest>>;
+    /// Store the snapshots of several entities, but only if each entity still has its
+    /// [`expected`][EntityWrite::expected] snapshot, returning the new snapshot digests in the same
+    /// order as the writes.
+    ///
+    /// Writes without [`data`][EntityWrite::data] are checked like the others, but leave the
+    /// entity as it is, and return its expected digest.
+    ///
+    /// All of the entities are checked before any of them are written, and if one has a different
+    /// snapshot, a [`BatchConflict`][crate::batch::BatchConflict] error is returned and nothing is
+    /// written. Stores without transactions, like the Iroh store, can still be left with some of
+    /// the entities written if there is an I/O error part way through writing them.
+    fn store_entities(&self, writes: Vec<EntityWrite>)
+        -> impl Future<Output = Result<Vec<Digest>>>;
     fn del_entity(&self, link: &ExactLink) -> impl Future<Output = Result<()>>;
     fn get_entity(&self, link: &ExactLink) -> impl Future<Output = Result<Option<Digest>>>;
+    /// Get the latest snapshot of the entity at the link, along with its [`EntityMeta`].
+    fn get_entity_meta(
+        &self,
+        link: &ExactLink,
+    ) -> impl Future<Output = Result<Option<(Digest, EntityMeta)>>>;
 
+    /// List the entities under the given link's path.
+    ///
+    /// The entity at the link itself is included in the results when listing
+    /// [`Descendants`][ListMode::Descendants], but not when listing [`Children`][ListMode::Children].
+    ///
+    /// Entities are always listed in the same order, so passing a [`ListCursor`] for the last
+    /// entity of one call resumes the listing where it left off. The `offset` is applied after the
+    /// cursor.
     fn list(
         &self,
         link: ExactLink,
+        mode: ListMode,
         limit: Option<u64>,
         offset: Option<u64>,
+        cursor: Option<ListCursor>,
     ) -> impl Future<Output = Result<impl Stream<Item = anyhow::Result<ExactLink>>>>;
+
+    /// Update the index of which entities have components with each schema, after the entity at
+    /// the link has been saved or deleted.
+    ///
+    /// Adding a schema that is already indexed for the entity, or removing one that isn't, does
+    /// nothing.
+    fn update_schema_index(
+        &self,
+        link: &ExactLink,
+        removed: &[Digest],
+        added: &[Digest],
+    ) -> impl Future<Output = Result<()>>;
+
+    /// List the entities in the namespace that have a component with the given schema, according
+    /// to the index maintained with [`update_schema_index()`][Self::update_schema_index].
+    ///
+    /// If `subspace` is set, only entities in that subspace are listed.
+    fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> impl Future<Output = Result<impl Stream<Item = anyhow::Result<ExactLink>>>>;
+
+    /// The field indexes that are kept up to date when entities are saved and deleted.
+    fn field_indexes(&self) -> &FieldIndexes;
+
+    /// Add a field index to the [`field_indexes()`][Self::field_indexes], replacing any index on
+    /// the same field and returning it.
+    ///
+    /// Stores with a local database remember the registration, and register the index again when
+    /// they are opened.
+    fn register_field_index(
+        &self,
+        index: FieldIndex,
+        format: BorshSchema,
+    ) -> impl Future<Output = Result<Option<FieldIndex>>>;
+
+    /// Remove the field index with the given [ID][FieldIndex::id] from the
+    /// [`field_indexes()`][Self::field_indexes], along with all of its values.
+    fn unregister_field_index(&self, index: Digest) -> impl Future<Output = Result<()>>;
+
+    /// Update a field index after the entity at the link has been saved or deleted.
+    ///
+    /// The values are the borsh-encoded field values, as returned by
+    /// [`BorshSchema::field_values()`][crate::types::BorshSchema::field_values].
+    fn update_field_index(
+        &self,
+        link: &ExactLink,
+        index: Digest,
+        removed: &[Vec<u8>],
+        added: &[Vec<u8>],
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Add values to a [`unique`][FieldIndex::unique] field index for the entity at the link, but
+    /// only if no other entity in the link's namespace has any of them, returning the values that
+    /// the entity didn't have yet.
+    ///
+    /// The values are checked and added together, so that two entities that are saved at the same
+    /// time can't both take the same value. If another entity has one of the values, an
+    /// [`IndexConflict`][crate::index::IndexConflict] error is returned and nothing is added.
+    fn claim_field_index_values(
+        &self,
+        link: &ExactLink,
+        index: &FieldIndex,
+        values: &[Vec<u8>],
+    ) -> impl Future<Output = Result<Vec<Vec<u8>>>>;
+
+    /// List the entities in the namespace that have the given value in a field index.
+    fn lookup_field_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: &[u8],
+    ) -> impl Future<Output = Result<Vec<ExactLink>>>;
+
+    /// Watch for changes to the entity at the given link, and all of the entities under it.
+    ///
+    /// Only changes made after the watch is started, whether they are made locally or received by
+    /// syncing with other peers, are reported.
+    ///
+    /// Only a limited number of updates are buffered for the stream: if it isn't read fast enough,
+    /// it may end with an error instead of buffering more.
+    fn watch(
+        &self,
+        link: ExactLink,
+    ) -> impl Future<Output = Result<impl Stream<Item = anyhow::Result<EntityUpdate>>>>;
+
+    /// Watch for changes to every entity in the namespace, in any subspace.
+    ///
+    /// Like with [`watch()`][Self::watch], only changes made after the watch is started are
+    /// reported.
+    fn watch_namespace(
+        &self,
+        namespace: NamespaceId,
+    ) -> impl Future<Output = Result<impl Stream<Item = anyhow::Result<EntityUpdate>>>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    #[cfg(feature = "backend_iroh")]
+    use crate::test_util::iroh_leaf;
+    use crate::{
+        test_util::{leaf, link, redb_leaf, save_name},
+        types::PathSegment,
+        Leaf,
+    };
+
+    /// Check streaming blobs in and out of the store behind `leaf`.
+    async fn check_blob_streams<S: LeafStore + Clone>(leaf: Leaf<S>) {
+        let link = link(&leaf, "a").await;
+        let snapshot = Digest::new(b"snapshot");
+        let data = (0..150_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let chunks = data
+            .chunks(10_000)
+            .map(|x| Ok(x.to_vec()))
+            .collect::<Vec<_>>();
+        let digest = leaf
+            .store
+            .put_blob_stream(futures::stream::iter(chunks), &link, snapshot)
+            .await
+            .unwrap();
+        assert_eq!(digest, Digest::new(&data));
+        assert_eq!(leaf.store.blob_size(digest).await.unwrap(), Some(150_000));
+        assert!(leaf
+            .store
+            .pinned_blobs(&link, snapshot)
+            .await
+            .unwrap()
+            .contains(&digest));
+
+        let read = |range| {
+            let store = leaf.store.clone();
+            async move {
+                store
+                    .get_blob_reader(digest, range)
+                    .await?
+                    .try_collect::<Vec<_>>()
+                    .await
+            }
+        };
+        let chunks = read(BlobRange::full()).await.unwrap();
+        assert!(chunks.iter().all(|x| x.len() <= BLOB_READ_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), data);
+        let part = read(BlobRange::new(100_000, 100)).await.unwrap().concat();
+        assert_eq!(part, &data[100_000..100_100]);
+        // Ranges are cut off at the end of the blob, but can't start past it.
+        let end = read(BlobRange::new(149_990, 100)).await.unwrap().concat();
+        assert_eq!(end, &data[149_990..]);
+        assert!(read(BlobRange::new(150_000, 1)).await.unwrap().is_empty());
+        assert!(read(BlobRange::new(150_001, 1)).await.is_err());
+
+        // A stream that fails part way through doesn't store or pin anything.
+        let other_snapshot = Digest::new(b"other");
+        let chunks = vec![Ok(b"partial".to_vec()), Err(anyhow::format_err!("failed"))];
+        assert!(leaf
+            .store
+            .put_blob_stream(futures::stream::iter(chunks), &link, other_snapshot)
+            .await
+            .is_err());
+        assert!(leaf
+            .store
+            .pinned_blobs(&link, other_snapshot)
+            .await
+            .unwrap()
+            .is_empty());
+        let partial = Digest::new(b"partial");
+        assert_eq!(leaf.store.blob_size(partial).await.unwrap(), None);
+        assert!(leaf
+            .store
+            .get_blob_reader(partial, BlobRange::full())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_blob_streams() {
+        check_blob_streams(leaf()).await;
+    }
+
+    #[tokio::test]
+    async fn redb_blob_streams() {
+        check_blob_streams(redb_leaf()).await;
+    }
+
+    /// Check that pages of children cover every child once, in order.
+    async fn check_list_pages<S: LeafStore + Clone>(leaf: &Leaf<S>) {
+        let root = link(leaf, "pages").await;
+        let mut expected = Vec::new();
+        for i in 0..7u64 {
+            let mut child = root.clone();
+            child.path.0.push(i.into());
+            save_name(leaf, &child, "child").await.unwrap();
+            let mut grandchild = child.clone();
+            grandchild.path.0.push("inner".into());
+            save_name(leaf, &grandchild, "grandchild").await.unwrap();
+            expected.push(child);
+        }
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = leaf
+                .list_page(root.clone(), ListMode::Children, 3, cursor)
+                .await
+                .unwrap();
+            assert!(page.links.len() <= 3);
+            assert_eq!(page.links.len(), page.meta.len());
+            listed.extend(page.links);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(listed, expected);
+
+        let descendants = leaf
+            .list(root, ListMode::Descendants)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(descendants.len(), 14);
+    }
+
+    /// Collect every page of the listing, `size` links at a time.
+    async fn all_pages<S: LeafStore + Clone>(
+        leaf: &Leaf<S>,
+        root: &ExactLink,
+        mode: ListMode,
+        size: u64,
+    ) -> Vec<ExactLink> {
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = leaf
+                .list_page(root.clone(), mode, size, cursor)
+                .await
+                .unwrap();
+            listed.extend(page.links);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => return listed,
+            }
+        }
+    }
+
+    /// Check that listing children skips over their subtrees, including the subtrees of paths
+    /// that don't have an entity themselves.
+    async fn check_list_subtrees<S: LeafStore + Clone>(leaf: &Leaf<S>) {
+        let root = link(leaf, "subtrees").await;
+        let child = |segments: &[PathSegment]| {
+            let mut link = root.clone();
+            link.path.0.extend_from_slice(segments);
+            link
+        };
+        let mut expected = Vec::new();
+        for segment in [
+            PathSegment::from("a"),
+            "c".into(),
+            "a longer name".into(),
+            5u64.into(),
+        ] {
+            let link = child(std::slice::from_ref(&segment));
+            save_name(leaf, &link, "child").await.unwrap();
+            for inner in ["x", "y"] {
+                save_name(leaf, &child(&[segment.clone(), inner.into()]), "inner")
+                    .await
+                    .unwrap();
+            }
+            expected.push(link);
+        }
+        // Only the grandchild of `b` exists, so `b` isn't listed.
+        save_name(leaf, &child(&["b".into(), "inner".into()]), "inner")
+            .await
+            .unwrap();
+
+        let children = leaf
+            .list(root.clone(), ListMode::Children)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let mut sorted = children.clone();
+        sorted.sort();
+        expected.sort();
+        assert_eq!(sorted, expected);
+        assert_eq!(
+            all_pages(leaf, &root, ListMode::Children, 1).await,
+            children
+        );
+
+        let descendants = leaf
+            .list(root.clone(), ListMode::Descendants)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(descendants.len(), 13);
+        assert_eq!(
+            all_pages(leaf, &root, ListMode::Descendants, 3).await,
+            descendants
+        );
+    }
+
+    /// Check that a cursor still works after the entity it points at is deleted.
+    async fn check_cursor_after_delete<S: LeafStore + Clone>(leaf: &Leaf<S>) {
+        let root = link(leaf, "deleted").await;
+        for i in 0..4u64 {
+            let mut child = root.clone();
+            child.path.0.push(i.into());
+            save_name(leaf, &child, "child").await.unwrap();
+        }
+        let page = leaf
+            .list_page(root.clone(), ListMode::Children, 2, None)
+            .await
+            .unwrap();
+        leaf.del_entity(page.links[1].clone()).await.unwrap();
+        let page = leaf
+            .list_page(root, ListMode::Children, 2, page.next)
+            .await
+            .unwrap();
+        let paths = page.links.iter().map(|x| x.path.0[1].clone());
+        assert_eq!(paths.collect::<Vec<_>>(), [2u64.into(), 3u64.into()]);
+        assert!(page.next.is_none());
+    }
+
+    /// Check that a cursor from somewhere else is rejected.
+    async fn check_cursor_outside_the_path<S: LeafStore + Clone>(leaf: &Leaf<S>) {
+        let root = link(leaf, "root").await;
+        let other = link(leaf, "other").await;
+        let cursor = ListCursor::after(&other);
+        assert!(leaf
+            .list_page(root, ListMode::Children, 2, Some(cursor))
+            .await
+            .is_err());
+    }
+
+    async fn check_listing<S: LeafStore + Clone>(leaf: &Leaf<S>) {
+        check_list_pages(leaf).await;
+        check_list_subtrees(leaf).await;
+        check_cursor_after_delete(leaf).await;
+        check_cursor_outside_the_path(leaf).await;
+    }
+
+    #[tokio::test]
+    async fn memory_listing() {
+        check_listing(&leaf()).await;
+    }
+
+    #[tokio::test]
+    async fn redb_listing() {
+        check_listing(&redb_leaf()).await;
+    }
+
+    #[cfg(feature = "backend_iroh")]
+    #[tokio::test]
+    async fn iroh_listing() {
+        let (node, leaf) = iroh_leaf().await;
+        check_listing(&leaf).await;
+        node.shutdown().await.unwrap();
+    }
 }
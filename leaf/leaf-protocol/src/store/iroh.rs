@@ -1,20 +1,39 @@
 use std::{
-    io::{Cursor, Read, Write},
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{self, Cursor, Read, Write},
     sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use futures::TryStreamExt;
+use futures::{channel::mpsc, pin_mut, SinkExt, Stream, StreamExt, TryStreamExt};
 use iroh::{
     base::node_addr::AddrInfoOptions,
-    docs::{store::Query, Author, AuthorId, Capability, NamespaceSecret},
+    blobs::util::SetTagOption,
+    client::{
+        blobs::BlobStatus,
+        docs::{Doc, Entry, LiveEvent},
+    },
+    docs::{
+        store::{Query, SortBy, SortDirection},
+        Author, AuthorId, Capability, CapabilityKind, NamespaceSecret,
+    },
 };
 use once_cell::sync::Lazy;
+use redb::Database;
 
 use crate::{
-    store::LeafStore,
-    types::{EntityPath, NamespaceSecretKey, PathSegment, SubspaceId},
-    Digest, ExactLink,
+    batch::BatchConflict,
+    encryption::XChaCha20Poly1305Algorithm,
+    index::{FieldIndex, FieldIndexes},
+    store::{
+        local_index::LocalIndexes, BlobRange, EntityMeta, EntityUpdate, EntityWrite, GcReport,
+        LeafStore, ListCursor, ListMode, DEFAULT_GC_GRACE_PERIOD,
+    },
+    types::{
+        BorshSchema, Entity, EntityPath, NamespaceId, NamespaceSecretKey, PathSegment, SubspaceId,
+    },
+    Digest, ExactLink, SnapshotConflict,
 };
 
 pub type LeafIroh = crate::Leaf<LeafIrohStore>;
@@ -90,6 +109,23 @@ impl LeafGcPath {
 pub struct LeafIrohStore {
     pub client: iroh::client::Iroh,
     pub docs: Arc<quick_cache::sync::Cache<iroh::docs::NamespaceId, iroh::client::Doc>>,
+    /// The built-in encryption algorithm, which holds the keys used for encrypted components.
+    pub encryption: XChaCha20Poly1305Algorithm,
+    /// The field indexes that this store keeps up to date.
+    pub field_indexes: FieldIndexes,
+    /// The schema and field indexes, which are kept in a local database instead of the documents
+    /// so that they aren't synced to other peers.
+    indexes: LocalIndexes,
+    /// GC pins younger than this are left alone by [`reconcile_gc()`][Self::reconcile_gc],
+    /// because they may belong to an entity snapshot that is still being saved.
+    pub gc_grace_period: Duration,
+    /// Held while entities are written, so that [`store_entities()`][LeafStore::store_entities]
+    /// can check the snapshots of the entities and write them without another local write
+    /// landing in between.
+    ///
+    /// Documents have no transactions, so this only covers writes made through this store.
+    /// Writes synced from peers can still land at any time, and the latest one wins.
+    write_lock: Arc<tokio::sync::Mutex<()>>,
 }
 pub struct IrohDocumentKeyFormat {
     pub path: Vec<PathSegment>,
@@ -134,7 +170,7 @@ impl IrohDocumentKeyFormat {
             reader.read_exact(&mut segment_len_bytes)?;
             let segment_len = u32::from_le_bytes(segment_len_bytes);
 
-            segment_bytes.extend(std::iter::repeat(0u8).take(segment_len as _));
+            segment_bytes.extend(std::iter::repeat_n(0u8, segment_len as _));
             reader.read_exact(&mut segment_bytes)?;
 
             path.push(PathSegment::deserialize(&mut &segment_bytes[..])?);
@@ -145,11 +181,19 @@ impl IrohDocumentKeyFormat {
 }
 
 impl LeafIrohStore {
-    pub fn new(client: iroh::client::Iroh) -> Self {
-        Self {
+    /// Create a store using the given Iroh node, keeping the data that is only relevant to this
+    /// node, like indexes, in the `local` database.
+    pub fn new(client: iroh::client::Iroh, local: Database) -> anyhow::Result<Self> {
+        let indexes = LocalIndexes::new(Arc::new(local))?;
+        Ok(Self {
             client,
             docs: Arc::new(quick_cache::sync::Cache::new(10)),
-        }
+            encryption: Default::default(),
+            field_indexes: indexes.field_indexes()?,
+            indexes,
+            gc_grace_period: DEFAULT_GC_GRACE_PERIOD,
+            write_lock: Default::default(),
+        })
     }
 
     /// Open a document using the local document cache.
@@ -164,6 +208,100 @@ impl LeafIrohStore {
             .await
     }
 
+    /// Sweep the `_leaf_gc_` pins written by this node in every writable document, removing the
+    /// pins for entity snapshots that are no longer the latest snapshot of their entity.
+    ///
+    /// Pins for the data referenced by a component, which are stored under the component's ID,
+    /// are kept as long as the component is part of the latest snapshot.
+    pub async fn reconcile_gc(&self) -> anyhow::Result<GcReport> {
+        let author = self.client.authors().default().await?;
+        let mut gc_prefix = Vec::new();
+        LEAF_GC_PREFIX_STR.to_string().serialize(&mut gc_prefix)?;
+        let cutoff = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .saturating_sub(self.gc_grace_period)
+            .as_micros() as u64;
+
+        let mut report = GcReport::default();
+        let namespaces = self
+            .client
+            .docs()
+            .list()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for (namespace, capability) in namespaces {
+            if !matches!(capability, CapabilityKind::Write) {
+                continue;
+            }
+            let doc = self.open(namespace).await?;
+            let pins = doc
+                .get_many(Query::author(author).key_prefix(&gc_prefix))
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+
+            // The IDs that pins may be stored under for each entity we've checked, or `None` if
+            // we can't tell which pins are live.
+            let mut live_ids =
+                HashMap::<(SubspaceId, Vec<PathSegment>), Option<HashSet<Digest>>>::new();
+            for pin in pins {
+                // Skip deleted and recently written pins.
+                if pin.content_len() == 0 || pin.timestamp() > cutoff {
+                    continue;
+                }
+                let Ok(path) = LeafGcPath::from_bytes(pin.key()) else {
+                    continue;
+                };
+                let prefix = path.prefix;
+                let key = (prefix.subspace, prefix.entity_path);
+                let ids = match live_ids.get(&key) {
+                    Some(ids) => ids,
+                    None => {
+                        let link = ExactLink {
+                            namespace: *namespace.as_bytes(),
+                            subspace: key.0,
+                            path: EntityPath(key.1.clone()),
+                        };
+                        let ids = self.live_gc_ids(&link).await?;
+                        live_ids.entry(key).or_insert(ids)
+                    }
+                };
+                let is_live = match ids {
+                    Some(ids) => ids.contains(&prefix.entity_snapshot_id),
+                    None => true,
+                };
+                if !is_live {
+                    doc.del(author, pin.key().to_vec()).await?;
+                    report.pins_removed += 1;
+                    report.bytes_unpinned += pin.content_len();
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Get the IDs that live GC pins for the entity may be stored under: the entity's latest
+    /// snapshot and the IDs of its components.
+    ///
+    /// Returns `None` if we don't have the latest snapshot or can't decode it, so we can't know its
+    /// components.
+    async fn live_gc_ids(&self, link: &ExactLink) -> anyhow::Result<Option<HashSet<Digest>>> {
+        let mut ids = HashSet::new();
+        if let Some(snapshot) = self.get_entity(link).await? {
+            let Ok(bytes) = self.get_blob(snapshot).await else {
+                return Ok(None);
+            };
+            let Ok(entity) = Entity::deserialize(&mut &bytes[..]) else {
+                return Ok(None);
+            };
+            ids.insert(snapshot);
+            ids.extend(entity.components.into_iter().map(|x| x.component_id));
+        }
+        Ok(Some(ids))
+    }
+
     pub fn get_entity_key(subspace: SubspaceId, path: &[PathSegment]) -> Vec<u8> {
         assert_ne!(
             path.first(),
@@ -175,6 +313,136 @@ impl LeafIrohStore {
         path.insert(0, PathSegment::Bytes(subspace.to_vec()));
         IrohDocumentKeyFormat::new(path).to_bytes()
     }
+
+    /// Write an entity snapshot to its document. The caller must hold the
+    /// [`write_lock`][Self::write_lock].
+    async fn write_entity(&self, link: &ExactLink, data: Vec<u8>) -> anyhow::Result<Digest> {
+        let doc = self.open(link.namespace.into()).await?;
+        let key = Self::get_entity_key(link.subspace, &link.path.0);
+        let digest = doc.set_bytes(link.subspace.into(), key, data).await?;
+        Ok(Digest(digest))
+    }
+}
+
+/// Get the link to the entity that a document entry is a snapshot of, or [`None`] if the entry is
+/// something else, like a GC pin.
+///
+/// Entity snapshots are written by their subspace, under a key that starts with the subspace.
+fn entry_link(namespace: NamespaceId, author: AuthorId, key: &[u8]) -> Option<ExactLink> {
+    if key.is_empty() {
+        return None;
+    }
+    let mut key = IrohDocumentKeyFormat::from_bytes(key).ok()?;
+    let subspace = *author.as_bytes();
+    if key.path.first() != Some(&PathSegment::Bytes(subspace.to_vec())) {
+        return None;
+    }
+    key.path.remove(0);
+    Some(ExactLink {
+        namespace,
+        subspace,
+        path: EntityPath(key.path),
+    })
+}
+
+/// List the entries from the author with keys that start with `within`, and come after all of the
+/// keys that start with `after`, in key order.
+///
+/// Documents can only be queried by key prefix, so instead of starting one query after `after`,
+/// this queries each of the prefixes that share the first `i` bytes of `after` and have a greater
+/// byte after them, from the longest to the shortest. The last key with each of the shared prefixes
+/// tells us which of those bytes could have any keys, so that we don't query the rest.
+fn entries_after(
+    doc: Doc,
+    author: AuthorId,
+    within_len: usize,
+    after: Vec<u8>,
+) -> impl Stream<Item = anyhow::Result<Entry>> {
+    futures::stream::iter((within_len..after.len()).rev().map(anyhow::Ok))
+        .and_then(move |i| {
+            let doc = doc.clone();
+            let shared = after[..i].to_vec();
+            let byte = after[i];
+            async move {
+                let last = doc
+                    .get_one(
+                        Query::key_prefix(&shared)
+                            .author(author)
+                            .sort_by(SortBy::AuthorKey, SortDirection::Desc)
+                            .limit(1),
+                    )
+                    .await?;
+                let max = last
+                    .and_then(|last| last.key().get(i).copied())
+                    .filter(|max| *max > byte)
+                    .unwrap_or(byte);
+                let prefixes = (byte..max).map(move |byte| {
+                    let mut prefix = shared.clone();
+                    prefix.push(byte + 1);
+                    prefix
+                });
+                let entries = futures::stream::iter(prefixes.map(anyhow::Ok))
+                    .and_then(move |prefix| {
+                        let doc = doc.clone();
+                        async move { doc.get_many(Query::key_prefix(prefix).author(author)).await }
+                    })
+                    .try_flatten();
+                anyhow::Ok(entries)
+            }
+        })
+        .try_flatten()
+}
+
+/// List the entries from the author for the direct children of the path with the key prefix
+/// `within`, starting after the child with the key prefix `after`.
+///
+/// After each child we query for the next one with [`entries_after()`], so the entries of the
+/// deeper descendants are skipped instead of read.
+fn child_entries(
+    doc: Doc,
+    author: AuthorId,
+    within: Vec<u8>,
+    after: Option<Vec<u8>>,
+) -> impl Stream<Item = anyhow::Result<Entry>> {
+    futures::stream::try_unfold(after, move |mut after| {
+        let doc = doc.clone();
+        let within = within.clone();
+        async move {
+            loop {
+                let next = match after {
+                    None => {
+                        doc.get_one(Query::key_prefix(&within).author(author).limit(1))
+                            .await?
+                    }
+                    Some(after) => {
+                        let entries = entries_after(doc.clone(), author, within.len(), after);
+                        pin_mut!(entries);
+                        entries.try_next().await?
+                    }
+                };
+                let Some(entry) = next else {
+                    return Ok(None);
+                };
+
+                // The key of the child is the key prefix of its subtree followed by the null
+                // terminator, so it is the first key in the subtree if the child exists.
+                let key = entry.key();
+                let len_end = within.len() + 4;
+                let segment_len = key
+                    .get(within.len()..len_end)
+                    .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                    .ok_or_else(|| anyhow::format_err!("Invalid document key: {key:?}"))?;
+                let child = key
+                    .get(..len_end + segment_len)
+                    .ok_or_else(|| anyhow::format_err!("Invalid document key: {key:?}"))?;
+                let is_child = key.len() == child.len() + 1;
+                after = Some(child.to_vec());
+                if is_child {
+                    return Ok(Some((entry, after)));
+                }
+            }
+        }
+    })
 }
 
 impl LeafStore for LeafIrohStore {
@@ -185,7 +453,7 @@ impl LeafStore for LeafIrohStore {
     fn encryption_algorithms(
         &self,
     ) -> Box<dyn Iterator<Item = &dyn super::EncryptionAlgorithmImpl<Digest>> + '_> {
-        Box::new([].into_iter())
+        Box::new([&self.encryption as _].into_iter())
     }
 
     async fn store_blob(
@@ -205,6 +473,24 @@ impl LeafStore for LeafIrohStore {
         Ok(Digest(hash))
     }
 
+    async fn pin_blob(
+        &self,
+        digest: Digest,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<bool> {
+        let Ok(BlobStatus::Complete { size }) = self.client.blobs().status(digest.0).await else {
+            return Ok(false);
+        };
+        let doc = self.open(link.namespace.into()).await?;
+
+        let key = LeafGcPath::new(link, entity_snapshot_id, digest);
+        let doc_key = key.to_bytes();
+        let author_id = self.client.authors().default().await?;
+        doc.set_hash(author_id, doc_key, digest.0, size).await?;
+        Ok(true)
+    }
+
     async fn del_blobs(
         &self,
         link: &ExactLink,
@@ -219,17 +505,132 @@ impl LeafStore for LeafIrohStore {
         Ok(deleted)
     }
 
+    async fn pinned_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Vec<Digest>> {
+        let doc = self.open(link.namespace.into()).await?;
+
+        let path_prefix = LeafGcPathPrefix::new(link, entity_snapshot_id).to_bytes();
+        let author_id = self.client.authors().default().await?;
+        let entries = doc
+            .get_many(Query::author(author_id).key_prefix(path_prefix))
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.content_len() > 0)
+            .map(|entry| Digest(entry.content_hash()))
+            .collect())
+    }
+
+    async fn gc(&self) -> anyhow::Result<GcReport> {
+        self.reconcile_gc().await
+    }
+
     async fn get_blob(&self, digest: Digest) -> anyhow::Result<Vec<u8>> {
         Ok(self.client.blobs().read_to_bytes(digest.0).await?.to_vec())
     }
 
-    async fn store_entity(&self, link: &ExactLink, data: Vec<u8>) -> anyhow::Result<Digest> {
+    async fn put_blob_stream(
+        &self,
+        data: impl futures::Stream<Item = anyhow::Result<Vec<u8>>>,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
         let doc = self.open(link.namespace.into()).await?;
-        let key = Self::get_entity_key(link.subspace, &link.path.0);
-        let digest = doc.set_bytes(link.subspace.into(), key, data).await?;
-        Ok(Digest(digest))
+
+        // Iroh needs a `'static` stream, so we forward the chunks to it through a channel.
+        let (mut chunks, receiver) = mpsc::channel(1);
+        let progress = self
+            .client
+            .blobs()
+            .add_stream(receiver, SetTagOption::Auto)
+            .await?;
+        let forward = async move {
+            pin_mut!(data);
+            while let Some(chunk) = data.next().await {
+                match chunk {
+                    Ok(chunk) => chunks.send(Ok(chunk.into())).await?,
+                    Err(e) => {
+                        // Make Iroh abort the import instead of storing a partial blob.
+                        chunks.send(Err(io::Error::other(e.to_string()))).await.ok();
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(())
+        };
+        let (forwarded, outcome) = futures::future::join(forward, progress.finish()).await;
+        forwarded?;
+        let outcome = outcome?;
+
+        // Document entries can't be empty, and there's nothing to collect for an empty blob anyway.
+        if outcome.size == 0 {
+            return Ok(Digest(outcome.hash));
+        }
+        let key = LeafGcPath::new(link, entity_snapshot_id, Digest(outcome.hash));
+        let doc_key = key.to_bytes();
+        let author_id = self.client.authors().default().await?;
+        doc.set_hash(author_id, doc_key, outcome.hash, outcome.size)
+            .await?;
+        Ok(Digest(outcome.hash))
+    }
+
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+        range: BlobRange,
+    ) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<Vec<u8>>>> {
+        let Some(size) = self.blob_size(digest).await? else {
+            anyhow::bail!("Blob not found: {digest}");
+        };
+        let (offset, len) = range.clamp(size)?;
+        let reader = self
+            .client
+            .blobs()
+            .read_at(digest.0, offset, Some(len as usize))
+            .await?;
+        Ok(reader.map(|chunk| Ok(chunk?.to_vec())))
+    }
+
+    async fn blob_size(&self, digest: Digest) -> anyhow::Result<Option<u64>> {
+        match self.client.blobs().status(digest.0).await {
+            Ok(BlobStatus::Complete { size }) => Ok(Some(size)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn store_entity(&self, link: &ExactLink, data: Vec<u8>) -> anyhow::Result<Digest> {
+        let _lock = self.write_lock.lock().await;
+        self.write_entity(link, data).await
+    }
+    async fn store_entities(&self, writes: Vec<EntityWrite>) -> anyhow::Result<Vec<Digest>> {
+        // Documents have no transactions, so we check every entity before writing any of them,
+        // under the write lock so that no other local write lands in between. Only an I/O error
+        // while writing can leave the batch partly written.
+        let _lock = self.write_lock.lock().await;
+        for write in &writes {
+            let actual = self.get_entity(&write.link).await?;
+            if let Some(conflict) = SnapshotConflict::check(&write.link, write.expected, actual) {
+                return Err(BatchConflict::from(conflict).into());
+            }
+        }
+        let mut digests = Vec::with_capacity(writes.len());
+        for write in writes {
+            let Some(data) = write.data else {
+                digests.push(write.expected);
+                continue;
+            };
+            digests.push(self.write_entity(&write.link, data).await?);
+        }
+        Ok(digests)
     }
     async fn del_entity(&self, link: &ExactLink) -> anyhow::Result<()> {
+        let _lock = self.write_lock.lock().await;
         let doc = self.open(link.namespace.into()).await?;
         let key = Self::get_entity_key(link.subspace, &link.path.0);
         doc.del(link.subspace.into(), key).await?;
@@ -244,11 +645,31 @@ impl LeafStore for LeafIrohStore {
         Ok(entity)
     }
 
+    async fn get_entity_meta(
+        &self,
+        link: &ExactLink,
+    ) -> anyhow::Result<Option<(Digest, EntityMeta)>> {
+        let doc = self.open(link.namespace.into()).await?;
+        let key = Self::get_entity_key(link.subspace, &link.path.0);
+        let entity = doc.get_exact(link.subspace.into(), key, false).await?;
+        let entity = entity.map(|entry| {
+            let meta = EntityMeta {
+                last_modified: entry.timestamp(),
+                author_subspace: *entry.author().as_bytes(),
+                size: entry.content_len(),
+            };
+            (Digest(entry.content_hash()), meta)
+        });
+        Ok(entity)
+    }
+
     async fn list(
         &self,
         link: ExactLink,
+        mode: ListMode,
         limit: Option<u64>,
         offset: Option<u64>,
+        cursor: Option<ListCursor>,
     ) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<ExactLink>>> {
         let link = link.clone();
         let doc = self.open(link.namespace.into()).await?;
@@ -259,29 +680,218 @@ impl LeafStore for LeafIrohStore {
         // Remove the null terminator so that we find all of the children of this path
         let path_bytes = &path_bytes[0..(path_bytes.len() - 1)];
 
-        let mut query = Query::key_prefix(path_bytes).author(link.subspace.into());
-        if let Some(limit) = limit {
-            query = query.limit(limit);
-        }
-        if let Some(offset) = offset {
-            query = query.limit(offset);
-        }
-        let stream = doc.get_many(query).await?;
+        let resume_key = cursor
+            .map(|cursor| {
+                let mut path = vec![PathSegment::Bytes(link.subspace.to_vec())];
+                path.extend(cursor.resume_link(&link)?.path.0);
+                anyhow::Ok(IrohDocumentKeyFormat::new(path).to_bytes())
+            })
+            .transpose()?;
+
+        let author = AuthorId::from(link.subspace);
+        let within = path_bytes.to_vec();
+        // The limit and offset can only be given to the document query when it lists the whole
+        // subtree, otherwise they are applied afterwards.
+        let (skip, take) = match (mode, &resume_key) {
+            (ListMode::Descendants, None) => (0, usize::MAX),
+            _ => (
+                offset.unwrap_or(0).try_into()?,
+                limit
+                    .map(usize::try_from)
+                    .transpose()?
+                    .unwrap_or(usize::MAX),
+            ),
+        };
+        let entries = match (mode, resume_key) {
+            (ListMode::Descendants, None) => {
+                let mut query = Query::key_prefix(&within).author(author);
+                if let Some(limit) = limit {
+                    query = query.limit(limit);
+                }
+                if let Some(offset) = offset {
+                    query = query.offset(offset);
+                }
+                doc.get_many(query).await?.boxed()
+            }
+            (ListMode::Descendants, Some(resume_key)) => {
+                // Keys that start with the cursor's key come right after it, and the rest of the
+                // keys after it can be queried by prefix.
+                let extending = doc
+                    .get_many(Query::key_prefix(&resume_key).author(author))
+                    .await?;
+                let after = resume_key.clone();
+                extending
+                    .try_filter(move |entry| futures::future::ready(entry.key() != after))
+                    .chain(entries_after(doc, author, within.len(), resume_key))
+                    .boxed()
+            }
+            (ListMode::Children, resume_key) => {
+                // Skip the subtree of the child that the cursor is for.
+                let after = resume_key.map(|mut key| {
+                    key.pop();
+                    key
+                });
+                child_entries(doc, author, within, after).boxed()
+            }
+        };
 
-        let s = stream.and_then(move |x| async move {
-            let mut key = IrohDocumentKeyFormat::from_bytes(x.key())?;
-            key.path.remove(0); // Remove the subspace path segment
+        let s = entries
+            .and_then(move |x| async move {
+                let mut key = IrohDocumentKeyFormat::from_bytes(x.key())?;
+                key.path.remove(0); // Remove the subspace path segment
 
-            Ok(ExactLink {
-                namespace: link.namespace,
-                subspace: link.subspace,
-                path: EntityPath(key.path),
+                Ok(ExactLink {
+                    namespace: link.namespace,
+                    subspace: link.subspace,
+                    path: EntityPath(key.path),
+                })
             })
+            .skip(skip)
+            .take(take);
+
+        Ok(s)
+    }
+
+    async fn update_schema_index(
+        &self,
+        link: &ExactLink,
+        removed: &[Digest],
+        added: &[Digest],
+    ) -> anyhow::Result<()> {
+        self.indexes.update_schema_index(link, removed, added)
+    }
+
+    async fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<ExactLink>>> {
+        let links = self.indexes.find_by_schema(namespace, subspace, schema)?;
+        Ok(futures::stream::iter(links))
+    }
+
+    fn field_indexes(&self) -> &FieldIndexes {
+        &self.field_indexes
+    }
+
+    async fn register_field_index(
+        &self,
+        index: FieldIndex,
+        format: BorshSchema,
+    ) -> anyhow::Result<Option<FieldIndex>> {
+        let previous = self.field_indexes.register(index.clone(), format.clone())?;
+        self.indexes.register_field_index(&index, &format)?;
+        Ok(previous)
+    }
+
+    async fn unregister_field_index(&self, index: Digest) -> anyhow::Result<()> {
+        self.field_indexes.remove(index);
+        self.indexes.unregister_field_index(index)
+    }
+
+    async fn update_field_index(
+        &self,
+        link: &ExactLink,
+        index: Digest,
+        removed: &[Vec<u8>],
+        added: &[Vec<u8>],
+    ) -> anyhow::Result<()> {
+        self.indexes.update_field_index(link, index, removed, added)
+    }
+
+    async fn claim_field_index_values(
+        &self,
+        link: &ExactLink,
+        index: &FieldIndex,
+        values: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        self.indexes.claim_field_index_values(link, index, values)
+    }
+
+    async fn lookup_field_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: &[u8],
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        self.indexes.lookup_field_index(namespace, index, value)
+    }
+
+    async fn watch(
+        &self,
+        link: ExactLink,
+    ) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<EntityUpdate>>> {
+        let doc = self.open(link.namespace.into()).await?;
+
+        let mut path = vec![PathSegment::Bytes(link.subspace.to_vec())];
+        path.extend(link.path.0.iter().cloned());
+        let mut prefix = IrohDocumentKeyFormat::new(path).to_bytes();
+        // Remove the null terminator so that we match all of the children of this path
+        prefix.pop();
+
+        let events = doc.subscribe().await?;
+        let s = events.try_filter_map(move |event| {
+            let entry = match event {
+                LiveEvent::InsertLocal { entry } => Some(entry),
+                LiveEvent::InsertRemote { entry, .. } => Some(entry),
+                _ => None,
+            };
+            // Skip garbage collector pins and entries that are outside of the watched prefix.
+            let update = entry
+                .filter(|entry| {
+                    entry.author() == AuthorId::from(link.subspace)
+                        && entry.key().starts_with(&prefix)
+                })
+                .map(|entry| {
+                    let mut key = IrohDocumentKeyFormat::from_bytes(entry.key())?;
+                    key.path.remove(0); // Remove the subspace path segment
+
+                    // Deletions are recorded as empty entries.
+                    let snapshot = (entry.content_len() > 0).then(|| Digest(entry.content_hash()));
+                    Ok::<_, anyhow::Error>(EntityUpdate {
+                        link: ExactLink {
+                            namespace: link.namespace,
+                            subspace: link.subspace,
+                            path: EntityPath(key.path),
+                        },
+                        snapshot,
+                    })
+                })
+                .transpose();
+            futures::future::ready(update)
         });
 
         Ok(s)
     }
 
+    async fn watch_namespace(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<EntityUpdate>>> {
+        let doc = self.open(namespace.into()).await?;
+        let events = doc.subscribe().await?;
+        let s = events.try_filter_map(move |event| {
+            let entry = match event {
+                LiveEvent::InsertLocal { entry } | LiveEvent::InsertRemote { entry, .. } => entry,
+                _ => return futures::future::ready(Ok(None)),
+            };
+            let update = entry_link(namespace, entry.author(), entry.key()).map(|link| {
+                EntityUpdate {
+                    link,
+                    // Deletions are recorded as empty entries.
+                    snapshot: (entry.content_len() > 0).then(|| Digest(entry.content_hash())),
+                }
+            });
+            futures::future::ready(Ok(update))
+        });
+        Ok(s)
+    }
+
+    fn subspace_id(&self, author_secret: &crate::prelude::SubspaceSecretKey) -> SubspaceId {
+        *Author::from_bytes(author_secret).public_key().as_bytes()
+    }
+
     async fn create_subspace(&self) -> anyhow::Result<SubspaceId> {
         let author = self.client.authors().create().await?;
         Ok(*author.as_bytes())
@@ -302,6 +912,32 @@ impl LeafStore for LeafIrohStore {
         Ok(author.map(|x| x.to_bytes()))
     }
 
+    fn namespace_id(&self, namespace_secret: &NamespaceSecretKey) -> crate::prelude::NamespaceId {
+        *NamespaceSecret::from_bytes(namespace_secret)
+            .id()
+            .as_bytes()
+    }
+
+    async fn list_namespace_subspaces(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<SubspaceId>>> {
+        let doc = self.open(namespace.into()).await?;
+        let subspaces = doc
+            .get_many(Query::all())
+            .await?
+            .try_filter_map(move |entry| {
+                let subspace = (entry.content_len() > 0)
+                    .then(|| entry_link(namespace, entry.author(), entry.key()))
+                    .flatten()
+                    .map(|link| link.subspace);
+                futures::future::ready(Ok(subspace))
+            })
+            .try_collect::<BTreeSet<_>>()
+            .await?;
+        Ok(futures::stream::iter(subspaces.into_iter().map(Ok)))
+    }
+
     async fn create_namespace(&self) -> anyhow::Result<crate::prelude::NamespaceId> {
         let doc = self.client.docs().create().await?;
         Ok(doc.id().to_bytes())
@@ -363,3 +999,250 @@ impl LeafStore for LeafIrohStore {
             .map_ok(move |(id, _)| *id.as_bytes()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::{Image, Name},
+        index::IndexConflict,
+        store::DEFAULT_GC_GRACE_PERIOD,
+        test_util::{image, iroh_leaf, link},
+        Component,
+    };
+
+    #[test]
+    fn document_keys_round_trip() {
+        // Segments of different lengths make sure each one is read into a buffer of its own size.
+        let path = vec![
+            PathSegment::String("a much longer first segment".into()),
+            PathSegment::Null,
+            PathSegment::Uint(7),
+            PathSegment::Bytes(vec![0; 3]),
+        ];
+        let bytes = IrohDocumentKeyFormat::new(path.clone()).to_bytes();
+        assert_eq!(
+            IrohDocumentKeyFormat::from_bytes(&bytes).unwrap().path,
+            path
+        );
+        assert!(IrohDocumentKeyFormat::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let empty = IrohDocumentKeyFormat::new(Vec::new()).to_bytes();
+        assert!(IrohDocumentKeyFormat::from_bytes(&empty)
+            .unwrap()
+            .path
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn namespace_watches_and_listings_cover_every_subspace() {
+        let (_node, leaf) = iroh_leaf().await;
+        let a = link(&leaf, "a").await;
+        let mut b = a.clone();
+        b.subspace = leaf.store.create_subspace().await.unwrap();
+        let updates = leaf.store.watch_namespace(a.namespace).await.unwrap();
+        pin_mut!(updates);
+        for link in [&a, &b] {
+            let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+            entity.set_component(Name("name".into())).unwrap();
+            entity.save().await.unwrap();
+        }
+
+        // The GC pins written along the way aren't reported.
+        for link in [&a, &b] {
+            let update = updates.try_next().await.unwrap().unwrap();
+            assert_eq!(&update.link, link);
+            assert_eq!(update.snapshot, leaf.store.get_entity(link).await.unwrap());
+        }
+        let mut subspaces = leaf
+            .store
+            .list_namespace_subspaces(a.namespace)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        subspaces.sort();
+        let mut expected = vec![a.subspace, b.subspace];
+        expected.sort();
+        assert_eq!(subspaces, expected);
+    }
+
+    /// The ID of the image component in the entity's latest snapshot.
+    async fn image_component(store: &LeafIrohStore, link: &ExactLink) -> Digest {
+        let snapshot = store.get_entity(link).await.unwrap().unwrap();
+        let bytes = store.get_blob(snapshot).await.unwrap();
+        let entity = Entity::deserialize(&mut &bytes[..]).unwrap();
+        let image_schema = Some(Image::schema_id());
+        let entry = entity
+            .components
+            .iter()
+            .find(|x| x.schema_id == image_schema);
+        entry.unwrap().component_id
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_the_pins_of_malformed_snapshots() {
+        let (_node, mut leaf) = iroh_leaf().await;
+        leaf.store.gc_grace_period = Duration::ZERO;
+        let a = link(&leaf, "a").await;
+        let b = link(&leaf, "b").await;
+        let blob = leaf
+            .store
+            .store_blob(b"image", &a, Digest::default())
+            .await
+            .unwrap();
+        let mut entity = leaf.entity(a.clone()).await.unwrap().get_or_init();
+        entity.add_component(image(blob)).unwrap();
+        entity.save().await.unwrap();
+        leaf.store
+            .store_entity(&a, b"not an entity".to_vec())
+            .await
+            .unwrap();
+        leaf.store
+            .store_blob(b"orphan", &b, Digest::default())
+            .await
+            .unwrap();
+
+        // The other entities are still collected.
+        let report = leaf.gc().await.unwrap();
+        assert_eq!(report.pins_removed, 1);
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_referenced_blobs_and_fresh_uploads() {
+        let (node, mut leaf) = iroh_leaf().await;
+        assert_eq!(leaf.store.gc_grace_period, DEFAULT_GC_GRACE_PERIOD);
+        let link = link(&leaf, "a").await;
+
+        leaf.store
+            .store_blob(b"orphan", &link, Digest::default())
+            .await
+            .unwrap();
+        let blob = leaf
+            .store
+            .store_blob(b"image", &link, Digest::default())
+            .await
+            .unwrap();
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity.add_component(image(blob)).unwrap();
+        entity.set_component(Name("first".into())).unwrap();
+        entity.save().await.unwrap();
+
+        // Uploads inside the grace period are left alone, even though nothing references them.
+        let report = leaf.gc().await.unwrap();
+        assert_eq!(report.pins_removed, 0);
+        assert_eq!(
+            leaf.store
+                .pinned_blobs(&link, Digest::default())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // After it, the upload pins are removed but the image stays pinned by its component.
+        leaf.store.gc_grace_period = Duration::ZERO;
+        let report = leaf.gc().await.unwrap();
+        assert_eq!(report.pins_removed, 2);
+        assert!(leaf
+            .store
+            .pinned_blobs(&link, Digest::default())
+            .await
+            .unwrap()
+            .is_empty());
+        let image = image_component(&leaf.store, &link).await;
+        let pins = leaf.store.pinned_blobs(&link, image).await.unwrap();
+        assert_eq!(pins, [blob]);
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+
+        // Once the image is gone, so is its pin.
+        entity.del_components::<Image>();
+        entity.save().await.unwrap();
+        leaf.gc().await.unwrap();
+        let pins = leaf.store.pinned_blobs(&link, image).await.unwrap();
+        assert!(pins.is_empty());
+
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_save_ifs_dont_lose_updates() {
+        let (node, leaf) = iroh_leaf().await;
+        let link = link(&leaf, "a").await;
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity.set_component(Name("first".into())).unwrap();
+        entity.save().await.unwrap();
+        let digest = entity.digest;
+
+        let mut a = leaf.entity(link.clone()).await.unwrap().entity().unwrap();
+        let mut b = leaf.entity(link.clone()).await.unwrap().entity().unwrap();
+        a.set_component(Name("a".into())).unwrap();
+        b.set_component(Name("b".into())).unwrap();
+        let (a, b) = futures::join!(
+            async move { a.save_if(digest).await.map(|_| a.digest) },
+            async move { b.save_if(digest).await.map(|_| b.digest) },
+        );
+        let (saved, conflict) = match (a, b) {
+            (Ok(saved), Err(conflict)) | (Err(conflict), Ok(saved)) => (saved, conflict),
+            (a, b) => panic!("Expected exactly one save to succeed: {a:?}, {b:?}"),
+        };
+        let conflict = conflict.downcast::<SnapshotConflict>().unwrap();
+        assert_eq!(conflict.actual, Some(saved));
+        assert_eq!(leaf.store.get_entity(&link).await.unwrap(), Some(saved));
+
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_entities_conflict_writes_nothing() {
+        let (node, leaf) = iroh_leaf().await;
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        let mut entity = leaf.entity(b.clone()).await.unwrap().get_or_init();
+        entity.set_component(Name("b".into())).unwrap();
+        entity.save().await.unwrap();
+
+        let write = |link: &ExactLink| EntityWrite {
+            link: link.clone(),
+            expected: Digest::default(),
+            data: Some(b"entity".to_vec()),
+        };
+        let error = leaf
+            .store
+            .store_entities(vec![write(&a), write(&b)])
+            .await
+            .unwrap_err();
+        let conflict = error.downcast_ref::<BatchConflict>().unwrap();
+        assert_eq!(conflict.conflict.actual, Some(entity.digest));
+        assert!(leaf.store.get_entity(&a).await.unwrap().is_none());
+
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_saves_dont_share_unique_values() {
+        let (node, leaf) = iroh_leaf().await;
+        let index = leaf.register_index::<Name>(&[], true).await.unwrap();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+
+        let save = |link: ExactLink| {
+            let leaf = leaf.clone();
+            async move {
+                let mut entity = leaf.entity(link).await?.get_or_init();
+                entity.set_component(Name("taken".into()))?;
+                entity.save().await
+            }
+        };
+        let results = futures::join!(save(a.clone()), save(b.clone()));
+        let errors = [results.0, results.1]
+            .into_iter()
+            .filter_map(|x| x.err())
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1, "Expected exactly one save to fail");
+        assert!(errors[0].downcast_ref::<IndexConflict>().is_some());
+        let found = leaf.lookup(a.namespace, &index, "taken").await.unwrap();
+        assert_eq!(found.len(), 1);
+
+        node.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! Schema and field indexes kept in a local [`redb`] database.
+//!
+//! The indexes only describe the entities that this node has saved, so they are never written
+//! anywhere that is synced with other peers.
+
+use std::sync::Arc;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use redb::{
+    Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
+};
+
+use crate::{
+    index::{FieldIndex, FieldIndexes, IndexConflict},
+    types::{BorshSchema, EntityPath, NamespaceId, PathSegment, SubspaceId},
+    Digest, ExactLink,
+};
+
+/// The [`entity_key()`]s of the entities that have components with each schema, by namespace and
+/// schema ID.
+const SCHEMA_INDEX: MultimapTableDefinition<([u8; 32], [u8; 32]), &[u8]> =
+    MultimapTableDefinition::new("leaf_schema_index");
+/// The [`entity_key()`]s of the entities that have each value in each field index, by
+/// [`field_index_key()`].
+const FIELD_INDEX: MultimapTableDefinition<&[u8], &[u8]> =
+    MultimapTableDefinition::new("leaf_field_index");
+/// The borsh-encoded [`FieldIndex`] and component [`BorshSchema`] of each registered field index,
+/// by [index ID][FieldIndex::id].
+const FIELD_INDEX_REGISTRATIONS: TableDefinition<[u8; 32], &[u8]> =
+    TableDefinition::new("leaf_field_index_registrations");
+
+/// Get the key of an entity in a local database.
+///
+/// The path segments are appended one after another instead of as a length-prefixed list, so that
+/// the keys of all of an entity's descendants start with the entity's own key.
+pub(crate) fn entity_key(link: &ExactLink) -> anyhow::Result<Vec<u8>> {
+    let mut key = [link.namespace, link.subspace].concat();
+    for segment in &link.path.0 {
+        segment.serialize(&mut key)?;
+    }
+    Ok(key)
+}
+
+/// Parse a key made with [`entity_key()`].
+pub(crate) fn parse_entity_key(mut key: &[u8]) -> anyhow::Result<ExactLink> {
+    let namespace = <[u8; 32]>::deserialize(&mut key)?;
+    let subspace = <[u8; 32]>::deserialize(&mut key)?;
+    let mut path = Vec::new();
+    while !key.is_empty() {
+        path.push(PathSegment::deserialize(&mut key)?);
+    }
+    Ok(ExactLink {
+        namespace,
+        subspace,
+        path: EntityPath(path),
+    })
+}
+
+/// The key for a value in a field index in the [`FIELD_INDEX`] table.
+fn field_index_key(namespace: NamespaceId, index: Digest, value: &[u8]) -> Vec<u8> {
+    [&namespace[..], index.as_bytes(), value].concat()
+}
+
+/// The implementation of the [`LeafStore`][crate::store::LeafStore] index methods for stores that
+/// have a local [`redb`] database.
+#[derive(Debug, Clone)]
+pub(crate) struct LocalIndexes {
+    db: Arc<Database>,
+}
+
+impl LocalIndexes {
+    /// Use the given database, creating the index tables if they don't exist.
+    pub fn new(db: Arc<Database>) -> anyhow::Result<Self> {
+        let tx = db.begin_write()?;
+        {
+            tx.open_multimap_table(SCHEMA_INDEX)?;
+            tx.open_multimap_table(FIELD_INDEX)?;
+            tx.open_table(FIELD_INDEX_REGISTRATIONS)?;
+        }
+        tx.commit()?;
+        Ok(Self { db })
+    }
+
+    pub fn update_schema_index(
+        &self,
+        link: &ExactLink,
+        removed: &[Digest],
+        added: &[Digest],
+    ) -> anyhow::Result<()> {
+        let key = entity_key(link)?;
+        let tx = self.db.begin_write()?;
+        {
+            let mut index = tx.open_multimap_table(SCHEMA_INDEX)?;
+            for schema in removed {
+                index.remove((link.namespace, *schema.as_bytes()), key.as_slice())?;
+            }
+            for schema in added {
+                index.insert((link.namespace, *schema.as_bytes()), key.as_slice())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> anyhow::Result<Vec<anyhow::Result<ExactLink>>> {
+        let prefix = match subspace {
+            Some(subspace) => [namespace, subspace].concat(),
+            None => namespace.to_vec(),
+        };
+        let tx = self.db.begin_read()?;
+        let mut links = Vec::new();
+        for key in tx
+            .open_multimap_table(SCHEMA_INDEX)?
+            .get((namespace, *schema.as_bytes()))?
+        {
+            let key = key?;
+            if key.value().starts_with(&prefix) {
+                links.push(parse_entity_key(key.value()));
+            }
+        }
+        Ok(links)
+    }
+
+    pub fn update_field_index(
+        &self,
+        link: &ExactLink,
+        index: Digest,
+        removed: &[Vec<u8>],
+        added: &[Vec<u8>],
+    ) -> anyhow::Result<()> {
+        let entity_key = entity_key(link)?;
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_multimap_table(FIELD_INDEX)?;
+            for value in removed {
+                let key = field_index_key(link.namespace, index, value);
+                table.remove(key.as_slice(), entity_key.as_slice())?;
+            }
+            for value in added {
+                let key = field_index_key(link.namespace, index, value);
+                table.insert(key.as_slice(), entity_key.as_slice())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn claim_field_index_values(
+        &self,
+        link: &ExactLink,
+        index: &FieldIndex,
+        values: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let entity_key = entity_key(link)?;
+        let id = index.id();
+        // Only one write transaction can be open at a time, so nobody can take a value between
+        // checking it and adding it.
+        let tx = self.db.begin_write()?;
+        let mut claimed = Vec::new();
+        {
+            let mut table = tx.open_multimap_table(FIELD_INDEX)?;
+            for value in values {
+                let key = field_index_key(link.namespace, id, value);
+                let mut has_value = false;
+                for existing in table.get(key.as_slice())? {
+                    let existing = existing?;
+                    if existing.value() == entity_key.as_slice() {
+                        has_value = true;
+                    } else {
+                        return Err(IndexConflict {
+                            index: index.clone(),
+                            value: value.clone(),
+                            existing: parse_entity_key(existing.value())?,
+                        }
+                        .into());
+                    }
+                }
+                if !has_value {
+                    table.insert(key.as_slice(), entity_key.as_slice())?;
+                    claimed.push(value.clone());
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(claimed)
+    }
+
+    pub fn lookup_field_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: &[u8],
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let key = field_index_key(namespace, index, value);
+        let tx = self.db.begin_read()?;
+        let links = tx
+            .open_multimap_table(FIELD_INDEX)?
+            .get(key.as_slice())?
+            .map(|x| parse_entity_key(x?.value()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(links)
+    }
+
+    /// Load the field indexes that were registered with
+    /// [`register_field_index()`][Self::register_field_index].
+    pub fn field_indexes(&self) -> anyhow::Result<FieldIndexes> {
+        let indexes = FieldIndexes::default();
+        let tx = self.db.begin_read()?;
+        for entry in tx.open_table(FIELD_INDEX_REGISTRATIONS)?.iter()? {
+            let (_, bytes) = entry?;
+            let (index, format) = <(FieldIndex, BorshSchema)>::try_from_slice(bytes.value())?;
+            indexes.register(index, format)?;
+        }
+        Ok(indexes)
+    }
+
+    pub fn register_field_index(
+        &self,
+        index: &FieldIndex,
+        format: &BorshSchema,
+    ) -> anyhow::Result<()> {
+        let bytes = borsh::to_vec(&(index, format))?;
+        let tx = self.db.begin_write()?;
+        tx.open_table(FIELD_INDEX_REGISTRATIONS)?
+            .insert(*index.id().as_bytes(), bytes.as_slice())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Forget a field index registration, and remove all of the index's values.
+    pub fn unregister_field_index(&self, index: Digest) -> anyhow::Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            tx.open_table(FIELD_INDEX_REGISTRATIONS)?
+                .remove(*index.as_bytes())?;
+            let mut table = tx.open_multimap_table(FIELD_INDEX)?;
+            // The index ID comes right after the namespace in each key.
+            let mut keys = Vec::new();
+            for entry in table.iter()? {
+                let (key, _) = entry?;
+                if key.value().get(32..64) == Some(index.as_bytes()) {
+                    keys.push(key.value().to_vec());
+                }
+            }
+            for key in keys {
+                table.remove_all(key.as_slice())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,754 @@
+//! An in-memory [`LeafStore`], useful for tests and examples.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use borsh::BorshDeserialize;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use futures::{Stream, TryStreamExt};
+
+use crate::{
+    batch::BatchConflict,
+    encryption::XChaCha20Poly1305Algorithm,
+    index::{FieldIndex, FieldIndexes, IndexConflict},
+    store::{
+        watchers::{is_under, Watchers},
+        BlobRange, EntityMeta, EntityUpdate, EntityWrite, GcReport, LeafStore, ListCursor,
+        ListMode, DEFAULT_GC_GRACE_PERIOD,
+    },
+    types::{BorshSchema, Entity, NamespaceId, NamespaceSecretKey, SubspaceId, SubspaceSecretKey},
+    Digest, ExactLink, SnapshotConflict,
+};
+
+pub type LeafMemory = crate::Leaf<MemoryStore>;
+
+/// A [`LeafStore`] that keeps all of its data in memory.
+///
+/// Nothing is persisted or synced, so this is mostly useful for tests and examples. Clones of the
+/// store share the same data.
+///
+/// Namespace and subspace IDs are derived by hashing their secret keys, so they will not match the
+/// IDs that other backends derive from the same keys.
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    state: Arc<Mutex<MemoryState>>,
+    watchers: Arc<Watchers>,
+    /// The built-in encryption algorithm, which holds the keys used for encrypted components.
+    pub encryption: XChaCha20Poly1305Algorithm,
+    /// The field indexes that this store keeps up to date.
+    pub field_indexes: FieldIndexes,
+    /// GC pins and unpinned blobs younger than this are left alone by [`gc()`][LeafStore::gc],
+    /// because they may belong to an entity snapshot that is still being saved.
+    pub gc_grace_period: Duration,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            watchers: Default::default(),
+            encryption: Default::default(),
+            field_indexes: Default::default(),
+            gc_grace_period: DEFAULT_GC_GRACE_PERIOD,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    namespaces: BTreeMap<NamespaceId, NamespaceSecretKey>,
+    subspaces: BTreeMap<SubspaceId, SubspaceSecretKey>,
+    blobs: HashMap<Digest, Vec<u8>>,
+    /// The blobs pinned for each entity snapshot, by link and entity snapshot ID.
+    pins: BTreeMap<(ExactLink, Digest), BTreeSet<Digest>>,
+    /// The time that each set of pins was created, in microseconds since the unix epoch.
+    pin_times: HashMap<(ExactLink, Digest), u64>,
+    /// The time that each blob was last stored, pinned, or unpinned, in microseconds since the
+    /// unix epoch.
+    blob_times: HashMap<Digest, u64>,
+    /// The latest snapshot of each entity.
+    entities: BTreeMap<ExactLink, Digest>,
+    /// The time that each entity was last stored, in microseconds since the unix epoch.
+    entity_times: HashMap<ExactLink, u64>,
+    /// The entities that have components with each schema.
+    schema_index: BTreeMap<Digest, BTreeSet<ExactLink>>,
+    /// The entities that have each value in each field index, by index ID and value.
+    field_index: BTreeMap<(Digest, Vec<u8>), BTreeSet<ExactLink>>,
+}
+
+impl MemoryState {
+    /// Pin the blob for the entity snapshot.
+    fn pin(
+        &mut self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+        digest: Digest,
+    ) -> anyhow::Result<()> {
+        let now = now()?;
+        let key = (link.clone(), entity_snapshot_id);
+        self.pin_times.entry(key.clone()).or_insert(now);
+        self.pins.entry(key).or_default().insert(digest);
+        self.blob_times.insert(digest, now);
+        Ok(())
+    }
+
+    /// Remove the pins for the entity snapshot, returning the digests that were unpinned.
+    fn unpin(&mut self, key: &(ExactLink, Digest)) -> anyhow::Result<BTreeSet<Digest>> {
+        let now = now()?;
+        self.pin_times.remove(key);
+        let digests = self.pins.remove(key).unwrap_or_default();
+        for digest in &digests {
+            self.blob_times.insert(*digest, now);
+        }
+        Ok(digests)
+    }
+
+    /// Get the IDs that live pins for the entity may be stored under: the entity's latest snapshot
+    /// and the IDs of its components.
+    ///
+    /// Returns `None` if we don't have the latest snapshot or can't decode it, so we can't know its
+    /// components.
+    fn live_pin_ids(&self, link: &ExactLink) -> Option<HashSet<Digest>> {
+        let mut ids = HashSet::new();
+        if let Some(snapshot) = self.entities.get(link) {
+            let entity = Entity::deserialize(&mut &self.blobs.get(snapshot)?[..]).ok()?;
+            ids.insert(*snapshot);
+            ids.extend(entity.components.into_iter().map(|x| x.component_id));
+        }
+        Some(ids)
+    }
+}
+
+/// The current time in microseconds since the unix epoch.
+fn now() -> anyhow::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+impl MemoryStore {
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl LeafStore for MemoryStore {
+    fn key_resolvers(&self) -> Box<dyn Iterator<Item = &dyn super::KeyResolverImpl<Digest>> + '_> {
+        Box::new([].into_iter())
+    }
+
+    fn encryption_algorithms(
+        &self,
+    ) -> Box<dyn Iterator<Item = &dyn super::EncryptionAlgorithmImpl<Digest>> + '_> {
+        Box::new([&self.encryption as _].into_iter())
+    }
+
+    fn subspace_id(&self, subspace_secret: &SubspaceSecretKey) -> SubspaceId {
+        *Digest::new(subspace_secret).as_bytes()
+    }
+
+    async fn create_subspace(&self) -> anyhow::Result<SubspaceId> {
+        self.import_subspace_secret(random_secret()).await
+    }
+
+    async fn get_subspace_secret(
+        &self,
+        subspace: SubspaceId,
+    ) -> anyhow::Result<Option<SubspaceSecretKey>> {
+        Ok(self.state().subspaces.get(&subspace).copied())
+    }
+
+    async fn list_subspaces(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<SubspaceId>>> {
+        let subspaces = self.state().subspaces.keys().copied().collect::<Vec<_>>();
+        Ok(futures::stream::iter(subspaces.into_iter().map(Ok)))
+    }
+
+    async fn import_subspace_secret(
+        &self,
+        subspace_secret: SubspaceSecretKey,
+    ) -> anyhow::Result<SubspaceId> {
+        let id = self.subspace_id(&subspace_secret);
+        self.state().subspaces.insert(id, subspace_secret);
+        Ok(id)
+    }
+
+    fn namespace_id(&self, secret: &NamespaceSecretKey) -> NamespaceId {
+        *Digest::new(secret).as_bytes()
+    }
+
+    async fn list_namespace_subspaces(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<SubspaceId>>> {
+        let subspaces = self
+            .state()
+            .entities
+            .keys()
+            .filter(|link| link.namespace == namespace)
+            .map(|link| link.subspace)
+            .collect::<BTreeSet<_>>();
+        Ok(futures::stream::iter(subspaces.into_iter().map(Ok)))
+    }
+
+    async fn create_namespace(&self) -> anyhow::Result<NamespaceId> {
+        self.import_namespace_secret(random_secret()).await
+    }
+
+    async fn list_namespaces(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<NamespaceId>>> {
+        let namespaces = self.state().namespaces.keys().copied().collect::<Vec<_>>();
+        Ok(futures::stream::iter(namespaces.into_iter().map(Ok)))
+    }
+
+    async fn get_namespace_secret(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<Option<NamespaceSecretKey>> {
+        Ok(self.state().namespaces.get(&namespace).copied())
+    }
+
+    async fn import_namespace_secret(&self, secret: [u8; 32]) -> anyhow::Result<NamespaceId> {
+        let id = self.namespace_id(&secret);
+        self.state().namespaces.insert(id, secret);
+        Ok(id)
+    }
+
+    async fn store_blob(
+        &self,
+        data: &[u8],
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
+        let digest = Digest::new(data);
+        let mut state = self.state();
+        state.blobs.entry(digest).or_insert_with(|| data.to_vec());
+        state.pin(link, entity_snapshot_id, digest)?;
+        Ok(digest)
+    }
+
+    async fn pin_blob(
+        &self,
+        digest: Digest,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<bool> {
+        let mut state = self.state();
+        if !state.blobs.contains_key(&digest) {
+            return Ok(false);
+        }
+        state.pin(link, entity_snapshot_id, digest)?;
+        Ok(true)
+    }
+
+    async fn del_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<usize> {
+        let pins = self.state().unpin(&(link.clone(), entity_snapshot_id))?;
+        Ok(pins.len())
+    }
+
+    async fn pinned_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Vec<Digest>> {
+        let pins = self
+            .state()
+            .pins
+            .get(&(link.clone(), entity_snapshot_id))
+            .cloned();
+        Ok(pins.into_iter().flatten().collect())
+    }
+
+    async fn gc(&self) -> anyhow::Result<GcReport> {
+        let cutoff = now()?.saturating_sub(self.gc_grace_period.as_micros() as u64);
+        let mut state = self.state();
+        let mut report = GcReport::default();
+
+        // Remove the pins for snapshots that are no longer live.
+        let mut live_ids = HashMap::new();
+        let mut dead_pins = Vec::new();
+        for key @ (link, entity_snapshot_id) in state.pins.keys() {
+            if state.pin_times.get(key).is_some_and(|&time| time > cutoff) {
+                continue;
+            }
+            if !live_ids.contains_key(link) {
+                live_ids.insert(link.clone(), state.live_pin_ids(link));
+            }
+            if live_ids[link]
+                .as_ref()
+                .is_some_and(|x| !x.contains(entity_snapshot_id))
+            {
+                dead_pins.push((link.clone(), *entity_snapshot_id));
+            }
+        }
+        for key in dead_pins {
+            // The blobs have been pinned for longer than the grace period, so they can be dropped
+            // right away if nothing else pins them.
+            state.pin_times.remove(&key);
+            for digest in state.pins.remove(&key).into_iter().flatten() {
+                report.pins_removed += 1;
+                report.bytes_unpinned +=
+                    state.blobs.get(&digest).map(|x| x.len()).unwrap_or(0) as u64;
+            }
+        }
+
+        // Drop the blobs that haven't been pinned or used as an entity snapshot for a while.
+        let used = state
+            .pins
+            .values()
+            .flatten()
+            .chain(state.entities.values())
+            .copied()
+            .collect::<HashSet<_>>();
+        let MemoryState {
+            blobs, blob_times, ..
+        } = &mut *state;
+        blobs.retain(|digest, _| {
+            used.contains(digest) || blob_times.get(digest).is_some_and(|&time| time > cutoff)
+        });
+        blob_times.retain(|digest, _| blobs.contains_key(digest));
+
+        Ok(report)
+    }
+
+    async fn get_blob(&self, digest: Digest) -> anyhow::Result<Vec<u8>> {
+        self.state()
+            .blobs
+            .get(&digest)
+            .cloned()
+            .ok_or_else(|| anyhow::format_err!("Blob not found: {digest}"))
+    }
+
+    async fn put_blob_stream(
+        &self,
+        data: impl Stream<Item = anyhow::Result<Vec<u8>>>,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
+        // Everything is kept in memory anyway, so there's no point in storing the chunks one by one.
+        let data = data.try_concat().await?;
+        self.store_blob(&data, link, entity_snapshot_id).await
+    }
+
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+        range: BlobRange,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Vec<u8>>>> {
+        let data = self.get_blob(digest).await?;
+        range.chunks(&data)
+    }
+
+    async fn blob_size(&self, digest: Digest) -> anyhow::Result<Option<u64>> {
+        Ok(self.state().blobs.get(&digest).map(|x| x.len() as u64))
+    }
+
+    async fn store_entity(&self, link: &ExactLink, data: Vec<u8>) -> anyhow::Result<Digest> {
+        let digest = Digest::new(&data);
+        let now = now()?;
+        let mut state = self.state();
+        state.blobs.insert(digest, data);
+        state.entities.insert(link.clone(), digest);
+        state.entity_times.insert(link.clone(), now);
+        self.watchers.notify(link, Some(digest));
+        Ok(digest)
+    }
+
+    async fn store_entities(&self, writes: Vec<EntityWrite>) -> anyhow::Result<Vec<Digest>> {
+        let now = now()?;
+        let mut state = self.state();
+        for write in &writes {
+            let actual = state.entities.get(&write.link).copied();
+            if let Some(conflict) = SnapshotConflict::check(&write.link, write.expected, actual) {
+                return Err(BatchConflict::from(conflict).into());
+            }
+        }
+        let mut digests = Vec::with_capacity(writes.len());
+        for write in writes {
+            let Some(data) = write.data else {
+                digests.push(write.expected);
+                continue;
+            };
+            let digest = Digest::new(&data);
+            state.blobs.insert(digest, data);
+            state.entities.insert(write.link.clone(), digest);
+            state.entity_times.insert(write.link.clone(), now);
+            self.watchers.notify(&write.link, Some(digest));
+            digests.push(digest);
+        }
+        Ok(digests)
+    }
+
+    async fn del_entity(&self, link: &ExactLink) -> anyhow::Result<()> {
+        let mut state = self.state();
+        state.entity_times.remove(link);
+        if state.entities.remove(link).is_some() {
+            self.watchers.notify(link, None);
+        }
+        Ok(())
+    }
+
+    async fn get_entity(&self, link: &ExactLink) -> anyhow::Result<Option<Digest>> {
+        Ok(self.state().entities.get(link).copied())
+    }
+
+    async fn get_entity_meta(
+        &self,
+        link: &ExactLink,
+    ) -> anyhow::Result<Option<(Digest, EntityMeta)>> {
+        let state = self.state();
+        let Some(&digest) = state.entities.get(link) else {
+            return Ok(None);
+        };
+        let meta = EntityMeta {
+            last_modified: state.entity_times[link],
+            author_subspace: link.subspace,
+            size: state.blobs.get(&digest).map_or(0, |x| x.len() as u64),
+        };
+        Ok(Some((digest, meta)))
+    }
+
+    async fn list(
+        &self,
+        link: ExactLink,
+        mode: ListMode,
+        limit: Option<u64>,
+        offset: Option<u64>,
+        cursor: Option<ListCursor>,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ExactLink>>> {
+        let depth = link.path.0.len();
+        // Descendants sort directly after their parent, so we can start at the link and stop at
+        // the first entity that isn't under it.
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor.resume_link(&link)?),
+            None => Bound::Included(link.clone()),
+        };
+        let links = self
+            .state()
+            .entities
+            .range((start, Bound::Unbounded))
+            .map(|(x, _)| x)
+            .take_while(|x| is_under(x, &link))
+            .filter(|x| match mode {
+                ListMode::Children => x.path.0.len() == depth + 1,
+                ListMode::Descendants => true,
+            })
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.map(|x| x as usize).unwrap_or(usize::MAX))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(futures::stream::iter(links.into_iter().map(Ok)))
+    }
+
+    async fn update_schema_index(
+        &self,
+        link: &ExactLink,
+        removed: &[Digest],
+        added: &[Digest],
+    ) -> anyhow::Result<()> {
+        let index = &mut self.state().schema_index;
+        for schema in removed {
+            if let Some(links) = index.get_mut(schema) {
+                links.remove(link);
+                if links.is_empty() {
+                    index.remove(schema);
+                }
+            }
+        }
+        for schema in added {
+            index.entry(*schema).or_default().insert(link.clone());
+        }
+        Ok(())
+    }
+
+    async fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ExactLink>>> {
+        let links = self
+            .state()
+            .schema_index
+            .get(&schema)
+            .into_iter()
+            .flatten()
+            .filter(|x| x.namespace == namespace && subspace.is_none_or(|s| x.subspace == s))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(futures::stream::iter(links.into_iter().map(Ok)))
+    }
+
+    fn field_indexes(&self) -> &FieldIndexes {
+        &self.field_indexes
+    }
+
+    async fn register_field_index(
+        &self,
+        index: FieldIndex,
+        format: BorshSchema,
+    ) -> anyhow::Result<Option<FieldIndex>> {
+        self.field_indexes.register(index, format)
+    }
+
+    async fn unregister_field_index(&self, index: Digest) -> anyhow::Result<()> {
+        self.field_indexes.remove(index);
+        self.state().field_index.retain(|(id, _), _| *id != index);
+        Ok(())
+    }
+
+    async fn update_field_index(
+        &self,
+        link: &ExactLink,
+        index: Digest,
+        removed: &[Vec<u8>],
+        added: &[Vec<u8>],
+    ) -> anyhow::Result<()> {
+        let field_index = &mut self.state().field_index;
+        for value in removed {
+            let key = (index, value.clone());
+            if let Some(links) = field_index.get_mut(&key) {
+                links.remove(link);
+                if links.is_empty() {
+                    field_index.remove(&key);
+                }
+            }
+        }
+        for value in added {
+            field_index
+                .entry((index, value.clone()))
+                .or_default()
+                .insert(link.clone());
+        }
+        Ok(())
+    }
+
+    async fn claim_field_index_values(
+        &self,
+        link: &ExactLink,
+        index: &FieldIndex,
+        values: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let id = index.id();
+        let field_index = &mut self.state().field_index;
+        for value in values {
+            let links = field_index.get(&(id, value.clone())).into_iter().flatten();
+            if let Some(existing) = links
+                .filter(|x| x.namespace == link.namespace)
+                .find(|x| *x != link)
+            {
+                return Err(IndexConflict {
+                    index: index.clone(),
+                    value: value.clone(),
+                    existing: existing.clone(),
+                }
+                .into());
+            }
+        }
+        let mut claimed = Vec::new();
+        for value in values {
+            if field_index
+                .entry((id, value.clone()))
+                .or_default()
+                .insert(link.clone())
+            {
+                claimed.push(value.clone());
+            }
+        }
+        Ok(claimed)
+    }
+
+    async fn lookup_field_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: &[u8],
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let state = self.state();
+        let links = state.field_index.get(&(index, value.to_vec()));
+        Ok(links
+            .into_iter()
+            .flatten()
+            .filter(|x| x.namespace == namespace)
+            .cloned()
+            .collect())
+    }
+
+    async fn watch(
+        &self,
+        link: ExactLink,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<EntityUpdate>>> {
+        Ok(self.watchers.watch(link))
+    }
+
+    async fn watch_namespace(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<EntityUpdate>>> {
+        Ok(self.watchers.watch_namespace(namespace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        components::{Image, Name},
+        store::{watchers::WATCH_BUFFER, DEFAULT_GC_GRACE_PERIOD},
+        test_util::{image, leaf, link, save_name},
+    };
+
+    #[tokio::test]
+    async fn gc_keeps_referenced_blobs_and_frees_the_rest() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+
+        // An upload that was never saved to a component is collected.
+        let orphan = leaf
+            .store
+            .store_blob(b"orphan", &link, Digest::default())
+            .await
+            .unwrap();
+        let blob = leaf
+            .store
+            .store_blob(b"image", &link, Digest::default())
+            .await
+            .unwrap();
+
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity.add_component(image(blob)).unwrap();
+        entity.set_component(Name("first".into())).unwrap();
+        entity.save().await.unwrap();
+
+        // Changing the other components keeps the image's references pinned.
+        entity.set_component(Name("second".into())).unwrap();
+        entity.save().await.unwrap();
+
+        let report = leaf.gc().await.unwrap();
+        assert_eq!(report.pins_removed, 2);
+        assert!(leaf.store.get_blob(orphan).await.is_err());
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+
+        // Once the image is gone, so is its blob.
+        entity.del_components::<Image>();
+        entity.save().await.unwrap();
+        leaf.gc().await.unwrap();
+        assert!(leaf.store.get_blob(blob).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_the_pins_of_malformed_snapshots() {
+        let leaf = leaf();
+        let a = link(&leaf, "a").await;
+        let b = link(&leaf, "b").await;
+        let blob = leaf
+            .store
+            .store_blob(b"image", &a, Digest::default())
+            .await
+            .unwrap();
+        let mut entity = leaf.entity(a.clone()).await.unwrap().get_or_init();
+        entity.add_component(image(blob)).unwrap();
+        entity.save().await.unwrap();
+        leaf.store
+            .store_entity(&a, b"not an entity".to_vec())
+            .await
+            .unwrap();
+        leaf.store
+            .store_blob(b"orphan", &b, Digest::default())
+            .await
+            .unwrap();
+
+        // The other entities are still collected.
+        let report = leaf.gc().await.unwrap();
+        assert_eq!(report.pins_removed, 1);
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+    }
+
+    #[tokio::test]
+    async fn gc_grace_period_keeps_new_uploads() {
+        let leaf = LeafMemory::new(MemoryStore::default());
+        assert_eq!(leaf.store.gc_grace_period, DEFAULT_GC_GRACE_PERIOD);
+        let link = link(&leaf, "a").await;
+        let upload = leaf
+            .store
+            .store_blob(b"upload", &link, Digest::default())
+            .await
+            .unwrap();
+        let report = leaf.gc().await.unwrap();
+        assert_eq!(report.pins_removed, 0);
+        assert!(leaf.store.get_blob(upload).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn store_entities_conflict_writes_nothing() {
+        let leaf = leaf();
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        let digest = save_name(&leaf, &b, "b").await.unwrap();
+
+        let write = |link: &ExactLink, expected| EntityWrite {
+            link: link.clone(),
+            expected,
+            data: Some(b"entity".to_vec()),
+        };
+        let writes = vec![write(&a, Digest::default()), write(&b, Digest::default())];
+        let error = leaf.store.store_entities(writes).await.unwrap_err();
+        let conflict = error.downcast_ref::<BatchConflict>().unwrap();
+        assert_eq!(conflict.conflict.actual, Some(digest));
+        assert!(leaf.store.get_entity(&a).await.unwrap().is_none());
+
+        // Check-only writes can conflict too, but are never written.
+        let check = |link: &ExactLink, expected| EntityWrite {
+            link: link.clone(),
+            expected,
+            data: None,
+        };
+        let writes = vec![write(&a, Digest::default()), check(&b, Digest::default())];
+        let error = leaf.store.store_entities(writes).await.unwrap_err();
+        assert!(error.downcast_ref::<BatchConflict>().is_some());
+        assert!(leaf.store.get_entity(&a).await.unwrap().is_none());
+        let digests = leaf.store.store_entities(vec![check(&b, digest)]).await;
+        assert_eq!(digests.unwrap(), [digest]);
+        assert_eq!(leaf.store.get_entity(&b).await.unwrap(), Some(digest));
+
+        let writes = vec![write(&a, Digest::default()), write(&b, digest)];
+        let digests = leaf.store.store_entities(writes).await.unwrap();
+        assert_eq!(digests, [Digest::new(b"entity"); 2]);
+        assert_eq!(leaf.store.get_entity(&b).await.unwrap(), Some(digests[1]));
+    }
+
+    #[tokio::test]
+    async fn watchers_that_fall_behind_get_an_error() {
+        let leaf = leaf();
+        let link = link(&leaf, "a").await;
+        let updates = leaf.store.watch(link.clone()).await.unwrap();
+        for i in 0..WATCH_BUFFER + 2 {
+            leaf.store
+                .store_entity(&link, i.to_le_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+
+        // The updates that fit in the buffer are kept, and then the watch ends with an error.
+        let updates = updates.collect::<Vec<_>>().await;
+        let (last, kept) = updates.split_last().unwrap();
+        assert!(last.is_err());
+        assert!(kept.len() >= WATCH_BUFFER);
+        assert!(kept.iter().all(|update| update.is_ok()));
+    }
+}
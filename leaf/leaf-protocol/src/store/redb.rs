@@ -0,0 +1,862 @@
+//! A [`LeafStore`] backed by a single [`redb`] database file.
+
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    ops::Bound,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use borsh::BorshDeserialize;
+use futures::{Stream, TryStreamExt};
+use iroh_base::key::SecretKey;
+use redb::{
+    Database, MultimapTableDefinition, ReadableTable, Table, TableDefinition, WriteTransaction,
+};
+
+use crate::{
+    batch::BatchConflict,
+    encryption::XChaCha20Poly1305Algorithm,
+    index::{FieldIndex, FieldIndexes},
+    store::{
+        local_index::{entity_key, parse_entity_key, LocalIndexes},
+        watchers::Watchers,
+        BlobRange, EncryptionAlgorithmImpl, EntityMeta, EntityUpdate, EntityWrite, GcReport,
+        KeyResolverImpl, LeafStore, ListCursor, ListMode, DEFAULT_GC_GRACE_PERIOD,
+    },
+    types::{BorshSchema, Entity, NamespaceId, NamespaceSecretKey, SubspaceId, SubspaceSecretKey},
+    Digest, ExactLink, SnapshotConflict,
+};
+
+pub type LeafRedb = crate::Leaf<LeafRedbStore>;
+
+/// Namespace secret keys, by namespace ID.
+const NAMESPACES: TableDefinition<[u8; 32], [u8; 32]> = TableDefinition::new("leaf_namespaces");
+/// Subspace secret keys, by subspace ID.
+const SUBSPACES: TableDefinition<[u8; 32], [u8; 32]> = TableDefinition::new("leaf_subspaces");
+/// Blob contents, by digest.
+const BLOBS: TableDefinition<[u8; 32], &[u8]> = TableDefinition::new("leaf_blobs");
+/// The number of pins and entities that reference each blob, along with the time that the count
+/// last changed, in microseconds since the unix epoch.
+const BLOB_REFS: TableDefinition<[u8; 32], (u64, u64)> = TableDefinition::new("leaf_blob_refs");
+/// The blobs pinned for each entity snapshot, by [`pin_key()`].
+const PINS: MultimapTableDefinition<&[u8], [u8; 32]> = MultimapTableDefinition::new("leaf_pins");
+/// The time that the pins for each entity snapshot were created, in microseconds since the unix
+/// epoch, by [`pin_key()`].
+const PIN_TIMES: TableDefinition<&[u8], u64> = TableDefinition::new("leaf_pin_times");
+/// The latest snapshot of each entity, by [`entity_key()`].
+const ENTITIES: TableDefinition<&[u8], [u8; 32]> = TableDefinition::new("leaf_entities");
+/// The time that each entity was last stored, in microseconds since the unix epoch, by
+/// [`entity_key()`].
+const ENTITY_TIMES: TableDefinition<&[u8], u64> = TableDefinition::new("leaf_entity_times");
+/// A [`LeafStore`] that keeps all of its data in a local [`redb`] database.
+///
+/// This is meant for single-node deployments that don't need to sync with other peers. Namespace
+/// and subspace IDs are the ed25519 public keys of their secrets, just like with the Iroh backend,
+/// so data can be moved between the two with a database dump.
+///
+/// Blobs are reference counted by their GC pins and the entities that use them as a snapshot.
+/// Blobs that are no longer referenced are deleted by [`LeafStore::gc()`].
+///
+/// Database operations are blocking, but they are small and only touch the local disk.
+#[derive(Debug, Clone)]
+pub struct LeafRedbStore {
+    pub db: Arc<Database>,
+    watchers: Arc<Watchers>,
+    indexes: LocalIndexes,
+    /// The built-in encryption algorithm, which holds the keys used for encrypted components.
+    pub encryption: XChaCha20Poly1305Algorithm,
+    /// The field indexes that this store keeps up to date.
+    pub field_indexes: FieldIndexes,
+    /// GC pins and unreferenced blobs younger than this are left alone by
+    /// [`gc()`][LeafStore::gc], because they may belong to an entity snapshot that is still being
+    /// saved.
+    pub gc_grace_period: Duration,
+}
+
+impl LeafRedbStore {
+    /// Open the database at the given path, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new(Database::create(path)?)
+    }
+
+    /// Create a store using the given database, creating the Leaf tables if they don't exist.
+    pub fn new(db: Database) -> anyhow::Result<Self> {
+        let tx = db.begin_write()?;
+        {
+            tx.open_table(NAMESPACES)?;
+            tx.open_table(SUBSPACES)?;
+            tx.open_table(BLOBS)?;
+            tx.open_table(BLOB_REFS)?;
+            tx.open_multimap_table(PINS)?;
+            tx.open_table(PIN_TIMES)?;
+            tx.open_table(ENTITIES)?;
+            tx.open_table(ENTITY_TIMES)?;
+        }
+        tx.commit()?;
+        let db = Arc::new(db);
+        let indexes = LocalIndexes::new(db.clone())?;
+        Ok(Self {
+            field_indexes: indexes.field_indexes()?,
+            indexes,
+            db,
+            watchers: Default::default(),
+            encryption: Default::default(),
+            gc_grace_period: DEFAULT_GC_GRACE_PERIOD,
+        })
+    }
+
+    fn import_secret(
+        &self,
+        table: TableDefinition<[u8; 32], [u8; 32]>,
+        secret: [u8; 32],
+    ) -> anyhow::Result<[u8; 32]> {
+        let id = public_key(&secret);
+        let tx = self.db.begin_write()?;
+        tx.open_table(table)?.insert(id, secret)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn get_secret(
+        &self,
+        table: TableDefinition<[u8; 32], [u8; 32]>,
+        id: [u8; 32],
+    ) -> anyhow::Result<Option<[u8; 32]>> {
+        let tx = self.db.begin_read()?;
+        let secret = tx.open_table(table)?.get(id)?.map(|x| x.value());
+        Ok(secret)
+    }
+
+    fn list_ids(
+        &self,
+        table: TableDefinition<[u8; 32], [u8; 32]>,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<[u8; 32]>>> {
+        let tx = self.db.begin_read()?;
+        let ids = tx
+            .open_table(table)?
+            .iter()?
+            .map(|entry| Ok(entry?.0.value()))
+            .collect::<Vec<_>>();
+        Ok(futures::stream::iter(ids))
+    }
+}
+
+fn now() -> anyhow::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
+
+/// Get the ID of a namespace or subspace, which is the ed25519 public key of its secret.
+fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    *SecretKey::from_bytes(secret).public().as_bytes()
+}
+
+/// Get the key of an entity snapshot's pins in the [`PINS`] table.
+fn pin_key(link: &ExactLink, entity_snapshot_id: Digest) -> anyhow::Result<Vec<u8>> {
+    Ok(borsh::to_vec(&(link, entity_snapshot_id))?)
+}
+
+/// Add `delta` to the reference count of a blob.
+fn add_blob_ref(tx: &WriteTransaction, digest: Digest, delta: i64) -> anyhow::Result<()> {
+    let mut refs = tx.open_table(BLOB_REFS)?;
+    let count = refs.get(digest.as_bytes())?.map(|x| x.value().0);
+    let count = count.unwrap_or(0).saturating_add_signed(delta);
+    refs.insert(digest.as_bytes(), (count, now()?))?;
+    Ok(())
+}
+
+/// Insert a blob if it isn't already in the store.
+fn insert_blob(tx: &WriteTransaction, digest: Digest, data: &[u8]) -> anyhow::Result<()> {
+    let mut blobs = tx.open_table(BLOBS)?;
+    if blobs.get(digest.as_bytes())?.is_none() {
+        blobs.insert(digest.as_bytes(), data)?;
+    }
+    Ok(())
+}
+
+/// Store an entity snapshot as the latest version of the entity at the link.
+fn write_entity(tx: &WriteTransaction, link: &ExactLink, data: &[u8]) -> anyhow::Result<Digest> {
+    let digest = Digest::new(data);
+    let key = entity_key(link)?;
+    insert_blob(tx, digest, data)?;
+    let old = tx
+        .open_table(ENTITIES)?
+        .insert(key.as_slice(), digest.as_bytes())?
+        .map(|x| Digest::from_bytes(x.value()));
+    tx.open_table(ENTITY_TIMES)?
+        .insert(key.as_slice(), now()?)?;
+    add_blob_ref(tx, digest, 1)?;
+    if let Some(old) = old {
+        add_blob_ref(tx, old, -1)?;
+    }
+    Ok(digest)
+}
+
+/// Pin a blob under the given [`pin_key()`].
+fn add_pin(tx: &WriteTransaction, key: &[u8], digest: Digest) -> anyhow::Result<()> {
+    let already_pinned = tx
+        .open_multimap_table(PINS)?
+        .insert(key, digest.as_bytes())?;
+    if !already_pinned {
+        add_blob_ref(tx, digest, 1)?;
+    }
+    let mut times = tx.open_table(PIN_TIMES)?;
+    if times.get(key)?.is_none() {
+        times.insert(key, now()?)?;
+    }
+    Ok(())
+}
+
+/// Remove all of the pins under the given [`pin_key()`], returning the digests that were unpinned.
+fn remove_pins(tx: &WriteTransaction, key: &[u8]) -> anyhow::Result<Vec<Digest>> {
+    let digests = tx
+        .open_multimap_table(PINS)?
+        .remove_all(key)?
+        .map(|x| Ok(Digest::from_bytes(x?.value())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    tx.open_table(PIN_TIMES)?.remove(key)?;
+    for &digest in &digests {
+        add_blob_ref(tx, digest, -1)?;
+    }
+    Ok(digests)
+}
+
+/// Get the IDs that live pins for the entity may be stored under: the entity's latest snapshot and
+/// the IDs of its components.
+///
+/// Returns [`None`] if the entity's snapshot can't be read, in which case nothing should be
+/// collected.
+fn live_pin_ids(
+    entities: &Table<&[u8], [u8; 32]>,
+    blobs: &Table<[u8; 32], &[u8]>,
+    link: &ExactLink,
+) -> anyhow::Result<Option<HashSet<Digest>>> {
+    let mut ids = HashSet::new();
+    let Some(snapshot) = entities.get(entity_key(link)?.as_slice())? else {
+        return Ok(Some(ids));
+    };
+    let snapshot = snapshot.value();
+    let Some(data) = blobs.get(snapshot)? else {
+        return Ok(None);
+    };
+    let Ok(entity) = Entity::deserialize(&mut data.value()) else {
+        return Ok(None);
+    };
+    ids.insert(Digest::from_bytes(snapshot));
+    ids.extend(entity.components.into_iter().map(|x| x.component_id));
+    Ok(Some(ids))
+}
+
+impl LeafStore for LeafRedbStore {
+    fn key_resolvers(&self) -> Box<dyn Iterator<Item = &dyn KeyResolverImpl<Digest>> + '_> {
+        Box::new([].into_iter())
+    }
+
+    fn encryption_algorithms(
+        &self,
+    ) -> Box<dyn Iterator<Item = &dyn EncryptionAlgorithmImpl<Digest>> + '_> {
+        Box::new([&self.encryption as _].into_iter())
+    }
+
+    fn subspace_id(&self, subspace_secret: &SubspaceSecretKey) -> SubspaceId {
+        public_key(subspace_secret)
+    }
+
+    async fn create_subspace(&self) -> anyhow::Result<SubspaceId> {
+        self.import_secret(SUBSPACES, SecretKey::generate().to_bytes())
+    }
+
+    async fn get_subspace_secret(
+        &self,
+        subspace: SubspaceId,
+    ) -> anyhow::Result<Option<SubspaceSecretKey>> {
+        self.get_secret(SUBSPACES, subspace)
+    }
+
+    async fn list_subspaces(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<SubspaceId>>> {
+        self.list_ids(SUBSPACES)
+    }
+
+    async fn import_subspace_secret(
+        &self,
+        subspace_secret: SubspaceSecretKey,
+    ) -> anyhow::Result<SubspaceId> {
+        self.import_secret(SUBSPACES, subspace_secret)
+    }
+
+    fn namespace_id(&self, secret: &NamespaceSecretKey) -> NamespaceId {
+        public_key(secret)
+    }
+
+    async fn list_namespace_subspaces(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<SubspaceId>>> {
+        let tx = self.db.begin_read()?;
+        let entities = tx.open_table(ENTITIES)?;
+        let mut subspaces = BTreeSet::new();
+        // Entity keys start with the namespace and then the subspace.
+        for entry in entities.range::<&[u8]>(namespace.as_slice()..)? {
+            let link = parse_entity_key(entry?.0.value())?;
+            if link.namespace != namespace {
+                break;
+            }
+            subspaces.insert(link.subspace);
+        }
+        Ok(futures::stream::iter(subspaces.into_iter().map(Ok)))
+    }
+
+    async fn create_namespace(&self) -> anyhow::Result<NamespaceId> {
+        self.import_secret(NAMESPACES, SecretKey::generate().to_bytes())
+    }
+
+    async fn list_namespaces(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<NamespaceId>>> {
+        self.list_ids(NAMESPACES)
+    }
+
+    async fn get_namespace_secret(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<Option<NamespaceSecretKey>> {
+        self.get_secret(NAMESPACES, namespace)
+    }
+
+    async fn import_namespace_secret(&self, secret: [u8; 32]) -> anyhow::Result<NamespaceId> {
+        self.import_secret(NAMESPACES, secret)
+    }
+
+    async fn store_blob(
+        &self,
+        data: &[u8],
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
+        let digest = Digest::new(data);
+        let key = pin_key(link, entity_snapshot_id)?;
+        let tx = self.db.begin_write()?;
+        insert_blob(&tx, digest, data)?;
+        add_pin(&tx, &key, digest)?;
+        tx.commit()?;
+        Ok(digest)
+    }
+
+    async fn pin_blob(
+        &self,
+        digest: Digest,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<bool> {
+        let key = pin_key(link, entity_snapshot_id)?;
+        let tx = self.db.begin_write()?;
+        if tx.open_table(BLOBS)?.get(digest.as_bytes())?.is_none() {
+            return Ok(false);
+        }
+        add_pin(&tx, &key, digest)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    async fn del_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<usize> {
+        let key = pin_key(link, entity_snapshot_id)?;
+        let tx = self.db.begin_write()?;
+        let count = remove_pins(&tx, &key)?.len();
+        tx.commit()?;
+        Ok(count)
+    }
+
+    async fn pinned_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Vec<Digest>> {
+        let key = pin_key(link, entity_snapshot_id)?;
+        let tx = self.db.begin_read()?;
+        let digests = tx
+            .open_multimap_table(PINS)?
+            .get(key.as_slice())?
+            .map(|x| Ok(Digest::from_bytes(x?.value())))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(digests)
+    }
+
+    async fn gc(&self) -> anyhow::Result<GcReport> {
+        let cutoff = now()?.saturating_sub(self.gc_grace_period.as_micros() as u64);
+        let mut report = GcReport::default();
+        let tx = self.db.begin_write()?;
+
+        // Find the pins for snapshots that are no longer live.
+        let mut dead_pins = Vec::new();
+        {
+            let times = tx.open_table(PIN_TIMES)?;
+            let entities = tx.open_table(ENTITIES)?;
+            let blobs = tx.open_table(BLOBS)?;
+            let mut live_ids = HashMap::new();
+            for entry in times.iter()? {
+                let (key, created) = entry?;
+                if created.value() > cutoff {
+                    continue;
+                }
+                let (link, entity_snapshot_id) =
+                    <(ExactLink, Digest)>::try_from_slice(key.value())?;
+                let live = match live_ids.entry(link) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        let ids = live_pin_ids(&entities, &blobs, e.key())?;
+                        e.insert(ids)
+                    }
+                };
+                if live
+                    .as_ref()
+                    .is_some_and(|x| !x.contains(&entity_snapshot_id))
+                {
+                    dead_pins.push(key.value().to_vec());
+                }
+            }
+        }
+
+        // The blobs have been pinned for longer than the grace period, so they can be deleted right
+        // away if nothing else pins them.
+        let mut unpinned = HashSet::new();
+        for key in dead_pins {
+            for digest in remove_pins(&tx, &key)? {
+                unpinned.insert(digest);
+                report.pins_removed += 1;
+                let size = tx
+                    .open_table(BLOBS)?
+                    .get(digest.as_bytes())?
+                    .map(|x| x.value().len());
+                report.bytes_unpinned += size.unwrap_or(0) as u64;
+            }
+        }
+
+        // Delete the blobs that haven't been referenced by anything for a while.
+        {
+            let mut refs = tx.open_table(BLOB_REFS)?;
+            let mut blobs = tx.open_table(BLOBS)?;
+            let unused = refs.extract_if(|digest, (count, changed)| {
+                count == 0 && (changed <= cutoff || unpinned.contains(&Digest::from_bytes(digest)))
+            })?;
+            for entry in unused {
+                let (digest, _) = entry?;
+                blobs.remove(digest.value())?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(report)
+    }
+
+    async fn get_blob(&self, digest: Digest) -> anyhow::Result<Vec<u8>> {
+        let tx = self.db.begin_read()?;
+        let data = tx
+            .open_table(BLOBS)?
+            .get(digest.as_bytes())?
+            .ok_or_else(|| anyhow::format_err!("Blob not found: {digest}"))?
+            .value()
+            .to_vec();
+        Ok(data)
+    }
+
+    async fn put_blob_stream(
+        &self,
+        data: impl Stream<Item = anyhow::Result<Vec<u8>>>,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
+        // Blobs are stored as a single database value, so we need the whole blob before we can
+        // write it.
+        let data = data.try_concat().await?;
+        self.store_blob(&data, link, entity_snapshot_id).await
+    }
+
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+        range: BlobRange,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Vec<u8>>>> {
+        let tx = self.db.begin_read()?;
+        let blobs = tx.open_table(BLOBS)?;
+        let data = blobs
+            .get(digest.as_bytes())?
+            .ok_or_else(|| anyhow::format_err!("Blob not found: {digest}"))?;
+        range.chunks(data.value())
+    }
+
+    async fn blob_size(&self, digest: Digest) -> anyhow::Result<Option<u64>> {
+        let tx = self.db.begin_read()?;
+        let size = tx
+            .open_table(BLOBS)?
+            .get(digest.as_bytes())?
+            .map(|data| data.value().len() as u64);
+        Ok(size)
+    }
+
+    async fn store_entity(&self, link: &ExactLink, data: Vec<u8>) -> anyhow::Result<Digest> {
+        let tx = self.db.begin_write()?;
+        let digest = write_entity(&tx, link, &data)?;
+        tx.commit()?;
+        self.watchers.notify(link, Some(digest));
+        Ok(digest)
+    }
+
+    async fn store_entities(&self, writes: Vec<EntityWrite>) -> anyhow::Result<Vec<Digest>> {
+        let tx = self.db.begin_write()?;
+        for write in &writes {
+            let key = entity_key(&write.link)?;
+            let actual = tx
+                .open_table(ENTITIES)?
+                .get(key.as_slice())?
+                .map(|x| Digest::from_bytes(x.value()));
+            // Returning drops the transaction without committing anything.
+            if let Some(conflict) = SnapshotConflict::check(&write.link, write.expected, actual) {
+                return Err(BatchConflict::from(conflict).into());
+            }
+        }
+        let digests = writes
+            .iter()
+            .map(|write| match &write.data {
+                Some(data) => write_entity(&tx, &write.link, data),
+                None => Ok(write.expected),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        tx.commit()?;
+        for (write, digest) in writes.iter().zip(&digests) {
+            if write.data.is_some() {
+                self.watchers.notify(&write.link, Some(*digest));
+            }
+        }
+        Ok(digests)
+    }
+
+    async fn del_entity(&self, link: &ExactLink) -> anyhow::Result<()> {
+        let key = entity_key(link)?;
+        let tx = self.db.begin_write()?;
+        let old = tx
+            .open_table(ENTITIES)?
+            .remove(key.as_slice())?
+            .map(|x| Digest::from_bytes(x.value()));
+        tx.open_table(ENTITY_TIMES)?.remove(key.as_slice())?;
+        if let Some(old) = old {
+            add_blob_ref(&tx, old, -1)?;
+        }
+        tx.commit()?;
+        if old.is_some() {
+            self.watchers.notify(link, None);
+        }
+        Ok(())
+    }
+
+    async fn get_entity(&self, link: &ExactLink) -> anyhow::Result<Option<Digest>> {
+        let key = entity_key(link)?;
+        let tx = self.db.begin_read()?;
+        let digest = tx
+            .open_table(ENTITIES)?
+            .get(key.as_slice())?
+            .map(|x| Digest::from_bytes(x.value()));
+        Ok(digest)
+    }
+
+    async fn get_entity_meta(
+        &self,
+        link: &ExactLink,
+    ) -> anyhow::Result<Option<(Digest, EntityMeta)>> {
+        let key = entity_key(link)?;
+        let tx = self.db.begin_read()?;
+        let Some(digest) = tx
+            .open_table(ENTITIES)?
+            .get(key.as_slice())?
+            .map(|x| Digest::from_bytes(x.value()))
+        else {
+            return Ok(None);
+        };
+        let last_modified = tx
+            .open_table(ENTITY_TIMES)?
+            .get(key.as_slice())?
+            .context("Entity has no stored time")?
+            .value();
+        let size = tx
+            .open_table(BLOBS)?
+            .get(digest.as_bytes())?
+            .map_or(0, |data| data.value().len() as u64);
+        let meta = EntityMeta {
+            last_modified,
+            author_subspace: link.subspace,
+            size,
+        };
+        Ok(Some((digest, meta)))
+    }
+
+    async fn list(
+        &self,
+        link: ExactLink,
+        mode: ListMode,
+        limit: Option<u64>,
+        offset: Option<u64>,
+        cursor: Option<ListCursor>,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ExactLink>>> {
+        let prefix = entity_key(&link)?;
+        let start = match &cursor {
+            Some(cursor) => Bound::Excluded(entity_key(&cursor.resume_link(&link)?)?),
+            None => Bound::Included(prefix.clone()),
+        };
+        let depth = link.path.0.len();
+        let mut to_skip = offset.unwrap_or(0);
+        let limit = limit.unwrap_or(u64::MAX) as usize;
+
+        let tx = self.db.begin_read()?;
+        let entities = tx.open_table(ENTITIES)?;
+        let mut links = Vec::new();
+        let start = start.as_ref().map(Vec::as_slice);
+        for entry in entities.range::<&[u8]>((start, Bound::Unbounded))? {
+            if links.len() >= limit {
+                break;
+            }
+            let (key, _) = entry?;
+            if !key.value().starts_with(&prefix) {
+                break;
+            }
+            let link = parse_entity_key(key.value())?;
+            if mode == ListMode::Children && link.path.0.len() != depth + 1 {
+                continue;
+            }
+            if to_skip > 0 {
+                to_skip -= 1;
+                continue;
+            }
+            links.push(Ok(link));
+        }
+
+        Ok(futures::stream::iter(links))
+    }
+
+    async fn update_schema_index(
+        &self,
+        link: &ExactLink,
+        removed: &[Digest],
+        added: &[Digest],
+    ) -> anyhow::Result<()> {
+        self.indexes.update_schema_index(link, removed, added)
+    }
+
+    async fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ExactLink>>> {
+        let links = self.indexes.find_by_schema(namespace, subspace, schema)?;
+        Ok(futures::stream::iter(links))
+    }
+
+    fn field_indexes(&self) -> &FieldIndexes {
+        &self.field_indexes
+    }
+
+    async fn register_field_index(
+        &self,
+        index: FieldIndex,
+        format: BorshSchema,
+    ) -> anyhow::Result<Option<FieldIndex>> {
+        let previous = self.field_indexes.register(index.clone(), format.clone())?;
+        self.indexes.register_field_index(&index, &format)?;
+        Ok(previous)
+    }
+
+    async fn unregister_field_index(&self, index: Digest) -> anyhow::Result<()> {
+        self.field_indexes.remove(index);
+        self.indexes.unregister_field_index(index)
+    }
+
+    async fn update_field_index(
+        &self,
+        link: &ExactLink,
+        index: Digest,
+        removed: &[Vec<u8>],
+        added: &[Vec<u8>],
+    ) -> anyhow::Result<()> {
+        self.indexes.update_field_index(link, index, removed, added)
+    }
+
+    async fn claim_field_index_values(
+        &self,
+        link: &ExactLink,
+        index: &FieldIndex,
+        values: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        self.indexes.claim_field_index_values(link, index, values)
+    }
+
+    async fn lookup_field_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: &[u8],
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        self.indexes.lookup_field_index(namespace, index, value)
+    }
+
+    async fn watch(
+        &self,
+        link: ExactLink,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<EntityUpdate>>> {
+        Ok(self.watchers.watch(link))
+    }
+
+    async fn watch_namespace(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<EntityUpdate>>> {
+        Ok(self.watchers.watch_namespace(namespace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::Name,
+        test_util::{image, link, save_name},
+    };
+
+    /// A store that garbage collects everything that isn't live right away.
+    fn open(path: &Path) -> LeafRedb {
+        let mut store = LeafRedbStore::open(path).unwrap();
+        store.gc_grace_period = Duration::ZERO;
+        LeafRedb::new(store)
+    }
+
+    #[tokio::test]
+    async fn entities_and_secrets_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leaf.redb");
+        let leaf = open(&path);
+        let link = link(&leaf, "a").await;
+        let digest = save_name(&leaf, &link, "name").await.unwrap();
+        drop(leaf);
+
+        let leaf = open(&path);
+        let secret = leaf.get_namespace_secret(link.namespace).await.unwrap();
+        assert_eq!(secret, Some([1; 32]));
+        let (stored, meta) = leaf.store.get_entity_meta(&link).await.unwrap().unwrap();
+        assert_eq!(stored, digest);
+        assert!(meta.last_modified > 0);
+        let entity = leaf.entity(link).await.unwrap().entity().unwrap();
+        let name = entity.get_component::<Name>().await.unwrap().unwrap();
+        assert_eq!(name.0, "name");
+    }
+
+    #[tokio::test]
+    async fn field_index_registrations_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leaf.redb");
+        let leaf = open(&path);
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        save_name(&leaf, &a, "taken").await.unwrap();
+        let index = leaf.register_index::<Name>(&[], true).await.unwrap();
+        drop(leaf);
+
+        let leaf = open(&path);
+        assert_eq!(
+            leaf.store.field_indexes().get(index.id()),
+            Some(index.clone())
+        );
+        assert!(save_name(&leaf, &b, "taken").await.is_err());
+        let found = leaf.lookup(a.namespace, &index, "taken").await.unwrap();
+        assert_eq!(found, std::slice::from_ref(&a));
+
+        // Unregistering forgets the index and its values.
+        leaf.store.unregister_field_index(index.id()).await.unwrap();
+        drop(leaf);
+        let leaf = open(&path);
+        assert!(leaf.store.field_indexes().list().is_empty());
+        let value = borsh::to_vec("taken").unwrap();
+        let found = leaf
+            .store
+            .lookup_field_index(a.namespace, index.id(), &value)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gc_frees_blobs_once_nothing_references_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let leaf = open(&dir.path().join("leaf.redb"));
+        let link = link(&leaf, "a").await;
+        let orphan = leaf
+            .store
+            .store_blob(b"orphan", &link, Digest::default())
+            .await
+            .unwrap();
+        let blob = leaf
+            .store
+            .store_blob(b"image", &link, Digest::default())
+            .await
+            .unwrap();
+
+        let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+        entity.add_component(image(blob)).unwrap();
+        entity.save().await.unwrap();
+        let old_snapshot = entity.digest;
+        entity.set_component(Name("name".into())).unwrap();
+        entity.save().await.unwrap();
+
+        leaf.gc().await.unwrap();
+        assert!(leaf.store.get_blob(orphan).await.is_err());
+        assert!(leaf.store.get_blob(old_snapshot).await.is_err());
+        assert_eq!(leaf.store.get_blob(blob).await.unwrap(), b"image");
+
+        leaf.del_entity(link).await.unwrap();
+        leaf.gc().await.unwrap();
+        assert!(leaf.store.get_blob(blob).await.is_err());
+        assert!(leaf.store.get_blob(entity.digest).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn store_entities_conflict_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let leaf = open(&dir.path().join("leaf.redb"));
+        let (a, b) = (link(&leaf, "a").await, link(&leaf, "b").await);
+        let digest = save_name(&leaf, &b, "b").await.unwrap();
+
+        let write = |link: &ExactLink, expected| EntityWrite {
+            link: link.clone(),
+            expected,
+            data: Some(b"entity".to_vec()),
+        };
+        let writes = vec![write(&a, Digest::default()), write(&b, Digest::default())];
+        let error = leaf.store.store_entities(writes).await.unwrap_err();
+        let conflict = error.downcast_ref::<BatchConflict>().unwrap();
+        assert_eq!(conflict.conflict.actual, Some(digest));
+        assert!(leaf.store.get_entity(&a).await.unwrap().is_none());
+
+        // Check-only writes can conflict too, but are never written.
+        let check = |link: &ExactLink, expected| EntityWrite {
+            link: link.clone(),
+            expected,
+            data: None,
+        };
+        let writes = vec![write(&a, Digest::default()), check(&b, Digest::default())];
+        let error = leaf.store.store_entities(writes).await.unwrap_err();
+        assert!(error.downcast_ref::<BatchConflict>().is_some());
+        assert!(leaf.store.get_entity(&a).await.unwrap().is_none());
+        let digests = leaf.store.store_entities(vec![check(&b, digest)]).await;
+        assert_eq!(digests.unwrap(), [digest]);
+        assert_eq!(leaf.store.get_entity(&b).await.unwrap(), Some(digest));
+
+        let writes = vec![write(&a, Digest::default()), write(&b, digest)];
+        let digests = leaf.store.store_entities(writes).await.unwrap();
+        assert_eq!(leaf.store.get_entity(&a).await.unwrap(), Some(digests[0]));
+        assert_eq!(leaf.store.get_entity(&b).await.unwrap(), Some(digests[1]));
+    }
+}
@@ -0,0 +1,90 @@
+//! Change notifications for stores that don't have a change feed of their own.
+
+use std::sync::Mutex;
+
+use futures::channel::mpsc;
+
+use crate::{store::EntityUpdate, types::NamespaceId, Digest, ExactLink};
+
+/// How many updates can be waiting for a watcher before it is considered to have fallen behind.
+pub(crate) const WATCH_BUFFER: usize = 256;
+
+type Watcher = (Watched, mpsc::Sender<anyhow::Result<EntityUpdate>>);
+
+/// The entities that a watcher is subscribed to.
+#[derive(Debug)]
+enum Watched {
+    /// The entity at the link and all of the entities under it.
+    Link(ExactLink),
+    /// Every entity in the namespace, in any subspace.
+    Namespace(NamespaceId),
+}
+
+impl Watched {
+    fn covers(&self, link: &ExactLink) -> bool {
+        match self {
+            Watched::Link(watched) => is_under(link, watched),
+            Watched::Namespace(namespace) => link.namespace == *namespace,
+        }
+    }
+}
+
+/// The active [`LeafStore::watch()`][super::LeafStore::watch] subscriptions of a store.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers(Mutex<Vec<Watcher>>);
+
+impl Watchers {
+    /// Subscribe to changes to the entity at the link and all of the entities under it.
+    pub fn watch(&self, link: ExactLink) -> mpsc::Receiver<anyhow::Result<EntityUpdate>> {
+        self.add(Watched::Link(link))
+    }
+
+    /// Subscribe to changes to every entity in the namespace.
+    pub fn watch_namespace(
+        &self,
+        namespace: NamespaceId,
+    ) -> mpsc::Receiver<anyhow::Result<EntityUpdate>> {
+        self.add(Watched::Namespace(namespace))
+    }
+
+    fn add(&self, watched: Watched) -> mpsc::Receiver<anyhow::Result<EntityUpdate>> {
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        self.0.lock().unwrap().push((watched, sender));
+        receiver
+    }
+
+    /// Notify watchers of a change to the entity at the link, dropping watchers that have gone
+    /// away.
+    ///
+    /// Watchers that have [`WATCH_BUFFER`] updates waiting are dropped too, after sending them an
+    /// error, so that a watcher that isn't keeping up can't make us buffer without limit.
+    pub fn notify(&self, link: &ExactLink, snapshot: Option<Digest>) {
+        self.0.lock().unwrap().retain_mut(|(watched, sender)| {
+            if !watched.covers(link) {
+                return !sender.is_closed();
+            }
+            let update = EntityUpdate {
+                link: link.clone(),
+                snapshot,
+            };
+            match sender.try_send(Ok(update)) {
+                Ok(()) => true,
+                Err(e) if e.is_full() => {
+                    // Every sender has a slot of its own on top of the buffer, so a new sender can
+                    // always send the error.
+                    let error = anyhow::format_err!("Watcher fell behind and missed updates");
+                    sender.clone().try_send(Err(error)).ok();
+                    false
+                }
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+/// Returns whether `link` is `parent` or one of its descendants.
+pub(crate) fn is_under(link: &ExactLink, parent: &ExactLink) -> bool {
+    link.namespace == parent.namespace
+        && link.subspace == parent.subspace
+        && link.path.0.starts_with(&parent.path.0)
+}
@@ -0,0 +1,77 @@
+//! Helpers shared by the unit tests.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{
+    components::{Image, ImageSize, Name},
+    store::{
+        memory::{LeafMemory, MemoryStore},
+        redb::{LeafRedb, LeafRedbStore},
+        LeafStore,
+    },
+    types::{Blob, ExactLink},
+    Digest,
+};
+
+/// A memory store that garbage collects everything that isn't live right away.
+pub fn leaf() -> LeafMemory {
+    let mut store = MemoryStore::default();
+    store.gc_grace_period = Duration::ZERO;
+    LeafMemory::new(store)
+}
+
+/// A redb store in an in-memory database, which garbage collects everything that isn't live right
+/// away.
+pub fn redb_leaf() -> LeafRedb {
+    let db = redb::Database::builder()
+        .create_with_backend(redb::backends::InMemoryBackend::new())
+        .unwrap();
+    let mut store = LeafRedbStore::new(db).unwrap();
+    store.gc_grace_period = Duration::ZERO;
+    LeafRedb::new(store)
+}
+
+/// An Iroh store backed by an in-memory node, which is returned too because it has to be kept
+/// alive while the store is used.
+#[cfg(feature = "backend_iroh")]
+pub async fn iroh_leaf() -> (iroh::node::MemNode, crate::store::iroh::LeafIroh) {
+    let node = iroh::node::Node::memory().spawn().await.unwrap();
+    let local = redb::Database::builder()
+        .create_with_backend(redb::backends::InMemoryBackend::new())
+        .unwrap();
+    let store = crate::store::iroh::LeafIrohStore::new(node.client().clone(), local).unwrap();
+    (node, crate::Leaf::new(store))
+}
+
+/// A link to the entity at `path` in the test namespace and subspace.
+pub async fn link<S: LeafStore + Clone>(leaf: &crate::Leaf<S>, path: &str) -> ExactLink {
+    let namespace = leaf.store.import_namespace_secret([1; 32]).await.unwrap();
+    let subspace = leaf.store.import_subspace_secret([2; 32]).await.unwrap();
+    (namespace, subspace, [path]).into()
+}
+
+/// Set the [`Name`] of the entity at the link, returning its new snapshot digest.
+pub async fn save_name<S: LeafStore + Clone>(
+    leaf: &crate::Leaf<S>,
+    link: &ExactLink,
+    name: &str,
+) -> Result<Digest> {
+    let mut entity = leaf.entity(link.clone()).await?.get_or_init();
+    entity.set_component(Name(name.into()))?;
+    entity.save().await?;
+    Ok(entity.digest)
+}
+
+/// A one pixel image with the given blob as its data.
+pub fn image(data: Digest) -> Image {
+    Image {
+        mime_type: "image/png".into(),
+        size: ImageSize {
+            width: 1,
+            height: 1,
+        },
+        data: Blob(data),
+    }
+}
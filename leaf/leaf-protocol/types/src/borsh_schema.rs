@@ -1,6 +1,9 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
 
-use crate::BorshSchema;
+use crate::{value::Decoder, BorshSchema, Digest, Value};
 
 pub trait HasBorshSchema {
     fn borsh_schema() -> BorshSchema;
@@ -23,10 +26,10 @@ impl_primitive!(u32, U32);
 impl_primitive!(u64, U64);
 impl_primitive!(u128, U128);
 impl_primitive!(i8, I8);
-impl_primitive!(i16, U16);
-impl_primitive!(i32, U32);
-impl_primitive!(i64, U64);
-impl_primitive!(i128, U128);
+impl_primitive!(i16, I16);
+impl_primitive!(i32, I32);
+impl_primitive!(i64, I64);
+impl_primitive!(i128, I128);
 impl_primitive!(f32, F32);
 impl_primitive!(f64, F64);
 impl_primitive!(String, String);
@@ -68,3 +71,557 @@ impl<K: HasBorshSchema, V: HasBorshSchema> HasBorshSchema for HashMap<K, V> {
         }
     }
 }
+
+/// A blob or entity snapshot that is referenced from inside of some borsh-encoded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Reference {
+    /// A [`Blob`][crate::Blob].
+    Blob(Digest),
+    /// A [`Snapshot`][crate::Snapshot], or the snapshot that a [`Link`] is pinned to.
+    Snapshot(Digest),
+}
+
+/// The most collection items that take up no bytes that may be decoded from one piece of data.
+///
+/// Collection lengths come from untrusted data, and array lengths from untrusted schemas. Items
+/// that take up no bytes can't be checked against how much data is left, so without a limit 4
+/// bytes could make us loop billions of times. Items are counted along with all of the zero-size
+/// values nested inside of them, across the whole of the data.
+pub const MAX_ZERO_SIZE_ITEMS: u32 = 1 << 16;
+
+impl BorshSchema {
+    /// The smallest number of bytes that a value of this schema can be encoded in.
+    pub fn min_encoded_size(&self) -> usize {
+        match self {
+            BorshSchema::Null => 0,
+            BorshSchema::Bool | BorshSchema::U8 | BorshSchema::I8 => 1,
+            BorshSchema::U16 | BorshSchema::I16 => 2,
+            BorshSchema::U32 | BorshSchema::I32 | BorshSchema::F32 => 4,
+            BorshSchema::U64 | BorshSchema::I64 | BorshSchema::F64 => 8,
+            BorshSchema::U128 | BorshSchema::I128 => 16,
+            // The length prefix.
+            BorshSchema::String
+            | BorshSchema::Vector { .. }
+            | BorshSchema::Set { .. }
+            | BorshSchema::Map { .. } => 4,
+            BorshSchema::Option { .. } => 1,
+            BorshSchema::Array { schema, len } => {
+                schema.min_encoded_size().saturating_mul(*len as usize)
+            }
+            BorshSchema::Struct { fields } => fields.iter().fold(0, |size, (_, schema)| {
+                size.saturating_add(schema.min_encoded_size())
+            }),
+            BorshSchema::Enum { variants } => variants
+                .iter()
+                .map(|(_, schema)| schema.min_encoded_size())
+                .min()
+                .unwrap_or(0)
+                .saturating_add(1),
+            BorshSchema::Blob | BorshSchema::Snapshot => 32,
+            // Two inline keys, an empty path, and no snapshot.
+            BorshSchema::Link => 33 + 33 + 4 + 1,
+        }
+    }
+
+    /// How many values are decoded for one value of this schema, if it takes up no bytes.
+    pub(crate) fn zero_size_values(&self) -> u64 {
+        match self {
+            BorshSchema::Struct { fields } => fields.iter().fold(1, |count, (_, schema)| {
+                count.saturating_add(schema.zero_size_values())
+            }),
+            BorshSchema::Array { len: 0, .. } => 1,
+            BorshSchema::Array { schema, len } => schema
+                .zero_size_values()
+                .saturating_mul(*len as u64)
+                .saturating_add(1),
+            _ => 1,
+        }
+    }
+
+    /// The fewest zero-size collection items that decoding a value of this schema counts against
+    /// [`MAX_ZERO_SIZE_ITEMS`].
+    fn min_zero_size_items(&self) -> u64 {
+        match self {
+            BorshSchema::Array { len: 0, .. } => 0,
+            BorshSchema::Array { schema, len } if schema.min_encoded_size() == 0 => {
+                schema.zero_size_values().saturating_mul(*len as u64)
+            }
+            BorshSchema::Array { schema, len } => {
+                schema.min_zero_size_items().saturating_mul(*len as u64)
+            }
+            BorshSchema::Struct { fields } => fields.iter().fold(0, |count, (_, schema)| {
+                count.saturating_add(schema.min_zero_size_items())
+            }),
+            BorshSchema::Enum { variants } => variants
+                .iter()
+                .map(|(_, schema)| schema.min_zero_size_items())
+                .min()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Whether any data could be decoded with this schema without going over
+    /// [`MAX_ZERO_SIZE_ITEMS`].
+    ///
+    /// Fixed-size arrays of zero-size items always count against the limit, so schemas whose
+    /// arrays add up to more than it can't be used for anything.
+    pub fn is_decodable(&self) -> bool {
+        self.min_zero_size_items() <= MAX_ZERO_SIZE_ITEMS as u64
+    }
+
+    /// Walk through borsh-encoded `data` matching this schema, and collect all of the blobs and
+    /// snapshots that it references.
+    ///
+    /// This uses the same decoder as [`Value::decode()`], with the same limits on how many items
+    /// it will walk over. Returns an error if the data does not match the schema.
+    pub fn references(&self, data: &[u8]) -> io::Result<Vec<Reference>> {
+        let mut decoder = Decoder::walker(data);
+        decoder
+            .finish(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(decoder.references)
+    }
+}
+
+/// The result of comparing an old [`BorshSchema`] to a new one with
+/// [`BorshSchema::compatibility()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compatibility {
+    /// The schemas are exactly the same.
+    Identical,
+    /// Data written with the old schema can still be read with the new schema, for example because
+    /// fields were renamed or enum variants were added to the end of an enum.
+    OldDataReadable,
+    /// Data written with the old schema can't be read with the new schema.
+    Breaking {
+        /// The path to the field that changed, starting at the root of the schema.
+        ///
+        /// Struct fields and enum variants are named, `[]` is an item in an array, vector, or set,
+        /// `?` is the value of an option, and `{key}` and `{value}` are map keys and values.
+        path: Vec<String>,
+        /// A description of the breaking change.
+        reason: String,
+    },
+}
+
+impl Compatibility {
+    /// Returns whether data written with the old schema can't be read with the new one.
+    pub fn is_breaking(&self) -> bool {
+        matches!(self, Compatibility::Breaking { .. })
+    }
+}
+
+impl std::fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compatibility::Identical => write!(f, "identical"),
+            Compatibility::OldDataReadable => write!(f, "old data readable"),
+            Compatibility::Breaking { path, reason } => {
+                write!(f, "breaking at `{}`: {reason}", path.join("."))
+            }
+        }
+    }
+}
+
+impl BorshSchema {
+    /// Check whether data written with the `old` schema can be read with the `new` schema.
+    ///
+    /// This can be used in tests to make sure that changes to a component don't break data that
+    /// has already been stored.
+    pub fn compatibility(old: &BorshSchema, new: &BorshSchema) -> Compatibility {
+        if old == new {
+            return Compatibility::Identical;
+        }
+        let mut path = Vec::new();
+        match Self::check_compatible(old, new, &mut path) {
+            Ok(()) => Compatibility::OldDataReadable,
+            Err(reason) => Compatibility::Breaking { path, reason },
+        }
+    }
+
+    /// Returns an error describing the first breaking change, leaving `path` pointing at it.
+    fn check_compatible(
+        old: &BorshSchema,
+        new: &BorshSchema,
+        path: &mut Vec<String>,
+    ) -> Result<(), String> {
+        fn nested(
+            segment: &str,
+            old: &BorshSchema,
+            new: &BorshSchema,
+            path: &mut Vec<String>,
+        ) -> Result<(), String> {
+            path.push(segment.into());
+            BorshSchema::check_compatible(old, new, path)?;
+            path.pop();
+            Ok(())
+        }
+
+        match (old, new) {
+            (BorshSchema::Option { schema: old }, BorshSchema::Option { schema: new }) => {
+                nested("?", old, new, path)
+            }
+            (
+                BorshSchema::Array {
+                    schema: old,
+                    len: old_len,
+                },
+                BorshSchema::Array {
+                    schema: new,
+                    len: new_len,
+                },
+            ) => {
+                if old_len != new_len {
+                    return Err(format!(
+                        "array length changed from {old_len} to {new_len}"
+                    ));
+                }
+                nested("[]", old, new, path)
+            }
+            (BorshSchema::Struct { fields: old }, BorshSchema::Struct { fields: new }) => {
+                for ((name, old), (new_name, new)) in old.iter().zip(new) {
+                    // Borsh doesn't encode field names, so renaming a field is fine.
+                    nested(new_name, old, new, path).map_err(|e| {
+                        if name != new_name {
+                            format!("{e} ( renamed from `{name}` )")
+                        } else {
+                            e
+                        }
+                    })?;
+                }
+                match old.len().cmp(&new.len()) {
+                    std::cmp::Ordering::Less => {
+                        path.push(new[old.len()].0.clone());
+                        Err("field was added".into())
+                    }
+                    std::cmp::Ordering::Greater => {
+                        path.push(old[new.len()].0.clone());
+                        Err("field was removed".into())
+                    }
+                    std::cmp::Ordering::Equal => Ok(()),
+                }
+            }
+            (BorshSchema::Enum { variants: old }, BorshSchema::Enum { variants: new }) => {
+                if new.len() < old.len() {
+                    path.push(old[new.len()].0.clone());
+                    return Err("variant was removed".into());
+                }
+                // New variants may be added to the end, because old data will never use them.
+                for ((_, old), (name, new)) in old.iter().zip(new) {
+                    nested(name, old, new, path)?;
+                }
+                Ok(())
+            }
+            (BorshSchema::Vector { schema: old }, BorshSchema::Vector { schema: new })
+            | (BorshSchema::Set { schema: old }, BorshSchema::Set { schema: new })
+            // Sets are encoded just like vectors, so they can be read as one.
+            | (BorshSchema::Set { schema: old }, BorshSchema::Vector { schema: new }) => {
+                nested("[]", old, new, path)
+            }
+            (
+                BorshSchema::Map {
+                    key: old_key,
+                    value: old_value,
+                },
+                BorshSchema::Map {
+                    key: new_key,
+                    value: new_value,
+                },
+            ) => {
+                nested("{key}", old_key, new_key, path)?;
+                nested("{value}", old_value, new_value, path)
+            }
+            (old, new) if old == new => Ok(()),
+            (old, new) => Err(format!(
+                "type changed from {} to {}",
+                old.kind_name(),
+                new.kind_name()
+            )),
+        }
+    }
+
+    /// A short name for the kind of schema, used in error messages.
+    fn kind_name(&self) -> &'static str {
+        match self {
+            BorshSchema::Null => "Null",
+            BorshSchema::Bool => "Bool",
+            BorshSchema::U8 => "U8",
+            BorshSchema::U16 => "U16",
+            BorshSchema::U32 => "U32",
+            BorshSchema::U64 => "U64",
+            BorshSchema::U128 => "U128",
+            BorshSchema::I8 => "I8",
+            BorshSchema::I16 => "I16",
+            BorshSchema::I32 => "I32",
+            BorshSchema::I64 => "I64",
+            BorshSchema::I128 => "I128",
+            BorshSchema::F32 => "F32",
+            BorshSchema::F64 => "F64",
+            BorshSchema::String => "String",
+            BorshSchema::Option { .. } => "Option",
+            BorshSchema::Array { .. } => "Array",
+            BorshSchema::Struct { .. } => "Struct",
+            BorshSchema::Enum { .. } => "Enum",
+            BorshSchema::Vector { .. } => "Vector",
+            BorshSchema::Map { .. } => "Map",
+            BorshSchema::Set { .. } => "Set",
+            BorshSchema::Blob => "Blob",
+            BorshSchema::Snapshot => "Snapshot",
+            BorshSchema::Link => "Link",
+        }
+    }
+}
+
+impl BorshSchema {
+    /// Get the schema of the field at `path`, or `None` if there is no such field.
+    ///
+    /// Path segments are struct field or enum variant names, `[]` for the items of an array,
+    /// vector, or set, `?` for the value of an option, and `{key}` and `{value}` for map keys and
+    /// values, the same as the paths in [`Compatibility::Breaking`].
+    pub fn field_schema(&self, path: &[String]) -> Option<&BorshSchema> {
+        let Some((segment, rest)) = path.split_first() else {
+            return Some(self);
+        };
+        let schema = match (self, segment.as_str()) {
+            (BorshSchema::Struct { fields: named }, name)
+            | (BorshSchema::Enum { variants: named }, name) => named
+                .iter()
+                .find(|(field_name, _)| field_name == name)
+                .map(|(_, schema)| schema)?,
+            (BorshSchema::Option { schema }, "?") => schema,
+            (
+                BorshSchema::Array { schema, .. }
+                | BorshSchema::Vector { schema }
+                | BorshSchema::Set { schema },
+                "[]",
+            ) => schema,
+            (BorshSchema::Map { key, .. }, "{key}") => key,
+            (BorshSchema::Map { value, .. }, "{value}") => value,
+            _ => return None,
+        };
+        schema.field_schema(rest)
+    }
+
+    /// Decode borsh `data` matching this schema, and return the borsh encoding of every value at
+    /// the field `path`.
+    ///
+    /// See [`field_schema()`][Self::field_schema] for the path format. A path may match more than
+    /// one value, if it goes through a collection, or none, if it goes through an empty option or
+    /// an enum variant that isn't the one in the data.
+    pub fn field_values(&self, data: &[u8], path: &[String]) -> io::Result<Vec<Vec<u8>>> {
+        fn collect<'a>(value: &'a Value, path: &[String], values: &mut Vec<&'a Value>) {
+            let Some((segment, rest)) = path.split_first() else {
+                values.push(value);
+                return;
+            };
+            match (value, segment.as_str()) {
+                (Value::Struct(fields), name) => {
+                    if let Some((_, value)) = fields.iter().find(|(field, _)| field == name) {
+                        collect(value, rest, values);
+                    }
+                }
+                (Value::Enum { variant, value }, name) if variant == name => {
+                    collect(value, rest, values)
+                }
+                (Value::Option(Some(value)), "?") => collect(value, rest, values),
+                (Value::Array(items) | Value::Vector(items) | Value::Set(items), "[]") => {
+                    for item in items {
+                        collect(item, rest, values);
+                    }
+                }
+                (Value::Map(entries), "{key}") => {
+                    for (key, _) in entries {
+                        collect(key, rest, values);
+                    }
+                }
+                (Value::Map(entries), "{value}") => {
+                    for (_, value) in entries {
+                        collect(value, rest, values);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let field_schema = self.field_schema(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Schema has no field at `{}`", path.join(".")),
+            )
+        })?;
+        let value = Value::decode(self, data)?;
+        let mut values = Vec::new();
+        collect(&value, path, &mut values);
+        values
+            .into_iter()
+            .map(|value| value.encode(field_schema))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Link;
+
+    fn structure(fields: &[(&str, BorshSchema)]) -> BorshSchema {
+        BorshSchema::Struct {
+            fields: fields
+                .iter()
+                .map(|(name, schema)| (name.to_string(), schema.clone()))
+                .collect(),
+        }
+    }
+
+    fn enumeration(variants: &[(&str, BorshSchema)]) -> BorshSchema {
+        BorshSchema::Enum {
+            variants: variants
+                .iter()
+                .map(|(name, schema)| (name.to_string(), schema.clone()))
+                .collect(),
+        }
+    }
+
+    fn breaking_path(old: &BorshSchema, new: &BorshSchema) -> Vec<String> {
+        match BorshSchema::compatibility(old, new) {
+            Compatibility::Breaking { path, .. } => path,
+            compatibility => panic!("Expected a breaking change, got {compatibility}"),
+        }
+    }
+
+    #[test]
+    fn identical_and_renamed_fields_are_compatible() {
+        let old = structure(&[("name", BorshSchema::String), ("age", BorshSchema::U8)]);
+        assert_eq!(
+            BorshSchema::compatibility(&old, &old),
+            Compatibility::Identical
+        );
+        let renamed = structure(&[("title", BorshSchema::String), ("age", BorshSchema::U8)]);
+        assert_eq!(
+            BorshSchema::compatibility(&old, &renamed),
+            Compatibility::OldDataReadable
+        );
+    }
+
+    #[test]
+    fn adding_or_removing_struct_fields_is_breaking() {
+        let old = structure(&[("name", BorshSchema::String)]);
+        let new = structure(&[("name", BorshSchema::String), ("age", BorshSchema::U8)]);
+        assert_eq!(breaking_path(&old, &new), ["age"]);
+        assert_eq!(breaking_path(&new, &old), ["age"]);
+    }
+
+    #[test]
+    fn enum_variants_may_only_be_added_to_the_end() {
+        let old = enumeration(&[("A", BorshSchema::Null), ("B", BorshSchema::U32)]);
+        let added = enumeration(&[
+            ("A", BorshSchema::Null),
+            ("B", BorshSchema::U32),
+            ("C", BorshSchema::String),
+        ]);
+        assert_eq!(
+            BorshSchema::compatibility(&old, &added),
+            Compatibility::OldDataReadable
+        );
+        assert_eq!(breaking_path(&added, &old), ["C"]);
+
+        let inserted = enumeration(&[
+            ("A", BorshSchema::Null),
+            ("C", BorshSchema::String),
+            ("B", BorshSchema::U32),
+        ]);
+        assert_eq!(breaking_path(&old, &inserted), ["C"]);
+    }
+
+    #[test]
+    fn changing_integer_width_is_breaking() {
+        let old = structure(&[("count", BorshSchema::U32)]);
+        let new = structure(&[("count", BorshSchema::U64)]);
+        let Compatibility::Breaking { path, reason } = BorshSchema::compatibility(&old, &new)
+        else {
+            panic!("Expected a breaking change");
+        };
+        assert_eq!(path, ["count"]);
+        assert_eq!(reason, "type changed from U32 to U64");
+
+        let old = BorshSchema::Array {
+            schema: Box::new(BorshSchema::U8),
+            len: 32,
+        };
+        let new = BorshSchema::Array {
+            schema: Box::new(BorshSchema::U8),
+            len: 64,
+        };
+        assert!(BorshSchema::compatibility(&old, &new).is_breaking());
+    }
+
+    #[test]
+    fn wrapping_in_an_option_is_breaking() {
+        let old = structure(&[("bio", BorshSchema::String)]);
+        let new = structure(&[(
+            "bio",
+            BorshSchema::Option {
+                schema: Box::new(BorshSchema::String),
+            },
+        )]);
+        assert_eq!(breaking_path(&old, &new), ["bio"]);
+        assert_eq!(breaking_path(&new, &old), ["bio"]);
+
+        // Changes inside the option are found too.
+        let wider = structure(&[(
+            "bio",
+            BorshSchema::Option {
+                schema: Box::new(BorshSchema::Vector {
+                    schema: Box::new(BorshSchema::U8),
+                }),
+            },
+        )]);
+        assert_eq!(breaking_path(&new, &wider), ["bio", "?"]);
+    }
+
+    #[test]
+    fn sets_can_be_read_as_vectors() {
+        let set = BorshSchema::Set {
+            schema: Box::new(BorshSchema::String),
+        };
+        let vector = BorshSchema::Vector {
+            schema: Box::new(BorshSchema::String),
+        };
+        assert_eq!(
+            BorshSchema::compatibility(&set, &vector),
+            Compatibility::OldDataReadable
+        );
+        assert!(BorshSchema::compatibility(&vector, &set).is_breaking());
+    }
+
+    #[test]
+    fn references_are_found_within_the_item_limits() {
+        let array = |schema, len| BorshSchema::Array {
+            schema: Box::new(schema),
+            len,
+        };
+        let schema = structure(&[
+            ("image", BorshSchema::Blob),
+            ("links", Vec::<Link>::borsh_schema()),
+        ]);
+        let snapshot = Digest::new(b"snapshot");
+        let link =
+            Link::from(crate::ExactLink::from(([1; 32], [2; 32], ()))).with_snapshot(snapshot);
+        let data = borsh::to_vec(&(Digest::new(b"image"), vec![link])).unwrap();
+        assert_eq!(
+            schema.references(&data).unwrap(),
+            [
+                Reference::Blob(Digest::new(b"image")),
+                Reference::Snapshot(snapshot)
+            ]
+        );
+
+        for schema in [
+            array(array(BorshSchema::Null, 1_000_000), 1_000),
+            array(array(BorshSchema::Null, u32::MAX), u32::MAX),
+        ] {
+            assert!(schema.references(&[]).is_err());
+        }
+    }
+}
@@ -4,9 +4,13 @@ use borsh::{BorshDeserialize, BorshSerialize};
 
 mod borsh_schema;
 mod digest;
+mod validate;
+mod value;
 
 pub use borsh_schema::*;
 pub use digest::*;
+pub use validate::*;
+pub use value::*;
 
 pub type NamespaceId = [u8; 32];
 pub type SubspaceId = [u8; 32];
@@ -171,7 +175,7 @@ pub struct ComponentData {
 }
 
 /// A [`Component`][crate::Component] schema.
-#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone)]
+#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone, PartialEq, Eq)]
 pub struct Schema {
     /// The name of the schema.
     pub name: String,
@@ -180,9 +184,19 @@ pub struct Schema {
     /// The collection ID containing the components that document this schema.
     pub specification: Digest,
 }
+impl Schema {
+    /// Compute the schema ID: the digest of the borsh-serialized `(name, specification)` pair.
+    pub fn id(&self) -> Digest {
+        let mut buf = Vec::new();
+        (&self.name, self.specification)
+            .serialize(&mut buf)
+            .unwrap();
+        Digest::new(&buf)
+    }
+}
 
 /// A [`borsh`] schema describing the data format of a [`Component`][crate::Component].
-#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone)]
+#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone, PartialEq, Eq)]
 pub enum BorshSchema {
     Null,
     Bool,
@@ -227,13 +241,63 @@ pub enum BorshSchema {
     Link,
 }
 
-#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone)]
+#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone, PartialEq, Eq)]
 pub struct Link {
     namespace: KeyResolverKind,
     subspace: KeyResolverKind,
     path: Vec<PathSegment>,
     snapshot: Option<Digest>,
 }
+impl Link {
+    /// Create a new link to the latest version of an entity.
+    pub fn new<P: Into<EntityPath>>(
+        namespace: KeyResolverKind,
+        subspace: KeyResolverKind,
+        path: P,
+    ) -> Self {
+        Self {
+            namespace,
+            subspace,
+            path: path.into().0,
+            snapshot: None,
+        }
+    }
+
+    /// Pin the link to a specific snapshot of the entity.
+    pub fn with_snapshot(mut self, snapshot: Digest) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// The key resolver for the namespace of the linked entity.
+    pub fn namespace(&self) -> &KeyResolverKind {
+        &self.namespace
+    }
+
+    /// The key resolver for the subspace of the linked entity.
+    pub fn subspace(&self) -> &KeyResolverKind {
+        &self.subspace
+    }
+
+    /// The path of the linked entity.
+    pub fn path(&self) -> &[PathSegment] {
+        &self.path
+    }
+
+    /// The snapshot of the linked entity, if the link is pinned to one.
+    pub fn snapshot(&self) -> Option<Digest> {
+        self.snapshot
+    }
+}
+impl From<ExactLink> for Link {
+    fn from(link: ExactLink) -> Self {
+        Self::new(
+            KeyResolverKind::Inline(link.namespace),
+            KeyResolverKind::Inline(link.subspace),
+            link.path,
+        )
+    }
+}
 impl HasBorshSchema for Link {
     fn borsh_schema() -> BorshSchema {
         BorshSchema::Link
@@ -297,7 +361,7 @@ impl HasBorshSchema for Snapshot {
 }
 
 /// A key-resolver algorithm.
-#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone)]
+#[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug, Clone, PartialEq, Eq)]
 pub enum KeyResolverKind {
     /// The key is stored inline and may be used directly.
     Inline([u8; 32]),
@@ -319,3 +383,13 @@ pub struct EncryptionAlgorithm {
     pub name: String,
     pub specification: Digest,
 }
+
+impl EncryptionAlgorithm {
+    /// Compute the ID of the encryption algorithm, which is the digest of it's Borsh
+    /// serialization.
+    pub fn id(&self) -> Digest {
+        let mut buf = Vec::new();
+        self.serialize(&mut buf).unwrap();
+        Digest::new(&buf)
+    }
+}
@@ -0,0 +1,167 @@
+//! Checking that borsh data matches a [`BorshSchema`].
+
+use crate::{value::Decoder, BorshSchema};
+
+/// Error returned by [`BorshSchema::validate()`] when data doesn't match the schema.
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Where the invalid value is, in the same format as [`BorshSchema::field_schema()`]: the
+    /// names of the struct fields and enum variants, `[]` for collection items, `?` for option
+    /// values, and `{key}` and `{value}` for map entries.
+    pub path: Vec<String>,
+    /// The offset of the invalid value in the data.
+    pub offset: u64,
+    pub kind: ValidationErrorKind,
+}
+
+/// The ways that data can fail to match a [`BorshSchema`].
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    /// The data ended in the middle of a value.
+    UnexpectedEnd,
+    /// There was data left over after the whole schema was read.
+    TrailingData { len: u64 },
+    /// A string was not valid UTF-8.
+    InvalidUtf8,
+    /// A bool was not `0` or `1`.
+    InvalidBool(u8),
+    /// An option tag was not `0` or `1`.
+    InvalidOptionTag(u8),
+    /// An enum tag was past the last variant of the enum.
+    InvalidEnumTag { tag: u8, variant_count: u64 },
+    /// A link could not be decoded.
+    InvalidLink,
+    /// A collection or array length was more items than the rest of the data could hold, or more
+    /// zero-size items than [`MAX_ZERO_SIZE_ITEMS`][crate::MAX_ZERO_SIZE_ITEMS] allows.
+    TooManyItems { len: u32 },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid data at byte {}", self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " (`{}`)", self.path.join("."))?;
+        }
+        match &self.kind {
+            ValidationErrorKind::UnexpectedEnd => {
+                write!(f, ": data ended in the middle of a value")
+            }
+            ValidationErrorKind::TrailingData { len } => {
+                write!(f, ": {len} bytes left over after the end of the value")
+            }
+            ValidationErrorKind::InvalidUtf8 => write!(f, ": string is not valid UTF-8"),
+            ValidationErrorKind::InvalidBool(value) => write!(f, ": invalid bool {value}"),
+            ValidationErrorKind::InvalidOptionTag(tag) => write!(f, ": invalid option tag {tag}"),
+            ValidationErrorKind::InvalidEnumTag { tag, variant_count } => write!(
+                f,
+                ": invalid enum tag {tag}, the enum only has {variant_count} variants"
+            ),
+            ValidationErrorKind::InvalidLink => write!(f, ": invalid link"),
+            ValidationErrorKind::TooManyItems { len } => {
+                write!(f, ": collection of {len} items is longer than the data")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl BorshSchema {
+    /// Check that the data is exactly one value of this schema.
+    ///
+    /// This uses the same decoder as [`Value::decode()`][crate::Value::decode], so data that validates can always be
+    /// decoded. That is stricter than deserializing into a Rust type usually is: strings must be
+    /// valid UTF-8, bool, option, and enum tags must be in range, and there must be no data left
+    /// over.
+    pub fn validate(&self, data: &[u8]) -> Result<(), ValidationError> {
+        Decoder::new(data).finish(self).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HasBorshSchema;
+
+    fn schema() -> BorshSchema {
+        BorshSchema::Struct {
+            fields: vec![
+                ("names".into(), Vec::<String>::borsh_schema()),
+                ("flag".into(), Option::<bool>::borsh_schema()),
+            ],
+        }
+    }
+
+    #[test]
+    fn valid_data_passes() {
+        let data = borsh::to_vec(&(vec!["a".to_string()], Some(true))).unwrap();
+        assert_eq!(schema().validate(&data), Ok(()));
+    }
+
+    #[test]
+    fn errors_use_field_schema_paths() {
+        let mut data = borsh::to_vec(&(vec!["a".to_string()], Some(true))).unwrap();
+        *data.last_mut().unwrap() = 2;
+        let error = schema().validate(&data).unwrap_err();
+        assert_eq!(error.path, ["flag", "?"]);
+        assert_eq!(error.kind, ValidationErrorKind::InvalidBool(2));
+        assert!(schema().field_schema(&error.path).is_some());
+
+        let data = borsh::to_vec(&(vec![vec![0xffu8]], None::<bool>)).unwrap();
+        let error = schema().validate(&data).unwrap_err();
+        assert_eq!(error.path, ["names", "[]"]);
+        assert_eq!(error.kind, ValidationErrorKind::InvalidUtf8);
+    }
+
+    #[test]
+    fn trailing_data_is_rejected() {
+        let mut data = borsh::to_vec(&(Vec::<String>::new(), None::<bool>)).unwrap();
+        data.push(0);
+        let error = schema().validate(&data).unwrap_err();
+        assert_eq!(error.kind, ValidationErrorKind::TrailingData { len: 1 });
+    }
+
+    #[test]
+    fn huge_collection_lengths_are_rejected() {
+        let data = borsh::to_vec(&u32::MAX).unwrap();
+        for schema in [Vec::<()>::borsh_schema(), Vec::<u8>::borsh_schema()] {
+            let error = schema.validate(&data).unwrap_err();
+            assert_eq!(
+                error.kind,
+                ValidationErrorKind::TooManyItems { len: u32::MAX }
+            );
+            assert_eq!(error.offset, 0);
+        }
+    }
+
+    #[test]
+    fn huge_array_lengths_are_rejected() {
+        let array = |schema, len| BorshSchema::Array {
+            schema: Box::new(schema),
+            len,
+        };
+        for schema in [
+            array(BorshSchema::Null, 200_000_000),
+            array(array(BorshSchema::Null, 1 << 10), 1 << 10),
+            array(BorshSchema::U64, u32::MAX),
+        ] {
+            let error = schema.validate(&[]).unwrap_err();
+            assert!(matches!(
+                error.kind,
+                ValidationErrorKind::TooManyItems { .. }
+            ));
+        }
+        assert!(!array(BorshSchema::Null, 200_000_000).is_decodable());
+        assert!(array(BorshSchema::Null, 1 << 10).is_decodable());
+        assert_eq!(array(BorshSchema::Null, 1 << 10).validate(&[]), Ok(()));
+
+        // The budget is shared by all of the collections in the data.
+        let schema = Vec::<[(); 1 << 10]>::borsh_schema();
+        let data = borsh::to_vec(&(1u32 << 10)).unwrap();
+        let error = schema.validate(&data).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ValidationErrorKind::TooManyItems { .. }
+        ));
+    }
+}
@@ -0,0 +1,727 @@
+//! A dynamic [`Value`] that can hold any data described by a [`BorshSchema`].
+
+use std::io::{self, Write};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use iroh_base::base32;
+use serde_json::{json, Map, Number};
+
+use crate::{
+    BorshSchema, Digest, KeyResolverKind, Link, PathSegment, Reference, ValidationError,
+    ValidationErrorKind, MAX_ZERO_SIZE_ITEMS,
+};
+
+/// A dynamically typed value, decoded from borsh data with a [`BorshSchema`].
+///
+/// This lets tools inspect and edit components without having the Rust type for them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Option(Option<Box<Value>>),
+    Array(Vec<Value>),
+    /// The fields of a struct, in the order of the schema.
+    Struct(Vec<(String, Value)>),
+    Enum {
+        variant: String,
+        value: Box<Value>,
+    },
+    Vector(Vec<Value>),
+    /// The entries of a map, in the order they were encoded.
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    Blob(Digest),
+    Snapshot(Digest),
+    Link(Link),
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn schema_mismatch(schema: &BorshSchema) -> io::Error {
+    invalid_data(format!("Value does not match schema: {schema:?}"))
+}
+
+impl Value {
+    /// Decode borsh `data` using the given schema.
+    ///
+    /// Returns an error if the data does not match the schema, or if there is data left over
+    /// after decoding. The error wraps a [`ValidationError`] saying where the data went wrong.
+    pub fn decode(schema: &BorshSchema, data: &[u8]) -> io::Result<Value> {
+        Decoder::new(data).finish(schema).map_err(invalid_data)
+    }
+
+    /// Decode a value from the start of `reader` using the given schema, advancing it past the
+    /// value.
+    pub fn decode_reader(schema: &BorshSchema, reader: &mut &[u8]) -> io::Result<Value> {
+        let mut decoder = Decoder::new(reader);
+        let value = decoder.value(schema).map_err(invalid_data)?;
+        *reader = &reader[decoder.offset..];
+        Ok(value)
+    }
+
+    /// Encode the value to borsh bytes, checking that it matches the given schema.
+    pub fn encode(&self, schema: &BorshSchema) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.encode_writer(schema, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Encode the value to the writer, checking that it matches the given schema.
+    pub fn encode_writer<W: Write>(&self, schema: &BorshSchema, writer: &mut W) -> io::Result<()> {
+        fn write_len<W: Write>(len: usize, writer: &mut W) -> io::Result<()> {
+            u32::try_from(len)
+                .map_err(|_| invalid_data("Collection is too long"))?
+                .serialize(writer)
+        }
+
+        match (schema, self) {
+            (BorshSchema::Null, Value::Null) => (),
+            (BorshSchema::Bool, Value::Bool(v)) => v.serialize(writer)?,
+            (BorshSchema::U8, Value::U8(v)) => v.serialize(writer)?,
+            (BorshSchema::U16, Value::U16(v)) => v.serialize(writer)?,
+            (BorshSchema::U32, Value::U32(v)) => v.serialize(writer)?,
+            (BorshSchema::U64, Value::U64(v)) => v.serialize(writer)?,
+            (BorshSchema::U128, Value::U128(v)) => v.serialize(writer)?,
+            (BorshSchema::I8, Value::I8(v)) => v.serialize(writer)?,
+            (BorshSchema::I16, Value::I16(v)) => v.serialize(writer)?,
+            (BorshSchema::I32, Value::I32(v)) => v.serialize(writer)?,
+            (BorshSchema::I64, Value::I64(v)) => v.serialize(writer)?,
+            (BorshSchema::I128, Value::I128(v)) => v.serialize(writer)?,
+            // Borsh refuses to write NaN, but the decoder accepts it, so write the bytes directly.
+            (BorshSchema::F32, Value::F32(v)) => writer.write_all(&v.to_le_bytes())?,
+            (BorshSchema::F64, Value::F64(v)) => writer.write_all(&v.to_le_bytes())?,
+            (BorshSchema::String, Value::String(v)) => v.serialize(writer)?,
+            (BorshSchema::Option { .. }, Value::Option(None)) => 0u8.serialize(writer)?,
+            (BorshSchema::Option { schema }, Value::Option(Some(v))) => {
+                1u8.serialize(writer)?;
+                v.encode_writer(schema, writer)?;
+            }
+            (BorshSchema::Array { schema, len }, Value::Array(items)) => {
+                if items.len() != *len as usize {
+                    return Err(invalid_data(format!(
+                        "Expected array of length {len} but found {}",
+                        items.len()
+                    )));
+                }
+                for item in items {
+                    item.encode_writer(schema, writer)?;
+                }
+            }
+            (BorshSchema::Struct { fields }, Value::Struct(values)) => {
+                if fields.len() != values.len()
+                    || fields.iter().zip(values).any(|((a, _), (b, _))| a != b)
+                {
+                    return Err(schema_mismatch(schema));
+                }
+                for ((_, schema), (_, value)) in fields.iter().zip(values) {
+                    value.encode_writer(schema, writer)?;
+                }
+            }
+            (BorshSchema::Enum { variants }, Value::Enum { variant, value }) => {
+                let (tag, (_, schema)) = variants
+                    .iter()
+                    .enumerate()
+                    .find(|(_, (name, _))| name == variant)
+                    .ok_or_else(|| invalid_data(format!("Unknown enum variant: {variant}")))?;
+                u8::try_from(tag)
+                    .map_err(|_| invalid_data(format!("Enum tag is too large: {tag}")))?
+                    .serialize(writer)?;
+                value.encode_writer(schema, writer)?;
+            }
+            (BorshSchema::Vector { schema }, Value::Vector(items))
+            | (BorshSchema::Set { schema }, Value::Set(items)) => {
+                write_len(items.len(), writer)?;
+                for item in items {
+                    item.encode_writer(schema, writer)?;
+                }
+            }
+            (BorshSchema::Map { key, value }, Value::Map(entries)) => {
+                write_len(entries.len(), writer)?;
+                for (k, v) in entries {
+                    k.encode_writer(key, writer)?;
+                    v.encode_writer(value, writer)?;
+                }
+            }
+            (BorshSchema::Blob, Value::Blob(v)) | (BorshSchema::Snapshot, Value::Snapshot(v)) => {
+                v.serialize(writer)?
+            }
+            (BorshSchema::Link, Value::Link(v)) => v.serialize(writer)?,
+            (schema, _) => return Err(schema_mismatch(schema)),
+        }
+        Ok(())
+    }
+
+    /// Convert the value to JSON.
+    ///
+    /// The conversion is lossless, and can be reversed with [`Value::from_json()`]:
+    ///
+    /// - 64 and 128 bit integers are strings, because they don't fit in a JSON number.
+    /// - Floats that aren't finite are the strings `"NaN"`, `"Infinity"`, and `"-Infinity"`.
+    /// - Options are `null` or their value, except when the value could be `null` itself, in which
+    ///   case it is wrapped like `{ "Some": value }`.
+    /// - Structs are objects, and enums are objects with the variant name as their only key.
+    /// - Maps are arrays of `[key, value]` pairs, because keys may not be strings.
+    /// - Blobs and snapshots are base32 digests, and links are objects.
+    pub fn to_json(&self) -> serde_json::Value {
+        fn float(v: f64) -> serde_json::Value {
+            match Number::from_f64(v) {
+                Some(n) => serde_json::Value::Number(n),
+                None if v.is_nan() => json!("NaN"),
+                None if v > 0.0 => json!("Infinity"),
+                None => json!("-Infinity"),
+            }
+        }
+        fn items(items: &[Value]) -> serde_json::Value {
+            serde_json::Value::Array(items.iter().map(Value::to_json).collect())
+        }
+
+        match self {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(v) => json!(v),
+            Value::U8(v) => json!(v),
+            Value::U16(v) => json!(v),
+            Value::U32(v) => json!(v),
+            Value::U64(v) => json!(v.to_string()),
+            Value::U128(v) => json!(v.to_string()),
+            Value::I8(v) => json!(v),
+            Value::I16(v) => json!(v),
+            Value::I32(v) => json!(v),
+            Value::I64(v) => json!(v.to_string()),
+            Value::I128(v) => json!(v.to_string()),
+            Value::F32(v) => float(*v as f64),
+            Value::F64(v) => float(*v),
+            Value::String(v) => json!(v),
+            Value::Option(None) => serde_json::Value::Null,
+            Value::Option(Some(v)) => match **v {
+                Value::Null | Value::Option(_) => json!({ "Some": v.to_json() }),
+                _ => v.to_json(),
+            },
+            Value::Array(v) | Value::Vector(v) | Value::Set(v) => items(v),
+            Value::Struct(fields) => serde_json::Value::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.to_json()))
+                    .collect(),
+            ),
+            Value::Enum { variant, value } => {
+                let mut map = Map::new();
+                map.insert(variant.clone(), value.to_json());
+                serde_json::Value::Object(map)
+            }
+            Value::Map(entries) => serde_json::Value::Array(
+                entries
+                    .iter()
+                    .map(|(k, v)| json!([k.to_json(), v.to_json()]))
+                    .collect(),
+            ),
+            Value::Blob(v) | Value::Snapshot(v) => json!(v.to_string()),
+            Value::Link(link) => link_to_json(link),
+        }
+    }
+
+    /// Convert JSON created by [`Value::to_json()`] back into a value with the given schema.
+    pub fn from_json(schema: &BorshSchema, json: &serde_json::Value) -> io::Result<Value> {
+        let mismatch = || invalid_data(format!("JSON does not match schema {schema:?}: {json}"));
+        fn int<T: TryFrom<u64> + TryFrom<i64>>(json: &serde_json::Value) -> Option<T> {
+            match json.as_u64() {
+                Some(v) => v.try_into().ok(),
+                None => json.as_i64()?.try_into().ok(),
+            }
+        }
+        fn parsed<T: std::str::FromStr>(json: &serde_json::Value) -> Option<T> {
+            json.as_str()?.parse().ok()
+        }
+        fn float(json: &serde_json::Value) -> Option<f64> {
+            match json.as_str() {
+                Some("NaN") => Some(f64::NAN),
+                Some("Infinity") => Some(f64::INFINITY),
+                Some("-Infinity") => Some(f64::NEG_INFINITY),
+                Some(_) => None,
+                None => json.as_f64(),
+            }
+        }
+        fn items(schema: &BorshSchema, json: &serde_json::Value) -> io::Result<Option<Vec<Value>>> {
+            let Some(items) = json.as_array() else {
+                return Ok(None);
+            };
+            items
+                .iter()
+                .map(|item| Value::from_json(schema, item))
+                .collect::<io::Result<_>>()
+                .map(Some)
+        }
+
+        Ok(match schema {
+            BorshSchema::Null => json.as_null().map(|_| Value::Null).ok_or_else(mismatch)?,
+            BorshSchema::Bool => json.as_bool().map(Value::Bool).ok_or_else(mismatch)?,
+            BorshSchema::U8 => int(json).map(Value::U8).ok_or_else(mismatch)?,
+            BorshSchema::U16 => int(json).map(Value::U16).ok_or_else(mismatch)?,
+            BorshSchema::U32 => int(json).map(Value::U32).ok_or_else(mismatch)?,
+            BorshSchema::U64 => parsed(json).map(Value::U64).ok_or_else(mismatch)?,
+            BorshSchema::U128 => parsed(json).map(Value::U128).ok_or_else(mismatch)?,
+            BorshSchema::I8 => int(json).map(Value::I8).ok_or_else(mismatch)?,
+            BorshSchema::I16 => int(json).map(Value::I16).ok_or_else(mismatch)?,
+            BorshSchema::I32 => int(json).map(Value::I32).ok_or_else(mismatch)?,
+            BorshSchema::I64 => parsed(json).map(Value::I64).ok_or_else(mismatch)?,
+            BorshSchema::I128 => parsed(json).map(Value::I128).ok_or_else(mismatch)?,
+            BorshSchema::F32 => float(json)
+                .map(|v| Value::F32(v as f32))
+                .ok_or_else(mismatch)?,
+            BorshSchema::F64 => float(json).map(Value::F64).ok_or_else(mismatch)?,
+            BorshSchema::String => json
+                .as_str()
+                .map(|v| Value::String(v.into()))
+                .ok_or_else(mismatch)?,
+            BorshSchema::Option { schema: inner } => {
+                if json.is_null() {
+                    Value::Option(None)
+                } else if matches!(**inner, BorshSchema::Null | BorshSchema::Option { .. }) {
+                    let value = json
+                        .as_object()
+                        .filter(|x| x.len() == 1)
+                        .and_then(|x| x.get("Some"))
+                        .ok_or_else(mismatch)?;
+                    Value::Option(Some(Box::new(Self::from_json(inner, value)?)))
+                } else {
+                    Value::Option(Some(Box::new(Self::from_json(inner, json)?)))
+                }
+            }
+            BorshSchema::Array { schema, len } => {
+                let items = items(schema, json)?.ok_or_else(mismatch)?;
+                if items.len() != *len as usize {
+                    return Err(mismatch());
+                }
+                Value::Array(items)
+            }
+            BorshSchema::Struct { fields } => {
+                let object = json.as_object().ok_or_else(mismatch)?;
+                if object.len() != fields.len() {
+                    return Err(mismatch());
+                }
+                Value::Struct(
+                    fields
+                        .iter()
+                        .map(|(name, schema)| {
+                            let value = object.get(name).ok_or_else(mismatch)?;
+                            Ok((name.clone(), Self::from_json(schema, value)?))
+                        })
+                        .collect::<io::Result<_>>()?,
+                )
+            }
+            BorshSchema::Enum { variants } => {
+                let (variant, value) = json
+                    .as_object()
+                    .filter(|x| x.len() == 1)
+                    .and_then(|x| x.iter().next())
+                    .ok_or_else(mismatch)?;
+                let (_, schema) = variants
+                    .iter()
+                    .find(|(name, _)| name == variant)
+                    .ok_or_else(mismatch)?;
+                Value::Enum {
+                    variant: variant.clone(),
+                    value: Box::new(Self::from_json(schema, value)?),
+                }
+            }
+            BorshSchema::Vector { schema } => {
+                Value::Vector(items(schema, json)?.ok_or_else(mismatch)?)
+            }
+            BorshSchema::Set { schema } => Value::Set(items(schema, json)?.ok_or_else(mismatch)?),
+            BorshSchema::Map { key, value } => Value::Map(
+                json.as_array()
+                    .ok_or_else(mismatch)?
+                    .iter()
+                    .map(|entry| match entry.as_array().map(|x| &x[..]) {
+                        Some([k, v]) => Ok((Self::from_json(key, k)?, Self::from_json(value, v)?)),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+            BorshSchema::Blob => Value::Blob(parsed(json).ok_or_else(mismatch)?),
+            BorshSchema::Snapshot => Value::Snapshot(parsed(json).ok_or_else(mismatch)?),
+            BorshSchema::Link => Value::Link(link_from_json(json).ok_or_else(mismatch)?),
+        })
+    }
+}
+
+/// Decodes [`Value`]s from borsh data, keeping track of where it is so that it can say where the
+/// data went wrong.
+///
+/// This is strict: strings must be valid UTF-8, bool, option, and enum tags must be in range, and
+/// collection lengths must fit in the data that is left. It is used to decode values, to
+/// [validate][BorshSchema::validate] data, and to find the [references][BorshSchema::references]
+/// in it.
+pub(crate) struct Decoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+    path: Vec<String>,
+    /// How many more zero-size collection items may be decoded, out of [`MAX_ZERO_SIZE_ITEMS`].
+    zero_size_items: u64,
+    /// Whether to keep the items of collections, or only walk over them.
+    keep_items: bool,
+    /// The blobs and snapshots referenced by the data decoded so far.
+    pub(crate) references: Vec<Reference>,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            path: Vec::new(),
+            zero_size_items: MAX_ZERO_SIZE_ITEMS as u64,
+            keep_items: true,
+            references: Vec::new(),
+        }
+    }
+
+    /// Create a decoder that walks over the data without keeping the items of collections, for
+    /// when only the [`references`][Self::references] are needed.
+    pub(crate) fn walker(data: &'a [u8]) -> Self {
+        Self {
+            keep_items: false,
+            ..Self::new(data)
+        }
+    }
+
+    /// Decode a value that must take up all of the data.
+    pub(crate) fn finish(&mut self, schema: &BorshSchema) -> Result<Value, ValidationError> {
+        let value = self.value(schema)?;
+        let len = (self.data.len() - self.offset) as u64;
+        if len > 0 {
+            return Err(self.error(ValidationErrorKind::TrailingData { len }));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, kind: ValidationErrorKind) -> ValidationError {
+        self.error_at(self.offset, kind)
+    }
+
+    fn error_at(&self, offset: usize, kind: ValidationErrorKind) -> ValidationError {
+        ValidationError {
+            path: self.path.clone(),
+            offset: offset as u64,
+            kind,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ValidationError> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.offset..end))
+            .ok_or_else(|| self.error(ValidationErrorKind::UnexpectedEnd))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], ValidationError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Read a collection length, checking that `len` items of the given schemas could fit in the
+    /// rest of the data.
+    fn len(&mut self, items: &[&BorshSchema]) -> Result<u32, ValidationError> {
+        let start = self.offset;
+        let len = u32::from_le_bytes(self.bytes()?);
+        self.check_len(start, items, len)?;
+        Ok(len)
+    }
+
+    /// Check that `len` items of the given schemas could fit in the rest of the data.
+    ///
+    /// Items that take up no bytes are counted against the decoder's [`MAX_ZERO_SIZE_ITEMS`]
+    /// budget instead.
+    fn check_len(
+        &mut self,
+        start: usize,
+        items: &[&BorshSchema],
+        len: u32,
+    ) -> Result<(), ValidationError> {
+        let item_size = items
+            .iter()
+            .fold(0usize, |size, x| size.saturating_add(x.min_encoded_size()));
+        let fits = if item_size == 0 {
+            let count = items
+                .iter()
+                .fold(0u64, |count, x| count.saturating_add(x.zero_size_values()))
+                .saturating_mul(len as u64);
+            let fits = count <= self.zero_size_items;
+            if fits {
+                self.zero_size_items -= count;
+            }
+            fits
+        } else {
+            (len as usize).saturating_mul(item_size) <= self.data.len() - self.offset
+        };
+        if !fits {
+            return Err(self.error_at(start, ValidationErrorKind::TooManyItems { len }));
+        }
+        Ok(())
+    }
+
+    /// Decode a value nested under the given path segment.
+    fn nested(&mut self, segment: &str, schema: &BorshSchema) -> Result<Value, ValidationError> {
+        self.path.push(segment.to_owned());
+        let value = self.value(schema)?;
+        self.path.pop();
+        Ok(value)
+    }
+
+    fn items(&mut self, schema: &BorshSchema, len: u32) -> Result<Vec<Value>, ValidationError> {
+        let mut items = Vec::new();
+        for _ in 0..len {
+            let item = self.nested("[]", schema)?;
+            if self.keep_items {
+                items.push(item);
+            }
+        }
+        Ok(items)
+    }
+
+    pub(crate) fn value(&mut self, schema: &BorshSchema) -> Result<Value, ValidationError> {
+        let start = self.offset;
+        Ok(match schema {
+            BorshSchema::Null => Value::Null,
+            BorshSchema::Bool => match self.bytes::<1>()?[0] {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                value => return Err(self.error_at(start, ValidationErrorKind::InvalidBool(value))),
+            },
+            BorshSchema::U8 => Value::U8(u8::from_le_bytes(self.bytes()?)),
+            BorshSchema::U16 => Value::U16(u16::from_le_bytes(self.bytes()?)),
+            BorshSchema::U32 => Value::U32(u32::from_le_bytes(self.bytes()?)),
+            BorshSchema::U64 => Value::U64(u64::from_le_bytes(self.bytes()?)),
+            BorshSchema::U128 => Value::U128(u128::from_le_bytes(self.bytes()?)),
+            BorshSchema::I8 => Value::I8(i8::from_le_bytes(self.bytes()?)),
+            BorshSchema::I16 => Value::I16(i16::from_le_bytes(self.bytes()?)),
+            BorshSchema::I32 => Value::I32(i32::from_le_bytes(self.bytes()?)),
+            BorshSchema::I64 => Value::I64(i64::from_le_bytes(self.bytes()?)),
+            BorshSchema::I128 => Value::I128(i128::from_le_bytes(self.bytes()?)),
+            BorshSchema::F32 => Value::F32(f32::from_le_bytes(self.bytes()?)),
+            BorshSchema::F64 => Value::F64(f64::from_le_bytes(self.bytes()?)),
+            BorshSchema::String => {
+                let len = self.len(&[&BorshSchema::U8])? as usize;
+                let start = self.offset;
+                let string = std::str::from_utf8(self.take(len)?)
+                    .map_err(|_| self.error_at(start, ValidationErrorKind::InvalidUtf8))?;
+                Value::String(string.to_owned())
+            }
+            BorshSchema::Option { schema } => match self.bytes::<1>()?[0] {
+                0 => Value::Option(None),
+                1 => Value::Option(Some(Box::new(self.nested("?", schema)?))),
+                tag => return Err(self.error_at(start, ValidationErrorKind::InvalidOptionTag(tag))),
+            },
+            BorshSchema::Array { schema, len } => {
+                self.check_len(start, &[schema], *len)?;
+                Value::Array(self.items(schema, *len)?)
+            }
+            BorshSchema::Struct { fields } => Value::Struct(
+                fields
+                    .iter()
+                    .map(|(name, schema)| Ok((name.clone(), self.nested(name, schema)?)))
+                    .collect::<Result<_, ValidationError>>()?,
+            ),
+            BorshSchema::Enum { variants } => {
+                let tag = self.bytes::<1>()?[0];
+                let Some((variant, schema)) = variants.get(tag as usize) else {
+                    let kind = ValidationErrorKind::InvalidEnumTag {
+                        tag,
+                        variant_count: variants.len() as u64,
+                    };
+                    return Err(self.error_at(start, kind));
+                };
+                Value::Enum {
+                    variant: variant.clone(),
+                    value: Box::new(self.nested(variant, schema)?),
+                }
+            }
+            BorshSchema::Vector { schema } => {
+                let len = self.len(&[schema])?;
+                Value::Vector(self.items(schema, len)?)
+            }
+            BorshSchema::Set { schema } => {
+                let len = self.len(&[schema])?;
+                Value::Set(self.items(schema, len)?)
+            }
+            BorshSchema::Map { key, value } => {
+                let len = self.len(&[key, value])?;
+                let mut entries = Vec::new();
+                for _ in 0..len {
+                    let entry = (self.nested("{key}", key)?, self.nested("{value}", value)?);
+                    if self.keep_items {
+                        entries.push(entry);
+                    }
+                }
+                Value::Map(entries)
+            }
+            BorshSchema::Blob => {
+                let digest = Digest::from_bytes(self.bytes()?);
+                self.references.push(Reference::Blob(digest));
+                Value::Blob(digest)
+            }
+            BorshSchema::Snapshot => {
+                let digest = Digest::from_bytes(self.bytes()?);
+                self.references.push(Reference::Snapshot(digest));
+                Value::Snapshot(digest)
+            }
+            BorshSchema::Link => {
+                let mut reader = &self.data[self.offset..];
+                let link = Link::deserialize_reader(&mut reader)
+                    .map_err(|_| self.error(ValidationErrorKind::InvalidLink))?;
+                self.offset = self.data.len() - reader.len();
+                if let Some(snapshot) = link.snapshot() {
+                    self.references.push(Reference::Snapshot(snapshot));
+                }
+                Value::Link(link)
+            }
+        })
+    }
+}
+
+fn link_to_json(link: &Link) -> serde_json::Value {
+    fn resolver(kind: &KeyResolverKind) -> serde_json::Value {
+        match kind {
+            KeyResolverKind::Inline(key) => json!({ "Inline": base32::fmt(key) }),
+            KeyResolverKind::Custom { id, data } => json!({
+                "Custom": { "id": id.to_string(), "data": base32::fmt(data) }
+            }),
+        }
+    }
+    let path = link
+        .path()
+        .iter()
+        .map(|segment| match segment {
+            PathSegment::Null => json!({ "Null": null }),
+            PathSegment::Bool(v) => json!({ "Bool": v }),
+            PathSegment::Uint(v) => json!({ "Uint": v.to_string() }),
+            PathSegment::Int(v) => json!({ "Int": v.to_string() }),
+            PathSegment::String(v) => json!({ "String": v }),
+            PathSegment::Bytes(v) => json!({ "Bytes": base32::fmt(v) }),
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "namespace": resolver(link.namespace()),
+        "subspace": resolver(link.subspace()),
+        "path": path,
+        "snapshot": link.snapshot().map(|x| x.to_string()),
+    })
+}
+
+fn link_from_json(json: &serde_json::Value) -> Option<Link> {
+    fn single(json: &serde_json::Value) -> Option<(&str, &serde_json::Value)> {
+        let object = json.as_object().filter(|x| x.len() == 1)?;
+        object.iter().next().map(|(k, v)| (k.as_str(), v))
+    }
+    fn resolver(json: &serde_json::Value) -> Option<KeyResolverKind> {
+        Some(match single(json)? {
+            ("Inline", key) => KeyResolverKind::Inline(base32::parse_array(key.as_str()?).ok()?),
+            ("Custom", custom) => KeyResolverKind::Custom {
+                id: custom.get("id")?.as_str()?.parse().ok()?,
+                data: base32::parse_vec(custom.get("data")?.as_str()?).ok()?,
+            },
+            _ => return None,
+        })
+    }
+    fn segment(json: &serde_json::Value) -> Option<PathSegment> {
+        Some(match single(json)? {
+            ("Null", v) if v.is_null() => PathSegment::Null,
+            ("Bool", v) => PathSegment::Bool(v.as_bool()?),
+            ("Uint", v) => PathSegment::Uint(v.as_str()?.parse().ok()?),
+            ("Int", v) => PathSegment::Int(v.as_str()?.parse().ok()?),
+            ("String", v) => PathSegment::String(v.as_str()?.into()),
+            ("Bytes", v) => PathSegment::Bytes(base32::parse_vec(v.as_str()?).ok()?),
+            _ => return None,
+        })
+    }
+
+    let object = json.as_object()?;
+    let path = object
+        .get("path")?
+        .as_array()?
+        .iter()
+        .map(segment)
+        .collect::<Option<Vec<_>>>()?;
+    let link = Link::new(
+        resolver(object.get("namespace")?)?,
+        resolver(object.get("subspace")?)?,
+        crate::EntityPath(path),
+    );
+    Some(match object.get("snapshot")? {
+        serde_json::Value::Null => link,
+        snapshot => link.with_snapshot(snapshot.as_str()?.parse().ok()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use borsh::BorshSerialize;
+
+    use super::*;
+    use crate::{HasBorshSchema, MAX_ZERO_SIZE_ITEMS};
+
+    #[test]
+    fn signed_ints_round_trip_through_json() {
+        let schema = i32::borsh_schema();
+        assert_eq!(schema, BorshSchema::I32);
+        let data = borsh::to_vec(&-1i32).unwrap();
+        let value = Value::decode(&schema, &data).unwrap();
+        assert_eq!(value, Value::I32(-1));
+        assert_eq!(value.to_json(), json!(-1));
+        let value = Value::from_json(&schema, &value.to_json()).unwrap();
+        assert_eq!(value.encode(&schema).unwrap(), data);
+    }
+
+    #[test]
+    fn collection_length_longer_than_data_is_rejected() {
+        let schema = Vec::<u64>::borsh_schema();
+        let mut data = Vec::new();
+        u32::MAX.serialize(&mut data).unwrap();
+        0u64.serialize(&mut data).unwrap();
+        assert!(Value::decode(&schema, &data).is_err());
+    }
+
+    #[test]
+    fn zero_size_collections_are_capped() {
+        let schema = Vec::<()>::borsh_schema();
+        let data = borsh::to_vec(&u32::MAX).unwrap();
+        assert!(Value::decode(&schema, &data).is_err());
+        assert!(schema.references(&data).is_err());
+
+        let data = borsh::to_vec(&MAX_ZERO_SIZE_ITEMS).unwrap();
+        let Value::Vector(items) = Value::decode(&schema, &data).unwrap() else {
+            panic!("Expected a vector");
+        };
+        assert_eq!(items.len(), MAX_ZERO_SIZE_ITEMS as usize);
+    }
+
+    #[test]
+    fn enum_tags_past_u8_are_rejected() {
+        let schema = BorshSchema::Enum {
+            variants: (0..300)
+                .map(|i| (format!("V{i}"), BorshSchema::Null))
+                .collect(),
+        };
+        let value = Value::Enum {
+            variant: "V299".into(),
+            value: Box::new(Value::Null),
+        };
+        assert!(value.encode(&schema).is_err());
+    }
+}
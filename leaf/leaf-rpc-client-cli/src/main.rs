@@ -1,8 +1,10 @@
+use std::{path::PathBuf, str::FromStr};
+
 use clap::Parser;
 use iroh_base::base32;
 use leaf_rpc_client::{
-    leaf_protocol::prelude::{Description, Name},
-    Uri,
+    leaf_protocol::prelude::{BorshDeserialize, BorshSchema, Description, Digest, Name},
+    RpcClient, Uri,
 };
 
 // TODO: turn this into a simple CLI or maybe a repl for accessing/modifying leaf data.
@@ -12,17 +14,116 @@ struct Args {
     pub uri: Uri,
     #[arg(short, long)]
     pub auth_token: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Check whether data written with one schema can be read with another.
+    ///
+    /// Each schema is either a local `.borsh` file containing a borsh-serialized `BorshSchema`,
+    /// such as the output of `borsh::to_vec(&MyComponent::borsh_schema())`, or the ID of a schema
+    /// published on the server. Comparing a local file to the published ID of the current version
+    /// checks a change to a component before it is published.
+    ///
+    /// Exits with an error if the change is breaking.
+    SchemaCompat {
+        /// The old schema file or published schema ID.
+        old: SchemaSource,
+        /// The new schema file or published schema ID.
+        new: SchemaSource,
+    },
+}
+
+/// Where the `schema-compat` command loads a schema from.
+#[derive(Debug, Clone)]
+enum SchemaSource {
+    /// A local file containing a borsh-serialized [`BorshSchema`].
+    File(PathBuf),
+    /// The ID of a schema published on the server.
+    Published(Digest),
+}
+
+impl FromStr for SchemaSource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = PathBuf::from(s);
+        if path.extension().is_some_and(|x| x == "borsh") || path.exists() {
+            return Ok(Self::File(path));
+        }
+        let id = Digest::from_str(s).map_err(|_| {
+            anyhow::format_err!("Not a `.borsh` schema file or a published schema ID: {s}")
+        })?;
+        Ok(Self::Published(id))
+    }
+}
+
+impl SchemaSource {
+    /// Load the schema, returning a name to show for it along with its format.
+    async fn load(&self, client: Option<&RpcClient>) -> anyhow::Result<(String, BorshSchema)> {
+        match self {
+            SchemaSource::File(path) => {
+                let data = std::fs::read(path).map_err(|e| {
+                    anyhow::format_err!("Error reading schema file {}: {e}", path.display())
+                })?;
+                let format = BorshSchema::try_from_slice(&data).map_err(|e| {
+                    anyhow::format_err!("Invalid schema file {}: {e}", path.display())
+                })?;
+                Ok((path.display().to_string(), format))
+            }
+            SchemaSource::Published(id) => {
+                let client = client.expect("client is connected for published schemas");
+                let schema = client
+                    .get_schema(*id)
+                    .await?
+                    .ok_or_else(|| anyhow::format_err!("Schema has not been published: {id}"))?;
+                Ok((schema.name, schema.format))
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let connect = || RpcClient::connect(args.uri.clone(), args.auth_token.as_deref());
+
+    match &args.command {
+        Some(Command::SchemaCompat { old, new }) => {
+            // Comparing two local files doesn't need the server.
+            let client = match (old, new) {
+                (SchemaSource::File(_), SchemaSource::File(_)) => None,
+                _ => Some(connect().await?),
+            };
+            schema_compat(client.as_ref(), old, new).await
+        }
+        None => test_entity(&connect().await?).await,
+    }
+}
+
+async fn schema_compat(
+    client: Option<&RpcClient>,
+    old: &SchemaSource,
+    new: &SchemaSource,
+) -> anyhow::Result<()> {
+    let (old_name, old) = old.load(client).await?;
+    let (new_name, new) = new.load(client).await?;
+
+    let compatibility = BorshSchema::compatibility(&old, &new);
+    println!("{old_name} -> {new_name}: {compatibility}");
+    if compatibility.is_breaking() {
+        anyhow::bail!("Schema change is breaking");
+    }
+    Ok(())
+}
+
+async fn test_entity(client: &RpcClient) -> anyhow::Result<()> {
     let secret = [
         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         25, 26, 27, 28, 29, 30, 31,
     ];
     println!("Secret: {}", base32::fmt(secret));
-    let args = Args::parse();
-    let client = leaf_rpc_client::RpcClient::connect(args.uri, args.auth_token.as_deref()).await?;
 
     let ns = client.import_namespace_secret(secret).await?;
     let ss = client.import_subspace_secret(secret).await?;
@@ -9,12 +9,12 @@ use std::{
 };
 
 use fastwebsockets::{FragmentCollectorRead, Frame};
-use futures::{future::Either, pin_mut, StreamExt};
+use futures::{future::Either, pin_mut, Stream, StreamExt, TryStreamExt};
 use hyper::{
     header::{CONNECTION, UPGRADE},
     Request,
 };
-use leaf_rpc_proto::{Req, ReqKind, Resp, RespKind};
+use leaf_rpc_proto::{RejectedComponent, Req, ReqKind, Resp, RespKind, SearchResult};
 use tokio::{
     net::TcpStream,
     sync::{mpsc, oneshot, Mutex},
@@ -26,11 +26,17 @@ pub use leaf_protocol;
 use leaf_protocol::prelude::*;
 use tokio_stream::wrappers::ReceiverStream;
 
+/// How many events can be waiting for a subscriber before it is considered to have fallen behind.
+const SUBSCRIPTION_BUFFER: usize = 256;
+
+type Subscriptions = HashMap<u64, mpsc::Sender<anyhow::Result<EntityEvent>>>;
+
 #[derive(Clone)]
 pub struct RpcClient {
     index: Arc<AtomicU64>,
     frame_writer: mpsc::Sender<Frame<'static>>,
     pending_reqs: Arc<Mutex<HashMap<u64, oneshot::Sender<Resp>>>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
 }
 
 // TODO: Implement graceful shutdown of RPC client.
@@ -62,6 +68,10 @@ impl RpcClient {
 
         let pending_reqs = Arc::new(Mutex::new(HashMap::<u64, oneshot::Sender<Resp>>::default()));
         let pending_reqs_ = pending_reqs.clone();
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let subscriptions_ = subscriptions.clone();
+        let index = Arc::new(AtomicU64::new(0));
+        let index_ = index.clone();
 
         let (ws, _) = fastwebsockets::handshake::client(&SpawnExecutor, req, stream).await?;
         let (ws_read, mut ws_write) = ws.split(tokio::io::split);
@@ -95,13 +105,71 @@ impl RpcClient {
                                 match resp {
                                     Ok(resp) => {
                                         let mut pending_reqs = pending_reqs_.lock().await;
-                                        let Some(sender) = pending_reqs.remove(&resp.id) else {
+                                        if let Some(sender) = pending_reqs.remove(&resp.id) {
+                                            sender.send(resp).ok();
+                                            continue;
+                                        }
+                                        drop(pending_reqs);
+
+                                        // Responses that aren't pending may be subscription events
+                                        let mut subscriptions = subscriptions_.lock().await;
+                                        let Some(sender) = subscriptions.get(&resp.id) else {
                                             tracing::warn!(
                                                 "Got response for request that is not pending"
                                             );
                                             continue;
                                         };
-                                        sender.send(resp).ok();
+                                        match resp.result {
+                                            // The last slot is kept for the error, so that a
+                                            // subscriber that isn't keeping up is told why its
+                                            // events stopped.
+                                            Ok(RespKind::EntityEvent(event))
+                                                if sender.capacity() > 1 =>
+                                            {
+                                                sender.try_send(Ok(event)).ok();
+                                            }
+                                            Ok(RespKind::EntityEvent(_)) => {
+                                                sender
+                                                    .try_send(Err(anyhow::format_err!(
+                                                        "Subscription fell behind and missed events"
+                                                    )))
+                                                    .ok();
+                                                subscriptions.remove(&resp.id);
+                                                drop(subscriptions);
+
+                                                // Stop the server from sending any more events.
+                                                // Nobody is waiting for the response, so the
+                                                // pending request's receiver is dropped.
+                                                let id = index_.fetch_add(1, SeqCst);
+                                                let req = Req {
+                                                    id,
+                                                    kind: ReqKind::Unsubscribe(resp.id),
+                                                };
+                                                let mut req_bytes = Vec::new();
+                                                req.serialize(&mut req_bytes).unwrap();
+                                                let (sender, _) = oneshot::channel();
+                                                pending_reqs_.lock().await.insert(id, sender);
+                                                let frame = Frame::binary(
+                                                    fastwebsockets::Payload::Owned(req_bytes),
+                                                );
+                                                if let Err(e) = ws_write.write_frame(frame).await {
+                                                    tracing::warn!(
+                                                        "Could not send request to server: {e}"
+                                                    );
+                                                }
+                                            }
+                                            Ok(_) => tracing::warn!(
+                                                "Got unexpected response for subscription"
+                                            ),
+                                            Err(e) => {
+                                                sender
+                                                    .try_send(Err(anyhow::format_err!(
+                                                        "Error from Leaf RPC endpoint: {e}"
+                                                    )))
+                                                    .ok();
+                                                subscriptions.remove(&resp.id);
+                                            }
+                                        }
                                     }
                                     Err(e) => tracing::error!(
                                         "Error deserializing response from server: {e}"
@@ -121,9 +189,10 @@ impl RpcClient {
         });
 
         let client = RpcClient {
-            index: Arc::new(0.into()),
+            index,
             frame_writer: client_frame_send,
             pending_reqs,
+            subscriptions,
         };
 
         if let Some(auth_token) = auth_token {
@@ -142,6 +211,10 @@ impl RpcClient {
 
     async fn send_req(&self, kind: ReqKind) -> anyhow::Result<Resp> {
         let id = self.index.fetch_add(1, SeqCst);
+        self.send_req_with_id(id, kind).await
+    }
+
+    async fn send_req_with_id(&self, id: u64, kind: ReqKind) -> anyhow::Result<Resp> {
         let req = Req { id, kind };
 
         let mut req_bytes = Vec::new();
@@ -163,10 +236,11 @@ impl RpcClient {
         Ok(resp)
     }
 
+    /// Read the latest snapshot of an entity, along with its digest and metadata.
     pub async fn read_entity<L: Into<ExactLink>>(
         &self,
         link: L,
-    ) -> anyhow::Result<Option<(Digest, Entity)>> {
+    ) -> anyhow::Result<Option<(Digest, Entity, EntityMeta)>> {
         let link = link.into();
         let resp = self.send_req(ReqKind::ReadEntity(link)).await?;
         let RespKind::ReadEntity(entity) = resp
@@ -178,6 +252,26 @@ impl RpcClient {
         Ok(entity)
     }
 
+    /// Read an entity as it was at a previous snapshot, or [`None`] if the server doesn't have the
+    /// snapshot.
+    pub async fn read_entity_at<L: Into<ExactLink>>(
+        &self,
+        link: L,
+        snapshot: Digest,
+    ) -> anyhow::Result<Option<Entity>> {
+        let link = link.into();
+        let resp = self
+            .send_req(ReqKind::ReadEntityAt { link, snapshot })
+            .await?;
+        let RespKind::ReadEntityAt(entity) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(entity)
+    }
+
     pub async fn del_entity<L: Into<ExactLink>>(&self, link: L) -> anyhow::Result<()> {
         let link = link.into();
         let resp = self.send_req(ReqKind::DelEntity(link)).await?;
@@ -190,25 +284,82 @@ impl RpcClient {
         Ok(())
     }
 
+    /// Delete the entity at the link and all of the entities under it, returning the number of
+    /// entities that were deleted.
+    ///
+    /// This is not atomic: if one of the entities can't be deleted, the returned error says how
+    /// many entities were already deleted.
+    pub async fn del_subtree<L: Into<ExactLink>>(&self, link: L) -> anyhow::Result<u64> {
+        let link = link.into();
+        let resp = self.send_req(ReqKind::DelSubtree(link)).await?;
+        let RespKind::DelSubtree(count) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(count)
+    }
+
+    /// List all of the entities under the link, fetching as many pages as it takes.
     pub async fn list_entities<L: Into<ExactLink>>(
         &self,
         link: L,
+        mode: ListMode,
     ) -> anyhow::Result<Vec<ExactLink>> {
         let link = link.into();
-        let resp = self.send_req(ReqKind::ListEntities(link)).await?;
-        let RespKind::ListEntities(entities) = resp
+        let mut entities = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self
+                .list_entities_page(link.clone(), mode, LIST_PAGE_SIZE, cursor)
+                .await?;
+            entities.extend(page.links);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        Ok(entities)
+    }
+
+    /// List a page of the entities under the link, along with their metadata, starting after the
+    /// `cursor` from the previous page.
+    pub async fn list_entities_page<L: Into<ExactLink>>(
+        &self,
+        link: L,
+        mode: ListMode,
+        limit: u64,
+        cursor: Option<ListCursor>,
+    ) -> anyhow::Result<ListPage> {
+        let link = link.into();
+        let resp = self
+            .send_req(ReqKind::ListEntities {
+                link,
+                mode,
+                limit,
+                cursor,
+            })
+            .await?;
+        let RespKind::ListEntities(page) = resp
             .result
             .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
         else {
             anyhow::bail!(INVALID_RPC_RESP_MSG);
         };
-        Ok(entities)
+        Ok(page)
     }
 
     // TODO: Support Operating on Multiple Components at a Time.
+    /// Delete all components of the given type from the entity.
+    ///
+    /// If `expected_digest` is set, the components will only be deleted if the entity hasn't been
+    /// changed from that snapshot. Otherwise the error can be downcast to a [`SnapshotConflict`]
+    /// with the entity's actual digest.
     pub async fn del_components<C: Component, L: Into<ExactLink>>(
         &self,
         link: L,
+        expected_digest: Option<Digest>,
     ) -> anyhow::Result<Option<Digest>> {
         let link = link.into();
 
@@ -216,23 +367,36 @@ impl RpcClient {
             .send_req(ReqKind::DelComponentsBySchema {
                 link,
                 schemas: vec![C::schema_id()],
+                expected_digest,
             })
             .await?;
-        let RespKind::DelComponentBySchema(new_digest) = resp
+        let resp = resp
             .result
-            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
-        else {
-            anyhow::bail!(INVALID_RPC_RESP_MSG);
-        };
-        Ok(new_digest)
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?;
+        match resp {
+            RespKind::DelComponentBySchema(new_digest) => Ok(new_digest),
+            RespKind::SnapshotConflict(conflict) => Err(conflict.into()),
+            _ => anyhow::bail!(INVALID_RPC_RESP_MSG),
+        }
     }
 
     // TODO: Support Operating on Multiple Components at a Time.
+    /// Add a component to the entity.
+    ///
+    /// If `expected_digest` is set, the component will only be added if the entity hasn't been
+    /// changed from that snapshot. A null digest means the entity must not exist yet. Otherwise the
+    /// error can be downcast to a [`SnapshotConflict`] with the entity's actual digest.
+    ///
+    /// If the component references blobs or snapshots, its schema should be
+    /// [published][Self::publish_schema] so that they are kept for as long as the component is.
+    /// Servers that validate components reject it if it isn't, and then the error can be downcast
+    /// to an [`InvalidComponent`].
     pub async fn add_component<C: Component, L: Into<ExactLink>>(
         &self,
         link: L,
         component: C,
         replace_existing: bool,
+        expected_digest: Option<Digest>,
     ) -> anyhow::Result<Digest> {
         let link = link.into();
         let component_data = component.make_data()?;
@@ -242,15 +406,18 @@ impl RpcClient {
                 link,
                 components: vec![component_data],
                 replace_existing,
+                expected_digest,
             })
             .await?;
-        let RespKind::AddComponents(entity_id) = resp
+        let resp = resp
             .result
-            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
-        else {
-            anyhow::bail!(INVALID_RPC_RESP_MSG);
-        };
-        Ok(entity_id)
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?;
+        match resp {
+            RespKind::AddComponents(entity_id) => Ok(entity_id),
+            RespKind::InvalidComponents(rejected) => Err(invalid_components_error(rejected)),
+            RespKind::SnapshotConflict(conflict) => Err(conflict.into()),
+            _ => anyhow::bail!(INVALID_RPC_RESP_MSG),
+        }
     }
 
     // TODO: implement way to get multiple components at a time.
@@ -298,6 +465,54 @@ impl RpcClient {
         // }
     }
 
+    /// Subscribe to changes to the entity at the link and all of the entities under it.
+    ///
+    /// Returns the subscription ID, that can be passed to [`unsubscribe()`][Self::unsubscribe], and
+    /// a stream of the changes. The stream will end after yielding an error, which is also what
+    /// happens if the stream isn't read fast enough to keep up with the changes.
+    pub async fn subscribe<L: Into<ExactLink>>(
+        &self,
+        link: L,
+    ) -> anyhow::Result<(u64, impl Stream<Item = anyhow::Result<EntityEvent>>)> {
+        let link = link.into();
+        let id = self.index.fetch_add(1, SeqCst);
+
+        // Register the subscription before sending the request so that we don't miss any events.
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        self.subscriptions.lock().await.insert(id, sender);
+
+        let result = async {
+            let resp = self.send_req_with_id(id, ReqKind::Subscribe(link)).await?;
+            let RespKind::Subscribe = resp
+                .result
+                .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+            else {
+                anyhow::bail!(INVALID_RPC_RESP_MSG);
+            };
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            self.subscriptions.lock().await.remove(&id);
+            return Err(e);
+        }
+
+        Ok((id, ReceiverStream::new(receiver)))
+    }
+
+    /// Cancel a subscription created with [`subscribe()`][Self::subscribe].
+    pub async fn unsubscribe(&self, subscription: u64) -> anyhow::Result<()> {
+        self.subscriptions.lock().await.remove(&subscription);
+        let resp = self.send_req(ReqKind::Unsubscribe(subscription)).await?;
+        let RespKind::Unsubscribe = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(())
+    }
+
     pub async fn create_namespace(&self) -> anyhow::Result<NamespaceId> {
         let resp = self.send_req(ReqKind::CreateNamespace).await?;
         let RespKind::CreateNamespace(id) = resp
@@ -377,8 +592,422 @@ impl RpcClient {
         };
         Ok(id)
     }
+
+    /// Publish the schema of a component type, so that peers can look up how to interpret it.
+    pub async fn publish_schema<C: Component>(&self) -> anyhow::Result<()> {
+        self.publish_schema_data(C::schema_id(), C::schema()).await
+    }
+
+    /// Publish a component schema under the given schema ID.
+    pub async fn publish_schema_data(
+        &self,
+        schema_id: Digest,
+        schema: Schema,
+    ) -> anyhow::Result<()> {
+        let resp = self
+            .send_req(ReqKind::PublishSchema { schema_id, schema })
+            .await?;
+        let RespKind::PublishSchema = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(())
+    }
+
+    /// Look up a published component schema by its ID.
+    pub async fn get_schema(&self, schema_id: Digest) -> anyhow::Result<Option<Schema>> {
+        let resp = self.send_req(ReqKind::GetSchema(schema_id)).await?;
+        let RespKind::GetSchema(schema) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(schema)
+    }
+
+    /// Run a garbage collector reconciliation pass on the server, removing leaked blob pins.
+    pub async fn gc(&self) -> anyhow::Result<GcReport> {
+        let resp = self.send_req(ReqKind::Gc).await?;
+        let RespKind::Gc(report) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(report)
+    }
+
+    /// Apply component changes to multiple entities at once, returning the new snapshot digest of
+    /// each entity that was changed.
+    ///
+    /// If any of the [`BatchOp::Expect`] checks fail, or the server validates components and
+    /// rejects any of them, nothing is written. A failed check returns an error that can be
+    /// downcast to a [`BatchConflict`].
+    pub async fn batch(&self, ops: Vec<BatchOp>) -> anyhow::Result<Vec<(ExactLink, Digest)>> {
+        let resp = self.send_req(ReqKind::Batch(ops)).await?;
+        let resp = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?;
+        match resp {
+            RespKind::Batch(digests) => Ok(digests),
+            RespKind::InvalidComponents(rejected) => Err(invalid_components_error(rejected)),
+            RespKind::SnapshotConflict(conflict) => Err(BatchConflict::from(conflict).into()),
+            _ => anyhow::bail!(INVALID_RPC_RESP_MSG),
+        }
+    }
+
+    /// Copy the entity at `from` to `to`, along with all of the entities under it if `recursive`
+    /// is set, returning the links of the new entities.
+    pub async fn copy_entity<L1: Into<ExactLink>, L2: Into<ExactLink>>(
+        &self,
+        from: L1,
+        to: L2,
+        recursive: bool,
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let resp = self
+            .send_req(ReqKind::CopyEntity {
+                from: from.into(),
+                to: to.into(),
+                recursive,
+            })
+            .await?;
+        let RespKind::CopyEntity(links) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(links)
+    }
+
+    /// Move the entity at `from` to `to`, along with all of the entities under it if `recursive`
+    /// is set, returning the new links of the moved entities.
+    ///
+    /// Moves are not atomic: every entity is copied before any of the originals are deleted, but if
+    /// the move fails partway, the copies and deletes that were already done are not rolled back.
+    pub async fn move_entity<L1: Into<ExactLink>, L2: Into<ExactLink>>(
+        &self,
+        from: L1,
+        to: L2,
+        recursive: bool,
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let resp = self
+            .send_req(ReqKind::MoveEntity {
+                from: from.into(),
+                to: to.into(),
+                recursive,
+            })
+            .await?;
+        let RespKind::MoveEntity(links) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(links)
+    }
+
+    /// Find the entities in the namespace that have a component of the given type, optionally only
+    /// looking in one subspace.
+    pub async fn find_by_schema<C: Component>(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        self.find_by_schema_id(namespace, subspace, C::schema_id())
+            .await
+    }
+
+    /// Find the entities in the namespace that have a component with the given schema ID,
+    /// optionally only looking in one subspace.
+    pub async fn find_by_schema_id(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let resp = self
+            .send_req(ReqKind::FindBySchema {
+                namespace,
+                subspace,
+                schema,
+            })
+            .await?;
+        let RespKind::FindBySchema(links) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(links)
+    }
+
+    /// Start indexing a field of the component `C` on the server. The component's schema must
+    /// have been published.
+    ///
+    /// Entities that were already saved are indexed too, and the server remembers the index when
+    /// it restarts.
+    pub async fn register_index<C: Component>(
+        &self,
+        field_path: &[&str],
+        unique: bool,
+    ) -> anyhow::Result<FieldIndex> {
+        self.register_index_with_schema(
+            C::schema_id(),
+            field_path.iter().map(|x| x.to_string()).collect(),
+            unique,
+        )
+        .await
+    }
+
+    /// Start indexing a field of the components with the given schema ID on the server. The
+    /// schema must have been published.
+    pub async fn register_index_with_schema(
+        &self,
+        schema: Digest,
+        field_path: Vec<String>,
+        unique: bool,
+    ) -> anyhow::Result<FieldIndex> {
+        let index = FieldIndex {
+            schema,
+            field_path,
+            unique,
+        };
+        let resp = self
+            .send_req(ReqKind::RegisterIndex {
+                schema,
+                field_path: index.field_path.clone(),
+                unique,
+            })
+            .await?;
+        let RespKind::RegisterIndex(id) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        if id != index.id() {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        }
+        Ok(index)
+    }
+
+    /// Find the entities in the namespace with the given value in an index.
+    pub async fn lookup<V: BorshSerialize + ?Sized>(
+        &self,
+        namespace: NamespaceId,
+        index: &FieldIndex,
+        value: &V,
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let mut bytes = Vec::new();
+        value.serialize(&mut bytes)?;
+        self.lookup_index(namespace, index.id(), bytes).await
+    }
+
+    /// Find the entities in the namespace with the given borsh-encoded value in the index with the
+    /// given ID.
+    pub async fn lookup_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: Vec<u8>,
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        let resp = self
+            .send_req(ReqKind::LookupIndex {
+                namespace,
+                index,
+                value,
+            })
+            .await?;
+        let RespKind::LookupIndex(links) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(links)
+    }
+
+    /// Search the text of the entities in the namespace, returning up to `limit` of the best
+    /// matches.
+    pub async fn search(
+        &self,
+        namespace: NamespaceId,
+        query: &str,
+        limit: u64,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let resp = self
+            .send_req(ReqKind::Search {
+                namespace,
+                query: query.into(),
+                limit,
+            })
+            .await?;
+        let RespKind::Search(results) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(results)
+    }
+
+    /// Rebuild the server's search index from its store, returning the number of entities that
+    /// were indexed.
+    pub async fn rebuild_search_index(&self) -> anyhow::Result<u64> {
+        let resp = self.send_req(ReqKind::RebuildSearchIndex).await?;
+        let RespKind::RebuildSearchIndex(count) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(count)
+    }
+
+    /// Upload a blob for the entity at the link, returning its digest.
+    ///
+    /// The server only keeps the blob for a short grace period unless a component that references
+    /// it is saved to the entity.
+    pub async fn put_blob<L: Into<ExactLink>>(
+        &self,
+        link: L,
+        data: &[u8],
+    ) -> anyhow::Result<Digest> {
+        let chunks = data
+            .chunks(BLOB_UPLOAD_CHUNK_SIZE)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        self.put_blob_stream(link, futures::stream::iter(chunks))
+            .await
+    }
+
+    /// Upload a blob from a stream of chunks, like [`put_blob()`][Self::put_blob].
+    ///
+    /// If the stream yields an error, the upload is cancelled and the error is returned.
+    pub async fn put_blob_stream<L: Into<ExactLink>>(
+        &self,
+        link: L,
+        data: impl Stream<Item = anyhow::Result<Vec<u8>>>,
+    ) -> anyhow::Result<Digest> {
+        let link = link.into();
+        let upload = self.index.fetch_add(1, SeqCst);
+        let resp = self
+            .send_req_with_id(upload, ReqKind::StartBlobUpload(link))
+            .await?;
+        let RespKind::StartBlobUpload = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+
+        let result = async {
+            pin_mut!(data);
+            while let Some(chunk) = data.next().await {
+                for chunk in chunk?.chunks(BLOB_UPLOAD_CHUNK_SIZE) {
+                    let data = chunk.to_vec();
+                    let resp = self
+                        .send_req(ReqKind::BlobUploadChunk { upload, data })
+                        .await?;
+                    let RespKind::BlobUploadChunk = resp
+                        .result
+                        .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+                    else {
+                        anyhow::bail!(INVALID_RPC_RESP_MSG);
+                    };
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            // The server may have already dropped the upload, so the cancel is allowed to fail.
+            self.send_req(ReqKind::CancelBlobUpload(upload)).await.ok();
+            return Err(e);
+        }
+
+        let resp = self.send_req(ReqKind::FinishBlobUpload(upload)).await?;
+        let RespKind::FinishBlobUpload(digest) = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok(digest)
+    }
+
+    /// Download a whole blob.
+    pub async fn get_blob(&self, digest: Digest) -> anyhow::Result<Vec<u8>> {
+        self.get_blob_reader(digest, BlobRange::full())
+            .await?
+            .try_concat()
+            .await
+    }
+
+    /// Download a range of a blob as a stream of chunks.
+    ///
+    /// The first chunk is requested right away, so that a missing blob or an invalid range is
+    /// reported here instead of by the stream.
+    pub async fn get_blob_reader(
+        &self,
+        digest: Digest,
+        range: BlobRange,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Vec<u8>>> + '_> {
+        let (size, first) = self.read_blob(digest, range).await?;
+        Ok(async_stream::try_stream! {
+            let mut offset = range.offset;
+            let mut remaining = range.len.unwrap_or(u64::MAX).min(size - offset);
+            let mut chunk = first;
+            loop {
+                offset += chunk.len() as u64;
+                remaining -= chunk.len() as u64;
+                let done = chunk.is_empty() || remaining == 0;
+                if !chunk.is_empty() {
+                    yield chunk;
+                }
+                if done {
+                    break;
+                }
+                let range = BlobRange::new(offset, remaining);
+                chunk = self.read_blob(digest, range).await?.1;
+            }
+        })
+    }
+
+    /// Read part of a range of a blob, returning the size of the whole blob and the data.
+    async fn read_blob(&self, digest: Digest, range: BlobRange) -> anyhow::Result<(u64, Vec<u8>)> {
+        let resp = self.send_req(ReqKind::ReadBlob { digest, range }).await?;
+        let RespKind::ReadBlob { size, data } = resp
+            .result
+            .map_err(|s| anyhow::format_err!("Error from Leaf RPC endpoint: {s}"))?
+        else {
+            anyhow::bail!(INVALID_RPC_RESP_MSG);
+        };
+        Ok((size, data))
+    }
 }
 const INVALID_RPC_RESP_MSG: &str = "Invalid response kind from RPC endpoint";
+/// The number of links requested per page by [`RpcClient::list_entities()`].
+const LIST_PAGE_SIZE: u64 = 1000;
+/// The most data sent in each chunk by [`RpcClient::put_blob_stream()`].
+const BLOB_UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Turn a [`RespKind::InvalidComponents`] response into an error that can be downcast to the
+/// [`InvalidComponent`] error of the first rejected component.
+fn invalid_components_error(rejected: Vec<RejectedComponent>) -> anyhow::Error {
+    let count = rejected.len();
+    let Some(first) = rejected.into_iter().next() else {
+        return anyhow::format_err!(INVALID_RPC_RESP_MSG);
+    };
+    anyhow::Error::new(first.error).context(format!(
+        "Leaf RPC endpoint rejected the component at index {} ({count} rejected in total)",
+        first.index
+    ))
+}
 
 struct SpawnExecutor;
 
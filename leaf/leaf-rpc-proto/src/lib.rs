@@ -1,9 +1,14 @@
 use std::collections::HashMap;
 
 use leaf_protocol::types::{
-    ComponentData, Digest, Entity, EntityPath, ExactLink, NamespaceId, NamespaceSecretKey,
+    ComponentData, Digest, Entity, EntityPath, ExactLink, NamespaceId, NamespaceSecretKey, Schema,
     SubspaceId, SubspaceSecretKey,
 };
+use leaf_protocol::{
+    batch::BatchOp,
+    store::{BlobRange, EntityMeta, GcReport, ListCursor, ListMode},
+    EntityEvent, InvalidComponent, ListPage, SnapshotConflict,
+};
 
 #[derive(borsh::BorshDeserialize, borsh::BorshSerialize, Debug)]
 pub struct Req {
@@ -29,10 +34,12 @@ pub enum ReqKind {
     DelComponentsBySchema {
         link: ExactLink,
         schemas: Vec<Digest>,
+        /// If set, the components will only be deleted if the entity's current snapshot digest
+        /// matches this one.
+        expected_digest: Option<Digest>,
     },
-    // TODO: Support Batch Updating Entities Somehow
-    // Such as when you want to delete and replace a number of components. That's mostly the
-    // use-case we need I think.
+    /// Add components to a single entity. Use [`ReqKind::Batch`] to update several entities at
+    /// once.
     AddComponents {
         /// The entity to update.
         link: ExactLink,
@@ -41,8 +48,20 @@ pub enum ReqKind {
         /// Causes any components with the same schema as an added component to replace the previous
         /// components.
         replace_existing: bool,
+        /// If set, the components will only be added if the entity's current snapshot digest
+        /// matches this one. A null digest means that the entity must not exist yet.
+        expected_digest: Option<Digest>,
+    },
+    /// List a page of the entities under a link.
+    ///
+    /// Pass the `next` cursor from the response to get the following page. The server may return
+    /// fewer than `limit` links per page.
+    ListEntities {
+        link: ExactLink,
+        mode: ListMode,
+        limit: u64,
+        cursor: Option<ListCursor>,
     },
-    ListEntities(ExactLink),
     CreateNamespace,
     ImportNamespaceSecret(NamespaceSecretKey),
     GetNamespaceSecret(NamespaceId),
@@ -56,6 +75,124 @@ pub enum ReqKind {
     RestoreDatabaseDump(DatabaseDump),
     ListNamespaces,
     ListSubspaces,
+    ReadEntityAt {
+        link: ExactLink,
+        snapshot: Digest,
+    },
+    GetComponentsBySchemaAt {
+        link: ExactLink,
+        snapshot: Digest,
+        schemas: Vec<Digest>,
+    },
+    /// Subscribe to changes to the entity at the link and all of the entities under it.
+    ///
+    /// The ID of the subscribe request is used as the subscription ID. After the
+    /// [`RespKind::Subscribe`] response, each change is pushed as a [`Resp`] with the same ID and a
+    /// [`RespKind::EntityEvent`] result. If the subscription fails, an error [`Resp`] with the
+    /// same ID is sent and the subscription is ended. This includes when the client doesn't read
+    /// the events as fast as they are sent, so that the server doesn't have to buffer them.
+    Subscribe(ExactLink),
+    /// Cancel the subscription with the given ID.
+    Unsubscribe(u64),
+    /// Publish a component schema under the given schema ID.
+    ///
+    /// Fails if the schema is already published with a different format.
+    PublishSchema {
+        schema_id: Digest,
+        schema: Schema,
+    },
+    /// Look up a published component schema by its ID.
+    GetSchema(Digest),
+    /// Run a garbage collector reconciliation pass, removing leaked blob pins.
+    Gc,
+    /// Apply component changes to multiple entities at once.
+    ///
+    /// All of the [`BatchOp::Expect`] checks are made before anything is written, and if one of
+    /// them fails the response is a [`RespKind::SnapshotConflict`]. Otherwise the response
+    /// contains the new snapshot digest of each entity that was changed.
+    Batch(Vec<BatchOp>),
+    /// Copy an entity, and all of the entities under it if `recursive` is set, to another link.
+    ///
+    /// The response contains the links of the new entities.
+    CopyEntity {
+        from: ExactLink,
+        to: ExactLink,
+        recursive: bool,
+    },
+    /// Move an entity, and all of the entities under it if `recursive` is set, to another link.
+    ///
+    /// The response contains the new links of the moved entities.
+    ///
+    /// Moves are not atomic: every entity is copied before any of the originals are deleted, but if
+    /// the move fails partway, the copies and deletes that were already done are not rolled back.
+    MoveEntity {
+        from: ExactLink,
+        to: ExactLink,
+        recursive: bool,
+    },
+    /// Delete an entity and all of the entities under it. The response contains the number of
+    /// entities that were deleted.
+    ///
+    /// This is not atomic: if one of the entities can't be deleted, the error says how many
+    /// entities were already deleted.
+    DelSubtree(ExactLink),
+    /// Find the entities in a namespace that have a component with the given schema, optionally
+    /// only looking in one subspace.
+    FindBySchema {
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    },
+    /// Start indexing a field of the components with the given schema, which must have been
+    /// published.
+    ///
+    /// The field path is in the format used by `BorshSchema::field_schema()`.
+    RegisterIndex {
+        schema: Digest,
+        field_path: Vec<String>,
+        unique: bool,
+    },
+    /// Find the entities in a namespace that have the given borsh-encoded value in a registered
+    /// index.
+    LookupIndex {
+        namespace: NamespaceId,
+        index: Digest,
+        value: Vec<u8>,
+    },
+    /// Search the text of the entities in a namespace, returning up to `limit` of the best
+    /// matches.
+    Search {
+        namespace: NamespaceId,
+        query: String,
+        limit: u64,
+    },
+    /// Rebuild the search index from the store. The response contains the number of entities that
+    /// were indexed.
+    RebuildSearchIndex,
+    /// Start uploading a blob for the entity at the link.
+    ///
+    /// The ID of this request is used as the upload ID. Send the data with
+    /// [`ReqKind::BlobUploadChunk`] and then [`ReqKind::FinishBlobUpload`] to get the digest of the
+    /// blob. The blob is only pinned for a short grace period, so it should be referenced by a
+    /// component that is saved to the entity soon after it is uploaded.
+    StartBlobUpload(ExactLink),
+    /// Append data to a blob upload.
+    BlobUploadChunk {
+        upload: u64,
+        data: Vec<u8>,
+    },
+    /// Finish a blob upload. The response contains the digest of the blob.
+    FinishBlobUpload(u64),
+    /// Cancel a blob upload without storing the blob.
+    CancelBlobUpload(u64),
+    /// Read a range of a blob.
+    ///
+    /// The server may return less data than was asked for, so clients should keep reading from
+    /// the end of the returned data until they have the whole range.
+    ReadBlob {
+        digest: Digest,
+        range: BlobRange,
+    },
 }
 
 #[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug)]
@@ -67,12 +204,12 @@ pub struct GetComponentsInner {
 #[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug)]
 pub enum RespKind {
     Authenticated,
-    ReadEntity(Option<(Digest, Entity)>),
+    ReadEntity(Option<(Digest, Entity, EntityMeta)>),
     DelEntity,
     GetComponentBySchema(Option<GetComponentsInner>),
     DelComponentBySchema(Option<Digest>),
     AddComponents(Digest),
-    ListEntities(Vec<ExactLink>),
+    ListEntities(ListPage),
     CreateNamespace(NamespaceId),
     ImportNamespaceSecret(NamespaceId),
     GetNamespaceSecret(Option<NamespaceSecretKey>),
@@ -86,6 +223,64 @@ pub enum RespKind {
     RestoreDatabaseDump,
     ListNamespaces(Vec<NamespaceId>),
     ListSubspaces(Vec<SubspaceId>),
+    ReadEntityAt(Option<Entity>),
+    GetComponentsBySchemaAt(Option<GetComponentsInner>),
+    Subscribe,
+    Unsubscribe,
+    EntityEvent(EntityEvent),
+    PublishSchema,
+    GetSchema(Option<Schema>),
+    Gc(GcReport),
+    Batch(Vec<(ExactLink, Digest)>),
+    CopyEntity(Vec<ExactLink>),
+    MoveEntity(Vec<ExactLink>),
+    DelSubtree(u64),
+    FindBySchema(Vec<ExactLink>),
+    /// The ID of the registered index.
+    RegisterIndex(Digest),
+    LookupIndex(Vec<ExactLink>),
+    Search(Vec<SearchResult>),
+    RebuildSearchIndex(u64),
+    StartBlobUpload,
+    BlobUploadChunk,
+    FinishBlobUpload(Digest),
+    CancelBlobUpload,
+    ReadBlob {
+        /// The size of the whole blob.
+        size: u64,
+        data: Vec<u8>,
+    },
+    /// Sent instead of the normal response when some of the components in a
+    /// [`ReqKind::AddComponents`] or [`ReqKind::Batch`] request were rejected. Nothing is written.
+    ///
+    /// Components are only rejected when the server validates components, in which case
+    /// components whose schema hasn't been published or that don't match it are rejected.
+    InvalidComponents(Vec<RejectedComponent>),
+    /// Sent instead of the normal response when a [`ReqKind::AddComponents`] or
+    /// [`ReqKind::DelComponentsBySchema`] request has an `expected_digest` that doesn't match the
+    /// entity's current snapshot, or one of the [`BatchOp::Expect`] checks in a [`ReqKind::Batch`]
+    /// request fails. Nothing is written.
+    SnapshotConflict(SnapshotConflict),
+}
+
+/// A component that didn't pass the server's validation, in a [`RespKind::InvalidComponents`]
+/// response.
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RejectedComponent {
+    /// The index of the component in [`ReqKind::AddComponents`], or of the operation in
+    /// [`ReqKind::Batch`].
+    pub index: u64,
+    pub error: InvalidComponent,
+}
+
+/// An entity that matched a [`ReqKind::Search`] query.
+#[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub link: ExactLink,
+    /// How well the entity matched the query. Results are sorted by descending score.
+    pub score: f32,
+    /// The part of the entity's text that matched the query.
+    pub snippet: String,
 }
 
 #[derive(borsh::BorshSerialize, borsh::BorshDeserialize, Debug, Default)]
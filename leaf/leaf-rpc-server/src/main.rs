@@ -2,13 +2,14 @@ use std::{path::PathBuf, sync::Arc};
 
 use axum::{response::IntoResponse, routing::get, Router};
 use clap::Parser;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use http::StatusCode;
 use leaf_protocol::{
     borsh::BorshDeserialize,
-    iroh::{client::Iroh, docs::store::Query, node::Node},
-    prelude::{IrohDocumentKeyFormat, LeafGcPath, LeafIroh, LeafIrohStore},
-    types::Entity,
+    iroh::{base::base32, client::Iroh, docs::store::Query, node::Node},
+    prelude::{IrohDocumentKeyFormat, LeafGcPath, LeafIrohStore, LeafRedbStore},
+    store::{LeafStore, ListMode},
+    types::{ComponentEntry, ComponentKind, Entity, EntityPath, ExactLink, Value},
     Leaf,
 };
 use once_cell::sync::Lazy;
@@ -17,6 +18,12 @@ use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod proto;
+mod search;
+mod store;
+#[cfg(test)]
+mod test_util;
+
+use store::{Backend, BackendStore, LeafServer, ServerStore};
 
 #[derive(clap::Parser)]
 pub struct Args {
@@ -28,9 +35,27 @@ pub struct Args {
     pub port: u16,
     #[arg(long, env)]
     pub enable_local_store: bool,
+    /// The store to keep Leaf data in.
+    #[arg(long, env, value_enum, default_value_t = Backend::Iroh)]
+    pub backend: Backend,
+    /// Keep the `DateCreated` and `DateUpdated` components of entities up to date when they are
+    /// saved.
+    #[arg(long, env)]
+    pub track_dates: bool,
+    /// Reject components that don't match their published schema, or whose schema hasn't been
+    /// published.
+    ///
+    /// The schemas of the standard components are published when the server starts, so that
+    /// clients can write them without publishing them first.
+    #[arg(long, env)]
+    pub validate_components: bool,
 }
 
+#[cfg(not(test))]
 pub static ARGS: Lazy<Args> = Lazy::new(Args::parse);
+/// The test harness has its own arguments, so tests use the defaults.
+#[cfg(test)]
+pub static ARGS: Lazy<Args> = Lazy::new(|| Args::parse_from(["leaf-rpc-server"]));
 pub static CLIENT: Lazy<reqwest::Client> =
     Lazy::new(|| reqwest::ClientBuilder::new().build().unwrap());
 
@@ -38,7 +63,7 @@ const SECRET_TABLE: redb::TableDefinition<&str, String> = redb::TableDefinition:
 
 pub type AppState = Arc<AppStateInner>;
 pub struct AppStateInner {
-    pub leaf: LeafIroh,
+    pub leaf: LeafServer,
     pub secretdb: Arc<Option<redb::Database>>,
 }
 
@@ -77,11 +102,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse CLI args.
     let args = &*ARGS;
 
-    // Initialize the leaf store and Iroh node
-    let node = Node::persistent(&ARGS.data_dir).await?.spawn().await?;
-    tracing::info!(id = %node.node_id(), "Started Iroh Node");
-    let leaf_store = LeafIrohStore::new(node.client().clone());
-    let leaf = Leaf::new(leaf_store);
+    // Initialize the leaf store, and the Iroh node if we are using it
+    let (leaf_store, node) = match ARGS.backend {
+        Backend::Iroh => {
+            let node = Node::persistent(&ARGS.data_dir).await?.spawn().await?;
+            tracing::info!(id = %node.node_id(), "Started Iroh Node");
+            let local = redb::Database::create(ARGS.data_dir.join("leaf-local.redb"))?;
+            let leaf_store = LeafIrohStore::new(node.client().clone(), local)?;
+            (BackendStore::Iroh(leaf_store), Some(node))
+        }
+        Backend::Redb => {
+            std::fs::create_dir_all(&ARGS.data_dir)?;
+            let leaf_store = LeafRedbStore::open(ARGS.data_dir.join("leaf.redb"))?;
+            tracing::info!("Opened redb store. Note that the redb store does not sync with peers.");
+            (BackendStore::Redb(leaf_store), None)
+        }
+    };
+    let mut leaf = Leaf::new(ServerStore::new(leaf_store));
+    leaf.track_dates = ARGS.track_dates;
+    leaf.publish_standard_schemas().await?;
+
+    // Follow the entities synced from peers before building the search index, so that none are
+    // missed in between, and build it in the background to pick up the ones synced before we
+    // started.
+    let namespaces = leaf
+        .store
+        .list_namespaces()
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    for namespace in namespaces {
+        leaf.store.follow_namespace(namespace);
+    }
+    tokio::spawn(rebuild_search_index(leaf.clone()));
 
     let secretdb = if ARGS.enable_local_store {
         tracing::info!(
@@ -102,8 +155,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     // Spawn a task to handle the debug CLI commands
-    let iroh = node.client().clone();
-    tokio::spawn(handle_cli_prompts(iroh));
+    let iroh = node.as_ref().map(|node| node.client().clone());
+    tokio::spawn(handle_cli_prompts(iroh, leaf.clone()));
 
     // Construct router
     let router = Router::new()
@@ -118,56 +171,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn handle_cli_prompts(iroh: Iroh) {
+/// Describe a component for the `dump` command, decoding it if its schema has been published.
+async fn describe_component(leaf: &LeafServer, entry: &ComponentEntry) -> anyhow::Result<String> {
+    let data = leaf.store.get_blob(entry.component_id).await?;
+    let ComponentKind::Unencrypted(component) = ComponentKind::deserialize(&mut &data[..])? else {
+        return Ok("Encrypted".into());
+    };
+    let Some(schema) = leaf.get_schema(component.schema).await? else {
+        return Ok(format!(
+            "Unknown schema {}: {} bytes",
+            component.schema,
+            component.data.len()
+        ));
+    };
+    let value = Value::decode(&schema.format, &component.data)?;
+    Ok(format!("{}: {}", schema.name, value.to_json()))
+}
+
+/// Rebuild the search index from the store, logging the result.
+async fn rebuild_search_index(leaf: LeafServer) {
+    match leaf.store.search.rebuild(&leaf.store).await {
+        Ok(count) => tracing::info!("Indexed {count} entities for search."),
+        Err(e) => tracing::error!("Error rebuilding search index: {e}"),
+    }
+}
+
+async fn handle_cli_prompts(iroh: Option<Iroh>, leaf: LeafServer) {
     let buf = tokio::io::BufReader::new(tokio::io::stdin());
     let mut stream = buf.lines();
     while let Ok(Some(line)) = stream.next_line().await {
         async {
             if &line == "dump" {
-                use std::fmt::Write;
-                let dump = &mut String::new();
-
-                let mut s = iroh.docs().list().await?;
-                while let Some(doc) = s.next().await {
-                    let (namespace, _cap) = doc?;
-                    writeln!(dump, "Doc: {namespace}")?;
-                    let doc = iroh
-                        .docs()
-                        .open(namespace)
-                        .await?
-                        .ok_or_else(|| anyhow::format_err!("Missing doc"))?;
-                    let mut s = doc
-                        .get_many(Query::single_latest_per_key().key_prefix(b""))
-                        .await?;
-                    while let Some(entry) = s.next().await {
-                        if let Err(e) = async {
-                            let entry = entry?;
-                            let key = entry.key();
-                            if let Ok(key) = LeafGcPath::from_bytes(key) {
-                                writeln!(dump, "    {key:?}")?;
-                                writeln!(dump, "        GC: {}", entry.content_hash())?;
-                            } else {
-                                let key = IrohDocumentKeyFormat::from_bytes(key)?.path;
-                                writeln!(dump, "    {key:?}")?;
-                                let hash = entry.content_hash();
-                                writeln!(dump, "        Hash: {hash}")?;
-                                let value = entry.content_bytes(&doc).await?;
-                                let value = Entity::deserialize(&mut &value[..])?;
-                                writeln!(dump, "        Value: {value:?}")?;
-                            }
-
-                            Ok::<_, anyhow::Error>(())
-                        }
-                        .await
-                        {
-                            writeln!(dump, "        Error: {e}")?;
-                        };
-                    }
-                }
-
-                tracing::info!("Iroh dump:\n{dump}");
+                let dump = match &iroh {
+                    Some(iroh) => dump_iroh(iroh, &leaf).await?,
+                    None => dump_store(&leaf).await?,
+                };
+                tracing::info!("Database dump:\n{dump}");
+            } else if &line == "gc" {
+                let report = leaf.gc().await?;
+                tracing::info!(
+                    "Removed {} GC pins, unpinning {} bytes.",
+                    report.pins_removed,
+                    report.bytes_unpinned
+                );
+            } else if &line == "reindex" {
+                rebuild_search_index(leaf.clone()).await;
             } else {
-                tracing::info!("Available commands: dump.")
+                tracing::info!("Available commands: dump, gc, reindex.")
             }
 
             Ok::<_, anyhow::Error>(())
@@ -180,3 +230,97 @@ async fn handle_cli_prompts(iroh: Iroh) {
         .ok();
     }
 }
+
+/// Dump the raw contents of the Iroh documents, including the GC pins, for the `dump` command.
+async fn dump_iroh(iroh: &Iroh, leaf: &LeafServer) -> anyhow::Result<String> {
+    use std::fmt::Write;
+    let mut dump = String::new();
+
+    let mut s = iroh.docs().list().await?;
+    while let Some(doc) = s.next().await {
+        let (namespace, _cap) = doc?;
+        writeln!(dump, "Doc: {namespace}")?;
+        let doc = iroh
+            .docs()
+            .open(namespace)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("Missing doc"))?;
+        let mut s = doc
+            .get_many(Query::single_latest_per_key().key_prefix(b""))
+            .await?;
+        while let Some(entry) = s.next().await {
+            if let Err(e) = async {
+                let entry = entry?;
+                let key = entry.key();
+                if let Ok(key) = LeafGcPath::from_bytes(key) {
+                    writeln!(dump, "    {key:?}")?;
+                    writeln!(dump, "        GC: {}", entry.content_hash())?;
+                } else {
+                    let key = IrohDocumentKeyFormat::from_bytes(key)?.path;
+                    writeln!(dump, "    {key:?}")?;
+                    let hash = entry.content_hash();
+                    writeln!(dump, "        Hash: {hash}")?;
+                    let value = entry.content_bytes(&doc).await?;
+                    let value = Entity::deserialize(&mut &value[..])?;
+                    writeln!(dump, "        Value: {value:?}")?;
+                    for entry in &value.components {
+                        let description = describe_component(leaf, entry)
+                            .await
+                            .unwrap_or_else(|e| format!("Error: {e}"));
+                        writeln!(dump, "        - {description}")?;
+                    }
+                }
+
+                Ok::<_, anyhow::Error>(())
+            }
+            .await
+            {
+                writeln!(dump, "        Error: {e}")?;
+            };
+        }
+    }
+
+    Ok(dump)
+}
+
+/// Dump the entities in the store for the `dump` command, when we aren't using Iroh.
+async fn dump_store(leaf: &LeafServer) -> anyhow::Result<String> {
+    use std::fmt::Write;
+    let mut dump = String::new();
+
+    let subspaces = leaf
+        .list_subspaces()
+        .await?
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    let namespaces = leaf.list_namespaces().await?.collect::<Vec<_>>().await;
+    for namespace in namespaces {
+        let namespace = namespace?;
+        writeln!(dump, "Namespace: {}", base32::fmt(namespace))?;
+        for &subspace in &subspaces {
+            let link = ExactLink {
+                namespace,
+                subspace,
+                path: EntityPath::default(),
+            };
+            let links = leaf.list(link, ListMode::Descendants).await?;
+            let links = links.collect::<Vec<_>>().await;
+            for link in links {
+                let entity = leaf.entity(link?).await?.entity()?;
+                writeln!(dump, "    {:?}", entity.link.path)?;
+                writeln!(dump, "        Hash: {}", entity.digest)?;
+                writeln!(dump, "        Value: {:?}", entity.entity)?;
+                for entry in &entity.entity.components {
+                    let description = describe_component(leaf, entry)
+                        .await
+                        .unwrap_or_else(|e| format!("Error: {e}"));
+                    writeln!(dump, "        - {description}")?;
+                }
+            }
+        }
+    }
+
+    Ok(dump)
+}
@@ -1,12 +1,24 @@
 use std::{collections::HashMap, sync::Arc};
 
 use axum::{extract::State, response::IntoResponse};
-use fastwebsockets::{Frame, OpCode, Payload, WebSocketError};
-use futures::{pin_mut, StreamExt};
+use fastwebsockets::{FragmentCollectorRead, Frame, OpCode, Payload, WebSocketError};
+use futures::{pin_mut, StreamExt, TryStreamExt};
 use leaf_protocol::prelude::*;
 use leaf_rpc_proto::*;
+use tokio::{sync::mpsc, task::JoinHandle};
 
-use crate::{AppState, ARGS, SECRET_TABLE};
+use crate::{
+    store::{LeafServer, ServerStore},
+    AppState, ARGS, SECRET_TABLE,
+};
+
+/// The largest number of links that will be returned in one page of a
+/// [`ReqKind::ListEntities`] response.
+const MAX_LIST_PAGE_SIZE: u64 = 1000;
+/// The largest number of results that will be returned in a [`ReqKind::Search`] response.
+const MAX_SEARCH_RESULTS: u64 = 100;
+/// The most blob data that is returned for a single [`ReqKind::ReadBlob`] request.
+const MAX_BLOB_READ_SIZE: u64 = 1024 * 1024;
 
 pub async fn ws_handler(
     state: State<AppState>,
@@ -29,82 +41,307 @@ async fn handle_client(
 ) -> Result<(), WebSocketError> {
     let leaf = &state.leaf;
     let secretdb = state.secretdb.clone();
-    let mut ws = fastwebsockets::FragmentCollector::new(fut.await?);
+    let (ws_read, mut ws_write) = fut.await?.split(tokio::io::split);
+    let mut ws_read = FragmentCollectorRead::new(ws_read);
     let mut authenticated = false;
 
-    loop {
-        let frame = ws.read_frame().await?;
-        let result: anyhow::Result<bool> = async {
-            match frame.opcode {
-                OpCode::Close => {
-                    return Ok(true);
-                }
-                OpCode::Text => {
-                    ws.write_frame(Frame::text(Payload::Borrowed(
-                        b"Text transport not supported",
-                    )))
-                    .await?;
-                }
-                OpCode::Binary => {
-                    let req = Req::deserialize(&mut &*frame.payload)?;
-
-                    if authenticated {
-                        let resp = handle_req(leaf, secretdb.clone(), req).await;
-                        let mut buf = Vec::new();
-                        resp.serialize(&mut buf)?;
-                        ws.write_frame(Frame::binary(Payload::Owned(buf))).await?;
-
-                    // If we aren't authenticated yet
-                    } else {
-                        if let ReqKind::Authenticate(token) = req.kind {
-                            if token == ARGS.api_key {
-                                authenticated = true;
-                                let mut buf = Vec::new();
-                                Resp {
-                                    id: req.id,
-                                    result: Ok(RespKind::Authenticated),
+    // Frames are written from a separate task so that subscriptions can push events to the client
+    // while we are waiting for the next request.
+    let (frame_sender, mut frame_receiver) = mpsc::channel::<Frame<'static>>(16);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = frame_receiver.recv().await {
+            if let Err(e) = ws_write.write_frame(frame).await {
+                tracing::warn!("Error writing to websocket: {e}");
+                break;
+            }
+        }
+    });
+    let mut subscriptions = HashMap::<u64, JoinHandle<()>>::default();
+    let mut uploads = HashMap::<u64, BlobUpload>::default();
+
+    let result = async {
+        loop {
+            let frame = ws_read
+                .read_frame(&mut |frame| {
+                    let frame_sender = frame_sender.clone();
+                    async move {
+                        frame_sender
+                            .send(frame)
+                            .await
+                            .map_err(|_| anyhow::format_err!("Websocket writer closed"))
+                    }
+                })
+                .await?;
+            let result: anyhow::Result<bool> = async {
+                match frame.opcode {
+                    OpCode::Close => {
+                        return Ok(true);
+                    }
+                    OpCode::Text => {
+                        send_frame(
+                            &frame_sender,
+                            Frame::text(Payload::Borrowed(b"Text transport not supported")),
+                        )
+                        .await?;
+                    }
+                    OpCode::Binary => {
+                        let req = Req::deserialize(&mut &*frame.payload)?;
+
+                        if authenticated {
+                            match req.kind {
+                                ReqKind::Subscribe(link) => {
+                                    let subscription = tokio::spawn(handle_subscription(
+                                        state.leaf.clone(),
+                                        req.id,
+                                        link,
+                                        frame_sender.clone(),
+                                    ));
+                                    if let Some(old) = subscriptions.insert(req.id, subscription) {
+                                        old.abort();
+                                    }
+                                }
+                                ReqKind::Unsubscribe(id) => {
+                                    if let Some(subscription) = subscriptions.remove(&id) {
+                                        subscription.abort();
+                                    }
+                                    send_resp(
+                                        &frame_sender,
+                                        Resp {
+                                            id: req.id,
+                                            result: Ok(RespKind::Unsubscribe),
+                                        },
+                                    )
+                                    .await?;
+                                }
+                                kind @ (ReqKind::StartBlobUpload(_)
+                                | ReqKind::BlobUploadChunk { .. }
+                                | ReqKind::FinishBlobUpload(_)
+                                | ReqKind::CancelBlobUpload(_)) => {
+                                    let req = Req { id: req.id, kind };
+                                    let resp = handle_blob_upload(leaf, &mut uploads, req).await;
+                                    send_resp(&frame_sender, resp).await?;
+                                }
+                                kind => {
+                                    let req = Req { id: req.id, kind };
+                                    let resp = handle_req(leaf, secretdb.clone(), req).await;
+                                    send_resp(&frame_sender, resp).await?;
                                 }
-                                .serialize(&mut buf)?;
-                                ws.write_frame(Frame::binary(Payload::Owned(buf))).await?;
-                                return Ok(false);
                             }
-                        }
 
-                        let mut buf = Vec::new();
-                        Resp {
-                            id: req.id,
-                            result: Err("Unauthenticated".into()),
+                        // If we aren't authenticated yet
+                        } else {
+                            if let ReqKind::Authenticate(token) = req.kind {
+                                if token == ARGS.api_key {
+                                    authenticated = true;
+                                    send_resp(
+                                        &frame_sender,
+                                        Resp {
+                                            id: req.id,
+                                            result: Ok(RespKind::Authenticated),
+                                        },
+                                    )
+                                    .await?;
+                                    return Ok(false);
+                                }
+                            }
+
+                            send_resp(
+                                &frame_sender,
+                                Resp {
+                                    id: req.id,
+                                    result: Err("Unauthenticated".into()),
+                                },
+                            )
+                            .await?;
+                            anyhow::bail!("Unauthenticated");
                         }
-                        .serialize(&mut buf)?;
-                        ws.write_frame(Frame::binary(Payload::Owned(buf))).await?;
-                        anyhow::bail!("Unauthenticated");
                     }
+                    _ => (),
                 }
-                _ => (),
+                Ok(false)
             }
-            Ok(false)
-        }
-        .await;
+            .await;
 
-        match result {
-            Ok(should_close) => {
-                if should_close {
+            match result {
+                Ok(should_close) => {
+                    if should_close {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    send_frame(
+                        &frame_sender,
+                        Frame::text(Payload::Owned(err.to_string().into())),
+                    )
+                    .await
+                    .ok();
+                    tracing::error!("Error in websocket handler: {err}");
                     break;
                 }
             }
-            Err(err) => {
-                ws.write_frame(Frame::text(Payload::Owned(err.to_string().into())))
-                    .await?;
-                tracing::error!("Error in websocket handler: {err}");
+        }
+        Ok(())
+    }
+    .await;
+
+    // Stop all of the subscriptions and unfinished uploads, and wait for the remaining frames to be
+    // written.
+    for (_, subscription) in subscriptions.drain() {
+        subscription.abort();
+    }
+    for (_, upload) in uploads.drain() {
+        upload.task.abort();
+    }
+    drop(frame_sender);
+    writer.await.ok();
+
+    result
+}
+
+async fn send_frame(
+    frame_sender: &mpsc::Sender<Frame<'static>>,
+    frame: Frame<'static>,
+) -> anyhow::Result<()> {
+    frame_sender
+        .send(frame)
+        .await
+        .map_err(|_| anyhow::format_err!("Websocket writer closed"))
+}
+
+async fn send_resp(frame_sender: &mpsc::Sender<Frame<'static>>, resp: Resp) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    resp.serialize(&mut buf)?;
+    send_frame(frame_sender, Frame::binary(Payload::Owned(buf))).await
+}
+
+/// Push the changes to the entities under `link` to the client until the subscription is aborted.
+///
+/// Events are only read from the store's watch as fast as they can be sent to the client. If the
+/// client falls behind, the watch ends with an error, which is sent on to the client.
+async fn handle_subscription(
+    leaf: LeafServer,
+    id: u64,
+    link: ExactLink,
+    frame_sender: mpsc::Sender<Frame<'static>>,
+) {
+    let result = async {
+        let events = match leaf.watch(link).await {
+            Ok(events) => events,
+            Err(e) => {
+                let result = Err(format!("{e}"));
+                return send_resp(&frame_sender, Resp { id, result }).await;
+            }
+        };
+        let result = Ok(RespKind::Subscribe);
+        send_resp(&frame_sender, Resp { id, result }).await?;
+
+        pin_mut!(events);
+        while let Some(event) = events.next().await {
+            let result = event.map(RespKind::EntityEvent).map_err(|e| format!("{e}"));
+            let is_err = result.is_err();
+            send_resp(&frame_sender, Resp { id, result }).await?;
+            if is_err {
                 break;
             }
         }
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::warn!("Error sending subscription event: {e}");
+    }
+}
+
+/// A blob that is being uploaded with [`ReqKind::StartBlobUpload`].
+struct BlobUpload {
+    chunks: mpsc::Sender<Vec<u8>>,
+    task: JoinHandle<anyhow::Result<Digest>>,
+}
+
+impl BlobUpload {
+    /// Start streaming the uploaded chunks into the store.
+    ///
+    /// The blob is pinned under a null snapshot ID, which is never live, so the pin only lasts
+    /// until the garbage collector's grace period is over. Adding a component that references the
+    /// blob with [`ReqKind::AddComponents`] or [`ReqKind::Batch`] pins it for as long as the
+    /// component is on the entity, as long as the component's schema has been published so that
+    /// its references can be found.
+    fn start(leaf: LeafServer, link: ExactLink) -> Self {
+        let (chunks, receiver) = mpsc::channel(4);
+        let data = futures::stream::unfold(receiver, |mut receiver| async move {
+            let chunk = receiver.recv().await?;
+            Some((Ok(chunk), receiver))
+        });
+        let task = tokio::spawn(async move {
+            leaf.store
+                .put_blob_stream(data, &link, Digest::default())
+                .await
+        });
+        Self { chunks, task }
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
+        if self.chunks.send(data).await.is_ok() {
+            return Ok(());
+        }
+        // The store stopped reading the upload, so report why.
+        (&mut self.task).await??;
+        anyhow::bail!("Blob upload was already finished")
+    }
+
+    async fn finish(self) -> anyhow::Result<Digest> {
+        drop(self.chunks);
+        self.task.await?
+    }
+}
+
+/// Handle the requests for uploading blobs, which keep track of the uploads on the connection.
+async fn handle_blob_upload(
+    leaf: &LeafServer,
+    uploads: &mut HashMap<u64, BlobUpload>,
+    req: Req,
+) -> Resp {
+    let result = async {
+        match req.kind {
+            ReqKind::StartBlobUpload(link) => {
+                let upload = BlobUpload::start(leaf.clone(), link);
+                if let Some(old) = uploads.insert(req.id, upload) {
+                    old.task.abort();
+                }
+                Ok(RespKind::StartBlobUpload)
+            }
+            ReqKind::BlobUploadChunk { upload: id, data } => {
+                let mut upload = take_upload(uploads, id)?;
+                upload.write(data).await?;
+                uploads.insert(id, upload);
+                Ok(RespKind::BlobUploadChunk)
+            }
+            ReqKind::FinishBlobUpload(id) => {
+                let digest = take_upload(uploads, id)?.finish().await?;
+                Ok(RespKind::FinishBlobUpload(digest))
+            }
+            ReqKind::CancelBlobUpload(id) => {
+                take_upload(uploads, id)?.task.abort();
+                Ok(RespKind::CancelBlobUpload)
+            }
+            _ => Err(anyhow::format_err!("not a blob upload request")),
+        }
     }
+    .await;
+    Resp {
+        id: req.id,
+        result: result.map_err(|e| format!("{e}")),
+    }
+}
 
-    Ok(())
+fn take_upload(uploads: &mut HashMap<u64, BlobUpload>, id: u64) -> anyhow::Result<BlobUpload> {
+    uploads
+        .remove(&id)
+        .ok_or_else(|| anyhow::format_err!("No blob upload with ID {id}"))
 }
 
-async fn handle_req(leaf: &LeafIroh, secretdb: Arc<Option<redb::Database>>, req: Req) -> Resp {
+async fn handle_req(leaf: &LeafServer, secretdb: Arc<Option<redb::Database>>, req: Req) -> Resp {
     let kind = match req.kind {
         ReqKind::Authenticate(_) => {
             // TODO: we can hit this somehow when restarting the RPC server while Weird tries to
@@ -116,15 +353,26 @@ async fn handle_req(leaf: &LeafIroh, secretdb: Arc<Option<redb::Database>>, req:
         ReqKind::GetComponentsBySchema { link, schemas } => {
             get_components_by_schema(leaf, link, schemas).await
         }
-        ReqKind::DelComponentsBySchema { link, schemas } => {
-            del_components_by_schema(leaf, link, schemas).await
-        }
+        ReqKind::DelComponentsBySchema {
+            link,
+            schemas,
+            expected_digest,
+        } => del_components_by_schema(leaf, link, schemas, expected_digest).await,
         ReqKind::AddComponents {
             link,
             components,
             replace_existing,
-        } => add_components(leaf, link, components, replace_existing).await,
-        ReqKind::ListEntities(link) => list_entities(leaf, link).await,
+            expected_digest,
+        } => add_components(leaf, link, components, replace_existing, expected_digest).await,
+        ReqKind::Subscribe(_) | ReqKind::Unsubscribe(_) => Err(anyhow::format_err!(
+            "subscription requests should be handled outside this function"
+        )),
+        ReqKind::ListEntities {
+            link,
+            mode,
+            limit,
+            cursor,
+        } => list_entities(leaf, link, mode, limit, cursor).await,
         ReqKind::CreateNamespace => create_namespace(leaf).await,
         ReqKind::ImportNamespaceSecret(secret) => import_namespace_secret(leaf, secret).await,
         ReqKind::GetNamespaceSecret(namespace) => get_namespace_secret(leaf, namespace).await,
@@ -138,6 +386,57 @@ async fn handle_req(leaf: &LeafIroh, secretdb: Arc<Option<redb::Database>>, req:
         ReqKind::RestoreDatabaseDump(dump) => restore_database_dump(leaf, dump).await,
         ReqKind::ListNamespaces => list_namespaces(leaf).await,
         ReqKind::ListSubspaces => list_subspaces(leaf).await,
+        ReqKind::ReadEntityAt { link, snapshot } => read_entity_at(leaf, link, snapshot).await,
+        ReqKind::GetComponentsBySchemaAt {
+            link,
+            snapshot,
+            schemas,
+        } => get_components_by_schema_at(leaf, link, snapshot, schemas).await,
+        ReqKind::PublishSchema { schema_id, schema } => {
+            publish_schema(leaf, schema_id, schema).await
+        }
+        ReqKind::GetSchema(schema_id) => get_schema(leaf, schema_id).await,
+        ReqKind::Gc => gc(leaf).await,
+        ReqKind::Batch(ops) => batch(leaf, ops).await,
+        ReqKind::CopyEntity {
+            from,
+            to,
+            recursive,
+        } => copy_entity(leaf, from, to, recursive).await,
+        ReqKind::MoveEntity {
+            from,
+            to,
+            recursive,
+        } => move_entity(leaf, from, to, recursive).await,
+        ReqKind::DelSubtree(link) => del_subtree(leaf, link).await,
+        ReqKind::FindBySchema {
+            namespace,
+            subspace,
+            schema,
+        } => find_by_schema(leaf, namespace, subspace, schema).await,
+        ReqKind::RegisterIndex {
+            schema,
+            field_path,
+            unique,
+        } => register_index(leaf, schema, field_path, unique).await,
+        ReqKind::LookupIndex {
+            namespace,
+            index,
+            value,
+        } => lookup_index(leaf, namespace, index, value).await,
+        ReqKind::Search {
+            namespace,
+            query,
+            limit,
+        } => search(leaf, namespace, &query, limit),
+        ReqKind::RebuildSearchIndex => rebuild_search_index(leaf).await,
+        ReqKind::StartBlobUpload(_)
+        | ReqKind::BlobUploadChunk { .. }
+        | ReqKind::FinishBlobUpload(_)
+        | ReqKind::CancelBlobUpload(_) => Err(anyhow::format_err!(
+            "blob upload requests should be handled outside this function"
+        )),
+        ReqKind::ReadBlob { digest, range } => read_blob(leaf, digest, range).await,
     };
     Resp {
         id: req.id,
@@ -145,23 +444,35 @@ async fn handle_req(leaf: &LeafIroh, secretdb: Arc<Option<redb::Database>>, req:
     }
 }
 
-async fn read_entity(leaf: &LeafIroh, link: ExactLink) -> anyhow::Result<RespKind> {
+async fn read_entity(leaf: &LeafServer, link: ExactLink) -> anyhow::Result<RespKind> {
     let entry = leaf.entity(link).await?;
     let entity = entry
         .entity()
         .ok()
-        .map(|loaded| (loaded.digest, loaded.entity));
+        .and_then(|loaded| Some((loaded.digest, loaded.entity, loaded.meta?)));
     Ok(RespKind::ReadEntity(entity))
 }
+async fn read_entity_at(
+    leaf: &LeafServer,
+    link: ExactLink,
+    snapshot: Digest,
+) -> anyhow::Result<RespKind> {
+    let loaded = leaf.entity_at(link, snapshot).await?;
+    Ok(RespKind::ReadEntityAt(loaded.map(|x| x.entity)))
+}
 async fn del_entity(
-    leaf: &Leaf<LeafIrohStore>,
+    leaf: &LeafServer,
     link: ExactLink,
 ) -> std::result::Result<RespKind, anyhow::Error> {
     leaf.del_entity(link).await?;
     Ok(RespKind::DelEntity)
 }
+async fn del_subtree(leaf: &LeafServer, link: ExactLink) -> anyhow::Result<RespKind> {
+    let count = leaf.del_subtree(link).await?;
+    Ok(RespKind::DelSubtree(count as u64))
+}
 async fn get_components_by_schema(
-    leaf: &LeafIroh,
+    leaf: &LeafServer,
     link: ExactLink,
     schemas: Vec<Digest>,
 ) -> anyhow::Result<RespKind> {
@@ -178,56 +489,142 @@ async fn get_components_by_schema(
         components: map,
     })))
 }
+async fn get_components_by_schema_at(
+    leaf: &LeafServer,
+    link: ExactLink,
+    snapshot: Digest,
+    schemas: Vec<Digest>,
+) -> anyhow::Result<RespKind> {
+    let mut map = HashMap::<Digest, Vec<Vec<u8>>>::default();
+    let Some(entity) = leaf.entity_at(link, snapshot).await? else {
+        return Ok(RespKind::GetComponentsBySchemaAt(None));
+    };
+    for schema in schemas {
+        let components = entity.get_components_by_schema(schema).await?;
+        map.entry(schema).or_default().extend(components)
+    }
+    Ok(RespKind::GetComponentsBySchemaAt(Some(
+        GetComponentsInner {
+            entity_digest: entity.digest,
+            components: map,
+        },
+    )))
+}
 async fn del_components_by_schema(
-    leaf: &LeafIroh,
+    leaf: &LeafServer,
     link: ExactLink,
     schemas: Vec<Digest>,
+    expected_digest: Option<Digest>,
 ) -> anyhow::Result<RespKind> {
     let resp = 'resp: {
-        let Ok(mut entity) = leaf.entity(link).await?.entity() else {
+        let Ok(mut entity) = leaf.entity(link.clone()).await?.entity() else {
+            // There is nothing to delete, but the caller may have expected the entity to exist.
+            if let Some(conflict) =
+                expected_digest.and_then(|expected| SnapshotConflict::check(&link, expected, None))
+            {
+                return Ok(RespKind::SnapshotConflict(conflict));
+            }
             break 'resp None;
         };
         for schema in schemas {
             entity.del_components_by_schema(schema);
         }
-        entity.save().await?;
+        if let Some(expected_digest) = expected_digest {
+            if let Some(conflict) = save_if(&mut entity, expected_digest).await? {
+                return Ok(conflict);
+            }
+        } else {
+            entity.save().await?;
+        }
         Some(entity.digest)
     };
     Ok(RespKind::DelComponentBySchema(resp))
 }
+/// Save the entity if it still has the `expected` snapshot, or return the
+/// [`RespKind::SnapshotConflict`] to send instead of the normal response if it doesn't.
+async fn save_if(
+    entity: &mut LoadedEntity<ServerStore>,
+    expected: Digest,
+) -> anyhow::Result<Option<RespKind>> {
+    match entity.save_if(expected).await {
+        Ok(()) => Ok(None),
+        Err(e) => Ok(Some(RespKind::SnapshotConflict(
+            e.downcast::<SnapshotConflict>()?,
+        ))),
+    }
+}
+/// Check the components against their published schemas if the server was started with
+/// `--validate-components`, returning the components that were rejected.
+///
+/// Without validation every component is accepted. Components whose schema hasn't been published
+/// are still written, but the blobs and snapshots they reference can't be found, so they aren't
+/// pinned.
+async fn validate_components<'a>(
+    leaf: &LeafServer,
+    components: impl Iterator<Item = (usize, &'a ComponentData)>,
+) -> anyhow::Result<Vec<RejectedComponent>> {
+    let mut rejected = Vec::new();
+    if !ARGS.validate_components {
+        return Ok(rejected);
+    }
+    for (index, component) in components {
+        if let Err(e) = leaf.validate_component(component).await {
+            let error = e.downcast::<InvalidComponent>()?;
+            rejected.push(RejectedComponent {
+                index: index as u64,
+                error,
+            });
+        }
+    }
+    Ok(rejected)
+}
+
 async fn add_components(
-    leaf: &LeafIroh,
+    leaf: &LeafServer,
     link: ExactLink,
     components: Vec<ComponentData>,
     replace_existing: bool,
+    expected_digest: Option<Digest>,
 ) -> anyhow::Result<RespKind> {
+    let rejected = validate_components(leaf, components.iter().enumerate()).await?;
+    if !rejected.is_empty() {
+        return Ok(RespKind::InvalidComponents(rejected));
+    }
+
     let mut entity = leaf.entity(link).await?.get_or_init();
     for comp in components {
         if replace_existing {
             entity.del_components_by_schema(comp.schema);
         }
-        entity.add_component_data(ComponentKind::Unencrypted(comp));
+        entity
+            .add_component_data(ComponentKind::Unencrypted(comp))
+            .await?;
+    }
+    if let Some(expected_digest) = expected_digest {
+        if let Some(conflict) = save_if(&mut entity, expected_digest).await? {
+            return Ok(conflict);
+        }
+    } else {
+        entity.save().await?;
     }
-    entity.save().await?;
     Ok(RespKind::AddComponents(entity.digest))
 }
-async fn list_entities(leaf: &LeafIroh, link: ExactLink) -> anyhow::Result<RespKind> {
-    let entities = leaf
-        .list(link)
-        .await?
-        .collect::<Vec<_>>()
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
-    Ok(RespKind::ListEntities(entities))
+async fn list_entities(
+    leaf: &LeafServer,
+    link: ExactLink,
+    mode: ListMode,
+    limit: u64,
+    cursor: Option<ListCursor>,
+) -> anyhow::Result<RespKind> {
+    let limit = limit.min(MAX_LIST_PAGE_SIZE);
+    let page = leaf.list_page(link, mode, limit, cursor).await?;
+    Ok(RespKind::ListEntities(page))
 }
-async fn create_namespace(
-    leaf: &Leaf<LeafIrohStore>,
-) -> std::result::Result<RespKind, anyhow::Error> {
+async fn create_namespace(leaf: &LeafServer) -> std::result::Result<RespKind, anyhow::Error> {
     Ok(RespKind::CreateNamespace(leaf.create_namespace().await?))
 }
 async fn import_namespace_secret(
-    leaf: &Leaf<LeafIrohStore>,
+    leaf: &LeafServer,
     secret: [u8; 32],
 ) -> std::result::Result<RespKind, anyhow::Error> {
     Ok(RespKind::ImportNamespaceSecret(
@@ -235,20 +632,18 @@ async fn import_namespace_secret(
     ))
 }
 async fn get_namespace_secret(
-    leaf: &Leaf<LeafIrohStore>,
+    leaf: &LeafServer,
     namespace: [u8; 32],
 ) -> std::result::Result<RespKind, anyhow::Error> {
     Ok(RespKind::GetNamespaceSecret(
         leaf.get_namespace_secret(namespace).await?,
     ))
 }
-async fn create_subspace(
-    leaf: &Leaf<LeafIrohStore>,
-) -> std::result::Result<RespKind, anyhow::Error> {
+async fn create_subspace(leaf: &LeafServer) -> std::result::Result<RespKind, anyhow::Error> {
     Ok(RespKind::CreateSubspace(leaf.create_subspace().await?))
 }
 async fn import_subspace_secret(
-    leaf: &Leaf<LeafIrohStore>,
+    leaf: &LeafServer,
     secret: [u8; 32],
 ) -> std::result::Result<RespKind, anyhow::Error> {
     Ok(RespKind::ImportSubspaceSecret(
@@ -256,7 +651,7 @@ async fn import_subspace_secret(
     ))
 }
 async fn get_subspace_secret(
-    leaf: &Leaf<LeafIrohStore>,
+    leaf: &LeafServer,
     subspace: [u8; 32],
 ) -> std::result::Result<RespKind, anyhow::Error> {
     Ok(RespKind::GetSubspaceSecret(
@@ -338,7 +733,7 @@ async fn list_local_secrets(
     .map_err(|_| anyhow::format_err!("Error executing database operation"))?
 }
 
-async fn create_database_dump(leaf: &Leaf<LeafIrohStore>) -> anyhow::Result<RespKind> {
+async fn create_database_dump(leaf: &LeafServer) -> anyhow::Result<RespKind> {
     let mut dump = DatabaseDump::default();
 
     let mut stream = leaf.list_subspaces().await?;
@@ -373,7 +768,7 @@ async fn create_database_dump(leaf: &Leaf<LeafIrohStore>) -> anyhow::Result<Resp
                 subspace,
                 path: EntityPath::default(),
             };
-            let stream = leaf.list(link).await?;
+            let stream = leaf.list(link, ListMode::Descendants).await?;
             pin_mut!(stream);
             while let Some(link) = stream.next().await {
                 let link = link?;
@@ -402,10 +797,7 @@ async fn create_database_dump(leaf: &Leaf<LeafIrohStore>) -> anyhow::Result<Resp
     Ok(RespKind::CreateDatabaseDump(dump))
 }
 
-async fn restore_database_dump(
-    leaf: &Leaf<LeafIrohStore>,
-    dump: DatabaseDump,
-) -> anyhow::Result<RespKind> {
+async fn restore_database_dump(leaf: &LeafServer, dump: DatabaseDump) -> anyhow::Result<RespKind> {
     for (subspace, secret) in dump.subspace_secrets {
         let s = leaf.import_subspace_secret(secret).await?;
         if s != subspace {
@@ -419,7 +811,13 @@ async fn restore_database_dump(
         }
     }
 
-    for (namespace, doc) in dump.documents {
+    // Restore the published schemas first, because they are needed to pin the blobs referenced
+    // by the other components.
+    let schema_namespace = leaf.schema_link(Digest::default()).namespace;
+    let mut documents = dump.documents.into_iter().collect::<Vec<_>>();
+    documents.sort_by_key(|(namespace, _)| *namespace != schema_namespace);
+
+    for (namespace, doc) in documents {
         let n = leaf.import_subspace_secret(doc.secret).await?;
         if n != namespace {
             tracing::warn!(
@@ -445,7 +843,8 @@ async fn restore_database_dump(
                         ent.add_component_data(ComponentKind::Unencrypted(ComponentData {
                             schema,
                             data,
-                        }));
+                        }))
+                        .await?;
                     }
                 }
                 ent.save().await?;
@@ -465,9 +864,7 @@ async fn restore_database_dump(
     Ok(RespKind::RestoreDatabaseDump)
 }
 
-async fn list_namespaces(
-    leaf: &Leaf<LeafIrohStore>,
-) -> std::result::Result<RespKind, anyhow::Error> {
+async fn list_namespaces(leaf: &LeafServer) -> std::result::Result<RespKind, anyhow::Error> {
     let namespaces = leaf
         .list_namespaces()
         .await?
@@ -478,9 +875,7 @@ async fn list_namespaces(
     Ok(RespKind::ListNamespaces(namespaces))
 }
 
-async fn list_subspaces(
-    leaf: &Leaf<LeafIrohStore>,
-) -> std::result::Result<RespKind, anyhow::Error> {
+async fn list_subspaces(leaf: &LeafServer) -> std::result::Result<RespKind, anyhow::Error> {
     let subspaces = leaf
         .list_subspaces()
         .await?
@@ -490,3 +885,472 @@ async fn list_subspaces(
         .collect::<Result<Vec<_>, _>>()?;
     Ok(RespKind::ListSubspaces(subspaces))
 }
+
+async fn publish_schema(
+    leaf: &LeafServer,
+    schema_id: Digest,
+    schema: Schema,
+) -> anyhow::Result<RespKind> {
+    leaf.publish_schema_data(schema_id, schema).await?;
+    Ok(RespKind::PublishSchema)
+}
+
+async fn get_schema(leaf: &LeafServer, schema_id: Digest) -> anyhow::Result<RespKind> {
+    Ok(RespKind::GetSchema(leaf.get_schema(schema_id).await?))
+}
+
+async fn gc(leaf: &LeafServer) -> anyhow::Result<RespKind> {
+    Ok(RespKind::Gc(leaf.gc().await?))
+}
+
+async fn batch(leaf: &LeafServer, ops: Vec<BatchOp>) -> anyhow::Result<RespKind> {
+    let components = ops.iter().enumerate().filter_map(|(i, op)| match op {
+        BatchOp::AddComponent { component, .. } => Some((i, component)),
+        _ => None,
+    });
+    let rejected = validate_components(leaf, components).await?;
+    if !rejected.is_empty() {
+        return Ok(RespKind::InvalidComponents(rejected));
+    }
+
+    let mut batch = leaf.batch();
+    for op in ops {
+        batch.op(op);
+    }
+    match batch.commit().await {
+        Ok(digests) => Ok(RespKind::Batch(digests)),
+        Err(e) => Ok(RespKind::SnapshotConflict(
+            e.downcast::<BatchConflict>()?.conflict,
+        )),
+    }
+}
+
+async fn copy_entity(
+    leaf: &LeafServer,
+    from: ExactLink,
+    to: ExactLink,
+    recursive: bool,
+) -> anyhow::Result<RespKind> {
+    Ok(RespKind::CopyEntity(leaf.copy(from, to, recursive).await?))
+}
+
+async fn move_entity(
+    leaf: &LeafServer,
+    from: ExactLink,
+    to: ExactLink,
+    recursive: bool,
+) -> anyhow::Result<RespKind> {
+    Ok(RespKind::MoveEntity(
+        leaf.move_entity(from, to, recursive).await?,
+    ))
+}
+
+async fn find_by_schema(
+    leaf: &LeafServer,
+    namespace: NamespaceId,
+    subspace: Option<SubspaceId>,
+    schema: Digest,
+) -> anyhow::Result<RespKind> {
+    let links = leaf
+        .find_by_schema(namespace, subspace, schema)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(RespKind::FindBySchema(links))
+}
+
+async fn register_index(
+    leaf: &LeafServer,
+    schema: Digest,
+    field_path: Vec<String>,
+    unique: bool,
+) -> anyhow::Result<RespKind> {
+    let Some(published) = leaf.get_schema(schema).await? else {
+        anyhow::bail!("Schema {schema} has not been published");
+    };
+    let index = leaf
+        .register_index_with_schema(schema, published.format, field_path, unique)
+        .await?;
+    Ok(RespKind::RegisterIndex(index.id()))
+}
+
+fn search(
+    leaf: &LeafServer,
+    namespace: NamespaceId,
+    query: &str,
+    limit: u64,
+) -> anyhow::Result<RespKind> {
+    let limit = limit.min(MAX_SEARCH_RESULTS) as usize;
+    Ok(RespKind::Search(
+        leaf.store.search.search(namespace, query, limit),
+    ))
+}
+
+async fn read_blob(
+    leaf: &LeafServer,
+    digest: Digest,
+    mut range: BlobRange,
+) -> anyhow::Result<RespKind> {
+    let Some(size) = leaf.store.blob_size(digest).await? else {
+        anyhow::bail!("Blob not found: {digest}");
+    };
+    range.len = Some(range.len.unwrap_or(u64::MAX).min(MAX_BLOB_READ_SIZE));
+    let data = leaf
+        .store
+        .get_blob_reader(digest, range)
+        .await?
+        .try_concat()
+        .await?;
+    Ok(RespKind::ReadBlob { size, data })
+}
+
+async fn rebuild_search_index(leaf: &LeafServer) -> anyhow::Result<RespKind> {
+    let count = leaf.store.search.rebuild(&leaf.store).await?;
+    Ok(RespKind::RebuildSearchIndex(count as u64))
+}
+
+async fn lookup_index(
+    leaf: &LeafServer,
+    namespace: NamespaceId,
+    index: Digest,
+    value: Vec<u8>,
+) -> anyhow::Result<RespKind> {
+    Ok(RespKind::LookupIndex(
+        leaf.lookup_bytes(namespace, index, &value).await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use leaf_protocol::types::Blob;
+
+    use super::*;
+    use crate::test_util::leaf;
+
+    async fn req(leaf: &LeafServer, kind: ReqKind) -> RespKind {
+        let resp = handle_req(leaf, Arc::new(None), Req { id: 0, kind }).await;
+        resp.result.unwrap()
+    }
+
+    async fn upload(leaf: &LeafServer, link: &ExactLink, data: &[u8]) -> Digest {
+        let mut upload = BlobUpload::start(leaf.clone(), link.clone());
+        upload.write(data.to_vec()).await.unwrap();
+        upload.finish().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn schemas_must_match_their_ids() {
+        use leaf_protocol::{components::Name, Component};
+        let leaf = leaf();
+        let schema_id = Digest::new(b"not the schema");
+        let kind = ReqKind::PublishSchema {
+            schema_id,
+            schema: Name::schema(),
+        };
+        let resp = handle_req(&leaf, Arc::new(None), Req { id: 0, kind }).await;
+        assert!(resp.result.is_err());
+        let resp = req(&leaf, ReqKind::GetSchema(schema_id)).await;
+        assert!(matches!(resp, RespKind::GetSchema(None)));
+
+        let kind = ReqKind::PublishSchema {
+            schema_id: Name::schema_id(),
+            schema: Name::schema(),
+        };
+        assert!(matches!(req(&leaf, kind).await, RespKind::PublishSchema));
+    }
+
+    #[tokio::test]
+    async fn components_with_unpublished_schemas_are_accepted() {
+        let leaf = leaf();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let link: ExactLink = (namespace, subspace, ["profile"]).into();
+        let component = ComponentData {
+            schema: Digest::new(b"unpublished"),
+            data: b"data".to_vec(),
+        };
+
+        let resp = req(
+            &leaf,
+            ReqKind::AddComponents {
+                link: link.clone(),
+                components: vec![component.clone()],
+                replace_existing: true,
+                expected_digest: None,
+            },
+        )
+        .await;
+        assert!(matches!(resp, RespKind::AddComponents(_)));
+
+        let op = BatchOp::AddComponent {
+            link: link.clone(),
+            component: component.clone(),
+        };
+        let resp = req(&leaf, ReqKind::Batch(vec![op])).await;
+        assert!(matches!(resp, RespKind::Batch(_)));
+
+        let entity = leaf.entity(link).await.unwrap().entity().unwrap();
+        let components = entity
+            .get_components_by_schema(component.schema)
+            .await
+            .unwrap();
+        assert_eq!(components, [component.data]);
+    }
+
+    #[tokio::test]
+    async fn entity_responses_include_metadata() {
+        let leaf = leaf();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let parent: ExactLink = (namespace, subspace, ["parent"]).into();
+        let mut link = parent.clone();
+        link.path.0.push("child".into());
+        let resp = req(
+            &leaf,
+            ReqKind::AddComponents {
+                link: link.clone(),
+                components: vec![Name("name".into()).make_data().unwrap()],
+                replace_existing: true,
+                expected_digest: None,
+            },
+        )
+        .await;
+        assert!(matches!(resp, RespKind::AddComponents(_)));
+
+        let RespKind::ReadEntity(Some((digest, _, meta))) =
+            req(&leaf, ReqKind::ReadEntity(link.clone())).await
+        else {
+            panic!("Expected the entity");
+        };
+        assert_eq!(meta.author_subspace, subspace);
+        assert!(meta.last_modified > 0);
+        let snapshot = leaf.store.get_blob(digest).await.unwrap();
+        assert_eq!(meta.size, snapshot.len() as u64);
+
+        let resp = req(
+            &leaf,
+            ReqKind::ListEntities {
+                link: parent,
+                mode: ListMode::Children,
+                limit: 10,
+                cursor: None,
+            },
+        )
+        .await;
+        let RespKind::ListEntities(page) = resp else {
+            panic!("Expected a list page");
+        };
+        assert_eq!(page.links, [link]);
+        assert_eq!(page.meta, [meta]);
+    }
+
+    #[tokio::test]
+    async fn stale_expected_digest_is_a_snapshot_conflict() {
+        let leaf = leaf();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let link: ExactLink = (namespace, subspace, ["profile"]).into();
+        let add = |expected_digest| ReqKind::AddComponents {
+            link: link.clone(),
+            components: vec![Name("name".into()).make_data().unwrap()],
+            replace_existing: true,
+            expected_digest,
+        };
+
+        let RespKind::AddComponents(digest) = req(&leaf, add(Some(Digest::default()))).await else {
+            panic!("Expected the entity to be created");
+        };
+        let RespKind::SnapshotConflict(conflict) = req(&leaf, add(Some(Digest::default()))).await
+        else {
+            panic!("Expected a snapshot conflict");
+        };
+        assert_eq!(conflict.expected, Digest::default());
+        assert_eq!(conflict.actual, Some(digest));
+
+        let resp = req(
+            &leaf,
+            ReqKind::DelComponentsBySchema {
+                link: link.clone(),
+                schemas: vec![Name::schema_id()],
+                expected_digest: Some(Digest::new(b"stale")),
+            },
+        )
+        .await;
+        let RespKind::SnapshotConflict(conflict) = resp else {
+            panic!("Expected a snapshot conflict");
+        };
+        assert_eq!(conflict.actual, Some(digest));
+        assert_eq!(leaf.store.get_entity(&link).await.unwrap(), Some(digest));
+    }
+
+    #[tokio::test]
+    async fn failed_batch_expectations_are_snapshot_conflicts() {
+        let leaf = leaf();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let a: ExactLink = (namespace, subspace, ["a"]).into();
+        let b: ExactLink = (namespace, subspace, ["b"]).into();
+        let ops = vec![
+            BatchOp::AddComponent {
+                link: a.clone(),
+                component: Name("a".into()).make_data().unwrap(),
+            },
+            BatchOp::Expect {
+                link: b.clone(),
+                digest: Digest::new(b"stale"),
+            },
+        ];
+
+        let RespKind::SnapshotConflict(conflict) = req(&leaf, ReqKind::Batch(ops)).await else {
+            panic!("Expected a snapshot conflict");
+        };
+        assert_eq!(conflict.link, b);
+        assert_eq!(conflict.expected, Digest::new(b"stale"));
+        assert_eq!(conflict.actual, None);
+        assert_eq!(leaf.store.get_entity(&a).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn deleting_from_a_missing_entity_checks_the_expected_digest() {
+        let leaf = leaf();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let link: ExactLink = (namespace, subspace, ["missing"]).into();
+        let del = |expected_digest| ReqKind::DelComponentsBySchema {
+            link: link.clone(),
+            schemas: vec![Name::schema_id()],
+            expected_digest,
+        };
+
+        let RespKind::SnapshotConflict(conflict) =
+            req(&leaf, del(Some(Digest::new(b"stale")))).await
+        else {
+            panic!("Expected a snapshot conflict");
+        };
+        assert_eq!(conflict.expected, Digest::new(b"stale"));
+        assert_eq!(conflict.actual, None);
+
+        // Expecting the entity not to exist, or not expecting anything, isn't a conflict.
+        let resp = req(&leaf, del(Some(Digest::default()))).await;
+        assert!(matches!(resp, RespKind::DelComponentBySchema(None)));
+        let resp = req(&leaf, del(None)).await;
+        assert!(matches!(resp, RespKind::DelComponentBySchema(None)));
+    }
+
+    #[tokio::test]
+    async fn entities_can_be_read_at_old_snapshots() {
+        let leaf = leaf();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let link: ExactLink = (namespace, subspace, ["page"]).into();
+        let mut digests = Vec::new();
+        for name in ["old", "new"] {
+            let resp = req(
+                &leaf,
+                ReqKind::AddComponents {
+                    link: link.clone(),
+                    components: vec![Name(name.into()).make_data().unwrap()],
+                    replace_existing: true,
+                    expected_digest: None,
+                },
+            )
+            .await;
+            let RespKind::AddComponents(digest) = resp else {
+                panic!("Expected the entity to be saved");
+            };
+            digests.push(digest);
+        }
+
+        let read_at = |snapshot| ReqKind::ReadEntityAt {
+            link: link.clone(),
+            snapshot,
+        };
+        let RespKind::ReadEntityAt(Some(entity)) = req(&leaf, read_at(digests[0])).await else {
+            panic!("Expected the old entity");
+        };
+        let old = leaf
+            .entity_at(link.clone(), digests[0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entity.components, old.entity.components);
+
+        let components_at = |snapshot| ReqKind::GetComponentsBySchemaAt {
+            link: link.clone(),
+            snapshot,
+            schemas: vec![Name::schema_id()],
+        };
+        let RespKind::GetComponentsBySchemaAt(Some(inner)) =
+            req(&leaf, components_at(digests[0])).await
+        else {
+            panic!("Expected the old components");
+        };
+        assert_eq!(inner.entity_digest, digests[0]);
+        let names = &inner.components[&Name::schema_id()];
+        assert_eq!(names.len(), 1);
+        assert_eq!(Name::deserialize(&mut &names[0][..]).unwrap().0, "old");
+
+        let unknown = Digest::new(b"unknown");
+        let resp = req(&leaf, read_at(unknown)).await;
+        assert!(matches!(resp, RespKind::ReadEntityAt(None)));
+        let resp = req(&leaf, components_at(unknown)).await;
+        assert!(matches!(resp, RespKind::GetComponentsBySchemaAt(None)));
+    }
+
+    #[tokio::test]
+    async fn uploaded_blob_survives_gc_once_referenced() {
+        let leaf = leaf();
+        leaf.publish_standard_schemas().await.unwrap();
+        let namespace = leaf.create_namespace().await.unwrap();
+        let subspace = leaf.create_subspace().await.unwrap();
+        let link: ExactLink = (namespace, subspace, ["post"]).into();
+
+        let orphan = upload(&leaf, &link, b"orphan").await;
+        let digest = upload(&leaf, &link, b"image").await;
+        let image = Image {
+            mime_type: "image/png".into(),
+            size: ImageSize {
+                width: 1,
+                height: 1,
+            },
+            data: Blob(digest),
+        };
+        let resp = req(
+            &leaf,
+            ReqKind::AddComponents {
+                link,
+                components: vec![image.make_data().unwrap()],
+                replace_existing: false,
+                expected_digest: None,
+            },
+        )
+        .await;
+        assert!(matches!(resp, RespKind::AddComponents(_)));
+
+        let RespKind::Gc(report) = req(&leaf, ReqKind::Gc).await else {
+            panic!("Expected a GC report");
+        };
+        assert_eq!(report.pins_removed, 2);
+
+        let range = BlobRange::default();
+        let resp = req(&leaf, ReqKind::ReadBlob { digest, range }).await;
+        let RespKind::ReadBlob { size, data } = resp else {
+            panic!("Expected blob data");
+        };
+        assert_eq!((size, &data[..]), (5, &b"image"[..]));
+
+        let resp = handle_req(
+            &leaf,
+            Arc::new(None),
+            Req {
+                id: 0,
+                kind: ReqKind::ReadBlob {
+                    digest: orphan,
+                    range,
+                },
+            },
+        )
+        .await;
+        assert!(resp.result.is_err());
+    }
+}
@@ -0,0 +1,561 @@
+//! Full-text search over the text components of entities.
+//!
+//! The index is kept in memory. It is updated by [`ServerStore`][crate::store::ServerStore]
+//! whenever an entity is stored or deleted, follows the changes that are synced from peers with
+//! [`SearchIndex::follow()`], and is rebuilt from the store when the server starts.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use futures::{pin_mut, TryStreamExt};
+use leaf_protocol::{
+    borsh::BorshDeserialize,
+    prelude::*,
+    store::{LeafStore, ListMode},
+};
+use leaf_rpc_proto::SearchResult;
+
+/// BM25 term frequency saturation.
+const K1: f32 = 1.2;
+/// BM25 document length normalization.
+const B: f32 = 0.75;
+/// How much less a query term counts when it only matches the start of a word.
+const PREFIX_MATCH_WEIGHT: f32 = 0.5;
+/// The rough number of characters in a search result snippet.
+const SNIPPET_LEN: usize = 160;
+/// How many characters of context to show before the match in a snippet.
+const SNIPPET_LEAD: usize = 40;
+
+/// Returns whether components with the given schema are indexed for search.
+///
+/// These are all components that are just a string.
+fn is_text_schema(schema: Digest) -> bool {
+    schema == Utf8::schema_id()
+        || schema == Name::schema_id()
+        || schema == Description::schema_id()
+        || schema == CommonMark::schema_id()
+}
+
+/// An in-memory full-text index of the text components of entities, by namespace.
+///
+/// Clones share the same index.
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    state: Arc<RwLock<IndexState>>,
+    /// Held while the index is being rebuilt, so that only one rebuild runs at a time.
+    rebuilding: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Debug, Default)]
+struct IndexState {
+    namespaces: HashMap<NamespaceId, NamespaceIndex>,
+    /// The entities that have been indexed since the running rebuild started, if there is one.
+    ///
+    /// The rebuild may have read older snapshots of these, so their live entries are kept when the
+    /// rebuilt index is swapped in.
+    changed_during_rebuild: Option<HashSet<ExactLink>>,
+}
+
+impl IndexState {
+    /// Set the text of the entity at the link, or remove it from the index if it has none.
+    fn set(&mut self, link: &ExactLink, text: Option<String>) {
+        if let Some(changed) = &mut self.changed_during_rebuild {
+            changed.insert(link.clone());
+        }
+        let index = self.namespaces.entry(link.namespace).or_default();
+        match text {
+            Some(text) => index.insert(link.clone(), text),
+            None => index.remove(link),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NamespaceIndex {
+    documents: HashMap<ExactLink, Document>,
+    /// The number of times each term appears in each document.
+    postings: BTreeMap<String, HashMap<ExactLink, u32>>,
+    /// The total number of terms in all of the documents, for computing the average length.
+    total_len: u64,
+}
+
+#[derive(Debug)]
+struct Document {
+    text: String,
+    len: u32,
+    terms: HashMap<String, u32>,
+}
+
+impl NamespaceIndex {
+    fn insert(&mut self, link: ExactLink, text: String) {
+        self.remove(&link);
+        let mut terms = HashMap::<String, u32>::new();
+        let mut len = 0;
+        for term in tokenize(&text) {
+            *terms.entry(term).or_default() += 1;
+            len += 1;
+        }
+        for (term, &count) in &terms {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(link.clone(), count);
+        }
+        self.total_len += len as u64;
+        self.documents.insert(link, Document { text, len, terms });
+    }
+
+    fn remove(&mut self, link: &ExactLink) {
+        let Some(document) = self.documents.remove(link) else {
+            return;
+        };
+        for term in document.terms.keys() {
+            if let Some(postings) = self.postings.get_mut(term) {
+                postings.remove(link);
+                if postings.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_len -= document.len as u64;
+    }
+
+    /// Score the documents containing every one of the query terms with BM25.
+    ///
+    /// Each query term also matches words that start with it, so that results show up while
+    /// someone is still typing.
+    fn score(&self, query: &[String]) -> HashMap<&ExactLink, f32> {
+        let document_count = self.documents.len() as f32;
+        let average_len = self.total_len as f32 / document_count.max(1.0);
+
+        let mut scores: Option<HashMap<&ExactLink, f32>> = None;
+        for query_term in query {
+            let mut term_scores = HashMap::<&ExactLink, f32>::new();
+            let matches = self
+                .postings
+                .range(query_term.clone()..)
+                .take_while(|(term, _)| term.starts_with(query_term.as_str()));
+            for (term, postings) in matches {
+                let weight = if term == query_term {
+                    1.0
+                } else {
+                    PREFIX_MATCH_WEIGHT
+                };
+                let df = postings.len() as f32;
+                let idf = (1.0 + (document_count - df + 0.5) / (df + 0.5)).ln();
+                for (link, &tf) in postings {
+                    let tf = tf as f32;
+                    let len = self.documents[link].len as f32;
+                    let score = weight * idf * tf * (K1 + 1.0)
+                        / (tf + K1 * (1.0 - B + B * len / average_len));
+                    let best = term_scores.entry(link).or_default();
+                    *best = best.max(score);
+                }
+            }
+
+            scores = Some(match scores {
+                None => term_scores,
+                Some(mut scores) => {
+                    scores.retain(|link, score| match term_scores.get(link) {
+                        Some(term_score) => {
+                            *score += term_score;
+                            true
+                        }
+                        None => false,
+                    });
+                    scores
+                }
+            });
+        }
+        scores.unwrap_or_default()
+    }
+}
+
+impl SearchIndex {
+    /// Update the index for the entity at the link, which has just been stored.
+    ///
+    /// Text components that can't be loaded are left out of the index.
+    pub async fn index_entity<S: LeafStore>(
+        &self,
+        store: &S,
+        link: &ExactLink,
+        entity: &Entity,
+    ) -> anyhow::Result<()> {
+        let text = entity_text(store, link, entity).await;
+        self.state.write().unwrap().set(link, text);
+        Ok(())
+    }
+
+    /// Remove the entity at the link from the index.
+    pub fn remove(&self, link: &ExactLink) {
+        self.state.write().unwrap().set(link, None);
+    }
+
+    /// Keep the index up to date with the entities in the namespace, in every subspace, until the
+    /// store stops reporting changes.
+    ///
+    /// This picks up entities that are synced from peers, which don't go through
+    /// [`ServerStore`][crate::store::ServerStore]'s write methods.
+    pub async fn follow<S: LeafStore>(
+        &self,
+        store: &S,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<()> {
+        let updates = store.watch_namespace(namespace).await?;
+        pin_mut!(updates);
+        while let Some(update) = updates.try_next().await? {
+            // Index the entity's current snapshot rather than the update's, so that a late update
+            // can't replace newer text.
+            let reindexed = match store.get_entity(&update.link).await {
+                Ok(Some(snapshot)) => match load_entity(store, snapshot).await {
+                    Ok(entity) => self.index_entity(store, &update.link, &entity).await,
+                    Err(e) => Err(e),
+                },
+                Ok(None) => {
+                    self.remove(&update.link);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = reindexed {
+                tracing::warn!("Could not index entity {:?} for search: {e}", update.link);
+            }
+        }
+        Ok(())
+    }
+
+    /// Search the entities in the namespace, returning up to `limit` of the best matches.
+    pub fn search(&self, namespace: NamespaceId, query: &str, limit: usize) -> Vec<SearchResult> {
+        let query = tokenize(query).collect::<Vec<_>>();
+        let state = self.state.read().unwrap();
+        let Some(index) = state.namespaces.get(&namespace) else {
+            return Vec::new();
+        };
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut scores = index.score(&query).into_iter().collect::<Vec<_>>();
+        scores.sort_by(|(link_a, a), (link_b, b)| b.total_cmp(a).then_with(|| link_a.cmp(link_b)));
+        scores.truncate(limit);
+        scores
+            .into_iter()
+            .map(|(link, score)| SearchResult {
+                link: link.clone(),
+                score,
+                snippet: snippet(&index.documents[link].text, &query),
+            })
+            .collect()
+    }
+
+    /// Replace the index with the text components of all of the entities in the store, in every
+    /// subspace.
+    ///
+    /// The new index is built on the side and swapped in at the end, so searches keep using the
+    /// old one in the meantime. Entities that are indexed while the rebuild is running keep their
+    /// newer entries.
+    ///
+    /// Entities that can't be loaded are logged and skipped. Returns the number of entities that
+    /// were indexed.
+    pub async fn rebuild<S: LeafStore>(&self, store: &S) -> anyhow::Result<usize> {
+        let _rebuilding = self.rebuilding.lock().await;
+        self.state.write().unwrap().changed_during_rebuild = Some(HashSet::new());
+        let rebuilt = read_all_entities(store).await;
+
+        let mut state = self.state.write().unwrap();
+        let changed = state.changed_during_rebuild.take().unwrap_or_default();
+        let (mut namespaces, indexed) = rebuilt?;
+        for link in changed {
+            let live = state
+                .namespaces
+                .get(&link.namespace)
+                .and_then(|index| index.documents.get(&link));
+            let index = namespaces.entry(link.namespace).or_default();
+            match live {
+                Some(document) => index.insert(link, document.text.clone()),
+                None => index.remove(&link),
+            }
+        }
+        state.namespaces = namespaces;
+        Ok(indexed)
+    }
+}
+
+/// Build a new index of all of the entities in the store, returning it along with the number of
+/// entities that were indexed.
+async fn read_all_entities<S: LeafStore>(
+    store: &S,
+) -> anyhow::Result<(HashMap<NamespaceId, NamespaceIndex>, usize)> {
+    let namespaces = store
+        .list_namespaces()
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    let mut indexes = HashMap::<NamespaceId, NamespaceIndex>::new();
+    let mut indexed = 0;
+    for namespace in namespaces {
+        let subspaces = store
+            .list_namespace_subspaces(namespace)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for subspace in subspaces {
+            let link = ExactLink {
+                namespace,
+                subspace,
+                path: EntityPath::default(),
+            };
+            let links = store
+                .list(link, ListMode::Descendants, None, None, None)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+            for link in links {
+                let Some(snapshot) = store.get_entity(&link).await? else {
+                    continue;
+                };
+                // One bad entity shouldn't keep the rest out of the index.
+                let entity = match load_entity(store, snapshot).await {
+                    Ok(entity) => entity,
+                    Err(e) => {
+                        tracing::warn!("Skipping entity {link:?} in the search index: {e}");
+                        continue;
+                    }
+                };
+                if let Some(text) = entity_text(store, &link, &entity).await {
+                    indexes.entry(namespace).or_default().insert(link, text);
+                }
+                indexed += 1;
+            }
+        }
+    }
+    Ok((indexes, indexed))
+}
+
+/// Load an entity snapshot.
+async fn load_entity<S: LeafStore>(store: &S, snapshot: Digest) -> anyhow::Result<Entity> {
+    let bytes = store.get_blob(snapshot).await?;
+    Ok(Entity::deserialize(&mut &bytes[..])?)
+}
+
+/// Load the text of the entity's text components, joined together, or [`None`] if it has none.
+///
+/// Text components that can't be loaded are logged and left out.
+async fn entity_text<S: LeafStore>(store: &S, link: &ExactLink, entity: &Entity) -> Option<String> {
+    let mut texts = Vec::new();
+    for entry in &entity.components {
+        if !entry.schema_id.is_some_and(is_text_schema) {
+            continue;
+        }
+        match component_text(store, entry.component_id).await {
+            Ok(Some(text)) => texts.push(text),
+            Ok(None) => (),
+            Err(e) => tracing::warn!(
+                "Skipping component {} of entity {link:?} in the search index: {e}",
+                entry.component_id
+            ),
+        }
+    }
+    (!texts.is_empty()).then(|| texts.join("\n"))
+}
+
+/// Load the text of a text component, or [`None`] if it is encrypted.
+async fn component_text<S: LeafStore>(
+    store: &S,
+    component_id: Digest,
+) -> anyhow::Result<Option<String>> {
+    let data = store.get_blob(component_id).await?;
+    let ComponentKind::Unencrypted(component) = ComponentKind::deserialize(&mut &data[..])? else {
+        return Ok(None);
+    };
+    Ok(Some(String::deserialize(&mut &component.data[..])?))
+}
+
+/// Split text into lowercase words.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+/// Cut out the part of the text around the first word that matches the query.
+fn snippet(text: &str, query: &[String]) -> String {
+    let text = text.trim();
+    let match_offset = text
+        .char_indices()
+        .filter(|&(i, c)| {
+            c.is_alphanumeric()
+                && !text[..i]
+                    .chars()
+                    .next_back()
+                    .is_some_and(char::is_alphanumeric)
+        })
+        .map(|(i, _)| i)
+        .find(|&i| {
+            let word = text[i..]
+                .split(|c: char| !c.is_alphanumeric())
+                .next()
+                .unwrap_or_default()
+                .to_lowercase();
+            query.iter().any(|term| word.starts_with(term.as_str()))
+        })
+        .unwrap_or(0);
+
+    // Start a little before the match, at the beginning of a word.
+    let lead_start = text[..match_offset]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_LEAD)
+        .map_or(0, |(i, _)| i);
+    let start = if lead_start == 0 {
+        0
+    } else {
+        text[lead_start..match_offset]
+            .find(char::is_whitespace)
+            .map_or(match_offset, |i| lead_start + i + 1)
+    };
+    let end = text[start..]
+        .char_indices()
+        .nth(SNIPPET_LEN)
+        .map_or(text.len(), |(i, _)| start + i);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(text[start..end].trim());
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use leaf_protocol::borsh;
+
+    use super::*;
+    use crate::{
+        store::{BackendStore, ServerStore},
+        test_util::{leaf, link, redb_store, save_name},
+    };
+
+    #[tokio::test]
+    async fn rebuild_skips_entities_that_cannot_be_loaded() {
+        let leaf = leaf();
+        let link = |path| link(&leaf, path);
+
+        save_name(&leaf, &link("a").await, "apple").await;
+        leaf.store
+            .store_entity(&link("b").await, b"not an entity".to_vec())
+            .await
+            .unwrap();
+        save_name(&leaf, &link("c").await, "apricot").await;
+
+        // An entity with one broken text component still has the rest of its text indexed.
+        let partial = link("d").await;
+        save_name(&leaf, &partial, "avocado").await;
+        let snapshot = leaf.store.get_entity(&partial).await.unwrap().unwrap();
+        let bytes = leaf.store.get_blob(snapshot).await.unwrap();
+        let mut entity = Entity::deserialize(&mut &bytes[..]).unwrap();
+        let garbage = leaf
+            .store
+            .store_blob(b"garbage", &partial, snapshot)
+            .await
+            .unwrap();
+        entity.components.push(ComponentEntry {
+            schema_id: Some(Description::schema_id()),
+            component_id: garbage,
+        });
+        entity.sort_components();
+        leaf.store
+            .store_entity(&partial, borsh::to_vec(&entity).unwrap())
+            .await
+            .unwrap();
+
+        // Entities in subspaces that we don't have the secret for, like ones synced from peers,
+        // are indexed too.
+        let mut peer = link("e").await;
+        peer.subspace = [9; 32];
+        save_name(&leaf, &peer, "almond").await;
+
+        let search = SearchIndex::default();
+        assert_eq!(search.rebuild(&leaf.store).await.unwrap(), 4);
+        let mut found = search
+            .search(peer.namespace, "a", 10)
+            .into_iter()
+            .map(|x| x.link.path.0[0].clone())
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["a".into(), "c".into(), "d".into(), "e".into()]);
+    }
+
+    #[tokio::test]
+    async fn search_follows_saves_and_deletes() {
+        let leaf = leaf();
+        let root = link(&leaf, "").await;
+        let link = |path: &str| ExactLink::from((root.namespace, root.subspace, [path]));
+        for (path, name) in [
+            ("a", "apple"),
+            ("b", "apple pie"),
+            ("c", "banana"),
+            ("d", "pineapple"),
+        ] {
+            save_name(&leaf, &link(path), name).await;
+        }
+        let search = |query: &str| {
+            leaf.store
+                .search
+                .search(root.namespace, query, 10)
+                .into_iter()
+                .map(|x| x.link)
+                .collect::<Vec<_>>()
+        };
+
+        // Shorter documents rank higher, and words only match from their start.
+        assert_eq!(search("apple"), [link("a"), link("b")]);
+        assert_eq!(search("APP"), [link("a"), link("b")]);
+        // Every query term has to match.
+        assert_eq!(search("apple pie"), [link("b")]);
+        assert_eq!(search("pi"), [link("d"), link("b")]);
+        assert!(search("cherry").is_empty());
+        let results = leaf.store.search.search(root.namespace, "banana", 10);
+        assert_eq!(results[0].snippet, "banana");
+
+        leaf.del_entity(link("a")).await.unwrap();
+        assert_eq!(search("apple"), [link("b")]);
+    }
+
+    #[tokio::test]
+    async fn search_follows_entities_written_around_the_server() {
+        let store = redb_store();
+        let leaf = Leaf::new(ServerStore::new(BackendStore::Redb(store.clone())));
+        // Writing to the backend directly stands in for syncing entities from a peer.
+        let peer = Leaf::new(store);
+        let link = link(&leaf, "a").await;
+        let follower = leaf.store.clone();
+        let follow = tokio::spawn(async move {
+            follower
+                .search
+                .follow(&follower, link.namespace)
+                .await
+                .unwrap()
+        });
+        tokio::task::yield_now().await;
+
+        let search = || async {
+            // Give the follower a chance to catch up.
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            leaf.store.search.search(link.namespace, "apple", 10)
+        };
+        save_name(&peer, &link, "apple").await;
+        assert_eq!(search().await[0].link, link);
+        peer.del_entity(link.clone()).await.unwrap();
+        assert!(search().await.is_empty());
+        follow.abort();
+    }
+}
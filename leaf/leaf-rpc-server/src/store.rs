@@ -0,0 +1,373 @@
+//! The [`LeafStore`] used by the server, which is backed by whichever store was selected with the
+//! `--backend` flag.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use futures::{future::Either, Stream};
+use leaf_protocol::{
+    borsh::BorshDeserialize,
+    prelude::*,
+    store::{BlobRange, EntityUpdate, EntityWrite, GcReport, LeafStore, ListMode},
+};
+
+use crate::search::SearchIndex;
+
+pub type LeafServer = Leaf<ServerStore>;
+
+/// The store backends that the server can run with.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Store data in an Iroh node, which can sync with other peers.
+    Iroh,
+    /// Store data in a local redb database, without peer-to-peer sync.
+    Redb,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerStore {
+    backend: BackendStore,
+    /// The full-text search index, which is updated whenever an entity is stored or deleted.
+    pub search: SearchIndex,
+    /// The namespaces that the search index is following, to pick up entities synced from peers.
+    followed: Arc<Mutex<HashSet<NamespaceId>>>,
+}
+
+/// The store for whichever [`Backend`] we are using.
+#[derive(Debug, Clone)]
+pub enum BackendStore {
+    Iroh(LeafIrohStore),
+    Redb(LeafRedbStore),
+}
+
+impl ServerStore {
+    pub fn new(backend: BackendStore) -> Self {
+        Self {
+            backend,
+            search: Default::default(),
+            followed: Default::default(),
+        }
+    }
+
+    /// Start keeping the search index up to date with the entities in the namespace that are
+    /// synced from peers, if we aren't already.
+    ///
+    /// Only the Iroh backend syncs with peers. Everything that the other backends store is written
+    /// through this store, which indexes it as it goes.
+    pub fn follow_namespace(&self, namespace: NamespaceId) {
+        if !matches!(self.backend, BackendStore::Iroh(_))
+            || !self.followed.lock().unwrap().insert(namespace)
+        {
+            return;
+        }
+        let store = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = store.search.follow(&store, namespace).await {
+                tracing::error!("Stopped following namespace {namespace:?} for search: {e}");
+            }
+            store.followed.lock().unwrap().remove(&namespace);
+        });
+    }
+
+    /// Add an entity that has just been stored to the search index.
+    ///
+    /// The entity has already been stored, so a failure to index it shouldn't fail the save.
+    async fn index_stored_entity(&self, link: &ExactLink, entity: std::io::Result<Entity>) {
+        let indexed = match entity {
+            Ok(entity) => self.search.index_entity(self, link, &entity).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = indexed {
+            tracing::warn!("Could not index entity {link:?} for search: {e}");
+        }
+    }
+}
+
+/// Call the same method on whichever store we are using.
+macro_rules! dispatch {
+    ($self:ident, $store:ident => $call:expr) => {
+        match &$self.backend {
+            BackendStore::Iroh($store) => $call,
+            BackendStore::Redb($store) => $call,
+        }
+    };
+}
+
+/// Like [`dispatch!`], but for methods that return a stream, which has a different type for each
+/// store.
+macro_rules! dispatch_stream {
+    ($self:ident, $store:ident => $call:expr) => {
+        match &$self.backend {
+            BackendStore::Iroh($store) => Ok(Either::Left($call.await?)),
+            BackendStore::Redb($store) => Ok(Either::Right($call.await?)),
+        }
+    };
+}
+
+impl LeafStore for ServerStore {
+    fn key_resolvers(&self) -> Box<dyn Iterator<Item = &dyn KeyResolverImpl<Digest>> + '_> {
+        dispatch!(self, s => s.key_resolvers())
+    }
+
+    fn encryption_algorithms(
+        &self,
+    ) -> Box<dyn Iterator<Item = &dyn EncryptionAlgorithmImpl<Digest>> + '_> {
+        dispatch!(self, s => s.encryption_algorithms())
+    }
+
+    fn subspace_id(&self, subspace_secret: &SubspaceSecretKey) -> SubspaceId {
+        dispatch!(self, s => s.subspace_id(subspace_secret))
+    }
+
+    async fn create_subspace(&self) -> anyhow::Result<SubspaceId> {
+        dispatch!(self, s => s.create_subspace().await)
+    }
+
+    async fn get_subspace_secret(
+        &self,
+        subspace: SubspaceId,
+    ) -> anyhow::Result<Option<SubspaceSecretKey>> {
+        dispatch!(self, s => s.get_subspace_secret(subspace).await)
+    }
+
+    async fn list_subspaces(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<SubspaceId>>> {
+        dispatch_stream!(self, s => s.list_subspaces())
+    }
+
+    async fn import_subspace_secret(
+        &self,
+        subspace_secret: SubspaceSecretKey,
+    ) -> anyhow::Result<SubspaceId> {
+        dispatch!(self, s => s.import_subspace_secret(subspace_secret).await)
+    }
+
+    fn namespace_id(&self, secret: &NamespaceSecretKey) -> NamespaceId {
+        dispatch!(self, s => s.namespace_id(secret))
+    }
+
+    async fn list_namespace_subspaces(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<SubspaceId>>> {
+        dispatch_stream!(self, s => s.list_namespace_subspaces(namespace))
+    }
+
+    async fn create_namespace(&self) -> anyhow::Result<NamespaceId> {
+        let namespace = dispatch!(self, s => s.create_namespace().await)?;
+        self.follow_namespace(namespace);
+        Ok(namespace)
+    }
+
+    async fn list_namespaces(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<NamespaceId>>> {
+        dispatch_stream!(self, s => s.list_namespaces())
+    }
+
+    async fn get_namespace_secret(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<Option<NamespaceSecretKey>> {
+        dispatch!(self, s => s.get_namespace_secret(namespace).await)
+    }
+
+    async fn import_namespace_secret(&self, secret: [u8; 32]) -> anyhow::Result<NamespaceId> {
+        let namespace = dispatch!(self, s => s.import_namespace_secret(secret).await)?;
+        self.follow_namespace(namespace);
+        Ok(namespace)
+    }
+
+    async fn store_blob(
+        &self,
+        data: &[u8],
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
+        dispatch!(self, s => s.store_blob(data, link, entity_snapshot_id).await)
+    }
+
+    async fn pin_blob(
+        &self,
+        digest: Digest,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<bool> {
+        dispatch!(self, s => s.pin_blob(digest, link, entity_snapshot_id).await)
+    }
+
+    async fn del_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<usize> {
+        dispatch!(self, s => s.del_blobs(link, entity_snapshot_id).await)
+    }
+
+    async fn pinned_blobs(
+        &self,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Vec<Digest>> {
+        dispatch!(self, s => s.pinned_blobs(link, entity_snapshot_id).await)
+    }
+
+    async fn gc(&self) -> anyhow::Result<GcReport> {
+        dispatch!(self, s => s.gc().await)
+    }
+
+    async fn get_blob(&self, digest: Digest) -> anyhow::Result<Vec<u8>> {
+        dispatch!(self, s => s.get_blob(digest).await)
+    }
+
+    async fn put_blob_stream(
+        &self,
+        data: impl Stream<Item = anyhow::Result<Vec<u8>>>,
+        link: &ExactLink,
+        entity_snapshot_id: Digest,
+    ) -> anyhow::Result<Digest> {
+        dispatch!(self, s => s.put_blob_stream(data, link, entity_snapshot_id).await)
+    }
+
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+        range: BlobRange,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Vec<u8>>>> {
+        dispatch_stream!(self, s => s.get_blob_reader(digest, range))
+    }
+
+    async fn blob_size(&self, digest: Digest) -> anyhow::Result<Option<u64>> {
+        dispatch!(self, s => s.blob_size(digest).await)
+    }
+
+    async fn store_entity(&self, link: &ExactLink, data: Vec<u8>) -> anyhow::Result<Digest> {
+        let entity = Entity::deserialize(&mut &data[..]);
+        let digest = dispatch!(self, s => s.store_entity(link, data).await)?;
+        self.index_stored_entity(link, entity).await;
+        Ok(digest)
+    }
+
+    async fn store_entities(&self, writes: Vec<EntityWrite>) -> anyhow::Result<Vec<Digest>> {
+        // Check-only writes don't change the entity, so there's nothing to index for them.
+        let entities = writes
+            .iter()
+            .filter_map(|write| {
+                let data = write.data.as_ref()?;
+                Some((write.link.clone(), Entity::deserialize(&mut &data[..])))
+            })
+            .collect::<Vec<_>>();
+        let digests = dispatch!(self, s => s.store_entities(writes).await)?;
+        for (link, entity) in entities {
+            self.index_stored_entity(&link, entity).await;
+        }
+        Ok(digests)
+    }
+
+    async fn del_entity(&self, link: &ExactLink) -> anyhow::Result<()> {
+        dispatch!(self, s => s.del_entity(link).await)?;
+        self.search.remove(link);
+        Ok(())
+    }
+
+    async fn get_entity(&self, link: &ExactLink) -> anyhow::Result<Option<Digest>> {
+        dispatch!(self, s => s.get_entity(link).await)
+    }
+
+    async fn get_entity_meta(
+        &self,
+        link: &ExactLink,
+    ) -> anyhow::Result<Option<(Digest, EntityMeta)>> {
+        dispatch!(self, s => s.get_entity_meta(link).await)
+    }
+
+    async fn list(
+        &self,
+        link: ExactLink,
+        mode: ListMode,
+        limit: Option<u64>,
+        offset: Option<u64>,
+        cursor: Option<ListCursor>,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ExactLink>>> {
+        dispatch_stream!(self, s => s.list(link, mode, limit, offset, cursor))
+    }
+
+    async fn update_schema_index(
+        &self,
+        link: &ExactLink,
+        removed: &[Digest],
+        added: &[Digest],
+    ) -> anyhow::Result<()> {
+        dispatch!(self, s => s.update_schema_index(link, removed, added).await)
+    }
+
+    async fn find_by_schema(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        schema: Digest,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ExactLink>>> {
+        dispatch_stream!(self, s => s.find_by_schema(namespace, subspace, schema))
+    }
+
+    fn field_indexes(&self) -> &FieldIndexes {
+        dispatch!(self, s => s.field_indexes())
+    }
+
+    async fn register_field_index(
+        &self,
+        index: FieldIndex,
+        format: BorshSchema,
+    ) -> anyhow::Result<Option<FieldIndex>> {
+        dispatch!(self, s => s.register_field_index(index, format).await)
+    }
+
+    async fn unregister_field_index(&self, index: Digest) -> anyhow::Result<()> {
+        dispatch!(self, s => s.unregister_field_index(index).await)
+    }
+
+    async fn update_field_index(
+        &self,
+        link: &ExactLink,
+        index: Digest,
+        removed: &[Vec<u8>],
+        added: &[Vec<u8>],
+    ) -> anyhow::Result<()> {
+        dispatch!(self, s => s.update_field_index(link, index, removed, added).await)
+    }
+
+    async fn claim_field_index_values(
+        &self,
+        link: &ExactLink,
+        index: &FieldIndex,
+        values: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        dispatch!(self, s => s.claim_field_index_values(link, index, values).await)
+    }
+
+    async fn lookup_field_index(
+        &self,
+        namespace: NamespaceId,
+        index: Digest,
+        value: &[u8],
+    ) -> anyhow::Result<Vec<ExactLink>> {
+        dispatch!(self, s => s.lookup_field_index(namespace, index, value).await)
+    }
+
+    async fn watch(
+        &self,
+        link: ExactLink,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<EntityUpdate>>> {
+        dispatch_stream!(self, s => s.watch(link))
+    }
+
+    async fn watch_namespace(
+        &self,
+        namespace: NamespaceId,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<EntityUpdate>>> {
+        dispatch_stream!(self, s => s.watch_namespace(namespace))
+    }
+}
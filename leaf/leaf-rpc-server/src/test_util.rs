@@ -0,0 +1,37 @@
+//! Helpers shared by the unit tests.
+
+use std::time::Duration;
+
+use leaf_protocol::prelude::*;
+
+use crate::store::{BackendStore, LeafServer, ServerStore};
+
+/// A redb store in an in-memory database, which garbage collects everything that isn't live right
+/// away.
+pub fn redb_store() -> LeafRedbStore {
+    let db = redb::Database::builder()
+        .create_with_backend(redb::backends::InMemoryBackend::new())
+        .unwrap();
+    let mut store = LeafRedbStore::new(db).unwrap();
+    store.gc_grace_period = Duration::ZERO;
+    store
+}
+
+/// A server backed by a [`redb_store()`].
+pub fn leaf() -> LeafServer {
+    Leaf::new(ServerStore::new(BackendStore::Redb(redb_store())))
+}
+
+/// A link to the entity at `path` in the test namespace and subspace.
+pub async fn link<S: LeafStore + Clone>(leaf: &Leaf<S>, path: &str) -> ExactLink {
+    let namespace = leaf.store.import_namespace_secret([1; 32]).await.unwrap();
+    let subspace = leaf.store.import_subspace_secret([2; 32]).await.unwrap();
+    (namespace, subspace, [path]).into()
+}
+
+/// Set the [`Name`] of the entity at the link.
+pub async fn save_name<S: LeafStore + Clone>(leaf: &Leaf<S>, link: &ExactLink, name: &str) {
+    let mut entity = leaf.entity(link.clone()).await.unwrap().get_or_init();
+    entity.set_component(Name(name.into())).unwrap();
+    entity.save().await.unwrap();
+}
@@ -88,6 +88,42 @@ export const ExactLinkSchema = BorshSchema.Struct({
 	path: EntityPathSchema
 });
 
+export type ListMode = { Children: Unit } | { Descendants: Unit };
+export const ListModeSchema = BorshSchema.Enum({
+	Children: BorshSchema.Unit,
+	Descendants: BorshSchema.Unit
+});
+
+/** An opaque position in a list of entities, used to fetch the next page. */
+export type ListCursor = EntityPath;
+export const ListCursorSchema = EntityPathSchema;
+
+/**
+ * Metadata about the latest snapshot of an entity. `last_modified` is in microseconds since the
+ * unix epoch, and `size` is the size of the snapshot in bytes, not counting its components.
+ */
+export type EntityMeta = { last_modified: bigint; author_subspace: SubspaceId; size: bigint };
+export const EntityMetaSchema = BorshSchema.Struct({
+	last_modified: BorshSchema.u64,
+	author_subspace: SubspaceIdSchema,
+	size: BorshSchema.u64
+});
+
+/** A page of entity links. `meta` holds the metadata of each entity in `links`, in the same order. */
+export type ListPage = { links: ExactLink[]; meta: EntityMeta[]; next: ListCursor | null };
+export const ListPageSchema = BorshSchema.Struct({
+	links: BorshSchema.Vec(ExactLinkSchema),
+	meta: BorshSchema.Vec(EntityMetaSchema),
+	next: BorshSchema.Option(ListCursorSchema)
+});
+
+/** A byte range of a blob. A `len` of `null` reads to the end of the blob. */
+export type BlobRange = { offset: bigint; len: bigint | null };
+export const BlobRangeSchema = BorshSchema.Struct({
+	offset: BorshSchema.u64,
+	len: BorshSchema.Option(BorshSchema.u64)
+});
+
 export type ComponentData = {
 	schema: Digest;
 	data: Uint8Array;
@@ -142,14 +178,82 @@ export const DatabaseDumpSchema = BorshSchema.Struct({
 	subspace_secrets: BorshSchema.HashMap(SubspaceIdSchema, SubspaceSecretKeySchema)
 });
 
+/**
+ * Build the borsh schema for a `LeafBorshFormat` that is nested at most `depth` levels deep.
+ *
+ * `borsher` can't describe recursive types, so formats nested deeper than this can't be decoded.
+ */
+function leafBorshFormatSchema(depth: number): BorshSchema {
+	const inner = depth > 0 ? leafBorshFormatSchema(depth - 1) : BorshSchema.Unit;
+	const named = BorshSchema.Vec(BorshSchema.Struct({ name: BorshSchema.String, schema: inner }));
+	return BorshSchema.Enum({
+		Null: BorshSchema.Unit,
+		Bool: BorshSchema.Unit,
+		U8: BorshSchema.Unit,
+		U16: BorshSchema.Unit,
+		U32: BorshSchema.Unit,
+		U64: BorshSchema.Unit,
+		U128: BorshSchema.Unit,
+		I8: BorshSchema.Unit,
+		I16: BorshSchema.Unit,
+		I32: BorshSchema.Unit,
+		I64: BorshSchema.Unit,
+		I128: BorshSchema.Unit,
+		F32: BorshSchema.Unit,
+		F64: BorshSchema.Unit,
+		String: BorshSchema.Unit,
+		Option: BorshSchema.Struct({ schema: inner }),
+		Array: BorshSchema.Struct({ schema: inner, len: BorshSchema.u32 }),
+		Struct: BorshSchema.Struct({ fields: named }),
+		Enum: BorshSchema.Struct({ variants: named }),
+		Vector: BorshSchema.Struct({ schema: inner }),
+		Map: BorshSchema.Struct({ key: inner, value: inner }),
+		Set: BorshSchema.Struct({ schema: inner }),
+		Blob: BorshSchema.Unit,
+		Snapshot: BorshSchema.Unit,
+		Link: BorshSchema.Unit
+	});
+}
+export const LeafBorshFormatSchema = leafBorshFormatSchema(16);
+
+export type LeafSchema = {
+	name: string;
+	format: LeafBorshFormat;
+	specification: Digest;
+};
+export const LeafSchemaSchema = BorshSchema.Struct({
+	name: BorshSchema.String,
+	format: LeafBorshFormatSchema,
+	specification: DigestSchema
+});
+
+export type BatchOp =
+	| { AddComponent: { link: ExactLink; component: ComponentData } }
+	| { DelComponents: { link: ExactLink; schema: Digest } }
+	| { Expect: { link: ExactLink; digest: Digest } };
+export const BatchOpSchema = BorshSchema.Enum({
+	AddComponent: BorshSchema.Struct({ link: ExactLinkSchema, component: ComponentDataSchema }),
+	DelComponents: BorshSchema.Struct({ link: ExactLinkSchema, schema: DigestSchema }),
+	Expect: BorshSchema.Struct({ link: ExactLinkSchema, digest: DigestSchema })
+});
+
 export type ReqKind =
 	| { Authenticate: string }
 	| { ReadEntity: ExactLink }
 	| { DelEntity: ExactLink }
 	| { GetComponentsBySchema: { link: ExactLink; schemas: Digest[] } }
-	| { DelComponentsBySchema: { link: ExactLink; schemas: Digest[] } }
-	| { AddComponents: { link: ExactLink; components: ComponentData[]; replace_existing: boolean } }
-	| { ListEntities: ExactLink }
+	| { DelComponentsBySchema: { link: ExactLink; schemas: Digest[]; expected_digest?: Digest } }
+	| {
+			AddComponents: {
+				link: ExactLink;
+				components: ComponentData[];
+				replace_existing: boolean;
+				expected_digest?: Digest;
+			};
+	  }
+	| {
+			ListEntities: { link: ExactLink; mode: ListMode; limit: bigint; cursor: ListCursor | null };
+	  }
 	| { CreateNamespace: Unit }
 	| { ImportNamespaceSecret: NamespaceId }
 	| { GetNamespaceSecret: NamespaceSecretKey }
@@ -162,7 +266,28 @@ export type ReqKind =
 	| { CreateDatabaseDump: Unit }
 	| { RestoreDatabaseDump: DatabaseDump }
 	| { ListNamespaces: Unit }
-	| { ListSubspaces: Unit };
+	| { ListSubspaces: Unit }
+	| { ReadEntityAt: { link: ExactLink; snapshot: Digest } }
+	| { GetComponentsBySchemaAt: { link: ExactLink; snapshot: Digest; schemas: Digest[] } }
+	| { Subscribe: ExactLink }
+	| { Unsubscribe: bigint }
+	| { PublishSchema: { schema_id: Digest; schema: LeafSchema } }
+	| { GetSchema: Digest }
+	| { Gc: Unit }
+	| { Batch: BatchOp[] }
+	| { CopyEntity: { from: ExactLink; to: ExactLink; recursive: boolean } }
+	| { MoveEntity: { from: ExactLink; to: ExactLink; recursive: boolean } }
+	| { DelSubtree: ExactLink }
+	| { FindBySchema: { namespace: NamespaceId; subspace: SubspaceId | null; schema: Digest } }
+	| { RegisterIndex: { schema: Digest; field_path: string[]; unique: boolean } }
+	| { LookupIndex: { namespace: NamespaceId; index: Digest; value: Uint8Array } }
+	| { Search: { namespace: NamespaceId; query: string; limit: bigint } }
+	| { RebuildSearchIndex: Unit }
+	| { StartBlobUpload: ExactLink }
+	| { BlobUploadChunk: { upload: bigint; data: Uint8Array } }
+	| { FinishBlobUpload: bigint }
+	| { CancelBlobUpload: bigint }
+	| { ReadBlob: { digest: Digest; range: BlobRange } };
 export const ReqKindSchema = BorshSchema.Enum({
 	Authenticate: BorshSchema.String,
 	ReadEntity: ExactLinkSchema,
@@ -173,14 +298,21 @@ export const ReqKindSchema = BorshSchema.Enum({
 	}),
 	DelComponentsBySchema: BorshSchema.Struct({
 		link: ExactLinkSchema,
-		schemas: BorshSchema.Vec(DigestSchema)
+		schemas: BorshSchema.Vec(DigestSchema),
+		expected_digest: BorshSchema.Option(DigestSchema)
 	}),
 	AddComponents: BorshSchema.Struct({
 		link: ExactLinkSchema,
 		components: BorshSchema.Vec(ComponentDataSchema),
-		replace_existing: BorshSchema.bool
+		replace_existing: BorshSchema.bool,
+		expected_digest: BorshSchema.Option(DigestSchema)
+	}),
+	ListEntities: BorshSchema.Struct({
+		link: ExactLinkSchema,
+		mode: ListModeSchema,
+		limit: BorshSchema.u64,
+		cursor: BorshSchema.Option(ListCursorSchema)
 	}),
-	ListEntities: ExactLinkSchema,
 	CreateNamespace: BorshSchema.Unit,
 	ImportNamespaceSecret: NamespaceSecretKeySchema,
 	GetNamespaceSecret: NamespaceIdSchema,
@@ -196,7 +328,62 @@ export const ReqKindSchema = BorshSchema.Enum({
 	CreateDatabaseDump: BorshSchema.Unit,
 	RestoreDatabaseDump: DatabaseDumpSchema,
 	ListNamespaces: BorshSchema.Unit,
-	ListSubspaces: BorshSchema.Unit
+	ListSubspaces: BorshSchema.Unit,
+	ReadEntityAt: BorshSchema.Struct({ link: ExactLinkSchema, snapshot: DigestSchema }),
+	GetComponentsBySchemaAt: BorshSchema.Struct({
+		link: ExactLinkSchema,
+		snapshot: DigestSchema,
+		schemas: BorshSchema.Vec(DigestSchema)
+	}),
+	Subscribe: ExactLinkSchema,
+	Unsubscribe: BorshSchema.u64,
+	PublishSchema: BorshSchema.Struct({ schema_id: DigestSchema, schema: LeafSchemaSchema }),
+	GetSchema: DigestSchema,
+	Gc: BorshSchema.Unit,
+	Batch: BorshSchema.Vec(BatchOpSchema),
+	CopyEntity: BorshSchema.Struct({
+		from: ExactLinkSchema,
+		to: ExactLinkSchema,
+		recursive: BorshSchema.bool
+	}),
+	MoveEntity: BorshSchema.Struct({
+		from: ExactLinkSchema,
+		to: ExactLinkSchema,
+		recursive: BorshSchema.bool
+	}),
+	DelSubtree: ExactLinkSchema,
+	FindBySchema: BorshSchema.Struct({
+		namespace: NamespaceIdSchema,
+		subspace: BorshSchema.Option(SubspaceIdSchema),
+		schema: DigestSchema
+	}),
+	RegisterIndex: BorshSchema.Struct({
+		schema: DigestSchema,
+		field_path: BorshSchema.Vec(BorshSchema.String),
+		unique: BorshSchema.bool
+	}),
+	LookupIndex: BorshSchema.Struct({
+		namespace: NamespaceIdSchema,
+		index: DigestSchema,
+		value: BorshSchema.Vec(BorshSchema.u8)
+	}),
+	Search: BorshSchema.Struct({
+		namespace: NamespaceIdSchema,
+		query: BorshSchema.String,
+		limit: BorshSchema.u64
+	}),
+	RebuildSearchIndex: BorshSchema.Unit,
+	StartBlobUpload: ExactLinkSchema,
+	BlobUploadChunk: BorshSchema.Struct({
+		upload: BorshSchema.u64,
+		data: BorshSchema.Vec(BorshSchema.u8)
+	}),
+	FinishBlobUpload: BorshSchema.u64,
+	CancelBlobUpload: BorshSchema.u64,
+	ReadBlob: BorshSchema.Struct({
+		digest: DigestSchema,
+		range: BlobRangeSchema
+	})
 });
 
 export type Req = {
@@ -243,14 +430,134 @@ export const GetComponentsInnerSchema = BorshSchema.Struct({
 	components: BorshSchema.HashMap(DigestSchema, BorshSchema.Vec(BorshSchema.Vec(BorshSchema.u8)))
 });
 
+export type EntityEvent =
+	| { Inserted: { link: ExactLink; snapshot: Digest } }
+	| { Updated: { link: ExactLink; old_snapshot: Digest; new_snapshot: Digest } }
+	| { Deleted: { link: ExactLink; old_snapshot: Digest } };
+export const EntityEventSchema = BorshSchema.Enum({
+	Inserted: BorshSchema.Struct({ link: ExactLinkSchema, snapshot: DigestSchema }),
+	Updated: BorshSchema.Struct({
+		link: ExactLinkSchema,
+		old_snapshot: DigestSchema,
+		new_snapshot: DigestSchema
+	}),
+	Deleted: BorshSchema.Struct({ link: ExactLinkSchema, old_snapshot: DigestSchema })
+});
+
+export type GcReport = {
+	pins_removed: bigint;
+	/**
+	 * The total size of the blobs whose pins were removed. Blobs that are still pinned by other
+	 * entities are counted too, so this is not how much space was freed.
+	 */
+	bytes_unpinned: bigint;
+};
+export const GcReportSchema = BorshSchema.Struct({
+	pins_removed: BorshSchema.u64,
+	bytes_unpinned: BorshSchema.u64
+});
+
+export type SearchResult = { link: ExactLink; score: number; snippet: string };
+export const SearchResultSchema = BorshSchema.Struct({
+	link: ExactLinkSchema,
+	score: BorshSchema.f32,
+	snippet: BorshSchema.String
+});
+
+export type ValidationErrorKind =
+	| { UnexpectedEnd: Unit }
+	| { TrailingData: { len: bigint } }
+	| { InvalidUtf8: Unit }
+	| { InvalidBool: number }
+	| { InvalidOptionTag: number }
+	| { InvalidEnumTag: { tag: number; variant_count: bigint } }
+	| { InvalidLink: Unit }
+	| { TooManyItems: { len: number } };
+export const ValidationErrorKindSchema = BorshSchema.Enum({
+	UnexpectedEnd: BorshSchema.Unit,
+	TrailingData: BorshSchema.Struct({ len: BorshSchema.u64 }),
+	InvalidUtf8: BorshSchema.Unit,
+	InvalidBool: BorshSchema.u8,
+	InvalidOptionTag: BorshSchema.u8,
+	InvalidEnumTag: BorshSchema.Struct({ tag: BorshSchema.u8, variant_count: BorshSchema.u64 }),
+	InvalidLink: BorshSchema.Unit,
+	TooManyItems: BorshSchema.Struct({ len: BorshSchema.u32 })
+});
+
+/** Where and how component data failed to match its schema. */
+export type ValidationError = { path: string[]; offset: bigint; kind: ValidationErrorKind };
+export const ValidationErrorSchema = BorshSchema.Struct({
+	path: BorshSchema.Vec(BorshSchema.String),
+	offset: BorshSchema.u64,
+	kind: ValidationErrorKindSchema
+});
+
+export type InvalidComponent =
+	| { UnpublishedSchema: Digest }
+	| { Mismatch: { schema: Digest; error: ValidationError } };
+export const InvalidComponentSchema = BorshSchema.Enum({
+	UnpublishedSchema: DigestSchema,
+	Mismatch: BorshSchema.Struct({ schema: DigestSchema, error: ValidationErrorSchema })
+});
+
+/**
+ * A component that was rejected by the server's validation. The index is the position of the
+ * component in `add_components()`, or of the operation in `batch()`.
+ */
+export type RejectedComponent = { index: bigint; error: InvalidComponent };
+export const RejectedComponentSchema = BorshSchema.Struct({
+	index: BorshSchema.u64,
+	error: InvalidComponentSchema
+});
+
+/**
+ * Thrown when a server that validates components rejects some of the components that were
+ * written, because their schema hasn't been published or they don't match it, in which case
+ * nothing is written.
+ */
+export class InvalidComponentsError extends Error {
+	rejected: RejectedComponent[];
+
+	constructor(rejected: RejectedComponent[]) {
+		super(`Leaf client error: ${rejected.length} invalid components were rejected`);
+		this.rejected = rejected;
+	}
+}
+
+export type SnapshotConflict = { link: ExactLink; expected: Digest; actual: Digest | null };
+export const SnapshotConflictSchema = BorshSchema.Struct({
+	link: ExactLinkSchema,
+	expected: DigestSchema,
+	actual: BorshSchema.Option(DigestSchema)
+});
+
+/**
+ * Thrown when an `expectedDigest` is passed to `add_components()` or `del_components()`, or an
+ * `Expect` operation is passed to `batch()`, and the entity no longer has that snapshot, in which
+ * case nothing is written. `actual` is the entity's current snapshot digest, or null if it doesn't
+ * exist.
+ */
+export class SnapshotConflictError extends Error {
+	link: ExactLink;
+	expected: Digest;
+	actual: Digest | null;
+
+	constructor(conflict: SnapshotConflict) {
+		super('Leaf client error: the entity has been changed since the expected snapshot');
+		this.link = conflict.link;
+		this.expected = new Uint8Array(conflict.expected);
+		this.actual = conflict.actual && new Uint8Array(conflict.actual);
+	}
+}
+
 export type RespKind =
 	| { Authenticated: Unit }
-	| { ReadEntity: { digest: Digest; entity: Entity } | null }
+	| { ReadEntity: { digest: Digest; entity: Entity; meta: EntityMeta } | null }
 	| { DelEntity: Unit }
 	| { GetComponentsBySchema: GetComponentsInner | null }
 	| { DelComponentsBySchema: Digest | null }
 	| { AddComponents: Digest }
-	| { ListEntities: ExactLink[] }
+	| { ListEntities: ListPage }
 	| { CreateNamespace: NamespaceId }
 	| { ImportNamespaceSecret: NamespaceId }
 	| { GetNamespaceSecret: NamespaceSecretKey | null }
@@ -263,17 +570,41 @@ export type RespKind =
 	| { CreateDatabaseDump: DatabaseDump }
 	| { RestoreDatabaseDump: Unit }
 	| { ListNamespaces: NamespaceId[] }
-	| { ListSubspaces: SubspaceId[] };
+	| { ListSubspaces: SubspaceId[] }
+	| { ReadEntityAt: Entity | null }
+	| { GetComponentsBySchemaAt: GetComponentsInner | null }
+	| { Subscribe: Unit }
+	| { Unsubscribe: Unit }
+	| { EntityEvent: EntityEvent }
+	| { PublishSchema: Unit }
+	| { GetSchema: LeafSchema | null }
+	| { Gc: GcReport }
+	| { Batch: { link: ExactLink; digest: Digest }[] }
+	| { CopyEntity: ExactLink[] }
+	| { MoveEntity: ExactLink[] }
+	| { DelSubtree: bigint }
+	| { FindBySchema: ExactLink[] }
+	| { RegisterIndex: Digest }
+	| { LookupIndex: ExactLink[] }
+	| { Search: SearchResult[] }
+	| { RebuildSearchIndex: bigint }
+	| { StartBlobUpload: Unit }
+	| { BlobUploadChunk: Unit }
+	| { FinishBlobUpload: Digest }
+	| { CancelBlobUpload: Unit }
+	| { ReadBlob: { size: bigint; data: Uint8Array } }
+	| { InvalidComponents: RejectedComponent[] }
+	| { SnapshotConflict: SnapshotConflict };
 export const RespKindSchema = BorshSchema.Enum({
 	Authenticated: BorshSchema.Unit,
 	ReadEntity: BorshSchema.Option(
-		BorshSchema.Struct({ digest: DigestSchema, entity: EntitySchema })
+		BorshSchema.Struct({ digest: DigestSchema, entity: EntitySchema, meta: EntityMetaSchema })
 	),
 	DelEntity: BorshSchema.Unit,
 	GetComponentsBySchema: BorshSchema.Option(GetComponentsInnerSchema),
 	DelComponentsBySchema: BorshSchema.Option(DigestSchema),
 	AddComponents: DigestSchema,
-	ListEntities: BorshSchema.Vec(ExactLinkSchema),
+	ListEntities: ListPageSchema,
 	CreateNamespace: NamespaceIdSchema,
 	ImportNamespaceSecret: NamespaceIdSchema,
 	GetNamespaceSecret: BorshSchema.Option(NamespaceSecretKeySchema),
@@ -288,7 +619,34 @@ export const RespKindSchema = BorshSchema.Enum({
 	CreateDatabaseDump: DatabaseDumpSchema,
 	RestoreDatabaseDump: BorshSchema.Unit,
 	ListNamespaces: BorshSchema.Vec(NamespaceIdSchema),
-	ListSubspaces: BorshSchema.Vec(SubspaceIdSchema)
+	ListSubspaces: BorshSchema.Vec(SubspaceIdSchema),
+	ReadEntityAt: BorshSchema.Option(EntitySchema),
+	GetComponentsBySchemaAt: BorshSchema.Option(GetComponentsInnerSchema),
+	Subscribe: BorshSchema.Unit,
+	Unsubscribe: BorshSchema.Unit,
+	EntityEvent: EntityEventSchema,
+	PublishSchema: BorshSchema.Unit,
+	GetSchema: BorshSchema.Option(LeafSchemaSchema),
+	Gc: GcReportSchema,
+	Batch: BorshSchema.Vec(BorshSchema.Struct({ link: ExactLinkSchema, digest: DigestSchema })),
+	CopyEntity: BorshSchema.Vec(ExactLinkSchema),
+	MoveEntity: BorshSchema.Vec(ExactLinkSchema),
+	DelSubtree: BorshSchema.u64,
+	FindBySchema: BorshSchema.Vec(ExactLinkSchema),
+	RegisterIndex: DigestSchema,
+	LookupIndex: BorshSchema.Vec(ExactLinkSchema),
+	Search: BorshSchema.Vec(SearchResultSchema),
+	RebuildSearchIndex: BorshSchema.u64,
+	StartBlobUpload: BorshSchema.Unit,
+	BlobUploadChunk: BorshSchema.Unit,
+	FinishBlobUpload: DigestSchema,
+	CancelBlobUpload: BorshSchema.Unit,
+	ReadBlob: BorshSchema.Struct({
+		size: BorshSchema.u64,
+		data: BorshSchema.Vec(BorshSchema.u8)
+	}),
+	InvalidComponents: BorshSchema.Vec(RejectedComponentSchema),
+	SnapshotConflict: SnapshotConflictSchema
 });
 
 export type RespResult = { Err: string } | { Ok: RespKind };
@@ -460,6 +818,11 @@ function arrEq(a: Uint8Array, b: Uint8Array) {
 	return a.length == b.length && a.every((v, i) => v == b[i]);
 }
 
+/** Copy decoded entity metadata so that its subspace is a plain `Uint8Array`. */
+function entity_meta(meta: EntityMeta): EntityMeta {
+	return { ...meta, author_subspace: new Uint8Array(meta.author_subspace) };
+}
+
 export class GetComponentsResult {
 	digest: Digest;
 	components: Map<new (...any: any) => Component, Component[]>;
@@ -475,6 +838,9 @@ export class GetComponentsResult {
 	}
 }
 
+/** The most data sent in each chunk by `RpcClient.put_blob()`. */
+const BLOB_UPLOAD_CHUNK_SIZE = 256 * 1024;
+
 export class RpcClient {
 	#ws: ReconnectingWebSocket;
 	#auth_token: undefined | string;
@@ -483,6 +849,7 @@ export class RpcClient {
 	#set_websocket_ready: undefined | (() => void) = undefined;
 	#next_req_id: bigint = 0n;
 	#pending_reqs: Map<bigint, (resp: Resp) => void> = new Map();
+	#subscriptions: Map<bigint, (resp: Resp) => void> = new Map();
 
 	#next_id(): bigint {
 		const i = this.#next_req_id;
@@ -490,7 +857,7 @@ export class RpcClient {
 		return i;
 	}
 
-	async #send_req(kind: ReqKind): Promise<Resp> {
+	async #send_req(kind: ReqKind, id?: bigint): Promise<Resp> {
 		if (this.#auth_token && !this.#authenticated) {
 			const resp = await this.#send_req_inner({ Authenticate: this.#auth_token });
 			if (resp.result && 'Ok' in resp.result) {
@@ -504,14 +871,14 @@ export class RpcClient {
 			this.#authenticated = true;
 		}
 
-		return await this.#send_req_inner(kind);
+		return await this.#send_req_inner(kind, id);
 	}
 
-	async #send_req_inner(kind: ReqKind): Promise<Resp> {
+	async #send_req_inner(kind: ReqKind, id?: bigint): Promise<Resp> {
 		return new Promise(async (resolve) => {
 			await this.#ready;
 			const req: Req = {
-				id: this.#next_id(),
+				id: id ?? this.#next_id(),
 				kind
 			};
 			const reqData = borshSerialize(ReqSchema, req);
@@ -541,8 +908,14 @@ export class RpcClient {
 		this.#ws.onmessage = async (ev: MessageEvent) => {
 			const data = 'arrayBuffer' in ev.data ? new Uint8Array(await ev.data.arrayBuffer()) : ev.data;
 			const resp: Resp = borshDeserialize(RespSchema, data);
-			this.#pending_reqs.get(resp.id)!(resp);
-			this.#pending_reqs.delete(resp.id);
+			const pending = this.#pending_reqs.get(resp.id);
+			if (pending) {
+				pending(resp);
+				this.#pending_reqs.delete(resp.id);
+			} else {
+				// Responses that aren't pending may be subscription events
+				this.#subscriptions.get(resp.id)?.(resp);
+			}
 		};
 	}
 
@@ -554,7 +927,10 @@ export class RpcClient {
 		}
 	}
 
-	async read_entity(link: ExactLink): Promise<{ digest: Digest; entity: Entity } | null> {
+	/** Read the latest snapshot of an entity, along with its digest and metadata. */
+	async read_entity(
+		link: ExactLink
+	): Promise<{ digest: Digest; entity: Entity; meta: EntityMeta } | null> {
 		const resp = await this.#send_req({ ReadEntity: link });
 		const respKind = this.#unwrap_resp(resp);
 		if ('ReadEntity' in respKind) {
@@ -566,7 +942,8 @@ export class RpcClient {
 							component_id: new Uint8Array(ent.component_id),
 							schema_id: ent.schema_id && new Uint8Array(ent.schema_id)
 						};
-					})
+					}),
+					meta: entity_meta(respKind.ReadEntity.meta)
 				}
 			);
 		} else {
@@ -574,6 +951,29 @@ export class RpcClient {
 		}
 	}
 
+	/**
+	 * Read an entity as it was at a previous snapshot digest, or `null` if the server doesn't have
+	 * the snapshot.
+	 */
+	async read_entity_at(link: ExactLink, snapshot: Digest): Promise<Entity | null> {
+		const resp = await this.#send_req({ ReadEntityAt: { link, snapshot } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('ReadEntityAt' in respKind) {
+			const entity = respKind.ReadEntityAt;
+			if (!entity) {
+				return null;
+			}
+			return entity.map((ent) => {
+				return {
+					component_id: new Uint8Array(ent.component_id),
+					schema_id: ent.schema_id && new Uint8Array(ent.schema_id)
+				};
+			});
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
 	async del_entity(link: ExactLink): Promise<Unit> {
 		const resp = await this.#send_req({ DelEntity: link });
 		const respKind = this.#unwrap_resp(resp);
@@ -584,35 +984,101 @@ export class RpcClient {
 		}
 	}
 
-	async list_entities(link: ExactLink): Promise<ExactLink[]> {
-		const resp = await this.#send_req({ ListEntities: link });
+	/**
+	 * Delete the entity at the link and all of the entities under it, returning the number of
+	 * entities that were deleted.
+	 *
+	 * This is not atomic: if one of the entities can't be deleted, the thrown error says how many
+	 * entities were already deleted.
+	 */
+	async del_subtree(link: ExactLink): Promise<bigint> {
+		const resp = await this.#send_req({ DelSubtree: link });
+		const respKind = this.#unwrap_resp(resp);
+		if ('DelSubtree' in respKind) {
+			return respKind.DelSubtree;
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * List the entities under a link.
+	 *
+	 * @param link the link to list the entities under
+	 * @param mode whether to list only the direct children of the link, or all of its descendants.
+	 */
+	async list_entities(
+		link: ExactLink,
+		mode: ListMode = { Descendants: {} }
+	): Promise<ExactLink[]> {
+		const links: ExactLink[] = [];
+		let cursor: ListCursor | null = null;
+		do {
+			const page: ListPage = await this.list_entities_page(link, mode, 1000n, cursor);
+			links.push(...page.links);
+			cursor = page.next;
+		} while (cursor);
+		return links;
+	}
+
+	/**
+	 * List a page of the entities under a link, along with their metadata.
+	 *
+	 * @param link the link to list the entities under
+	 * @param mode whether to list only the direct children of the link, or all of its descendants.
+	 * @param limit the maximum number of links to return.
+	 * @param cursor the `next` cursor from the previous page, or `null` to start from the beginning.
+	 */
+	async list_entities_page(
+		link: ExactLink,
+		mode: ListMode,
+		limit: bigint,
+		cursor: ListCursor | null = null
+	): Promise<ListPage> {
+		const resp = await this.#send_req({ ListEntities: { link, mode, limit, cursor } });
 		const respKind = this.#unwrap_resp(resp);
 		if ('ListEntities' in respKind) {
-			return respKind.ListEntities.map((ent) => {
-				return {
-					namespace: new Uint8Array(ent.namespace),
-					subspace: new Uint8Array(ent.subspace),
-					path: ent.path
-				};
-			});
+			return {
+				links: respKind.ListEntities.links.map((ent) => {
+					return {
+						namespace: new Uint8Array(ent.namespace),
+						subspace: new Uint8Array(ent.subspace),
+						path: ent.path
+					};
+				}),
+				meta: respKind.ListEntities.meta.map(entity_meta),
+				next: respKind.ListEntities.next
+			};
 		} else {
 			throw 'Invalid RPC response';
 		}
 	}
 
+	/**
+	 * Delete all components of the given types from the entity.
+	 *
+	 * @param link the entity to delete the components from
+	 * @param components the list of component types to delete
+	 * @param expectedDigest if set, the delete will fail if the entity has been changed since it
+	 * had this snapshot digest.
+	 */
 	async del_components(
 		link: ExactLink,
-		components: (new (...any: any) => Component)[]
+		components: (new (...any: any) => Component)[],
+		expectedDigest?: Digest
 	): Promise<Digest | null> {
 		const resp = await this.#send_req({
 			DelComponentsBySchema: {
 				link,
-				schemas: components.map((component) => (component as any).schemaId())
+				schemas: components.map((component) => (component as any).schemaId()),
+				expected_digest: expectedDigest
 			}
 		});
 		const respKind = this.#unwrap_resp(resp);
 		if ('DelComponentsBySchema' in respKind) {
 			return respKind.DelComponentsBySchema && new Uint8Array(respKind.DelComponentsBySchema);
+		} else if ('SnapshotConflict' in respKind) {
+			throw new SnapshotConflictError(respKind.SnapshotConflict);
 		} else {
 			throw 'Invalid RPC response';
 		}
@@ -639,11 +1105,14 @@ export class RpcClient {
 	 * @param link the entity to update the components of
 	 * @param components the list of components
 	 * @param replaceExisting whether or not added components replace existing ones.
+	 * @param expectedDigest if set, the update will fail if the entity has been changed since it
+	 * had this snapshot digest.
 	 */
 	async update_components<C extends Component>(
 		link: ExactLink,
 		components: (C | (new (...any: any) => Component))[],
-		replaceExisting = true
+		replaceExisting = true,
+		expectedDigest?: Digest
 	) {
 		const toAdd = [];
 		const toDelete = [];
@@ -659,15 +1128,17 @@ export class RpcClient {
 
 		// TODO: add RPC method for adding and deleting at the same time.
 		if (toDelete.length > 0) {
-			await this.del_components(link, toDelete as any);
+			const digest = await this.del_components(link, toDelete as any, expectedDigest);
+			if (expectedDigest && digest) expectedDigest = digest;
 		}
-		await this.add_components(link, toAdd as any, replaceExisting);
+		await this.add_components(link, toAdd as any, replaceExisting, expectedDigest);
 	}
 
 	async add_components<C extends Component>(
 		link: ExactLink,
 		components: (C | { schema: Digest; data: Uint8Array })[],
-		replaceExisting = true
+		replaceExisting = true,
+		expectedDigest?: Digest
 	): Promise<Digest> {
 		let componentData = components.map((component) => {
 			return component instanceof Component
@@ -681,12 +1152,17 @@ export class RpcClient {
 			AddComponents: {
 				link,
 				components: componentData,
-				replace_existing: replaceExisting
+				replace_existing: replaceExisting,
+				expected_digest: expectedDigest
 			}
 		});
 		const respKind = this.#unwrap_resp(resp);
 		if ('AddComponents' in respKind) {
 			return new Uint8Array(respKind.AddComponents);
+		} else if ('InvalidComponents' in respKind) {
+			throw new InvalidComponentsError(respKind.InvalidComponents);
+		} else if ('SnapshotConflict' in respKind) {
+			throw new SnapshotConflictError(respKind.SnapshotConflict);
 		} else {
 			throw 'Invalid RPC response';
 		}
@@ -704,30 +1180,40 @@ export class RpcClient {
 			}
 		});
 		const respKind = this.#unwrap_resp(resp);
-		const map: Map<new (...any: any) => Component, Component[]> = new Map();
 		if ('GetComponentsBySchema' in respKind) {
 			let resp = respKind.GetComponentsBySchema;
 			if (resp) {
-				for (const data of resp.components) {
-					const [comps_schema, comps_bytes] = data;
-					let ctor = null;
-					for (const c of components) {
-						const id = (c as any).schemaId();
-						if (arrEq(id, comps_schema)) {
-							ctor = c;
-							break;
-						}
-					}
-					if (!ctor) throw 'Unreachable';
-					const list = [];
-					for (const comp_bytes of comps_bytes) {
-						const c = new ctor();
-						c.value = (ctor as any).deserialize(comp_bytes);
-						list.push(c);
-					}
-					map.set(ctor, list);
-				}
-				return new GetComponentsResult(new Uint8Array(resp.entity_digest), map);
+				return this.#parse_components(resp, components);
+			} else {
+				return null;
+			}
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Get components from an entity as it was at a previous snapshot digest, or `null` if the
+	 * server doesn't have the snapshot.
+	 */
+	async get_components_at(
+		link: ExactLink,
+		snapshot: Digest,
+		...components: (new (...any: any) => Component)[]
+	): Promise<GetComponentsResult | null> {
+		const schemas = components.map((component) => (component as any).schemaId());
+		const resp = await this.#send_req({
+			GetComponentsBySchemaAt: {
+				link,
+				snapshot,
+				schemas
+			}
+		});
+		const respKind = this.#unwrap_resp(resp);
+		if ('GetComponentsBySchemaAt' in respKind) {
+			let resp = respKind.GetComponentsBySchemaAt;
+			if (resp) {
+				return this.#parse_components(resp, components);
 			} else {
 				return null;
 			}
@@ -736,6 +1222,363 @@ export class RpcClient {
 		}
 	}
 
+	#parse_components(
+		resp: GetComponentsInner,
+		components: (new (...any: any) => Component)[]
+	): GetComponentsResult {
+		const map: Map<new (...any: any) => Component, Component[]> = new Map();
+		for (const data of resp.components) {
+			const [comps_schema, comps_bytes] = data;
+			let ctor = null;
+			for (const c of components) {
+				const id = (c as any).schemaId();
+				if (arrEq(id, comps_schema)) {
+					ctor = c;
+					break;
+				}
+			}
+			if (!ctor) throw 'Unreachable';
+			const list = [];
+			for (const comp_bytes of comps_bytes) {
+				const c = new ctor();
+				c.value = (ctor as any).deserialize(comp_bytes);
+				list.push(c);
+			}
+			map.set(ctor, list);
+		}
+		return new GetComponentsResult(new Uint8Array(resp.entity_digest), map);
+	}
+
+	/**
+	 * Subscribe to changes to the entity at the link and all of the entities under it.
+	 *
+	 * Subscriptions are not restored if the connection to the server is lost.
+	 *
+	 * @param link the link to watch for changes
+	 * @param onEvent called with each change
+	 * @param onError called if the subscription fails, after which no more events are sent.
+	 * @returns the subscription ID, which may be passed to `unsubscribe()`.
+	 */
+	async subscribe(
+		link: ExactLink,
+		onEvent: (event: EntityEvent) => void,
+		onError?: (error: string) => void
+	): Promise<bigint> {
+		const id = this.#next_id();
+		this.#subscriptions.set(id, (resp) => {
+			if ('Ok' in resp.result) {
+				if ('EntityEvent' in resp.result.Ok) {
+					onEvent(resp.result.Ok.EntityEvent);
+				}
+			} else {
+				this.#subscriptions.delete(id);
+				onError?.(resp.result.Err);
+			}
+		});
+		try {
+			const resp = await this.#send_req({ Subscribe: link }, id);
+			const respKind = this.#unwrap_resp(resp);
+			if (!('Subscribe' in respKind)) {
+				throw 'Invalid RPC response';
+			}
+		} catch (e) {
+			this.#subscriptions.delete(id);
+			throw e;
+		}
+		return id;
+	}
+
+	/** Cancel a subscription created with `subscribe()`. */
+	async unsubscribe(subscription: bigint): Promise<void> {
+		this.#subscriptions.delete(subscription);
+		const resp = await this.#send_req({ Unsubscribe: subscription });
+		const respKind = this.#unwrap_resp(resp);
+		if ('Unsubscribe' in respKind) {
+			return;
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	async publish_schema(schemaId: Digest, schema: LeafSchema): Promise<void> {
+		const resp = await this.#send_req({ PublishSchema: { schema_id: schemaId, schema } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('PublishSchema' in respKind) {
+			return;
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	async get_schema(schemaId: Digest): Promise<LeafSchema | null> {
+		const resp = await this.#send_req({ GetSchema: schemaId });
+		const respKind = this.#unwrap_resp(resp);
+		if ('GetSchema' in respKind) {
+			return respKind.GetSchema;
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	async gc(): Promise<GcReport> {
+		const resp = await this.#send_req({ Gc: {} });
+		const respKind = this.#unwrap_resp(resp);
+		if ('Gc' in respKind) {
+			return respKind.Gc;
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Apply component changes to multiple entities at once, returning the new snapshot digest of
+	 * each entity that was changed. If any of the `Expect` checks fail, or the server rejects any
+	 * of the components, nothing is written. A failed check throws a `SnapshotConflictError`.
+	 */
+	async batch(ops: BatchOp[]): Promise<{ link: ExactLink; digest: Digest }[]> {
+		const resp = await this.#send_req({ Batch: ops });
+		const respKind = this.#unwrap_resp(resp);
+		if ('Batch' in respKind) {
+			return respKind.Batch.map(({ link, digest }) => ({ link, digest: new Uint8Array(digest) }));
+		} else if ('InvalidComponents' in respKind) {
+			throw new InvalidComponentsError(respKind.InvalidComponents);
+		} else if ('SnapshotConflict' in respKind) {
+			throw new SnapshotConflictError(respKind.SnapshotConflict);
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Copy the entity at `from` to `to`, along with all of the entities under it if `recursive` is
+	 * set, returning the links of the new entities.
+	 */
+	async copy_entity(from: ExactLink, to: ExactLink, recursive: boolean): Promise<ExactLink[]> {
+		const resp = await this.#send_req({ CopyEntity: { from, to, recursive } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('CopyEntity' in respKind) {
+			return respKind.CopyEntity.map((ent) => ({
+				namespace: new Uint8Array(ent.namespace),
+				subspace: new Uint8Array(ent.subspace),
+				path: ent.path
+			}));
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Move the entity at `from` to `to`, along with all of the entities under it if `recursive` is
+	 * set, returning the new links of the moved entities.
+	 *
+	 * Moves are not atomic: every entity is copied before any of the originals are deleted, but if
+	 * the move fails partway, the copies and deletes that were already done are not rolled back.
+	 */
+	async move_entity(from: ExactLink, to: ExactLink, recursive: boolean): Promise<ExactLink[]> {
+		const resp = await this.#send_req({ MoveEntity: { from, to, recursive } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('MoveEntity' in respKind) {
+			return respKind.MoveEntity.map((ent) => ({
+				namespace: new Uint8Array(ent.namespace),
+				subspace: new Uint8Array(ent.subspace),
+				path: ent.path
+			}));
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Find the entities in a namespace that have a component with the given schema, optionally only
+	 * looking in one subspace.
+	 */
+	async find_by_schema(
+		namespace: NamespaceId,
+		schema: Digest,
+		subspace: SubspaceId | null = null
+	): Promise<ExactLink[]> {
+		const resp = await this.#send_req({ FindBySchema: { namespace, subspace, schema } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('FindBySchema' in respKind) {
+			return respKind.FindBySchema.map((ent) => ({
+				namespace: new Uint8Array(ent.namespace),
+				subspace: new Uint8Array(ent.subspace),
+				path: ent.path
+			}));
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Start indexing a field of the components with the given schema, which must have been
+	 * published. If `unique` is set, saving an entity with a value that another entity in the
+	 * namespace already has will fail.
+	 *
+	 * Entities that were already saved are indexed too, and the server remembers the index when it
+	 * restarts.
+	 *
+	 * Returns the ID of the index, to pass to `lookup_index()`.
+	 */
+	async register_index(
+		schema: Digest,
+		field_path: string[],
+		unique: boolean = false
+	): Promise<Digest> {
+		const resp = await this.#send_req({ RegisterIndex: { schema, field_path, unique } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('RegisterIndex' in respKind) {
+			return new Uint8Array(respKind.RegisterIndex);
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Find the entities in a namespace with the given borsh-encoded value in the index with the
+	 * given ID.
+	 */
+	async lookup_index(
+		namespace: NamespaceId,
+		index: Digest,
+		value: Uint8Array
+	): Promise<ExactLink[]> {
+		const resp = await this.#send_req({ LookupIndex: { namespace, index, value } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('LookupIndex' in respKind) {
+			return respKind.LookupIndex.map((ent) => ({
+				namespace: new Uint8Array(ent.namespace),
+				subspace: new Uint8Array(ent.subspace),
+				path: ent.path
+			}));
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Search the text of the entities in a namespace, returning up to `limit` of the best matches.
+	 */
+	async search(
+		namespace: NamespaceId,
+		query: string,
+		limit: bigint = 20n
+	): Promise<SearchResult[]> {
+		const resp = await this.#send_req({ Search: { namespace, query, limit } });
+		const respKind = this.#unwrap_resp(resp);
+		if ('Search' in respKind) {
+			return respKind.Search.map((result) => ({
+				link: {
+					namespace: new Uint8Array(result.link.namespace),
+					subspace: new Uint8Array(result.link.subspace),
+					path: result.link.path
+				},
+				score: result.score,
+				snippet: result.snippet
+			}));
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Rebuild the server's search index from its store, returning the number of entities that were
+	 * indexed.
+	 */
+	async rebuild_search_index(): Promise<bigint> {
+		const resp = await this.#send_req({ RebuildSearchIndex: {} });
+		const respKind = this.#unwrap_resp(resp);
+		if ('RebuildSearchIndex' in respKind) {
+			return respKind.RebuildSearchIndex;
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/**
+	 * Upload a blob for the entity at the link, returning its digest.
+	 *
+	 * The server only keeps the blob for a short grace period unless a component that references
+	 * it is saved to the entity.
+	 */
+	async put_blob(link: ExactLink, data: Uint8Array): Promise<Digest> {
+		const upload = this.#next_id();
+		const resp = await this.#send_req({ StartBlobUpload: link }, upload);
+		if (!('StartBlobUpload' in this.#unwrap_resp(resp))) {
+			throw 'Invalid RPC response';
+		}
+
+		try {
+			for (let offset = 0; offset < data.length; offset += BLOB_UPLOAD_CHUNK_SIZE) {
+				const chunk = data.subarray(offset, offset + BLOB_UPLOAD_CHUNK_SIZE);
+				const resp = await this.#send_req({ BlobUploadChunk: { upload, data: chunk } });
+				if (!('BlobUploadChunk' in this.#unwrap_resp(resp))) {
+					throw 'Invalid RPC response';
+				}
+			}
+		} catch (e) {
+			// The server may have already dropped the upload, so the cancel is allowed to fail.
+			await this.#send_req({ CancelBlobUpload: upload }).catch(() => {});
+			throw e;
+		}
+
+		const finishResp = await this.#send_req({ FinishBlobUpload: upload });
+		const respKind = this.#unwrap_resp(finishResp);
+		if ('FinishBlobUpload' in respKind) {
+			return new Uint8Array(respKind.FinishBlobUpload);
+		} else {
+			throw 'Invalid RPC response';
+		}
+	}
+
+	/** Download a blob, or a range of it. */
+	async get_blob(
+		digest: Digest,
+		range: BlobRange = { offset: 0n, len: null }
+	): Promise<Uint8Array> {
+		const chunks = [];
+		for await (const chunk of this.get_blob_reader(digest, range)) {
+			chunks.push(chunk);
+		}
+		const data = new Uint8Array(chunks.reduce((len, chunk) => len + chunk.length, 0));
+		let offset = 0;
+		for (const chunk of chunks) {
+			data.set(chunk, offset);
+			offset += chunk.length;
+		}
+		return data;
+	}
+
+	/** Download a blob, or a range of it, one chunk at a time. */
+	async *get_blob_reader(
+		digest: Digest,
+		range: BlobRange = { offset: 0n, len: null }
+	): AsyncGenerator<Uint8Array> {
+		let offset = range.offset;
+		let remaining = range.len;
+		while (remaining === null || remaining > 0n) {
+			const resp = await this.#send_req({
+				ReadBlob: { digest, range: { offset, len: remaining } }
+			});
+			const respKind = this.#unwrap_resp(resp);
+			if (!('ReadBlob' in respKind)) {
+				throw 'Invalid RPC response';
+			}
+			const { size, data } = respKind.ReadBlob;
+			if (data.length == 0) {
+				break;
+			}
+			yield new Uint8Array(data);
+			offset += BigInt(data.length);
+			if (remaining !== null) {
+				remaining -= BigInt(data.length);
+			}
+			if (offset >= size) {
+				break;
+			}
+		}
+	}
+
 	async create_namespace(): Promise<NamespaceId> {
 		const resp = await this.#send_req({ CreateNamespace: {} });
 		const respKind = this.#unwrap_resp(resp);